@@ -1012,6 +1012,45 @@ namespace psibase::http
                 server.http_config->get_metrics,
                 "application/openmetrics-text; version=1.0.0; charset=utf-8");
          }
+         else if (req_target.starts_with("/native/admin/trace/") && server.http_config->get_trace)
+         {
+            if (!is_admin(*server.http_config, req_host))
+            {
+               return send(not_found(req.target()));
+            }
+            if (req.method() != bhttp::verb::get)
+            {
+               return send(method_not_allowed(req.target(), req.method_string(), "GET"));
+            }
+            if (!check_admin_auth(authz::mode_type::read))
+            {
+               return;
+            }
+
+            auto        hex = req_target.substr(std::string_view{"/native/admin/trace/"}.size());
+            Checksum256 id;
+            if (hex.size() != 2 * id.size() || !psio::unhex(id.begin(), hex.begin(), hex.end()))
+            {
+               return send(bad_request("Invalid transaction id"));
+            }
+
+            // Only recent transactions that were pushed to this node are available
+            run_native_handler(
+                [&](auto&& callback)
+                { server.http_config->get_trace(id, std::move(callback)); },
+                [error, ok, target = std::string(req.target())](auto&& result)
+                {
+                   if (!result)
+                   {
+                      return error(bhttp::status::not_found,
+                                   "The resource '" + target + "' was not found.");
+                   }
+                   std::vector<char>   data;
+                   psio::vector_stream stream{data};
+                   psio::to_json(*result, stream);
+                   return ok(std::move(data), "application/json");
+                });
+         }  // trace
          else if (req_target == "/native/admin/peers" && server.http_config->get_peers)
          {
             if (!is_admin(*server.http_config, req_host))
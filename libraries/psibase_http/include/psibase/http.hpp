@@ -29,6 +29,10 @@ namespace psibase::http
 
    using shutdown_t = std::function<void(std::vector<char>)>;
 
+   using get_trace_result   = std::optional<TransactionTrace>;
+   using get_trace_callback = std::function<void(get_trace_result)>;
+   using get_trace_t        = std::function<void(const Checksum256& id, get_trace_callback)>;
+
    using accept_p2p_websocket1 = boost::beast::websocket::stream<boost::beast::tcp_stream>;
 #ifdef PSIBASE_ENABLE_SSL
    using accept_p2p_websocket2 =
@@ -312,6 +316,7 @@ namespace psibase::http
       push_transaction_t     push_transaction_async = {};
       accept_p2p_websocket_t accept_p2p_websocket   = {};
       shutdown_t             shutdown               = {};
+      get_trace_t            get_trace              = {};
       get_config_t           get_perf               = {};
       get_config_t           get_metrics            = {};
       get_peers_t            get_peers              = {};
@@ -28,9 +28,11 @@
 #include <boost/asio/system_timer.hpp>
 
 #include <charconv>
+#include <deque>
 #include <filesystem>
 #include <fstream>
 #include <iostream>
+#include <map>
 #include <mutex>
 #include <thread>
 
@@ -960,6 +962,57 @@ struct TransactionStats
 };
 PSIO_REFLECT(TransactionStats, unprocessed, total, failed, succeeded, skipped)
 
+// Keeps the traces of the most recent transactions pushed to this node,
+// so that an administrator can fetch them again after the push returns.
+// The traces are kept packed, and the oldest are dropped to stay under
+// max_bytes.
+class RecentTraces
+{
+  public:
+   static constexpr std::size_t max_bytes = 16 * 1024 * 1024;
+
+   void add(const Checksum256& id, const TransactionTrace& trace)
+   {
+      auto packed = psio::to_frac(trace);
+      if (packed.size() > max_bytes)
+         return;
+      std::lock_guard l{mutex};
+      if (auto pos = traces.find(id); pos != traces.end())
+      {
+         bytes -= pos->second.size();
+         pos->second = std::move(packed);
+         bytes += pos->second.size();
+      }
+      else
+      {
+         bytes += packed.size();
+         traces.emplace(id, std::move(packed));
+         order.push_back(id);
+      }
+      while (bytes > max_bytes)
+      {
+         auto pos = traces.find(order.front());
+         bytes -= pos->second.size();
+         traces.erase(pos);
+         order.pop_front();
+      }
+   }
+
+   std::optional<TransactionTrace> get(const Checksum256& id)
+   {
+      std::lock_guard l{mutex};
+      if (auto pos = traces.find(id); pos != traces.end())
+         return psio::from_frac<TransactionTrace>(pos->second);
+      return std::nullopt;
+   }
+
+  private:
+   std::mutex                               mutex;
+   std::map<Checksum256, std::vector<char>> traces;
+   std::deque<Checksum256>                  order;
+   std::size_t                              bytes = 0;
+};
+
 struct Perf
 {
    std::int64_t            timestamp;
@@ -1594,6 +1647,7 @@ void run(const std::string&              db_path,
    //
    TransactionStats transactionStats = {};
    std::mutex       transactionStatsMutex;
+   auto             recentTraces = std::make_shared<RecentTraces>();
 
    if (system->sharedDatabase.isSlow())
    {
@@ -1854,7 +1908,7 @@ void run(const std::string&              db_path,
       };
 
       http_config->push_transaction_async =
-          [queue, &transactionStats, &transactionStatsMutex](
+          [queue, recentTraces, &transactionStats, &transactionStatsMutex](
               std::vector<char> packed_signed_trx, http::push_transaction_callback callback)
       {
          {
@@ -1862,10 +1916,32 @@ void run(const std::string&              db_path,
             ++transactionStats.total;
             ++transactionStats.unprocessed;
          }
+         // Malformed transactions are reported by pushTransaction
+         std::optional<Checksum256> id;
+         try
+         {
+            auto trx = psio::from_frac<SignedTransaction>(packed_signed_trx);
+            id       = sha256(trx.transaction.data(), trx.transaction.size());
+         }
+         RETHROW_BAD_ALLOC
+         CATCH_IGNORE
+         if (id)
+         {
+            callback = [recentTraces, id = *id,
+                        callback = std::move(callback)](http::push_transaction_result result)
+            {
+               if (auto* trace = std::get_if<TransactionTrace>(&result))
+                  recentTraces->add(id, *trace);
+               callback(std::move(result));
+            };
+         }
          std::scoped_lock lock{queue->mutex};
          queue->entries.push_back({false, std::move(packed_signed_trx), {}, std::move(callback)});
       };
 
+      http_config->get_trace = [recentTraces](const Checksum256& id, http::get_trace_callback callback)
+      { callback(recentTraces->get(id)); };
+
       http_config->accept_p2p_websocket = [&chainContext, &node](auto&& stream)
       {
          boost::asio::post(
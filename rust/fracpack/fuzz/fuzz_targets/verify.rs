@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../tests/common/verify.rs"]
+mod shared;
+use shared::{check, Outer, Variant};
+
+fuzz_target!(|data: &[u8]| {
+    check::<Outer>(data);
+    check::<Variant>(data);
+    check::<Vec<String>>(data);
+    check::<(u32, Option<String>)>(data);
+});
@@ -1,4 +1,3 @@
-// TODO: fix reading structs and tuples which have unknown fields
 // TODO: option to allow/disallow unknown fields during verify and unpack
 // TODO: replace 'a with 'de; change macro to look for 'de specifically instead of assuming
 
@@ -42,15 +41,89 @@
 //! Note: `#[fracpack(fracpack_mod = "fracpack")]` is only needed when using the `fracpack`
 //! library directly instead of through the [psibase crate](https://docs.rs/psibase).
 //!
+//! # Transparent structs
+//!
+//! Tuple structs with one field already pack like that field. Add
+//! `transparent` to give a struct with one named field the same layout,
+//! so that a wrapper can replace its inner type without changing the
+//! encoding:
+//!
+//! ```
+//! use fracpack::{Pack, Unpack};
+//!
+//! #[derive(Pack, Unpack, PartialEq, Debug)]
+//! #[fracpack(fracpack_mod = "fracpack", transparent)]
+//! struct Name {
+//!     value: String,
+//! }
+//!
+//! let name = Name { value: "alice".into() };
+//! assert_eq!(name.packed(), String::from("alice").packed());
+//! ```
+//!
+//! # Extensible types
+//!
+//! Structs are extensible unless they are marked
+//! `definition_will_not_change`, and tuples are always extensible.
+//! Newer versions of an extensible type may add optional fields at the
+//! end. Older code unpacks and verifies such data by skipping the fields
+//! it doesn't know about, so each unknown field must be an offset
+//! pointer: 0 or 1 if it is empty, otherwise pointing at its heap data.
+//! The unknown heap data can't be interpreted, so it is skipped along with
+//! anything else up to the next offset that is known. Non-extensible
+//! structs, enums, and the other built-in types can't gain fields.
+//!
+//! ```
+//! use fracpack::{Pack, Unpack};
+//!
+//! #[derive(Pack, Unpack, PartialEq, Debug)]
+//! #[fracpack(fracpack_mod = "fracpack")]
+//! struct Old {
+//!     name: String,
+//! }
+//!
+//! #[derive(Pack, Unpack)]
+//! #[fracpack(fracpack_mod = "fracpack")]
+//! struct New {
+//!     name: String,
+//!     note: Option<String>,
+//! }
+//!
+//! let packed = New { name: "a".into(), note: Some("b".into()) }.packed();
+//! assert_eq!(Old::unpacked(&packed)?, Old { name: "a".into() });
+//! assert!(Old::verify_all(&packed).is_ok());
+//! # Ok::<(), fracpack::Error>(())
+//! ```
+//!
 //! # Caution
 //!
 //! It's easy to accidentally convert from a fixed-size
 //! array reference (`&[T;7]`) to a slice (`&[T]`). This matters
 //! to fracpack, which has different, and incompatible, encodings
 //! for the two types.
+//!
+//! # Fuzzing
+//!
+//! `fuzz/` holds a [cargo-fuzz](https://github.com/rust-fuzz/cargo-fuzz)
+//! target which feeds arbitrary bytes to `verify` and `unpack`. Run it
+//! with `cargo +nightly fuzz run verify` from this crate's directory.
 
 use custom_error::custom_error;
-use std::{cell::RefCell, mem, rc::Rc, sync::Arc};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{BTreeSet, HashSet},
+    hash::{BuildHasher, Hash},
+    io::{self, Seek, SeekFrom, Write},
+    mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    num::{
+        NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI8, NonZeroU16, NonZeroU32, NonZeroU64,
+        NonZeroU8, Wrapping,
+    },
+    rc::Rc,
+    sync::Arc,
+};
 
 pub use psibase_macros::{Pack, Unpack};
 
@@ -61,6 +134,10 @@ custom_error! {pub Error
     BadUTF8             = "Bad UTF-8 encoding",
     BadEnumIndex        = "Bad enum index",
     ExtraData           = "Extra data in buffer",
+    NonCanonical        = "Data is not in canonical form",
+    BadNonZero          = "Zero value for a non-zero type",
+    UnsortedSet         = "Set elements are out of order or repeated",
+    TrailingData        = "Trailing data after the end of the message",
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -130,6 +207,32 @@ pub trait Pack {
         bytes
     }
 
+    /// Convert to fracpack format, writing the result to `dest`
+    ///
+    /// Offsets within the output are filled in after the data they
+    /// point to has been written, so `dest` must support seeking.
+    /// Structs, vectors, and options are written one member at a time.
+    /// Other types, such as the elements of a vector, are packed in
+    /// memory first, so memory use is bounded by the largest of these
+    /// instead of by the whole output. Since this issues many small
+    /// writes, wrap files in a [std::io::BufWriter].
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use fracpack::Pack;
+    /// use std::io::Cursor;
+    ///
+    /// let value = (7u32, vec!["a".to_string(), "b".to_string()]);
+    /// let mut dest = Cursor::new(Vec::new());
+    /// value.pack_into(&mut dest)?;
+    /// assert_eq!(dest.into_inner(), value.packed());
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    fn pack_into<W: Write + Seek>(&self, dest: &mut W) -> io::Result<()> {
+        dest.write_all(&self.packed())
+    }
+
     #[doc(hidden)]
     fn is_empty_container(&self) -> bool {
         false
@@ -158,6 +261,46 @@ pub trait Pack {
             self.pack(dest);
         }
     }
+
+    #[doc(hidden)]
+    fn embedded_fixed_pack_into<W: Write + Seek>(&self, dest: &mut W) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        self.embedded_fixed_pack(&mut bytes);
+        dest.write_all(&bytes)
+    }
+
+    // The current position of dest is the heap position
+    #[doc(hidden)]
+    fn embedded_fixed_repack_into<W: Write + Seek>(
+        &self,
+        fixed_pos: u64,
+        dest: &mut W,
+    ) -> io::Result<()> {
+        if !Self::VARIABLE_SIZE {
+            return Ok(());
+        }
+        let heap_pos = dest.stream_position()?;
+        let offset = u32::try_from(heap_pos - fixed_pos)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "fracpack offset overflow"))?;
+        let mut orig = Vec::new();
+        self.embedded_fixed_pack(&mut orig);
+        let mut fixed = orig.clone();
+        self.embedded_fixed_repack(0, offset, &mut fixed);
+        if fixed != orig {
+            dest.seek(SeekFrom::Start(fixed_pos))?;
+            dest.write_all(&fixed)?;
+            dest.seek(SeekFrom::Start(heap_pos))?;
+        }
+        Ok(())
+    }
+
+    #[doc(hidden)]
+    fn embedded_variable_pack_into<W: Write + Seek>(&self, dest: &mut W) -> io::Result<()> {
+        if Self::VARIABLE_SIZE && !self.is_empty_container() {
+            self.pack_into(dest)?;
+        }
+        Ok(())
+    }
 }
 
 /// Unpack fracpack data
@@ -190,8 +333,30 @@ pub trait Unpack<'a>: Sized {
     /// ```
     ///
     /// See [Pack::unpacked], which is often more convenient.
+    ///
+    /// If the data ends with the heap data of fields that `Self` doesn't
+    /// know about, its end can't be determined, and `pos` is moved to the
+    /// end of `src`.
     fn unpack(src: &'a [u8], pos: &mut u32) -> Result<Self>;
 
+    /// Like [Unpack::unpack], but clears `known_end` instead of moving
+    /// `pos` when the end of the data can't be determined. `known_end`
+    /// must be set on entry. Containers check their lengths against
+    /// `options` and pass it on to their contents.
+    ///
+    /// The default calls [Unpack::unpack]. Types that can contain
+    /// extensible structs override it, and implement [Unpack::unpack]
+    /// with [unpack_to_end].
+    #[doc(hidden)]
+    fn unpack_at(
+        src: &'a [u8],
+        pos: &mut u32,
+        _known_end: &mut bool,
+        _options: &UnpackOptions,
+    ) -> Result<Self> {
+        Self::unpack(src, pos)
+    }
+
     /// Convert from fracpack format. Also verifies the integrity of the data.
     ///
     /// This unpacks `Self` from `src`.
@@ -212,19 +377,87 @@ pub trait Unpack<'a>: Sized {
 
     /// Verify the integrity of fracpack data. You don't need to call this if
     /// using [Pack::unpack] since it verifies integrity during unpack.
+    ///
+    /// `pos` is moved to the end of `src` if the end of the data can't be
+    /// determined, as with [Unpack::unpack].
     fn verify(src: &'a [u8], pos: &mut u32) -> Result<()>;
 
-    /// Verify the integrity of fracpack data, plus make sure there is no
-    /// leftover data after it.
-    fn verify_no_extra(src: &'a [u8]) -> Result<()> {
+    /// Like [Unpack::verify], but clears `known_end` instead of moving
+    /// `pos` when the end of the data can't be determined. `known_end`
+    /// must be set on entry. `options` is used as in [Unpack::unpack_at].
+    ///
+    /// The default calls [Unpack::verify]. Types that override it
+    /// implement [Unpack::verify] with [verify_to_end].
+    #[doc(hidden)]
+    fn verify_at(
+        src: &'a [u8],
+        pos: &mut u32,
+        _known_end: &mut bool,
+        _options: &UnpackOptions,
+    ) -> Result<()> {
+        Self::verify(src, pos)
+    }
+
+    /// Verify the integrity of fracpack data at the start of `src` and
+    /// return the number of bytes that it takes up. Anything after that
+    /// is left for the caller.
+    ///
+    /// ```
+    /// use fracpack::{Pack, Unpack};
+    ///
+    /// let mut packed = "abc".packed();
+    /// packed.extend_from_slice(&[1, 2]);
+    /// assert_eq!(String::verify_prefix(&packed).unwrap(), 7);
+    /// ```
+    fn verify_prefix(src: &'a [u8]) -> Result<usize> {
         let mut pos = 0;
         Self::verify(src, &mut pos)?;
+        Ok(pos as usize)
+    }
+
+    /// Verify that `src` is exactly one complete message. Use this to
+    /// validate whole messages received from elsewhere.
+    ///
+    /// ```
+    /// use fracpack::{Error, Pack, Unpack};
+    ///
+    /// let mut packed = "abc".packed();
+    /// assert!(String::verify_all(&packed).is_ok());
+    /// packed.push(0);
+    /// assert!(matches!(String::verify_all(&packed), Err(Error::TrailingData)));
+    /// ```
+    fn verify_all(src: &'a [u8]) -> Result<()> {
+        if Self::verify_prefix(src)? != src.len() {
+            return Err(Error::TrailingData);
+        }
+        Ok(())
+    }
+
+    /// Like [Unpack::unpacked], but with the limits in `options`. Use
+    /// this for data received from elsewhere.
+    fn unpacked_with(src: &'a [u8], options: &UnpackOptions) -> Result<Self> {
+        let mut pos = 0;
+        unpack_to_end_with(src, &mut pos, options)
+    }
+
+    /// Like [Unpack::verify_all], but with the limits in `options`. Use
+    /// this for data received from elsewhere.
+    fn verify_all_with(src: &'a [u8], options: &UnpackOptions) -> Result<()> {
+        let mut pos = 0;
+        verify_to_end_with::<Self>(src, &mut pos, options)?;
         if pos as usize != src.len() {
-            return Err(Error::ExtraData);
+            return Err(Error::TrailingData);
         }
         Ok(())
     }
 
+    /// Verify the integrity of fracpack data, plus make sure there is no
+    /// leftover data after it.
+    #[deprecated(note = "use verify_all, which this calls")]
+    fn verify_no_extra(src: &'a [u8]) -> Result<()> {
+        Self::verify_all(src)
+    }
+
     #[doc(hidden)]
     fn new_empty_container() -> Result<Self> {
         Err(Error::BadOffset)
@@ -235,24 +468,30 @@ pub trait Unpack<'a>: Sized {
         src: &'a [u8],
         fixed_pos: &mut u32,
         heap_pos: &mut u32,
+        known_end: &mut bool,
+        options: &UnpackOptions,
     ) -> Result<Self> {
         let orig_pos = *fixed_pos;
         let offset = u32::unpack(src, fixed_pos)?;
         if offset == 0 {
             return Self::new_empty_container();
         }
-        if *heap_pos as u64 != orig_pos as u64 + offset as u64 {
-            return Err(Error::BadOffset);
-        }
-        Self::unpack(src, heap_pos)
+        seek_heap(heap_pos, orig_pos as u64 + offset as u64, known_end)?;
+        Self::unpack_at(src, heap_pos, known_end, options)
     }
 
     #[doc(hidden)]
-    fn embedded_unpack(src: &'a [u8], fixed_pos: &mut u32, heap_pos: &mut u32) -> Result<Self> {
+    fn embedded_unpack(
+        src: &'a [u8],
+        fixed_pos: &mut u32,
+        heap_pos: &mut u32,
+        known_end: &mut bool,
+        options: &UnpackOptions,
+    ) -> Result<Self> {
         if Self::VARIABLE_SIZE {
-            Self::embedded_variable_unpack(src, fixed_pos, heap_pos)
+            Self::embedded_variable_unpack(src, fixed_pos, heap_pos, known_end, options)
         } else {
-            Self::unpack(src, fixed_pos)
+            Self::unpack_at(src, fixed_pos, known_end, options)
         }
     }
 
@@ -261,6 +500,8 @@ pub trait Unpack<'a>: Sized {
         src: &'a [u8],
         fixed_pos: &mut u32,
         heap_pos: &mut u32,
+        known_end: &mut bool,
+        options: &UnpackOptions,
     ) -> Result<()> {
         let orig_pos = *fixed_pos;
         let offset = u32::unpack(src, fixed_pos)?;
@@ -268,32 +509,289 @@ pub trait Unpack<'a>: Sized {
             let _ = Self::new_empty_container();
             return Ok(());
         }
-        if *heap_pos as u64 != orig_pos as u64 + offset as u64 {
-            return Err(Error::BadOffset);
-        }
-        Self::verify(src, heap_pos)
+        seek_heap(heap_pos, orig_pos as u64 + offset as u64, known_end)?;
+        Self::verify_at(src, heap_pos, known_end, options)
     }
 
     #[doc(hidden)]
-    fn embedded_verify(src: &'a [u8], fixed_pos: &mut u32, heap_pos: &mut u32) -> Result<()> {
+    fn embedded_verify(
+        src: &'a [u8],
+        fixed_pos: &mut u32,
+        heap_pos: &mut u32,
+        known_end: &mut bool,
+        options: &UnpackOptions,
+    ) -> Result<()> {
         if Self::VARIABLE_SIZE {
-            Self::embedded_variable_verify(src, fixed_pos, heap_pos)
+            Self::embedded_variable_verify(src, fixed_pos, heap_pos, known_end, options)
         } else {
-            Self::verify(src, fixed_pos)
+            Self::verify_at(src, fixed_pos, known_end, options)
         }
     }
 }
 
+/// Verify that `src` holds exactly one `T` in canonical form.
+///
+/// [Unpack::verify_all] accepts some encodings that [Pack::pack]
+/// never produces, e.g. `bool` values other than 0 or 1. This also
+/// rejects those, so that each value has a single valid encoding.
+///
+/// ```
+/// use fracpack::{verify_canonical, Pack};
+///
+/// let packed = (1u32, "abc").packed();
+/// assert!(verify_canonical::<(u32, String)>(&packed).is_ok());
+/// assert!(verify_canonical::<bool>(&[2]).is_err());
+/// ```
+pub fn verify_canonical<'a, T: Unpack<'a> + Pack>(src: &'a [u8]) -> Result<()> {
+    T::verify_all(src)?;
+    if T::unpacked(src)?.packed() != src {
+        return Err(Error::NonCanonical);
+    }
+    Ok(())
+}
+
+/// Unpack one field of a packed struct without unpacking the others.
+///
+/// `offset` is the position of the field within the struct's fixed data:
+/// the sum of the fixed sizes of the fields before it (4 for each variable
+/// size field). This requires knowing the layout of the struct, e.g. from
+/// its `Reflect` implementation in the psibase crate. Only structs which
+/// may be extended (the default) are supported; their fixed data follows
+/// a `u16` size.
+///
+/// Only the requested field is checked, so this does not verify the rest
+/// of `src`.
+///
+/// ```
+/// use fracpack::{peek_field, Pack, Unpack};
+///
+/// #[derive(Pack, Unpack)]
+/// #[fracpack(fracpack_mod = "fracpack")]
+/// struct Row {
+///     name: String,
+///     count: u32,
+/// }
+///
+/// let packed = Row { name: "row".into(), count: 7 }.packed();
+/// assert_eq!(peek_field::<u32>(&packed, 4)?, 7);
+/// assert_eq!(peek_field::<String>(&packed, 0)?, "row");
+/// # Ok::<(), fracpack::Error>(())
+/// ```
+pub fn peek_field<'a, T: Unpack<'a>>(src: &'a [u8], offset: u32) -> Result<T> {
+    let mut pos = 0;
+    let fixed_size = u16::unpack(src, &mut pos)?;
+    if offset as u64 + T::FIXED_SIZE as u64 > fixed_size as u64 {
+        return Err(Error::BadOffset);
+    }
+    let mut fixed_pos = pos + offset;
+    let mut heap_pos = 0;
+    if T::VARIABLE_SIZE {
+        let heap_offset = u32::unpacked(src.get(fixed_pos as usize..).unwrap_or_default())?;
+        heap_pos =
+            u32::try_from(fixed_pos as u64 + heap_offset as u64).map_err(|_| Error::BadOffset)?;
+    }
+    T::embedded_unpack(
+        src,
+        &mut fixed_pos,
+        &mut heap_pos,
+        &mut true,
+        &UnpackOptions::default(),
+    )
+}
+
+/// Limits for unpacking or verifying untrusted data with
+/// [Unpack::unpacked_with] and [Unpack::verify_all_with].
+///
+/// ```
+/// use fracpack::{Error, Pack, Unpack, UnpackOptions};
+///
+/// let packed = vec![0u8; 100].packed();
+/// let options = UnpackOptions {
+///     max_container_len: 64,
+/// };
+/// assert!(<Vec<u8>>::verify_all(&packed).is_ok());
+/// assert!(matches!(
+///     <Vec<u8>>::verify_all_with(&packed, &options),
+///     Err(Error::BadSize)
+/// ));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnpackOptions {
+    /// A vector with more than this many elements, or a string or byte
+    /// slice with more than this many bytes, fails with [Error::BadSize].
+    /// The length prefix is checked before anything is allocated, so this
+    /// bounds the memory that a single crafted length can claim. The
+    /// default only limits lengths by the size of the source buffer.
+    pub max_container_len: u32,
+}
+
+impl Default for UnpackOptions {
+    fn default() -> Self {
+        UnpackOptions {
+            max_container_len: u32::MAX,
+        }
+    }
+}
+
+fn check_container_len(len: u32, options: &UnpackOptions) -> Result<u32> {
+    if len > options.max_container_len {
+        return Err(Error::BadSize);
+    }
+    Ok(len)
+}
+
+/// Implements [Unpack::unpack] for types that override
+/// [Unpack::unpack_at]. `pos` is moved to the end of `src` if the end of
+/// the data can't be determined.
+#[doc(hidden)]
+pub fn unpack_to_end<'a, T: Unpack<'a>>(src: &'a [u8], pos: &mut u32) -> Result<T> {
+    unpack_to_end_with(src, pos, &UnpackOptions::default())
+}
+
+fn unpack_to_end_with<'a, T: Unpack<'a>>(
+    src: &'a [u8],
+    pos: &mut u32,
+    options: &UnpackOptions,
+) -> Result<T> {
+    let mut known_end = true;
+    let result = T::unpack_at(src, pos, &mut known_end, options)?;
+    if !known_end {
+        *pos = src.len() as u32;
+    }
+    Ok(result)
+}
+
+/// Implements [Unpack::verify] for types that override
+/// [Unpack::verify_at].
+#[doc(hidden)]
+pub fn verify_to_end<'a, T: Unpack<'a>>(src: &'a [u8], pos: &mut u32) -> Result<()> {
+    verify_to_end_with::<T>(src, pos, &UnpackOptions::default())
+}
+
+fn verify_to_end_with<'a, T: Unpack<'a>>(
+    src: &'a [u8],
+    pos: &mut u32,
+    options: &UnpackOptions,
+) -> Result<()> {
+    let mut known_end = true;
+    T::verify_at(src, pos, &mut known_end, options)?;
+    if !known_end {
+        *pos = src.len() as u32;
+    }
+    Ok(())
+}
+
+// Moves heap_pos to the target of an offset pointer. The target must be
+// the end of the preceding object, or, if that end is unknown because
+// known_end is clear, after it.
+fn seek_heap(heap_pos: &mut u32, target: u64, known_end: &mut bool) -> Result<()> {
+    if *heap_pos as u64 != target {
+        if *known_end || target < *heap_pos as u64 {
+            return Err(Error::BadOffset);
+        }
+        *heap_pos = u32::try_from(target).map_err(|_| Error::BadOffset)?;
+    }
+    *known_end = true;
+    Ok(())
+}
+
+/// Skip the fields of an extensible struct or tuple that come after the
+/// fields the local type knows about. See [Extensible types](crate#extensible-types).
+///
+/// `fixed_pos` is the end of the known fields and `heap_start` is the end
+/// of the fixed data. Derived and tuple implementations call this after
+/// unpacking or verifying their known fields. `known_end` is cleared if
+/// unknown heap data was skipped, since its size isn't known.
+#[doc(hidden)]
+pub fn skip_unknown_fields(
+    src: &[u8],
+    fixed_pos: &mut u32,
+    heap_start: u32,
+    heap_pos: &mut u32,
+    known_end: &mut bool,
+) -> Result<()> {
+    // Known fields must not spill into the heap
+    if *fixed_pos > heap_start || (heap_start - *fixed_pos) % 4 != 0 {
+        return Err(Error::BadSize);
+    }
+    while *fixed_pos < heap_start {
+        let orig_pos = *fixed_pos;
+        let offset = u32::unpack(src, fixed_pos)?;
+        if offset <= 1 {
+            continue;
+        }
+        let target = orig_pos as u64 + offset as u64;
+        if target < *heap_pos as u64 || target > src.len() as u64 {
+            return Err(Error::BadOffset);
+        }
+        *heap_pos = target as u32;
+        *known_end = false;
+    }
+    Ok(())
+}
+
+/// Check that the content of an enum variant ends at `end`, which comes
+/// from the size in front of it. Content that ends with the heap data of
+/// unknown fields runs to `end`.
+#[doc(hidden)]
+pub fn end_variant(src: &[u8], pos: &mut u32, end: u64, known_end: &mut bool) -> Result<()> {
+    if !*known_end && *pos as u64 <= end && end <= src.len() as u64 {
+        *pos = end as u32;
+        *known_end = true;
+    }
+    if *pos as u64 != end {
+        return Err(Error::BadSize);
+    }
+    Ok(())
+}
+
+// Advances pos by size, failing if the result would be past the end of src
+fn advance(src: &[u8], pos: &mut u32, size: u32) -> Result<()> {
+    match pos.checked_add(size) {
+        Some(end) if end as u64 <= src.len() as u64 => {
+            *pos = end;
+            Ok(())
+        }
+        _ => Err(Error::ReadPastEnd),
+    }
+}
+
 fn read_u8_arr<const SIZE: usize>(src: &[u8], pos: &mut u32) -> Result<[u8; SIZE]> {
+    let start = *pos;
+    advance(src, pos, SIZE as u32)?;
     let mut bytes: [u8; SIZE] = [0; SIZE];
-    bytes.copy_from_slice(
-        src.get(*pos as usize..*pos as usize + SIZE)
-            .ok_or(Error::ReadPastEnd)?,
-    );
-    *pos += SIZE as u32;
+    bytes.copy_from_slice(&src[start as usize..*pos as usize]);
+    Ok(bytes)
+}
+
+fn read_bytes<'a>(src: &'a [u8], pos: &mut u32, len: u32) -> Result<&'a [u8]> {
+    let end = *pos as u64 + len as u64;
+    if end > src.len() as u64 {
+        return Err(Error::ReadPastEnd);
+    }
+    let bytes = &src[*pos as usize..end as usize];
+    *pos = end as u32;
     Ok(bytes)
 }
 
+// Number of elements in a vector whose fixed data takes num_bytes
+fn vec_len(num_bytes: u32, fixed_size: u32, options: &UnpackOptions) -> Result<u32> {
+    match num_bytes.checked_rem(fixed_size) {
+        Some(0) => check_container_len(num_bytes / fixed_size, options),
+        None if num_bytes == 0 => Ok(0),
+        _ => Err(Error::BadSize),
+    }
+}
+
+// Start of the heap for a vector or array whose fixed data takes num_bytes
+fn heap_start(src: &[u8], pos: u32, num_bytes: u64) -> Result<u32> {
+    let hp = pos as u64 + num_bytes;
+    if hp > src.len() as u64 || hp > u32::MAX as u64 {
+        return Err(Error::ReadPastEnd);
+    }
+    Ok(hp as u32)
+}
+
 // TODO: violates single-valid-serialization rule
 trait MissingBoolConversions {
     fn from_le_bytes(bytes: [u8; 1]) -> bool;
@@ -336,6 +834,26 @@ impl<'a, T: Pack> Pack for &'a T {
     fn embedded_variable_pack(&self, dest: &mut Vec<u8>) {
         (*self).embedded_variable_pack(dest)
     }
+
+    fn pack_into<W: Write + Seek>(&self, dest: &mut W) -> io::Result<()> {
+        (*self).pack_into(dest)
+    }
+
+    fn embedded_fixed_pack_into<W: Write + Seek>(&self, dest: &mut W) -> io::Result<()> {
+        (*self).embedded_fixed_pack_into(dest)
+    }
+
+    fn embedded_fixed_repack_into<W: Write + Seek>(
+        &self,
+        fixed_pos: u64,
+        dest: &mut W,
+    ) -> io::Result<()> {
+        (*self).embedded_fixed_repack_into(fixed_pos, dest)
+    }
+
+    fn embedded_variable_pack_into<W: Write + Seek>(&self, dest: &mut W) -> io::Result<()> {
+        (*self).embedded_variable_pack_into(dest)
+    }
 }
 
 macro_rules! scalar_impl {
@@ -354,12 +872,7 @@ macro_rules! scalar_impl {
                 Ok(Self::from_le_bytes(read_u8_arr(src, pos)?.into()))
             }
             fn verify(src: &'a [u8], pos: &mut u32) -> Result<()> {
-                if (*pos as u64 + <Self as Unpack>::FIXED_SIZE as u64 > src.len() as u64) {
-                    Err(Error::ReadPastEnd)
-                } else {
-                    *pos += <Self as Unpack>::FIXED_SIZE;
-                    Ok(())
-                }
+                advance(src, pos, <Self as Unpack>::FIXED_SIZE)
             }
         }
     };
@@ -377,6 +890,74 @@ scalar_impl! {u64}
 scalar_impl! {f32}
 scalar_impl! {f64}
 
+// NonZero types pack like the underlying integer
+macro_rules! nonzero_impl {
+    ($t:ty, $inner:ty) => {
+        impl Pack for $t {
+            const FIXED_SIZE: u32 = mem::size_of::<$inner>() as u32;
+            const VARIABLE_SIZE: bool = false;
+            fn pack(&self, dest: &mut Vec<u8>) {
+                self.get().pack(dest)
+            }
+        }
+        impl<'a> Unpack<'a> for $t {
+            const FIXED_SIZE: u32 = mem::size_of::<$inner>() as u32;
+            const VARIABLE_SIZE: bool = false;
+            fn unpack(src: &'a [u8], pos: &mut u32) -> Result<Self> {
+                let mut p = *pos;
+                let result = Self::new(<$inner>::unpack(src, &mut p)?).ok_or(Error::BadNonZero)?;
+                *pos = p;
+                Ok(result)
+            }
+            fn verify(src: &'a [u8], pos: &mut u32) -> Result<()> {
+                Self::unpack(src, pos)?;
+                Ok(())
+            }
+        }
+    };
+} // nonzero_impl
+
+nonzero_impl! {NonZeroI8, i8}
+nonzero_impl! {NonZeroI16, i16}
+nonzero_impl! {NonZeroI32, i32}
+nonzero_impl! {NonZeroI64, i64}
+nonzero_impl! {NonZeroU8, u8}
+nonzero_impl! {NonZeroU16, u16}
+nonzero_impl! {NonZeroU32, u32}
+nonzero_impl! {NonZeroU64, u64}
+
+// Wrapping integers pack exactly like the bare integer
+macro_rules! wrapping_impl {
+    ($t:ty) => {
+        impl Pack for Wrapping<$t> {
+            const FIXED_SIZE: u32 = mem::size_of::<$t>() as u32;
+            const VARIABLE_SIZE: bool = false;
+            fn pack(&self, dest: &mut Vec<u8>) {
+                self.0.pack(dest)
+            }
+        }
+        impl<'a> Unpack<'a> for Wrapping<$t> {
+            const FIXED_SIZE: u32 = mem::size_of::<$t>() as u32;
+            const VARIABLE_SIZE: bool = false;
+            fn unpack(src: &'a [u8], pos: &mut u32) -> Result<Self> {
+                Ok(Wrapping(<$t>::unpack(src, pos)?))
+            }
+            fn verify(src: &'a [u8], pos: &mut u32) -> Result<()> {
+                <$t>::verify(src, pos)
+            }
+        }
+    };
+} // wrapping_impl
+
+wrapping_impl! {i8}
+wrapping_impl! {i16}
+wrapping_impl! {i32}
+wrapping_impl! {i64}
+wrapping_impl! {u8}
+wrapping_impl! {u16}
+wrapping_impl! {u32}
+wrapping_impl! {u64}
+
 macro_rules! pack_ptr {
     ($ptr:ident, $to_ref:ident) => {
         impl<T: Pack> Pack for $ptr<T> {
@@ -404,6 +985,26 @@ macro_rules! pack_ptr {
             fn embedded_variable_pack(&self, dest: &mut Vec<u8>) {
                 self.$to_ref().embedded_variable_pack(dest)
             }
+
+            fn pack_into<W: Write + Seek>(&self, dest: &mut W) -> io::Result<()> {
+                self.$to_ref().pack_into(dest)
+            }
+
+            fn embedded_fixed_pack_into<W: Write + Seek>(&self, dest: &mut W) -> io::Result<()> {
+                self.$to_ref().embedded_fixed_pack_into(dest)
+            }
+
+            fn embedded_fixed_repack_into<W: Write + Seek>(
+                &self,
+                fixed_pos: u64,
+                dest: &mut W,
+            ) -> io::Result<()> {
+                self.$to_ref().embedded_fixed_repack_into(fixed_pos, dest)
+            }
+
+            fn embedded_variable_pack_into<W: Write + Seek>(&self, dest: &mut W) -> io::Result<()> {
+                self.$to_ref().embedded_variable_pack_into(dest)
+            }
         }
     };
 }
@@ -419,10 +1020,28 @@ macro_rules! unpack_ptr {
                 Ok(Self::new(<T>::unpack(src, pos)?))
             }
 
+            fn unpack_at(
+                src: &'a [u8],
+                pos: &mut u32,
+                known_end: &mut bool,
+                options: &UnpackOptions,
+            ) -> Result<Self> {
+                Ok(Self::new(<T>::unpack_at(src, pos, known_end, options)?))
+            }
+
             fn verify(src: &'a [u8], pos: &mut u32) -> Result<()> {
                 <T>::verify(src, pos)
             }
 
+            fn verify_at(
+                src: &'a [u8],
+                pos: &mut u32,
+                known_end: &mut bool,
+                options: &UnpackOptions,
+            ) -> Result<()> {
+                <T>::verify_at(src, pos, known_end, options)
+            }
+
             fn new_empty_container() -> Result<Self> {
                 Ok(Self::new(<T>::new_empty_container()?))
             }
@@ -431,9 +1050,11 @@ macro_rules! unpack_ptr {
                 src: &'a [u8],
                 fixed_pos: &mut u32,
                 heap_pos: &mut u32,
+                known_end: &mut bool,
+                options: &UnpackOptions,
             ) -> Result<Self> {
                 Ok(Self::new(<T>::embedded_variable_unpack(
-                    src, fixed_pos, heap_pos,
+                    src, fixed_pos, heap_pos, known_end, options,
                 )?))
             }
 
@@ -441,24 +1062,32 @@ macro_rules! unpack_ptr {
                 src: &'a [u8],
                 fixed_pos: &mut u32,
                 heap_pos: &mut u32,
+                known_end: &mut bool,
+                options: &UnpackOptions,
             ) -> Result<Self> {
-                Ok(Self::new(<T>::embedded_unpack(src, fixed_pos, heap_pos)?))
+                Ok(Self::new(<T>::embedded_unpack(
+                    src, fixed_pos, heap_pos, known_end, options,
+                )?))
             }
 
             fn embedded_variable_verify(
                 src: &'a [u8],
                 fixed_pos: &mut u32,
                 heap_pos: &mut u32,
+                known_end: &mut bool,
+                options: &UnpackOptions,
             ) -> Result<()> {
-                <T>::embedded_variable_verify(src, fixed_pos, heap_pos)
+                <T>::embedded_variable_verify(src, fixed_pos, heap_pos, known_end, options)
             }
 
             fn embedded_verify(
                 src: &'a [u8],
                 fixed_pos: &mut u32,
                 heap_pos: &mut u32,
+                known_end: &mut bool,
+                options: &UnpackOptions,
             ) -> Result<()> {
-                <T>::embedded_verify(src, fixed_pos, heap_pos)
+                <T>::embedded_verify(src, fixed_pos, heap_pos, known_end, options)
             }
         }
     };
@@ -474,6 +1103,137 @@ unpack_ptr!(Rc);
 unpack_ptr!(Arc);
 unpack_ptr!(RefCell);
 
+macro_rules! ip_impl {
+    ($t:ty, $size:expr) => {
+        impl Pack for $t {
+            const FIXED_SIZE: u32 = $size;
+            const VARIABLE_SIZE: bool = false;
+            fn pack(&self, dest: &mut Vec<u8>) {
+                dest.extend_from_slice(&self.octets());
+            }
+        }
+        impl<'a> Unpack<'a> for $t {
+            const FIXED_SIZE: u32 = $size;
+            const VARIABLE_SIZE: bool = false;
+            fn unpack(src: &'a [u8], pos: &mut u32) -> Result<Self> {
+                Ok(read_u8_arr::<$size>(src, pos)?.into())
+            }
+            fn verify(src: &'a [u8], pos: &mut u32) -> Result<()> {
+                advance(src, pos, $size)
+            }
+        }
+    };
+} // ip_impl
+
+ip_impl! {Ipv4Addr, 4}
+ip_impl! {Ipv6Addr, 16}
+
+// IpAddr and SocketAddr are packed like an enum with the variants
+// V4 (index 0) and V6 (index 1). The content is the address followed,
+// for SocketAddr, by the port. Unlike a derived enum, verify rejects
+// unknown indexes. The flow info and scope id of a V6 SocketAddr are
+// not packed.
+
+fn pack_variant(dest: &mut Vec<u8>, index: u8, content: impl FnOnce(&mut Vec<u8>)) {
+    dest.push(index);
+    let size_pos = dest.len();
+    dest.extend_from_slice(&0_u32.to_le_bytes());
+    content(dest);
+    let size = (dest.len() - size_pos - 4) as u32;
+    dest[size_pos..size_pos + 4].copy_from_slice(&size.to_le_bytes());
+}
+
+// Reads the header written by pack_variant. Returns the index and the
+// position of the end of the content.
+fn unpack_variant_header(src: &[u8], pos: &mut u32) -> Result<(u8, u64)> {
+    let index = u8::unpack(src, pos)?;
+    let size = u32::unpack(src, pos)?;
+    Ok((index, *pos as u64 + size as u64))
+}
+
+fn check_variant_end(pos: u32, end: u64) -> Result<()> {
+    if pos as u64 != end {
+        return Err(Error::BadSize);
+    }
+    Ok(())
+}
+
+impl Pack for IpAddr {
+    const FIXED_SIZE: u32 = 4;
+    const VARIABLE_SIZE: bool = true;
+    fn pack(&self, dest: &mut Vec<u8>) {
+        match self {
+            IpAddr::V4(addr) => pack_variant(dest, 0, |dest| addr.pack(dest)),
+            IpAddr::V6(addr) => pack_variant(dest, 1, |dest| addr.pack(dest)),
+        }
+    }
+}
+
+impl<'a> Unpack<'a> for IpAddr {
+    const FIXED_SIZE: u32 = 4;
+    const VARIABLE_SIZE: bool = true;
+    fn unpack(src: &'a [u8], pos: &mut u32) -> Result<Self> {
+        let (index, end) = unpack_variant_header(src, pos)?;
+        let result = match index {
+            0 => IpAddr::V4(Ipv4Addr::unpack(src, pos)?),
+            1 => IpAddr::V6(Ipv6Addr::unpack(src, pos)?),
+            _ => return Err(Error::BadEnumIndex),
+        };
+        check_variant_end(*pos, end)?;
+        Ok(result)
+    }
+    fn verify(src: &'a [u8], pos: &mut u32) -> Result<()> {
+        let (index, end) = unpack_variant_header(src, pos)?;
+        match index {
+            0 => Ipv4Addr::verify(src, pos)?,
+            1 => Ipv6Addr::verify(src, pos)?,
+            _ => return Err(Error::BadEnumIndex),
+        }
+        check_variant_end(*pos, end)
+    }
+}
+
+impl Pack for SocketAddr {
+    const FIXED_SIZE: u32 = 4;
+    const VARIABLE_SIZE: bool = true;
+    fn pack(&self, dest: &mut Vec<u8>) {
+        let content = |dest: &mut Vec<u8>| {
+            match self.ip() {
+                IpAddr::V4(addr) => addr.pack(dest),
+                IpAddr::V6(addr) => addr.pack(dest),
+            }
+            self.port().pack(dest);
+        };
+        pack_variant(dest, self.is_ipv6() as u8, content);
+    }
+}
+
+impl<'a> Unpack<'a> for SocketAddr {
+    const FIXED_SIZE: u32 = 4;
+    const VARIABLE_SIZE: bool = true;
+    fn unpack(src: &'a [u8], pos: &mut u32) -> Result<Self> {
+        let (index, end) = unpack_variant_header(src, pos)?;
+        let ip = match index {
+            0 => IpAddr::V4(Ipv4Addr::unpack(src, pos)?),
+            1 => IpAddr::V6(Ipv6Addr::unpack(src, pos)?),
+            _ => return Err(Error::BadEnumIndex),
+        };
+        let port = u16::unpack(src, pos)?;
+        check_variant_end(*pos, end)?;
+        Ok(SocketAddr::new(ip, port))
+    }
+    fn verify(src: &'a [u8], pos: &mut u32) -> Result<()> {
+        let (index, end) = unpack_variant_header(src, pos)?;
+        match index {
+            0 => Ipv4Addr::verify(src, pos)?,
+            1 => Ipv6Addr::verify(src, pos)?,
+            _ => return Err(Error::BadEnumIndex),
+        }
+        u16::verify(src, pos)?;
+        check_variant_end(*pos, end)
+    }
+}
+
 impl<T: Pack> Pack for Option<T> {
     const FIXED_SIZE: u32 = 4;
     const VARIABLE_SIZE: bool = true;
@@ -516,6 +1276,22 @@ impl<T: Pack> Pack for Option<T> {
             }
         }
     }
+
+    fn pack_into<W: Write + Seek>(&self, dest: &mut W) -> io::Result<()> {
+        let fixed_pos = dest.stream_position()?;
+        self.embedded_fixed_pack_into(dest)?;
+        self.embedded_fixed_repack_into(fixed_pos, dest)?;
+        self.embedded_variable_pack_into(dest)
+    }
+
+    fn embedded_variable_pack_into<W: Write + Seek>(&self, dest: &mut W) -> io::Result<()> {
+        if let Some(x) = self {
+            if !x.is_empty_container() {
+                x.pack_into(dest)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<'a, T: Unpack<'a>> Unpack<'a> for Option<T> {
@@ -524,18 +1300,42 @@ impl<'a, T: Unpack<'a>> Unpack<'a> for Option<T> {
     const IS_OPTIONAL: bool = true;
 
     fn unpack(src: &'a [u8], pos: &mut u32) -> Result<Self> {
+        unpack_to_end(src, pos)
+    }
+
+    fn unpack_at(
+        src: &'a [u8],
+        pos: &mut u32,
+        known_end: &mut bool,
+        options: &UnpackOptions,
+    ) -> Result<Self> {
         let mut fixed_pos = *pos;
-        *pos += 4;
-        Self::embedded_unpack(src, &mut fixed_pos, pos)
+        *pos = pos.checked_add(4).ok_or(Error::ReadPastEnd)?;
+        Self::embedded_unpack(src, &mut fixed_pos, pos, known_end, options)
     }
 
     fn verify(src: &'a [u8], pos: &mut u32) -> Result<()> {
+        verify_to_end::<Self>(src, pos)
+    }
+
+    fn verify_at(
+        src: &'a [u8],
+        pos: &mut u32,
+        known_end: &mut bool,
+        options: &UnpackOptions,
+    ) -> Result<()> {
         let mut fixed_pos = *pos;
-        *pos += 4;
-        Self::embedded_verify(src, &mut fixed_pos, pos)
+        *pos = pos.checked_add(4).ok_or(Error::ReadPastEnd)?;
+        Self::embedded_verify(src, &mut fixed_pos, pos, known_end, options)
     }
 
-    fn embedded_unpack(src: &'a [u8], fixed_pos: &mut u32, heap_pos: &mut u32) -> Result<Self> {
+    fn embedded_unpack(
+        src: &'a [u8],
+        fixed_pos: &mut u32,
+        heap_pos: &mut u32,
+        known_end: &mut bool,
+        options: &UnpackOptions,
+    ) -> Result<Self> {
         let orig_pos = *fixed_pos;
         let offset = u32::unpack(src, fixed_pos)?;
         if offset == 1 {
@@ -543,18 +1343,24 @@ impl<'a, T: Unpack<'a>> Unpack<'a> for Option<T> {
         }
         *fixed_pos = orig_pos;
         Ok(Some(<T>::embedded_variable_unpack(
-            src, fixed_pos, heap_pos,
+            src, fixed_pos, heap_pos, known_end, options,
         )?))
     }
 
-    fn embedded_verify(src: &'a [u8], fixed_pos: &mut u32, heap_pos: &mut u32) -> Result<()> {
+    fn embedded_verify(
+        src: &'a [u8],
+        fixed_pos: &mut u32,
+        heap_pos: &mut u32,
+        known_end: &mut bool,
+        options: &UnpackOptions,
+    ) -> Result<()> {
         let orig_pos = *fixed_pos;
         let offset = u32::unpack(src, fixed_pos)?;
         if offset == 1 {
             return Ok(());
         }
         *fixed_pos = orig_pos;
-        T::embedded_variable_verify(src, fixed_pos, heap_pos)
+        T::embedded_variable_verify(src, fixed_pos, heap_pos, known_end, options)
     }
 }
 
@@ -602,9 +1408,36 @@ impl<'a> BytesConversion<'a> for &'a [u8] {
     }
 }
 
+// A Cow packs like its owned form. Unpacking always produces the owned
+// variant, so the result doesn't borrow from the source.
+impl<'a, 'b> BytesConversion<'a> for Cow<'b, str> {
+    fn fracpack_verify_if_str(bytes: &'a [u8]) -> Result<()> {
+        std::str::from_utf8(bytes).or(Err(Error::BadUTF8))?;
+        Ok(())
+    }
+    fn fracpack_from_bytes(bytes: &'a [u8]) -> Result<Self> {
+        Ok(Cow::Owned(String::fracpack_from_bytes(bytes)?))
+    }
+    fn fracpack_as_bytes(&'a self) -> &'a [u8] {
+        self.as_bytes()
+    }
+}
+
+impl<'a, 'b> BytesConversion<'a> for Cow<'b, [u8]> {
+    fn fracpack_verify_if_str(_bytes: &'a [u8]) -> Result<()> {
+        Ok(())
+    }
+    fn fracpack_from_bytes(bytes: &'a [u8]) -> Result<Self> {
+        Ok(Cow::Owned(bytes.to_vec()))
+    }
+    fn fracpack_as_bytes(&'a self) -> &'a [u8] {
+        self
+    }
+}
+
 macro_rules! bytes_impl {
-    ($t:ty) => {
-        impl<'a> Pack for $t {
+    ([$($lt:lifetime),*] $t:ty) => {
+        impl<'a, $($lt),*> Pack for $t {
             const FIXED_SIZE: u32 = 4;
             const VARIABLE_SIZE: bool = true;
 
@@ -618,25 +1451,37 @@ macro_rules! bytes_impl {
             }
         }
 
-        impl<'a> Unpack<'a> for $t {
+        impl<'a, $($lt),*> Unpack<'a> for $t {
             const FIXED_SIZE: u32 = 4;
             const VARIABLE_SIZE: bool = true;
 
             fn unpack(src: &'a [u8], pos: &mut u32) -> Result<$t> {
-                let len = u32::unpack(src, pos)?;
-                let bytes = src
-                    .get(*pos as usize..(*pos + len) as usize)
-                    .ok_or(Error::ReadPastEnd)?;
-                *pos += len;
+                unpack_to_end(src, pos)
+            }
+
+            fn unpack_at(
+                src: &'a [u8],
+                pos: &mut u32,
+                _known_end: &mut bool,
+                options: &UnpackOptions,
+            ) -> Result<$t> {
+                let len = check_container_len(u32::unpack(src, pos)?, options)?;
+                let bytes = read_bytes(src, pos, len)?;
                 <$t>::fracpack_from_bytes(bytes)
             }
 
             fn verify(src: &'a [u8], pos: &mut u32) -> Result<()> {
-                let len = u32::unpack(src, pos)?;
-                let bytes = src
-                    .get(*pos as usize..(*pos + len) as usize)
-                    .ok_or(Error::ReadPastEnd)?;
-                *pos += len;
+                verify_to_end::<Self>(src, pos)
+            }
+
+            fn verify_at(
+                src: &'a [u8],
+                pos: &mut u32,
+                _known_end: &mut bool,
+                options: &UnpackOptions,
+            ) -> Result<()> {
+                let len = check_container_len(u32::unpack(src, pos)?, options)?;
+                let bytes = read_bytes(src, pos, len)?;
                 <$t>::fracpack_verify_if_str(bytes)?;
                 Ok(())
             }
@@ -646,11 +1491,16 @@ macro_rules! bytes_impl {
             }
         }
     };
+    ($t:ty) => {
+        bytes_impl! {[] $t}
+    };
 } // bytes_impl
 
 bytes_impl! {String}
 bytes_impl! {&'a str}
 bytes_impl! {&'a [u8]}
+bytes_impl! {['b] Cow<'b, str>}
+bytes_impl! {['b] Cow<'b, [u8]>}
 
 impl<T: Pack> Pack for Vec<T> {
     const FIXED_SIZE: u32 = 4;
@@ -672,6 +1522,29 @@ impl<T: Pack> Pack for Vec<T> {
         }
     }
 
+    fn pack_into<W: Write + Seek>(&self, dest: &mut W) -> io::Result<()> {
+        let num_bytes = self.len() as u32 * T::FIXED_SIZE;
+        dest.write_all(&num_bytes.to_le_bytes())?;
+        let start = dest.stream_position()?;
+        // Batch the fixed data, which is often a long run of scalars
+        let mut fixed = Vec::new();
+        for x in self {
+            T::embedded_fixed_pack(x, &mut fixed);
+            if fixed.len() >= 0x10000 {
+                dest.write_all(&fixed)?;
+                fixed.clear();
+            }
+        }
+        dest.write_all(&fixed)?;
+        if T::VARIABLE_SIZE {
+            for (i, x) in self.iter().enumerate() {
+                T::embedded_fixed_repack_into(x, start + i as u64 * T::FIXED_SIZE as u64, dest)?;
+                T::embedded_variable_pack_into(x, dest)?;
+            }
+        }
+        Ok(())
+    }
+
     fn is_empty_container(&self) -> bool {
         self.is_empty()
     }
@@ -681,39 +1554,52 @@ impl<'a, T: Unpack<'a>> Unpack<'a> for Vec<T> {
     const FIXED_SIZE: u32 = 4;
     const VARIABLE_SIZE: bool = true;
 
-    // TODO: optimize scalar
     fn unpack(src: &'a [u8], pos: &mut u32) -> Result<Self> {
+        unpack_to_end(src, pos)
+    }
+
+    // TODO: optimize scalar
+    fn unpack_at(
+        src: &'a [u8],
+        pos: &mut u32,
+        known_end: &mut bool,
+        options: &UnpackOptions,
+    ) -> Result<Self> {
         let num_bytes = u32::unpack(src, pos)?;
-        if num_bytes % T::FIXED_SIZE != 0 {
-            return Err(Error::BadSize);
-        }
-        let hp = *pos as u64 + num_bytes as u64;
-        let mut heap_pos = hp as u32;
-        if heap_pos as u64 != hp {
-            return Err(Error::ReadPastEnd);
-        }
-        let len = (num_bytes / T::FIXED_SIZE) as usize;
+        let len = vec_len(num_bytes, T::FIXED_SIZE, options)? as usize;
+        // Checking this before allocating keeps a bogus size from
+        // reserving far more memory than the data could fill
+        let mut heap_pos = heap_start(src, *pos, num_bytes as u64)?;
         let mut result = Self::with_capacity(len);
         for _ in 0..len {
-            result.push(T::embedded_unpack(src, pos, &mut heap_pos)?);
+            result.push(T::embedded_unpack(
+                src,
+                pos,
+                &mut heap_pos,
+                known_end,
+                options,
+            )?);
         }
         *pos = heap_pos;
         Ok(result)
     }
 
-    // TODO: optimize scalar
     fn verify(src: &'a [u8], pos: &mut u32) -> Result<()> {
+        verify_to_end::<Self>(src, pos)
+    }
+
+    // TODO: optimize scalar
+    fn verify_at(
+        src: &'a [u8],
+        pos: &mut u32,
+        known_end: &mut bool,
+        options: &UnpackOptions,
+    ) -> Result<()> {
         let num_bytes = u32::unpack(src, pos)?;
-        if num_bytes % T::FIXED_SIZE != 0 {
-            return Err(Error::BadSize);
-        }
-        let hp = *pos as u64 + num_bytes as u64;
-        let mut heap_pos = hp as u32;
-        if heap_pos as u64 != hp {
-            return Err(Error::ReadPastEnd);
-        }
-        for _ in 0..num_bytes / T::FIXED_SIZE {
-            T::embedded_verify(src, pos, &mut heap_pos)?;
+        let len = vec_len(num_bytes, T::FIXED_SIZE, options)?;
+        let mut heap_pos = heap_start(src, *pos, num_bytes as u64)?;
+        for _ in 0..len {
+            T::embedded_verify(src, pos, &mut heap_pos, known_end, options)?;
         }
         *pos = heap_pos;
         Ok(())
@@ -724,6 +1610,126 @@ impl<'a, T: Unpack<'a>> Unpack<'a> for Vec<T> {
     }
 }
 
+// Sets are packed as a vector of their elements in increasing order,
+// so equal sets have the same encoding. The elements must be unpacked
+// to check the order, even by verify.
+fn unpack_sorted<'a, T: Unpack<'a> + Ord>(
+    src: &'a [u8],
+    pos: &mut u32,
+    known_end: &mut bool,
+    options: &UnpackOptions,
+) -> Result<Vec<T>> {
+    let items = Vec::<T>::unpack_at(src, pos, known_end, options)?;
+    if items.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(Error::UnsortedSet);
+    }
+    Ok(items)
+}
+
+impl<T: Pack + Ord> Pack for BTreeSet<T> {
+    const FIXED_SIZE: u32 = 4;
+    const VARIABLE_SIZE: bool = true;
+
+    fn pack(&self, dest: &mut Vec<u8>) {
+        self.iter().collect::<Vec<_>>().pack(dest)
+    }
+
+    fn is_empty_container(&self) -> bool {
+        self.is_empty()
+    }
+}
+
+impl<'a, T: Unpack<'a> + Ord> Unpack<'a> for BTreeSet<T> {
+    const FIXED_SIZE: u32 = 4;
+    const VARIABLE_SIZE: bool = true;
+
+    fn unpack(src: &'a [u8], pos: &mut u32) -> Result<Self> {
+        unpack_to_end(src, pos)
+    }
+
+    fn unpack_at(
+        src: &'a [u8],
+        pos: &mut u32,
+        known_end: &mut bool,
+        options: &UnpackOptions,
+    ) -> Result<Self> {
+        Ok(unpack_sorted(src, pos, known_end, options)?
+            .into_iter()
+            .collect())
+    }
+
+    fn verify(src: &'a [u8], pos: &mut u32) -> Result<()> {
+        verify_to_end::<Self>(src, pos)
+    }
+
+    fn verify_at(
+        src: &'a [u8],
+        pos: &mut u32,
+        known_end: &mut bool,
+        options: &UnpackOptions,
+    ) -> Result<()> {
+        unpack_sorted::<T>(src, pos, known_end, options)?;
+        Ok(())
+    }
+
+    fn new_empty_container() -> Result<Self> {
+        Ok(Default::default())
+    }
+}
+
+impl<T: Pack + Ord, S> Pack for HashSet<T, S> {
+    const FIXED_SIZE: u32 = 4;
+    const VARIABLE_SIZE: bool = true;
+
+    fn pack(&self, dest: &mut Vec<u8>) {
+        let mut items: Vec<_> = self.iter().collect();
+        items.sort_unstable();
+        items.pack(dest)
+    }
+
+    fn is_empty_container(&self) -> bool {
+        self.is_empty()
+    }
+}
+
+impl<'a, T: Unpack<'a> + Ord + Hash, S: BuildHasher + Default> Unpack<'a> for HashSet<T, S> {
+    const FIXED_SIZE: u32 = 4;
+    const VARIABLE_SIZE: bool = true;
+
+    fn unpack(src: &'a [u8], pos: &mut u32) -> Result<Self> {
+        unpack_to_end(src, pos)
+    }
+
+    fn unpack_at(
+        src: &'a [u8],
+        pos: &mut u32,
+        known_end: &mut bool,
+        options: &UnpackOptions,
+    ) -> Result<Self> {
+        Ok(unpack_sorted(src, pos, known_end, options)?
+            .into_iter()
+            .collect())
+    }
+
+    fn verify(src: &'a [u8], pos: &mut u32) -> Result<()> {
+        verify_to_end::<Self>(src, pos)
+    }
+
+    fn verify_at(
+        src: &'a [u8],
+        pos: &mut u32,
+        known_end: &mut bool,
+        options: &UnpackOptions,
+    ) -> Result<()> {
+        unpack_sorted::<T>(src, pos, known_end, options)?;
+        Ok(())
+    }
+
+    fn new_empty_container() -> Result<Self> {
+        Ok(Default::default())
+    }
+}
+
 impl<T: Pack, const N: usize> Pack for [T; N] {
     const VARIABLE_SIZE: bool = T::VARIABLE_SIZE;
     const FIXED_SIZE: u32 = if T::VARIABLE_SIZE {
@@ -754,15 +1760,26 @@ impl<'a, T: Unpack<'a>, const N: usize> Unpack<'a> for [T; N] {
     };
 
     fn unpack(src: &'a [u8], pos: &mut u32) -> Result<Self> {
-        let hp = *pos as u64 + T::FIXED_SIZE as u64 * N as u64;
-        let mut heap_pos = hp as u32;
-        if heap_pos as u64 != hp {
-            return Err(Error::ReadPastEnd);
-        }
+        unpack_to_end(src, pos)
+    }
+
+    fn unpack_at(
+        src: &'a [u8],
+        pos: &mut u32,
+        known_end: &mut bool,
+        options: &UnpackOptions,
+    ) -> Result<Self> {
+        let mut heap_pos = heap_start(src, *pos, T::FIXED_SIZE as u64 * N as u64)?;
 
         let mut items: Vec<T> = Vec::with_capacity(N);
         for _ in 0..N {
-            items.push(T::embedded_unpack(src, pos, &mut heap_pos)?);
+            items.push(T::embedded_unpack(
+                src,
+                pos,
+                &mut heap_pos,
+                known_end,
+                options,
+            )?);
         }
 
         let result: [T; N] = items.try_into().unwrap_or_else(|v: Vec<T>| {
@@ -777,13 +1794,18 @@ impl<'a, T: Unpack<'a>, const N: usize> Unpack<'a> for [T; N] {
     }
 
     fn verify(src: &'a [u8], pos: &mut u32) -> Result<()> {
-        let hp = *pos as u64 + T::FIXED_SIZE as u64 * N as u64;
-        let mut heap_pos = hp as u32;
-        if heap_pos as u64 != hp {
-            return Err(Error::ReadPastEnd);
-        }
+        verify_to_end::<Self>(src, pos)
+    }
+
+    fn verify_at(
+        src: &'a [u8],
+        pos: &mut u32,
+        known_end: &mut bool,
+        options: &UnpackOptions,
+    ) -> Result<()> {
+        let mut heap_pos = heap_start(src, *pos, T::FIXED_SIZE as u64 * N as u64)?;
         for _ in 0..N {
-            T::embedded_verify(src, pos, &mut heap_pos)?;
+            T::embedded_verify(src, pos, &mut heap_pos, known_end, options)?;
         }
         *pos = heap_pos;
         Ok(())
@@ -820,30 +1842,47 @@ macro_rules! tuple_impls {
                 const VARIABLE_SIZE: bool = true;
                 const FIXED_SIZE: u32 = 4;
 
-                #[allow(non_snake_case,unused_mut)]
                 fn unpack(src: &'a [u8], pos: &mut u32) -> Result<Self> {
+                    unpack_to_end(src, pos)
+                }
+
+                #[allow(non_snake_case, unused_mut, unused_variables)]
+                fn unpack_at(
+                    src: &'a [u8],
+                    pos: &mut u32,
+                    known_end: &mut bool,
+                    options: &UnpackOptions,
+                ) -> Result<Self> {
                     let fixed_size = u16::unpack(src, pos)?;
-                    let mut heap_pos = *pos + fixed_size as u32;
-                    if heap_pos < *pos {
-                        return Err(Error::BadOffset);
-                    }
+                    let heap_start = pos.checked_add(fixed_size as u32).ok_or(Error::BadOffset)?;
+                    let mut heap_pos = heap_start;
                     $(
-                        let $name = $name::embedded_unpack(src, pos, &mut heap_pos)?;
+                        let $name =
+                            $name::embedded_unpack(src, pos, &mut heap_pos, known_end, options)?;
                     )*
+                    skip_unknown_fields(src, pos, heap_start, &mut heap_pos, known_end)?;
                     *pos = heap_pos;
                     Ok(($($name,)*))
                 }
 
-                #[allow(unused_mut)]
                 fn verify(src: &'a [u8], pos: &mut u32) -> Result<()> {
+                    verify_to_end::<Self>(src, pos)
+                }
+
+                #[allow(unused_mut, unused_variables)]
+                fn verify_at(
+                    src: &'a [u8],
+                    pos: &mut u32,
+                    known_end: &mut bool,
+                    options: &UnpackOptions,
+                ) -> Result<()> {
                     let fixed_size = u16::unpack(src, pos)?;
-                    let mut heap_pos = *pos + fixed_size as u32;
-                    if heap_pos < *pos {
-                        return Err(Error::BadOffset);
-                    }
+                    let heap_start = pos.checked_add(fixed_size as u32).ok_or(Error::BadOffset)?;
+                    let mut heap_pos = heap_start;
                     $(
-                        $name::embedded_unpack(src, pos, &mut heap_pos)?;
+                        $name::embedded_verify(src, pos, &mut heap_pos, known_end, options)?;
                     )*
+                    skip_unknown_fields(src, pos, heap_start, &mut heap_pos, known_end)?;
                     *pos = heap_pos;
                     Ok(())
                 }
@@ -852,6 +1891,10 @@ macro_rules! tuple_impls {
     }
 }
 
+// `()` is the empty tuple. It packs as a fixed size of 0 (`[0, 0]`)
+// rather than as no bytes at all, which is the same encoding that
+// C++ uses for the arguments of an action that takes none. Because
+// tuples are variable size, `Vec<()>` has no zero-sized elements.
 tuple_impls! {
     0 => ()
     1 => (0 T0)
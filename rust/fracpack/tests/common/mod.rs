@@ -0,0 +1,22 @@
+use fracpack::{verify_canonical, Pack, Unpack};
+use std::fmt::Debug;
+
+/// Pack `value`, check that the result verifies and is canonical, and
+/// check that it unpacks to an equal value. Returns the packed bytes.
+pub fn assert_roundtrip<T: Pack + for<'a> Unpack<'a> + PartialEq + Debug>(value: T) -> Vec<u8> {
+    let packed = value.packed();
+    if let Err(e) = T::verify_all(&packed) {
+        panic!(
+            "{:?} packed to {:02x?}, which fails verify: {}",
+            value, packed, e
+        );
+    }
+    if let Err(e) = verify_canonical::<T>(&packed) {
+        panic!(
+            "{:?} packed to {:02x?}, which is not canonical: {}",
+            value, packed, e
+        );
+    }
+    assert_eq!(T::unpacked(&packed).unwrap(), value);
+    packed
+}
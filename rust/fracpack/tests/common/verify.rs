@@ -0,0 +1,41 @@
+// Types and checks shared by tests/verify.rs and the verify fuzz target
+
+use fracpack::{verify_canonical, Error, Pack, Unpack};
+
+#[derive(Pack, Unpack, PartialEq, Debug, Clone)]
+#[fracpack(fracpack_mod = "fracpack")]
+pub struct Inner {
+    pub flag: bool,
+    pub name: String,
+}
+
+#[derive(Pack, Unpack, PartialEq, Debug, Clone)]
+#[fracpack(fracpack_mod = "fracpack")]
+pub struct Outer {
+    pub id: u64,
+    pub label: String,
+    pub data: Option<Vec<u8>>,
+    pub items: Vec<Inner>,
+    pub pair: (u16, String),
+    pub fixed: [u32; 2],
+}
+
+#[derive(Pack, Unpack, PartialEq, Debug, Clone)]
+#[fracpack(fracpack_mod = "fracpack")]
+pub enum Variant {
+    Empty(()),
+    Value(Outer),
+}
+
+/// Anything verify accepts must unpack, except for enum variants that
+/// verify skips for forward compatibility. verify_canonical must not
+/// panic either.
+pub fn check<'a, T: Unpack<'a> + Pack>(src: &'a [u8]) {
+    if T::verify_all(src).is_ok() {
+        match T::unpacked(src) {
+            Ok(_) | Err(Error::BadEnumIndex) => (),
+            Err(e) => panic!("verify passed but unpack failed: {}", e),
+        }
+    }
+    let _ = verify_canonical::<T>(src);
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use common::assert_roundtrip;
+use fracpack::{Error, Pack, Unpack};
+use std::borrow::Cow;
+
+#[derive(Pack, Unpack, PartialEq, Debug)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct Args {
+    name: Cow<'static, str>,
+    data: Cow<'static, [u8]>,
+    memo: Option<Cow<'static, str>>,
+}
+
+#[test]
+fn cow_str_packs_like_string() {
+    let owned = String::from("alice");
+    let borrowed: Cow<str> = Cow::Borrowed("alice");
+    assert_eq!(borrowed.packed(), owned.packed());
+    assert_eq!(
+        assert_roundtrip(Cow::<str>::Owned(owned.clone())),
+        owned.packed()
+    );
+    assert_eq!(
+        assert_roundtrip(Cow::<str>::Borrowed("")),
+        String::new().packed()
+    );
+
+    let unpacked = Cow::<str>::unpacked(&owned.packed()).unwrap();
+    assert!(matches!(unpacked, Cow::Owned(ref s) if s == "alice"));
+}
+
+#[test]
+fn cow_bytes_packs_like_vec() {
+    let owned = vec![1u8, 2, 3];
+    let borrowed: Cow<[u8]> = Cow::Borrowed(&owned);
+    assert_eq!(borrowed.packed(), owned.packed());
+    assert_eq!(
+        assert_roundtrip(Cow::<[u8]>::Owned(owned.clone())),
+        owned.packed()
+    );
+
+    let unpacked = Cow::<[u8]>::unpacked(&owned.packed()).unwrap();
+    assert!(matches!(unpacked, Cow::Owned(ref v) if *v == owned));
+}
+
+#[test]
+fn cow_fields() {
+    let borrowed = Args {
+        name: Cow::Borrowed("transfer"),
+        data: Cow::Borrowed(&[0xff, 0]),
+        memo: Some(Cow::Borrowed("")),
+    };
+    let owned = Args {
+        name: Cow::Owned("transfer".to_string()),
+        data: Cow::Owned(vec![0xff, 0]),
+        memo: Some(Cow::Owned(String::new())),
+    };
+    assert_eq!(borrowed.packed(), owned.packed());
+    assert_eq!(
+        borrowed.packed(),
+        ("transfer".to_string(), vec![0xffu8, 0], Some(String::new())).packed()
+    );
+    assert_roundtrip(borrowed);
+}
+
+#[test]
+fn cow_str_rejects_bad_utf8() {
+    let packed = vec![0xffu8].packed();
+    assert!(matches!(Cow::<str>::unpacked(&packed), Err(Error::BadUTF8)));
+    assert!(matches!(
+        Cow::<str>::verify_all(&packed),
+        Err(Error::BadUTF8)
+    ));
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use common::assert_roundtrip;
+use fracpack::{Error, Pack, Unpack};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+#[derive(Pack, Unpack, PartialEq, Debug)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct Peer {
+    addr: IpAddr,
+    endpoint: SocketAddr,
+    mask: Ipv4Addr,
+}
+
+#[test]
+fn ipv4_round_trip() {
+    let addr = Ipv4Addr::new(192, 168, 1, 2);
+    assert_eq!(assert_roundtrip(addr), vec![192, 168, 1, 2]);
+    assert_eq!(
+        assert_roundtrip(IpAddr::V4(addr)),
+        vec![0, 4, 0, 0, 0, 192, 168, 1, 2]
+    );
+}
+
+#[test]
+fn ipv6_round_trip() {
+    let addr: Ipv6Addr = "2001:db8::1".parse().unwrap();
+    assert_eq!(assert_roundtrip(addr), addr.octets().to_vec());
+    let packed = assert_roundtrip(IpAddr::V6(addr));
+    assert_eq!(&packed[..5], &[1, 16, 0, 0, 0]);
+}
+
+#[test]
+fn socket_addr_round_trip() {
+    let v4: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+    assert_eq!(
+        assert_roundtrip(v4),
+        vec![0, 6, 0, 0, 0, 127, 0, 0, 1, 0x90, 0x1f]
+    );
+    let v6: SocketAddr = "[::1]:443".parse().unwrap();
+    assert_eq!(assert_roundtrip(v6).len(), 5 + 16 + 2);
+    assert_roundtrip(Peer {
+        addr: "::ffff:10.0.0.1".parse().unwrap(),
+        endpoint: v4,
+        mask: Ipv4Addr::new(255, 255, 255, 0),
+    });
+}
+
+#[test]
+fn bad_tag() {
+    let mut packed = IpAddr::V4(Ipv4Addr::LOCALHOST).packed();
+    packed[0] = 2;
+    assert!(matches!(
+        IpAddr::verify_all(&packed),
+        Err(Error::BadEnumIndex)
+    ));
+    assert!(matches!(
+        IpAddr::unpacked(&packed),
+        Err(Error::BadEnumIndex)
+    ));
+
+    let mut packed = "127.0.0.1:80".parse::<SocketAddr>().unwrap().packed();
+    packed[0] = 0xff;
+    assert!(matches!(
+        SocketAddr::verify_all(&packed),
+        Err(Error::BadEnumIndex)
+    ));
+}
+
+#[test]
+fn bad_size() {
+    let mut packed = IpAddr::V4(Ipv4Addr::LOCALHOST).packed();
+    packed[1] = 16;
+    packed.resize(5 + 16, 0);
+    assert!(matches!(IpAddr::verify_all(&packed), Err(Error::BadSize)));
+}
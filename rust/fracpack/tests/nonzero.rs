@@ -0,0 +1,60 @@
+mod common;
+
+use common::assert_roundtrip;
+use fracpack::{Error, Pack, Unpack};
+use std::num::{NonZeroI8, NonZeroU32, NonZeroU64};
+
+#[derive(Pack, Unpack, PartialEq, Debug)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct Counter {
+    id: NonZeroU64,
+    next: Option<NonZeroU32>,
+    history: Vec<NonZeroU32>,
+}
+
+#[test]
+fn nonzero_round_trip() {
+    let n = NonZeroU32::new(0x01020304).unwrap();
+    assert_eq!(assert_roundtrip(n), 0x01020304u32.packed());
+    assert_eq!(assert_roundtrip(NonZeroU64::MAX), u64::MAX.packed());
+    assert_eq!(assert_roundtrip(NonZeroI8::new(-1).unwrap()), vec![0xff]);
+    assert_roundtrip(Counter {
+        id: NonZeroU64::new(7).unwrap(),
+        next: None,
+        history: vec![n, NonZeroU32::MIN],
+    });
+    assert_roundtrip(Counter {
+        id: NonZeroU64::new(7).unwrap(),
+        next: Some(n),
+        history: vec![],
+    });
+}
+
+#[test]
+fn nonzero_rejects_zero() {
+    let zero = 0u32.packed();
+    assert!(matches!(
+        NonZeroU32::verify_all(&zero),
+        Err(Error::BadNonZero)
+    ));
+    assert!(matches!(
+        NonZeroU32::unpacked(&zero),
+        Err(Error::BadNonZero)
+    ));
+
+    let mut pos = 0;
+    assert!(NonZeroU32::unpack(&zero, &mut pos).is_err());
+    assert_eq!(pos, 0);
+
+    let packed = (0u64, None::<u32>, vec![1u32, 0]).packed();
+    assert!(matches!(
+        Counter::verify_all(&packed),
+        Err(Error::BadNonZero)
+    ));
+    let packed = (1u64, None::<u32>, vec![1u32, 0]).packed();
+    assert!(matches!(
+        Counter::verify_all(&packed),
+        Err(Error::BadNonZero)
+    ));
+    assert!(matches!(Counter::unpacked(&packed), Err(Error::BadNonZero)));
+}
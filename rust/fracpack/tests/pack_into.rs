@@ -0,0 +1,90 @@
+use fracpack::{Pack, Unpack};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom};
+
+#[derive(Pack, Unpack, PartialEq, Debug)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct Entry {
+    name: String,
+    data: Vec<u8>,
+    parent: Option<u32>,
+}
+
+#[derive(Pack, Unpack, PartialEq, Debug)]
+#[fracpack(fracpack_mod = "fracpack", definition_will_not_change)]
+struct Fixed {
+    a: u32,
+    b: u8,
+}
+
+#[derive(Pack, Unpack, PartialEq, Debug)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct Archive {
+    version: u32,
+    entries: Vec<Entry>,
+    empty: Vec<String>,
+    missing: Option<String>,
+    nested: Option<Option<Vec<u8>>>,
+    fixed: Vec<Fixed>,
+    blob: Vec<u8>,
+}
+
+fn sample() -> Archive {
+    Archive {
+        version: 3,
+        entries: (0..2000)
+            .map(|i| Entry {
+                name: format!("entry-{}", i),
+                data: vec![i as u8; i % 300],
+                parent: if i % 3 == 0 { None } else { Some(i as u32 / 2) },
+            })
+            .collect(),
+        empty: vec![],
+        missing: None,
+        nested: Some(Some(vec![])),
+        fixed: (0..100).map(|i| Fixed { a: i, b: i as u8 }).collect(),
+        blob: (0..300_000).map(|i| (i % 251) as u8).collect(),
+    }
+}
+
+#[test]
+fn pack_into_file_round_trip() -> std::io::Result<()> {
+    let orig = sample();
+    let mut file = BufWriter::new(tempfile::tempfile()?);
+    orig.pack_into(&mut file)?;
+    let mut file = BufReader::new(file.into_inner()?);
+    file.seek(SeekFrom::Start(0))?;
+    let mut packed = Vec::new();
+    file.read_to_end(&mut packed)?;
+    assert_eq!(packed, orig.packed());
+    assert_eq!(Archive::unpacked(&packed).unwrap(), orig);
+    Ok(())
+}
+
+#[test]
+fn pack_into_after_existing_data() -> std::io::Result<()> {
+    let orig = (vec![Some("a".to_string()), None], sample().entries);
+    let mut dest = std::io::Cursor::new(vec![0xff; 7]);
+    dest.seek(SeekFrom::End(0))?;
+    orig.pack_into(&mut dest)?;
+    assert_eq!(dest.into_inner()[7..], orig.packed());
+    Ok(())
+}
+
+#[derive(Pack)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct Oversized {
+    id: u32,
+    data: [u8; 0x10000],
+}
+
+#[test]
+fn pack_into_rejects_oversized_fixed_data() {
+    let value = Oversized {
+        id: 1,
+        data: [0; 0x10000],
+    };
+    let mut dest = std::io::Cursor::new(Vec::new());
+    let err = value.pack_into(&mut dest).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(dest.into_inner().is_empty());
+}
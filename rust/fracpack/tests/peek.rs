@@ -0,0 +1,51 @@
+use fracpack::{peek_field, Error, Pack, Unpack};
+
+#[derive(Pack, Unpack, PartialEq, Debug)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct Large {
+    names: Vec<String>,
+    count: u32,
+    note: Option<String>,
+    data: Vec<u8>,
+}
+
+fn large() -> Large {
+    Large {
+        names: (0..1000).map(|i| format!("name{}", i)).collect(),
+        count: 42,
+        note: None,
+        data: vec![7; 10000],
+    }
+}
+
+#[test]
+fn peek_u32_without_siblings() {
+    let mut packed = large().packed();
+    // Break a string in `names`. Unpacking the whole struct fails, but
+    // reading `count` never looks at it.
+    let bad = packed.iter().position(|b| *b == b'n').unwrap();
+    packed[bad] = 0xff;
+    assert!(Large::unpacked(&packed).is_err());
+    assert_eq!(peek_field::<u32>(&packed, 4).unwrap(), 42);
+}
+
+#[test]
+fn peek_variable_size_fields() {
+    let packed = large().packed();
+    assert_eq!(peek_field::<Vec<u8>>(&packed, 12).unwrap(), vec![7; 10000]);
+    assert_eq!(peek_field::<Option<String>>(&packed, 8).unwrap(), None);
+    assert_eq!(peek_field::<Vec<String>>(&packed, 0).unwrap().len(), 1000);
+}
+
+#[test]
+fn peek_past_fixed_data() {
+    let packed = large().packed();
+    assert!(matches!(
+        peek_field::<u32>(&packed, 14),
+        Err(Error::BadOffset)
+    ));
+    assert!(matches!(
+        peek_field::<u32>(&packed[..1], 0),
+        Err(Error::ReadPastEnd)
+    ));
+}
@@ -0,0 +1,178 @@
+mod common;
+
+use common::assert_roundtrip;
+use fracpack::{Pack, Unpack};
+use proptest::prelude::*;
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+
+#[derive(Pack, Unpack, PartialEq, Debug, Clone)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct Fixed {
+    a: u8,
+    b: i64,
+    c: [u16; 3],
+}
+
+#[derive(Pack, Unpack, PartialEq, Debug, Clone)]
+#[fracpack(fracpack_mod = "fracpack", definition_will_not_change)]
+struct Frozen {
+    id: u32,
+    name: String,
+    tags: Vec<u8>,
+}
+
+// Trailing empty options are left out of the packed form, so these
+// exercise the extensible struct layout
+#[derive(Pack, Unpack, PartialEq, Debug, Clone)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct Record {
+    name: String,
+    fixed: Fixed,
+    frozen: Option<Frozen>,
+    children: Vec<Option<String>>,
+    pair: (bool, Option<u32>),
+    count: Option<NonZeroU32>,
+    nested: Option<Option<Vec<u8>>>,
+}
+
+#[derive(Pack, Unpack, PartialEq, Debug, Clone)]
+#[fracpack(fracpack_mod = "fracpack")]
+enum Variant {
+    Empty(()),
+    Number(u64),
+    Text(String),
+    Record(Record),
+}
+
+// NaN doesn't compare equal to itself
+fn f32s() -> impl Strategy<Value = f32> {
+    prop::num::f32::POSITIVE
+        | prop::num::f32::NEGATIVE
+        | prop::num::f32::ZERO
+        | prop::num::f32::INFINITE
+        | prop::num::f32::SUBNORMAL
+}
+
+fn f64s() -> impl Strategy<Value = f64> {
+    prop::num::f64::POSITIVE
+        | prop::num::f64::NEGATIVE
+        | prop::num::f64::ZERO
+        | prop::num::f64::INFINITE
+        | prop::num::f64::SUBNORMAL
+}
+
+fn fixed() -> impl Strategy<Value = Fixed> {
+    (any::<u8>(), any::<i64>(), any::<[u16; 3]>()).prop_map(|(a, b, c)| Fixed { a, b, c })
+}
+
+fn frozen() -> impl Strategy<Value = Frozen> {
+    (any::<u32>(), ".*", prop::collection::vec(any::<u8>(), 0..8))
+        .prop_map(|(id, name, tags)| Frozen { id, name, tags })
+}
+
+fn record() -> impl Strategy<Value = Record> {
+    (
+        ".{0,8}",
+        fixed(),
+        prop::option::of(frozen()),
+        prop::collection::vec(prop::option::of(".{0,4}"), 0..6),
+        any::<(bool, Option<u32>)>(),
+        any::<Option<NonZeroU32>>(),
+        any::<Option<Option<Vec<u8>>>>(),
+    )
+        .prop_map(
+            |(name, fixed, frozen, children, pair, count, nested)| Record {
+                name,
+                fixed,
+                frozen,
+                children,
+                pair,
+                count,
+                nested,
+            },
+        )
+}
+
+fn variant() -> impl Strategy<Value = Variant> {
+    prop_oneof![
+        Just(Variant::Empty(())),
+        any::<u64>().prop_map(Variant::Number),
+        ".*".prop_map(Variant::Text),
+        record().prop_map(Variant::Record),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn scalars(
+        values in any::<(bool, i8, i16, i32, i64, u8, u16, u32, u64)>(),
+        x in f32s(),
+        y in f64s(),
+    ) {
+        assert_roundtrip(values);
+        assert_roundtrip((x, y));
+    }
+
+    #[test]
+    fn options(
+        a in any::<Option<u32>>(),
+        b in any::<Option<String>>(),
+        c in any::<Option<Option<u8>>>(),
+        d in any::<Option<Option<String>>>(),
+        e in any::<Option<Vec<u16>>>(),
+    ) {
+        assert_roundtrip(a);
+        assert_roundtrip(b);
+        assert_roundtrip(c);
+        assert_roundtrip(d);
+        assert_roundtrip(e);
+    }
+
+    #[test]
+    fn strings(s in ".*", t in any::<Vec<String>>()) {
+        assert_roundtrip(s);
+        assert_roundtrip(t);
+    }
+
+    #[test]
+    fn vectors(
+        a in any::<Vec<u8>>(),
+        b in any::<Vec<Option<u64>>>(),
+        c in prop::collection::vec(prop::collection::vec(".{0,4}", 0..8), 0..8),
+        d in any::<Vec<(u8, String)>>(),
+        e in any::<Vec<[Option<u8>; 2]>>(),
+    ) {
+        assert_roundtrip(a);
+        assert_roundtrip(b);
+        assert_roundtrip(c);
+        assert_roundtrip(d);
+        assert_roundtrip(e);
+    }
+
+    #[test]
+    fn tuples_and_arrays(
+        a in any::<(String, Option<u32>, Vec<bool>)>(),
+        b in any::<[String; 3]>(),
+        c in any::<(Option<String>, (u16, Option<String>))>(),
+        d in any::<(IpAddr, Option<IpAddr>)>(),
+    ) {
+        assert_roundtrip(a);
+        assert_roundtrip(b);
+        assert_roundtrip(c);
+        assert_roundtrip(d);
+    }
+
+    #[test]
+    fn structs(r in record(), f in frozen()) {
+        assert_roundtrip(r.clone());
+        assert_roundtrip(f);
+        assert_roundtrip(Some(r));
+    }
+
+    #[test]
+    fn variants(v in prop::collection::vec(variant(), 0..4)) {
+        assert_roundtrip(v.clone());
+        assert_roundtrip((v, Option::<Variant>::None));
+    }
+}
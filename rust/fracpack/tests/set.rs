@@ -0,0 +1,81 @@
+mod common;
+
+use common::assert_roundtrip;
+use fracpack::{Error, Pack, Unpack};
+use std::collections::{BTreeSet, HashSet};
+
+#[derive(Pack, Unpack, PartialEq, Debug)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct State {
+    ids: BTreeSet<u64>,
+    names: HashSet<String>,
+    owners: Option<BTreeSet<String>>,
+}
+
+#[test]
+fn insertion_order_does_not_matter() {
+    let a: BTreeSet<u32> = [3, 1, 2].into_iter().collect();
+    let b: BTreeSet<u32> = [2, 3, 1].into_iter().collect();
+    assert_eq!(a.packed(), b.packed());
+    assert_eq!(assert_roundtrip(a), vec![1u32, 2, 3].packed());
+
+    let mut a = HashSet::new();
+    let mut b = HashSet::new();
+    for name in ["carol", "alice", "bob"] {
+        a.insert(name.to_string());
+    }
+    for name in ["bob", "carol", "alice"] {
+        b.insert(name.to_string());
+    }
+    assert_eq!(a.packed(), b.packed());
+    assert_eq!(assert_roundtrip(a), vec!["alice", "bob", "carol"].packed());
+}
+
+#[test]
+fn hash_set_packs_like_btree_set() {
+    let items = [5u16, 9, 1, 7];
+    let hashed: HashSet<u16> = items.into_iter().collect();
+    let sorted: BTreeSet<u16> = items.into_iter().collect();
+    assert_eq!(hashed.packed(), sorted.packed());
+}
+
+#[test]
+fn duplicates_are_rejected() {
+    let packed = vec![1u32, 2, 2].packed();
+    assert!(matches!(
+        BTreeSet::<u32>::verify_all(&packed),
+        Err(Error::UnsortedSet)
+    ));
+    assert!(matches!(
+        BTreeSet::<u32>::unpacked(&packed),
+        Err(Error::UnsortedSet)
+    ));
+    assert!(matches!(
+        HashSet::<u32>::unpacked(&packed),
+        Err(Error::UnsortedSet)
+    ));
+
+    let packed = vec!["b", "a"].packed();
+    assert!(matches!(
+        HashSet::<String>::verify_all(&packed),
+        Err(Error::UnsortedSet)
+    ));
+    assert!(matches!(
+        BTreeSet::<String>::unpacked(&packed),
+        Err(Error::UnsortedSet)
+    ));
+}
+
+#[test]
+fn sets_in_structs() {
+    assert_roundtrip(State {
+        ids: [10, 2, 7].into_iter().collect(),
+        names: ["x".to_string(), "a".to_string()].into_iter().collect(),
+        owners: Some(BTreeSet::new()),
+    });
+    assert_roundtrip(State {
+        ids: BTreeSet::new(),
+        names: HashSet::new(),
+        owners: None,
+    });
+}
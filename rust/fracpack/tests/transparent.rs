@@ -0,0 +1,87 @@
+use fracpack::{verify_canonical, Pack, Unpack};
+
+#[derive(Pack, Unpack, PartialEq, Debug, Clone)]
+#[fracpack(fracpack_mod = "fracpack", transparent)]
+struct Name {
+    value: String,
+}
+
+#[derive(Pack, Unpack, PartialEq, Debug, Clone)]
+#[fracpack(fracpack_mod = "fracpack", transparent)]
+struct Version {
+    parts: (u32, u32, u32),
+}
+
+#[derive(Pack, Unpack, PartialEq, Debug, Clone)]
+#[fracpack(fracpack_mod = "fracpack", transparent)]
+struct Ids(Vec<u64>);
+
+#[derive(Pack, Unpack, PartialEq, Debug, Clone)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct Package {
+    name: Name,
+    version: Version,
+    ids: Ids,
+    alias: Option<Name>,
+}
+
+// The same layout, written out by hand
+#[derive(Pack, Unpack, PartialEq, Debug, Clone)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct PackageReference {
+    name: String,
+    version: (u32, u32, u32),
+    ids: Vec<u64>,
+    alias: Option<String>,
+}
+
+fn package(name: &str, alias: Option<&str>) -> (Package, PackageReference) {
+    (
+        Package {
+            name: Name {
+                value: name.to_string(),
+            },
+            version: Version { parts: (1, 2, 3) },
+            ids: Ids(vec![7, 8]),
+            alias: alias.map(|value| Name {
+                value: value.to_string(),
+            }),
+        },
+        PackageReference {
+            name: name.to_string(),
+            version: (1, 2, 3),
+            ids: vec![7, 8],
+            alias: alias.map(str::to_string),
+        },
+    )
+}
+
+#[test]
+fn transparent_packs_like_inner() {
+    assert_eq!(<Name as Pack>::FIXED_SIZE, <String as Pack>::FIXED_SIZE);
+    assert_eq!(
+        <Name as Pack>::VARIABLE_SIZE,
+        <String as Pack>::VARIABLE_SIZE
+    );
+    assert_eq!(
+        <Version as Pack>::FIXED_SIZE,
+        <(u32, u32, u32) as Pack>::FIXED_SIZE
+    );
+    let name = Name {
+        value: "abc".to_string(),
+    };
+    assert_eq!(name.packed(), "abc".to_string().packed());
+    assert_eq!(Name::unpacked(&name.packed()).unwrap(), name);
+}
+
+#[test]
+fn transparent_fields_pack_like_inlined_fields() {
+    for (name, alias) in [("pkg", Some("other")), ("", None), ("pkg", Some(""))] {
+        let (package, reference) = package(name, alias);
+        let packed = package.packed();
+        assert_eq!(packed, reference.packed());
+        verify_canonical::<Package>(&packed).unwrap();
+        assert_eq!(Package::unpacked(&packed).unwrap(), package);
+        assert_eq!(PackageReference::unpacked(&packed).unwrap(), reference);
+    }
+}
@@ -0,0 +1,41 @@
+use fracpack::{Pack, Unpack};
+
+#[derive(Pack, Unpack, PartialEq, Debug)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct NoArgs {}
+
+#[test]
+fn unit_matches_empty_struct() {
+    assert_eq!(().packed(), vec![0, 0]);
+    assert_eq!(().packed(), NoArgs {}.packed());
+    <()>::unpacked(&NoArgs {}.packed()).unwrap();
+    <()>::verify_all(&[0, 0]).unwrap();
+}
+
+#[test]
+fn unit_rejects_bad_encodings() {
+    assert!(<()>::verify_all(&[]).is_err());
+    assert!(<()>::verify_all(&[0]).is_err());
+    assert!(<()>::verify_all(&[0, 0, 0]).is_err());
+}
+
+#[test]
+fn vec_of_unit_round_trip() {
+    let orig = vec![(), (), ()];
+    let packed = orig.packed();
+    <Vec<()>>::verify_all(&packed).unwrap();
+    assert_eq!(<Vec<()>>::unpacked(&packed).unwrap(), orig);
+    assert_eq!(
+        <Vec<()>>::unpacked(&Vec::<()>::new().packed()).unwrap(),
+        vec![]
+    );
+}
+
+#[test]
+fn optional_unit_round_trip() {
+    for orig in [None, Some(())] {
+        let packed = (orig, 1u8).packed();
+        <(Option<()>, u8)>::verify_all(&packed).unwrap();
+        assert_eq!(<(Option<()>, u8)>::unpacked(&packed).unwrap(), (orig, 1));
+    }
+}
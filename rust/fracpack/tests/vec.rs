@@ -0,0 +1,33 @@
+use fracpack::{Pack, Unpack};
+
+// Has neither Clone nor Default, so unpacking a Vec of it can't
+// construct placeholder elements
+#[derive(Pack, Unpack, PartialEq, Debug)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct Entry {
+    key: String,
+    value: Option<Vec<u8>>,
+}
+
+#[test]
+fn large_vec_of_strings() {
+    let orig: Vec<String> = (0..50_000).map(|i| format!("item-{}", i)).collect();
+    let packed = orig.packed();
+    let unpacked = <Vec<String>>::unpacked(&packed).unwrap();
+    assert_eq!(unpacked, orig);
+    assert_eq!(unpacked.capacity(), orig.len());
+    assert_eq!(unpacked.packed(), packed);
+}
+
+#[test]
+fn vec_of_non_clone_structs() {
+    let orig: Vec<Entry> = (0..1000u32)
+        .map(|i| Entry {
+            key: i.to_string(),
+            value: (i % 3 != 0).then(|| i.to_le_bytes().to_vec()),
+        })
+        .collect();
+    let unpacked = <Vec<Entry>>::unpacked(&orig.packed()).unwrap();
+    assert_eq!(unpacked, orig);
+    assert_eq!(unpacked.capacity(), orig.len());
+}
@@ -0,0 +1,429 @@
+use fracpack::{verify_canonical, Error, Pack, Unpack, UnpackOptions};
+use proptest::prelude::*;
+
+#[path = "common/verify.rs"]
+mod shared;
+use shared::{check, Inner, Outer, Variant};
+
+#[derive(Pack, Unpack, PartialEq, Debug, Clone)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct Point {
+    x: u32,
+    y: u32,
+}
+
+fn sample() -> Outer {
+    Outer {
+        id: 7,
+        label: "label".into(),
+        data: Some(vec![1, 2, 3]),
+        items: vec![
+            Inner {
+                flag: true,
+                name: "a".into(),
+            },
+            Inner {
+                flag: false,
+                name: "".into(),
+            },
+        ],
+        pair: (3, "pair".into()),
+        fixed: [4, 5],
+    }
+}
+
+#[test]
+fn string_length_past_end() {
+    let src = [0xff, 0xff, 0xff, 0xff, b'a'];
+    assert!(matches!(String::verify_all(&src), Err(Error::ReadPastEnd)));
+    assert!(matches!(String::unpacked(&src), Err(Error::ReadPastEnd)));
+}
+
+#[test]
+fn vec_size_past_end() {
+    let src = [0xfc, 0xff, 0xff, 0xff];
+    assert!(matches!(
+        <Vec<u32>>::verify_all(&src),
+        Err(Error::ReadPastEnd)
+    ));
+    assert!(matches!(
+        <Vec<u32>>::unpacked(&src),
+        Err(Error::ReadPastEnd)
+    ));
+}
+
+#[test]
+fn vec_of_zero_size_elements() {
+    assert!(<Vec<[u8; 0]>>::unpacked(&[0, 0, 0, 0]).unwrap().is_empty());
+    assert!(matches!(
+        <Vec<[u8; 0]>>::verify_all(&[1, 0, 0, 0, 0]),
+        Err(Error::BadSize)
+    ));
+}
+
+#[test]
+fn tuple_fields_past_fixed_size() {
+    let src = [0, 0, 1, 2, 3, 4];
+    assert!(matches!(<(u32,)>::verify_all(&src), Err(Error::BadSize)));
+    assert!(matches!(<(u32,)>::unpacked(&src), Err(Error::BadSize)));
+}
+
+#[test]
+fn struct_fields_past_fixed_size() {
+    let mut src = Point { x: 1, y: 2 }.packed();
+    src[0] = 4;
+    assert!(matches!(Point::verify_all(&src), Err(Error::BadSize)));
+    assert!(matches!(Point::unpacked(&src), Err(Error::BadSize)));
+}
+
+#[test]
+fn enum_size_past_end() {
+    let src = [9, 0xff, 0xff, 0xff, 0xff];
+    assert!(matches!(Variant::verify_all(&src), Err(Error::ReadPastEnd)));
+}
+
+#[test]
+fn canonical_round_trip() {
+    let packed = Variant::Value(sample()).packed();
+    verify_canonical::<Variant>(&packed).unwrap();
+    let mut bad = (true, 1u8).packed();
+    bad[2] = 2;
+    assert!(matches!(
+        verify_canonical::<(bool, u8)>(&bad),
+        Err(Error::NonCanonical)
+    ));
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(6000))]
+
+    #[test]
+    fn mutated_encodings_do_not_panic(
+        seed in prop::sample::select(vec![
+            sample().packed(),
+            Variant::Value(sample()).packed(),
+            Variant::Empty(()).packed(),
+        ]),
+        edits in prop::collection::vec(
+            (any::<prop::sample::Index>(), prop_oneof![Just(0u8), Just(0xff), any::<u8>()]),
+            1..=4,
+        ),
+        truncate in prop::option::weighted(0.125, any::<prop::sample::Index>()),
+    ) {
+        let mut data = seed;
+        for (i, byte) in edits {
+            let i = i.index(data.len());
+            data[i] = byte;
+        }
+        if let Some(len) = truncate {
+            data.truncate(len.index(data.len()));
+        }
+        check::<Outer>(&data);
+        check::<Variant>(&data);
+    }
+}
+
+#[test]
+fn scalar_pos_near_u32_max() {
+    let src = [0u8; 8];
+    for start in [u32::MAX - 3, u32::MAX - 1, u32::MAX] {
+        let mut pos = start;
+        assert!(matches!(
+            u32::unpack(&src, &mut pos),
+            Err(Error::ReadPastEnd)
+        ));
+        assert!(matches!(
+            u32::verify(&src, &mut pos),
+            Err(Error::ReadPastEnd)
+        ));
+        assert!(matches!(
+            u8::verify(&src, &mut pos),
+            Err(Error::ReadPastEnd)
+        ));
+        assert_eq!(pos, start);
+    }
+}
+
+#[test]
+fn option_pos_near_u32_max() {
+    let src = [0u8; 8];
+    let mut pos = u32::MAX - 1;
+    assert!(matches!(
+        <Option<u32>>::unpack(&src, &mut pos),
+        Err(Error::ReadPastEnd)
+    ));
+    let mut pos = u32::MAX - 1;
+    assert!(matches!(
+        <Option<u32>>::verify(&src, &mut pos),
+        Err(Error::ReadPastEnd)
+    ));
+}
+
+#[test]
+fn variable_pos_near_u32_max() {
+    let src = [0u8; 8];
+    let mut pos = u32::MAX - 2;
+    assert!(String::unpack(&src, &mut pos).is_err());
+    let mut pos = u32::MAX - 2;
+    assert!(<Vec<u8>>::verify(&src, &mut pos).is_err());
+    let mut pos = u32::MAX - 2;
+    assert!(<(u32, String)>::verify(&src, &mut pos).is_err());
+    let mut pos = u32::MAX - 2;
+    assert!(Outer::verify(&src, &mut pos).is_err());
+}
+
+#[test]
+fn container_len_over_limit() {
+    // Claims 0x00ff_ff00 elements, all of which are present
+    let mut src = vec![0u8; 4 + 0x00ff_ff00];
+    src[..4].copy_from_slice(&0x00ff_ff00u32.to_le_bytes());
+    assert!(<Vec<u8>>::verify_all(&src).is_ok());
+    let options = UnpackOptions {
+        max_container_len: 0x1000,
+    };
+    assert!(matches!(
+        <Vec<u8>>::verify_all_with(&src, &options),
+        Err(Error::BadSize)
+    ));
+    assert!(matches!(
+        <Vec<u8>>::unpacked_with(&src, &options),
+        Err(Error::BadSize)
+    ));
+    assert!(matches!(
+        <&[u8]>::unpacked_with(&src, &options),
+        Err(Error::BadSize)
+    ));
+    assert!(matches!(
+        <Vec<u32>>::unpacked_with(&src, &options),
+        Err(Error::BadSize)
+    ));
+    let long = "x".repeat(0x1001).packed();
+    assert!(matches!(
+        String::unpacked_with(&long, &options),
+        Err(Error::BadSize)
+    ));
+    // The limit applies to nested containers too
+    let nested = (7u32, Some(vec!["x".repeat(0x1001)])).packed();
+    assert!(matches!(
+        <(u32, Option<Vec<String>>)>::verify_all_with(&nested, &options),
+        Err(Error::BadSize)
+    ));
+    let nested = Outer {
+        label: "x".repeat(0x1001),
+        ..sample()
+    }
+    .packed();
+    assert!(matches!(
+        Outer::unpacked_with(&nested, &options),
+        Err(Error::BadSize)
+    ));
+    Outer::verify_all_with(&sample().packed(), &options).unwrap();
+    let items = vec!["a".to_string(); 0x1000];
+    assert_eq!(
+        <Vec<String>>::unpacked_with(&items.packed(), &options).unwrap(),
+        items
+    );
+    assert!(<Vec<u8>>::unpacked_with(&src, &UnpackOptions::default()).is_ok());
+    assert!(<Vec<u8>>::unpacked(&src).is_ok());
+}
+
+// Inner with a field added at the end
+#[derive(Pack, Unpack, PartialEq, Debug, Clone)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct InnerV2 {
+    flag: bool,
+    name: String,
+    note: Option<String>,
+}
+
+#[test]
+fn verify_prefix_reports_consumed_length() {
+    let packed = sample().packed();
+    assert_eq!(Outer::verify_prefix(&packed).unwrap(), packed.len());
+    Outer::verify_all(&packed).unwrap();
+
+    let mut extra = packed.clone();
+    extra.extend_from_slice(&[0xff; 3]);
+    assert_eq!(Outer::verify_prefix(&extra).unwrap(), packed.len());
+    assert!(matches!(
+        Outer::verify_all(&extra),
+        Err(Error::TrailingData)
+    ));
+
+    // Values can be read back to back
+    let mut both = Point { x: 1, y: 2 }.packed();
+    both.extend_from_slice(&"next".packed());
+    let len = Point::verify_prefix(&both).unwrap();
+    assert_eq!(
+        String::verify_prefix(&both[len..]).unwrap(),
+        both.len() - len
+    );
+
+    for len in [0, 1, packed.len() / 2, packed.len() - 1] {
+        assert!(Outer::verify_prefix(&packed[..len]).is_err());
+    }
+
+    // The heap data of unknown fields may run to the end
+    let packed = InnerV2 {
+        flag: true,
+        name: "a".into(),
+        note: Some("note".into()),
+    }
+    .packed();
+    assert_eq!(Inner::verify_prefix(&packed).unwrap(), packed.len());
+}
+
+#[test]
+fn verify_all_requires_exact_length() {
+    let packed = sample().packed();
+    Outer::verify_all(&packed).unwrap();
+    <Vec<Point>>::verify_all(&vec![Point { x: 1, y: 2 }].packed()).unwrap();
+
+    let mut extra = packed.clone();
+    extra.push(0);
+    assert!(matches!(
+        Outer::verify_all(&extra),
+        Err(Error::TrailingData)
+    ));
+    let mut extra = 5u32.packed();
+    extra.extend_from_slice(&[0; 4]);
+    assert!(matches!(u32::verify_all(&extra), Err(Error::TrailingData)));
+    #[allow(deprecated)]
+    let old = u32::verify_no_extra(&extra);
+    assert!(matches!(old, Err(Error::TrailingData)));
+
+    for len in [0, 1, packed.len() / 2, packed.len() - 1] {
+        let err = Outer::verify_all(&packed[..len]).unwrap_err();
+        assert!(!matches!(err, Error::TrailingData), "{}", err);
+    }
+}
+
+#[test]
+fn unknown_trailing_fields() {
+    let new = |note: Option<&str>| InnerV2 {
+        flag: true,
+        name: "a".into(),
+        note: note.map(|n| n.into()),
+    };
+    let old = Inner {
+        flag: true,
+        name: "a".into(),
+    };
+    for note in [None, Some(""), Some("note")] {
+        let packed = new(note).packed();
+        Inner::verify_all(&packed).unwrap();
+        assert_eq!(Inner::unpacked(&packed).unwrap(), old);
+
+        // The unknown heap data is skipped when more data follows
+        let packed = (vec![new(note), new(Some("x"))], "end").packed();
+        <(Vec<Inner>, String)>::verify_all(&packed).unwrap();
+        assert_eq!(
+            <(Vec<Inner>, String)>::unpacked(&packed).unwrap(),
+            (vec![old.clone(), old.clone()], "end".to_string())
+        );
+    }
+
+    // Tuples are extensible too
+    let packed = (7u32, "a", Some(vec![1u8])).packed();
+    <(u32, String)>::verify_all(&packed).unwrap();
+    assert_eq!(
+        <(u32, String)>::unpacked(&packed).unwrap(),
+        (7, "a".to_string())
+    );
+
+    // Unknown fields must be offset pointers
+    let packed = (7u32, "a", 1u16).packed();
+    assert!(matches!(
+        <(u32, String)>::unpacked(&packed),
+        Err(Error::BadSize)
+    ));
+    let packed = (7u32, "a", 2u32).packed();
+    assert!(matches!(
+        <(u32, String)>::unpacked(&packed),
+        Err(Error::BadOffset)
+    ));
+}
+
+#[test]
+fn verify_after_failed_skip() {
+    let new = InnerV2 {
+        flag: true,
+        name: "a".into(),
+        note: Some("note".into()),
+    };
+    let old = Inner {
+        flag: true,
+        name: "a".into(),
+    };
+
+    // Fails after skipping the unknown heap data of the Inner
+    let packed = (new.clone(), 1u16).packed();
+    assert!(matches!(
+        <(Inner,)>::verify_all(&packed),
+        Err(Error::BadSize)
+    ));
+    let mut extra = (old.clone(), "b").packed();
+    extra.push(0);
+    assert!(matches!(
+        <(Inner, String)>::verify_all(&extra),
+        Err(Error::TrailingData)
+    ));
+
+    // A direct verify can't tell where the data ends, so it takes all of it
+    let packed = new.packed();
+    let mut pos = 0;
+    Inner::verify(&packed, &mut pos).unwrap();
+    assert_eq!(pos as usize, packed.len());
+
+    // The size of an enum variant gives the end of its content
+    #[derive(Pack, Unpack, PartialEq, Debug)]
+    #[fracpack(fracpack_mod = "fracpack")]
+    enum Versioned {
+        Old(Inner),
+    }
+    #[derive(Pack, Unpack)]
+    #[fracpack(fracpack_mod = "fracpack")]
+    enum VersionedV2 {
+        Old(InnerV2),
+    }
+    let packed = (VersionedV2::Old(new), "b").packed();
+    assert_eq!(
+        <(Versioned, String)>::unpacked(&packed).unwrap(),
+        (Versioned::Old(old), "b".to_string())
+    );
+    <(Versioned, String)>::verify_all(&packed).unwrap();
+}
+
+// A manual impl only needs unpack and verify
+#[derive(PartialEq, Debug)]
+struct Celsius(i16);
+
+impl Pack for Celsius {
+    const FIXED_SIZE: u32 = 2;
+    const VARIABLE_SIZE: bool = false;
+    fn pack(&self, dest: &mut Vec<u8>) {
+        self.0.pack(dest)
+    }
+}
+
+impl<'a> Unpack<'a> for Celsius {
+    const FIXED_SIZE: u32 = 2;
+    const VARIABLE_SIZE: bool = false;
+    fn unpack(src: &'a [u8], pos: &mut u32) -> fracpack::Result<Self> {
+        Ok(Celsius(i16::unpack(src, pos)?))
+    }
+    fn verify(src: &'a [u8], pos: &mut u32) -> fracpack::Result<()> {
+        i16::verify(src, pos)
+    }
+}
+
+#[test]
+fn manual_unpack_impl() {
+    let value = (vec![Celsius(-5), Celsius(20)], Some(Celsius(3)));
+    let packed = value.packed();
+    <(Vec<Celsius>, Option<Celsius>)>::verify_all(&packed).unwrap();
+    assert_eq!(
+        <(Vec<Celsius>, Option<Celsius>)>::unpacked(&packed).unwrap(),
+        value
+    );
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use common::assert_roundtrip;
+use fracpack::{Error, Pack, Unpack};
+use std::num::Wrapping;
+
+#[derive(Pack, Unpack, PartialEq, Debug)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct Sequence {
+    next: Wrapping<u32>,
+    offset: Option<Wrapping<i64>>,
+    history: Vec<Wrapping<u16>>,
+}
+
+#[derive(Pack, Unpack, PartialEq, Debug)]
+#[fracpack(fracpack_mod = "fracpack")]
+struct BareSequence {
+    next: u32,
+    offset: Option<i64>,
+    history: Vec<u16>,
+}
+
+#[test]
+fn wrapping_packs_like_inner() {
+    assert_eq!(
+        assert_roundtrip(Wrapping(0x01020304u32)),
+        0x01020304u32.packed()
+    );
+    assert_eq!(assert_roundtrip(Wrapping(u64::MAX)), u64::MAX.packed());
+    assert_eq!(assert_roundtrip(Wrapping(-1i8)), vec![0xff]);
+    assert_eq!(assert_roundtrip(Wrapping(i16::MIN)), i16::MIN.packed());
+    assert_eq!(
+        assert_roundtrip(Wrapping(u32::MAX) + Wrapping(1)),
+        0u32.packed()
+    );
+}
+
+#[test]
+fn wrapping_in_structs() {
+    let wrapped = assert_roundtrip(Sequence {
+        next: Wrapping(7),
+        offset: Some(Wrapping(-3)),
+        history: vec![Wrapping(1), Wrapping(u16::MAX)],
+    });
+    let bare = BareSequence {
+        next: 7,
+        offset: Some(-3),
+        history: vec![1, u16::MAX],
+    };
+    assert_eq!(wrapped, bare.packed());
+    assert_eq!(
+        Sequence::unpacked(&wrapped).unwrap().history,
+        vec![Wrapping(1), Wrapping(u16::MAX)]
+    );
+
+    let wrapped = assert_roundtrip(Sequence {
+        next: Wrapping(0),
+        offset: None,
+        history: vec![],
+    });
+    let bare = BareSequence {
+        next: 0,
+        offset: None,
+        history: vec![],
+    };
+    assert_eq!(wrapped, bare.packed());
+}
+
+#[test]
+fn wrapping_rejects_short_input() {
+    assert!(matches!(
+        Wrapping::<u32>::verify_all(&[1, 2]),
+        Err(Error::ReadPastEnd)
+    ));
+    assert!(Wrapping::<u64>::unpacked(&[0; 4]).is_err());
+}
@@ -4,7 +4,10 @@ use psibase_names::{account_number_from_str, account_number_to_string};
 use std::{num::ParseIntError, str::FromStr};
 
 custom_error! { pub AccountNumberError
-    Invalid{s:String} = "Invalid AccountNumber {s}",
+    Invalid{s:String} = "Invalid AccountNumber {s}: name does not fit in 64 bits",
+    TooLong{s:String} = "Invalid AccountNumber {s}: names may have at most 18 characters",
+    InvalidChar{s:String, c:char} = "Invalid AccountNumber {s}: '{c}' is not allowed; names may only contain a-z, 0-9, and -",
+    InvalidStart{s:String} = "Invalid AccountNumber {s}: names must begin with a letter",
 }
 
 /// An account number.
@@ -36,7 +39,21 @@ impl AccountNumber {
         AccountNumber { value }
     }
 
+    /// Parses a name, failing unless it converts back to exactly `s`.
+    /// The empty name is value 0.
     pub fn from_exact(s: &str) -> Result<Self, AccountNumberError> {
+        if let Some(c) = s
+            .chars()
+            .find(|c| !matches!(c, 'a'..='z' | '0'..='9' | '-'))
+        {
+            return Err(AccountNumberError::InvalidChar { s: s.into(), c });
+        }
+        if s.len() > 18 {
+            return Err(AccountNumberError::TooLong { s: s.into() });
+        }
+        if s.starts_with(|c: char| !c.is_ascii_lowercase()) {
+            return Err(AccountNumberError::InvalidStart { s: s.into() });
+        }
         let result: Self = s.into();
         if result.to_string() != s {
             return Err(AccountNumberError::Invalid { s: s.into() });
@@ -127,6 +144,7 @@ impl std::fmt::Display for ExactAccountNumber {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn empty_name_is_zero() {
@@ -257,4 +275,78 @@ mod tests {
         let name = AccountNumber::from(0);
         assert_eq!(name.to_string(), "");
     }
+
+    #[test]
+    fn exact_boundary_names() {
+        assert_eq!(ExactAccountNumber::from_str("").unwrap().value, 0);
+        assert_eq!(ExactAccountNumber::new(0).to_string(), "");
+        for name in ["a", "a-", "a0", "natasharomanoff"] {
+            assert_eq!(
+                ExactAccountNumber::from_str(name).unwrap().to_string(),
+                name
+            );
+        }
+        assert!(matches!(
+            AccountNumber::from_exact("eeeeeeeeeeeeeeeeeee"),
+            Err(AccountNumberError::TooLong { .. })
+        ));
+        for (name, bad) in [("Alice", 'A'), ("a b", ' '), ("a_b", '_'), ("né", 'é')] {
+            match AccountNumber::from_exact(name) {
+                Err(AccountNumberError::InvalidChar { c, .. }) => assert_eq!(c, bad),
+                other => panic!("{}: {:?}", name, other),
+            }
+        }
+        for name in ["1abc", "-abc", "9"] {
+            assert!(matches!(
+                AccountNumber::from_exact(name),
+                Err(AccountNumberError::InvalidStart { .. })
+            ));
+        }
+        // Well-formed, but too long to compress. The second one would
+        // otherwise be silently truncated to a different name.
+        for name in ["ajv1fmi1tohwyuxy", "s0b--"] {
+            assert!(matches!(
+                AccountNumber::from_exact(name),
+                Err(AccountNumberError::Invalid { .. })
+            ));
+        }
+        assert_eq!(
+            ExactAccountNumber::from_str("a b").unwrap_err().to_string(),
+            "Invalid AccountNumber a b: ' ' is not allowed; names may only contain a-z, 0-9, and -"
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn exact_names_round_trip(
+            names in prop::collection::vec("[a-z][a-z0-9-]{0,17}", 100),
+        ) {
+            let mut valid = 0;
+            for name in &names {
+                match ExactAccountNumber::from_str(name) {
+                    Ok(account) => {
+                        prop_assert_eq!(&account.to_string(), name);
+                        prop_assert_eq!(AccountNumber::from(account), AccountNumber::from(&name[..]));
+                        valid += 1;
+                    }
+                    Err(AccountNumberError::Invalid { .. }) => {
+                        prop_assert_ne!(&AccountNumber::from(&name[..]).to_string(), name)
+                    }
+                    Err(e) => panic!("{}: {}", name, e),
+                }
+            }
+            // Enough of the names compress to exercise the round trip
+            prop_assert!(valid > 10);
+        }
+
+        // Every number displays as something, and the name, if accepted,
+        // is canonical
+        #[test]
+        fn numbers_display_canonically(value: u64, shift in 0..64u32) {
+            let name = AccountNumber::new(value >> shift).to_string();
+            if let Ok(account) = AccountNumber::from_exact(&name) {
+                prop_assert_eq!(account.to_string(), name);
+            }
+        }
+    }
 }
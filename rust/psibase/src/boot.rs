@@ -4,6 +4,7 @@ use crate::{
     Action, AnyPublicKey, Claim, ExactAccountNumber, GenesisActionData, MethodNumber,
     PackagedService, ProducerConfigRow, SignedTransaction, Tapos, TimePointSec, Transaction,
 };
+use custom_error::custom_error;
 use fracpack::Pack;
 use serde_bytes::ByteBuf;
 use sha2::{Digest, Sha256};
@@ -12,17 +13,40 @@ use std::io::{Cursor, Read, Seek};
 use std::str::FromStr;
 use wasm_bindgen::prelude::*;
 
+custom_error! { pub BootError
+    NoProducers                            = "Boot needs at least one producer",
+    DuplicateProducer{name: AccountNumber} = "Producer {name} is listed more than once",
+}
+
 macro_rules! method {
     ($name:expr) => {
         MethodNumber::new(method_raw!($name))
     };
 }
 
-fn set_producers_action(name: AccountNumber, key: Claim) -> Action {
-    producers::Wrapper::pack().setProducers(vec![ProducerConfigRow {
-        producerName: name,
-        producerAuth: key,
-    }])
+fn set_producers_action(names: &[AccountNumber], key: Claim) -> Action {
+    producers::Wrapper::pack().setProducers(
+        names
+            .iter()
+            .map(|name| ProducerConfigRow {
+                producerName: *name,
+                producerAuth: key.clone(),
+            })
+            .collect(),
+    )
+}
+
+fn check_producers(producers: &[AccountNumber]) -> Result<(), BootError> {
+    if producers.is_empty() {
+        return Err(BootError::NoProducers);
+    }
+    let mut seen = HashSet::new();
+    for name in producers {
+        if !seen.insert(*name) {
+            return Err(BootError::DuplicateProducer { name: *name });
+        }
+    }
+    Ok(())
 }
 
 fn to_claim(key: &AnyPublicKey) -> Claim {
@@ -76,13 +100,15 @@ fn genesis_transaction<R: Read + Seek>(
 /// Get initial actions
 ///
 /// This returns all actions that need to be packed into the transactions pushed after the
-/// boot block.
+/// boot block. `initial_producers` is the initial producer schedule; every producer
+/// signs with `initial_key`.
 pub fn get_initial_actions<R: Read + Seek>(
     initial_key: &Option<AnyPublicKey>,
-    initial_producer: AccountNumber,
+    initial_producers: &[AccountNumber],
     install_ui: bool,
     service_packages: &mut [PackagedService<R>],
 ) -> Result<Vec<Action>, anyhow::Error> {
+    check_producers(initial_producers)?;
     let mut actions = Vec::new();
     let has_packages = true;
 
@@ -102,7 +128,7 @@ pub fn get_initial_actions<R: Read + Seek>(
     }
 
     actions.push(set_producers_action(
-        initial_producer,
+        initial_producers,
         match initial_key {
             Some(k) => to_claim(k),
             None => Claim {
@@ -163,13 +189,13 @@ pub fn get_initial_actions<R: Read + Seek>(
 /// and install apps and documentation.
 ///
 /// If `initial_key` is set, then this initializes all accounts to use
-/// that key and sets the key the initial producer signs blocks with.
+/// that key and sets the key the initial producers sign blocks with.
 /// If it is not set, then this initializes all accounts to use
 /// `auth-any` (no keys required) and sets it up so producers
 /// don't need to sign blocks.
 pub fn create_boot_transactions<R: Read + Seek>(
     initial_key: &Option<AnyPublicKey>,
-    initial_producer: AccountNumber,
+    initial_producers: &[AccountNumber],
     install_ui: bool,
     expiration: TimePointSec,
     service_packages: &mut [PackagedService<R>],
@@ -177,7 +203,7 @@ pub fn create_boot_transactions<R: Read + Seek>(
     validate_dependencies(service_packages)?;
     let mut boot_transactions = vec![genesis_transaction(expiration, service_packages)?];
     let mut actions =
-        get_initial_actions(initial_key, initial_producer, install_ui, service_packages)?;
+        get_initial_actions(initial_key, initial_producers, install_ui, service_packages)?;
     let mut transactions = Vec::new();
     while !actions.is_empty() {
         let mut n = 0;
@@ -230,15 +256,14 @@ pub fn js_create_boot_transactions(
     for s in &deserialized_services[..] {
         services.push(js_err(PackagedService::new(Cursor::new(&s[..])))?);
     }
-    let now_plus_120secs = chrono::Utc::now() + chrono::Duration::seconds(120);
-    let expiration = TimePointSec {
-        seconds: now_plus_120secs.timestamp() as u32,
-    };
+    let expiration = js_err(TimePointSec::try_from(
+        chrono::Utc::now() + chrono::Duration::seconds(120),
+    ))?;
     let prod = js_err(ExactAccountNumber::from_str(&producer))?;
 
     let (boot_transactions, transactions) = js_err(create_boot_transactions(
         &None,
-        prod.into(),
+        &[prod.into()],
         true,
         expiration,
         &mut services[..],
@@ -255,3 +280,43 @@ pub fn js_create_boot_transactions(
         transactions,
     ))?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate as psibase;
+    use crate::{account, services::producers::action_structs::setProducers};
+    use fracpack::Unpack;
+
+    fn initial_producers(names: &[AccountNumber]) -> Result<Vec<AccountNumber>, anyhow::Error> {
+        let key = crate::PrivateKey::from_str(
+            "PVT_K1_2bfGi9rYsXQSXXTvJbDAPhHLQUojjaNLomdm3cEJ1XTzMqUt3V",
+        )?
+        .into_k1()?;
+        let key = crate::PublicKey::from(&secp256k1::PublicKey::from_secret_key(
+            secp256k1::SECP256K1,
+            &key,
+        ));
+        let key = AnyPublicKey::from_str(&key.to_string())?;
+        let actions = get_initial_actions::<Cursor<Vec<u8>>>(&Some(key), names, true, &mut [])?;
+        let action = actions
+            .iter()
+            .find(|act| act.service == producers::SERVICE && act.method == method!("setProducers"))
+            .unwrap();
+        let prods = setProducers::unpacked(&action.rawData)?.prods;
+        for prod in &prods {
+            assert_eq!(prod.producerAuth.service, account!("verifyk1"));
+        }
+        Ok(prods.iter().map(|prod| prod.producerName).collect())
+    }
+
+    #[test]
+    fn boot_configures_all_producers() -> Result<(), anyhow::Error> {
+        let names = [account!("prod-a"), account!("prod-b"), account!("prod-c")];
+        assert_eq!(initial_producers(&names)?, names);
+        assert_eq!(initial_producers(&names[..1])?, &names[..1]);
+        assert!(initial_producers(&[]).is_err());
+        assert!(initial_producers(&[names[0], names[1], names[0]]).is_err());
+        Ok(())
+    }
+}
@@ -55,12 +55,15 @@ custom_error! {
     PublicKeyNotFound   = "Public key not found",
     KeyTypeNotSupported = "Key type not supported",
     BadPinSource        = "Cannot interpret pin-source",
+    PrivateKeyNotPublic = "Expected a public key, but this looks like a private key. Use the matching PUB_ key instead",
+    UnknownPublicKey{key: String} = "Cannot parse {key} as a public key. Expected a PUB_K1_ or PUB_R1_ key, a PKCS #11 URI, or a file containing a PEM or DER encoded public key",
 }
 
 #[cfg(not(target_family = "wasm"))]
 custom_error! { pub K1Error
     OnlyK1              = "Only K1 keys are fully supported",
     Msg{s:String}       = "{s}",
+    SignerFailed{command: String, message: String} = "Signer {command} failed: {message}",
 }
 
 pub type EccPublicKey = [u8; 33];
@@ -335,30 +338,26 @@ struct PKCS8PrivateKeyK1 {
 
 #[cfg(not(target_family = "wasm"))]
 pub trait Signer: std::fmt::Debug {
-    fn get_claim(&self) -> crate::Claim;
-    fn sign(&self, data: &[u8]) -> Vec<u8>;
+    fn get_claim(&self) -> Result<crate::Claim, K1Error>;
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, K1Error>;
 }
 
 #[cfg(not(target_family = "wasm"))]
 impl Signer for PrivateKey {
-    fn get_claim(&self) -> crate::Claim {
-        crate::Claim {
+    fn get_claim(&self) -> Result<crate::Claim, K1Error> {
+        Ok(crate::Claim {
             service: AccountNumber::new(account_raw!("verifyk1")),
             rawData: fracpack::Pack::packed(&PublicKey::from(
-                &secp256k1::PublicKey::from_secret_key(
-                    secp256k1::SECP256K1,
-                    &self.into_k1().unwrap(),
-                ),
+                &secp256k1::PublicKey::from_secret_key(secp256k1::SECP256K1, &self.into_k1()?),
             ))
             .into(),
-        }
+        })
     }
-    fn sign(&self, data: &[u8]) -> Vec<u8> {
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, K1Error> {
         let digest = secp256k1::Message::from_hashed_data::<secp256k1::hashes::sha256::Hash>(data);
-        fracpack::Pack::packed(&Signature::from(
-            secp256k1::SECP256K1.sign_ecdsa(&digest, &self.into_k1().unwrap()),
-        ))
-        .into()
+        Ok(fracpack::Pack::packed(&Signature::from(
+            secp256k1::SECP256K1.sign_ecdsa(&digest, &self.into_k1()?),
+        )))
     }
 }
 
@@ -369,7 +368,7 @@ const OID_SECP256K1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.132.0.
 
 #[cfg(not(target_family = "wasm"))]
 impl Signer for PKCS8PrivateKeyK1 {
-    fn get_claim(&self) -> crate::Claim {
+    fn get_claim(&self) -> Result<crate::Claim, K1Error> {
         let algid = spki::AlgorithmIdentifier {
             oid: OID_ECDSA,
             parameters: Some(OID_SECP256K1),
@@ -381,17 +380,17 @@ impl Signer for PKCS8PrivateKeyK1 {
             subject_public_key: BitStringRef::from_bytes(&pubkey).unwrap(),
         })
         .unwrap();
-        crate::Claim {
+        Ok(crate::Claim {
             service: AccountNumber::new(account_raw!("verify-sig")),
             rawData: crate::Hex::from(keydata),
-        }
+        })
     }
-    fn sign(&self, data: &[u8]) -> Vec<u8> {
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, K1Error> {
         let digest = secp256k1::Message::from_hashed_data::<secp256k1::hashes::sha256::Hash>(data);
-        secp256k1::SECP256K1
+        Ok(secp256k1::SECP256K1
             .sign_ecdsa(&digest, &self.key)
             .serialize_compact()
-            .into()
+            .into())
     }
 }
 
@@ -451,16 +450,84 @@ fn lookup_public_key(session: &Session, key: ObjectHandle) -> Result<ObjectHandl
 
 #[cfg(not(target_family = "wasm"))]
 impl Signer for PKCS11PrivateKey {
-    fn get_claim(&self) -> crate::Claim {
-        crate::Claim {
+    fn get_claim(&self) -> Result<crate::Claim, K1Error> {
+        Ok(crate::Claim {
             service: AccountNumber::new(account_raw!("verify-sig")),
             rawData: crate::Hex::from(self.pubkey.clone()),
-        }
+        })
     }
-    fn sign(&self, data: &[u8]) -> Vec<u8> {
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, K1Error> {
         let digest = Sha256::digest(data);
         let session = self.session.lock().unwrap();
-        session.sign(&Mechanism::Ecdsa, self.key, &digest).unwrap()
+        session
+            .sign(&Mechanism::Ecdsa, self.key, &digest)
+            .map_err(|e| K1Error::Msg { s: e.to_string() })
+    }
+}
+
+// Signs by running an external program. See AnyPrivateKey::from_command.
+// The public key is only requested once it is needed.
+#[cfg(not(target_family = "wasm"))]
+#[derive(Debug)]
+struct ExternalSigner {
+    command: String,
+    claim: Mutex<Option<crate::Claim>>,
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl ExternalSigner {
+    fn failed(&self, message: String) -> K1Error {
+        K1Error::SignerFailed {
+            command: self.command.clone(),
+            message,
+        }
+    }
+    fn run(&self, args: &str) -> Result<String, K1Error> {
+        let result = Exec::shell(format!("{} {}", self.command, args))
+            .stdout(Redirection::Pipe)
+            .capture()
+            .map_err(|e| self.failed(e.to_string()))?;
+        if !result.success() {
+            return Err(self.failed(format!("{:?}", result.exit_status)));
+        }
+        Ok(result.stdout_str().trim().to_string())
+    }
+    fn claim(&self) -> Result<crate::Claim, K1Error> {
+        let mut cached = self.claim.lock().unwrap();
+        if let Some(claim) = &*cached {
+            return Ok(claim.clone());
+        }
+        let key = self.run("public-key")?;
+        let claim = AnyPublicKey::from_str(&key)
+            .map_err(|e| self.failed(format!("invalid public key {:?}: {}", key, e)))?
+            .key;
+        *cached = Some(claim.clone());
+        Ok(claim)
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl Signer for ExternalSigner {
+    fn get_claim(&self) -> Result<crate::Claim, K1Error> {
+        self.claim()
+    }
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, K1Error> {
+        let service = self.claim()?.service;
+        let digest: [u8; 32] = Sha256::digest(data).into();
+        let output = self.run(&format!("sign {}", crate::Hex::from(digest)))?;
+        let signature = crate::Hex::<[u8; 64]>::from_str(&output).map_err(|_| {
+            self.failed(format!(
+                "expected a 64 byte hex signature, got {:?}",
+                output
+            ))
+        })?;
+        if service == AccountNumber::new(account_raw!("verifyk1")) {
+            Ok(fracpack::Pack::packed(&Signature {
+                data: SignatureEnum::K1(signature.0),
+            }))
+        } else {
+            Ok(signature.0.into())
+        }
     }
 }
 
@@ -831,17 +898,51 @@ pub fn load_private_key(key: &str) -> Result<Box<dyn Signer>, anyhow::Error> {
     }
 
     let data = read_key_file(key, "PRIVATE KEY", Error::ExpectedPrivateKey)?;
-    let pkcs8_key = data.decode_msg::<pkcs8::PrivateKeyInfo>()?;
-    match pkcs8_key.algorithm.oids()? {
-        (OID_ECDSA, Some(OID_SECP256K1)) => Ok(Box::new(PKCS8PrivateKeyK1 {
-            key: secp256k1::SecretKey::from_slice(
-                sec1::EcPrivateKey::from_der(pkcs8_key.private_key)?.private_key,
-            )?,
-        })),
+    Ok(Box::new(PKCS8PrivateKeyK1 {
+        key: k1_from_pkcs8(&data.decode_msg::<pkcs8::PrivateKeyInfo>()?)?,
+    }))
+}
+
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn k1_from_pkcs8(
+    info: &pkcs8::PrivateKeyInfo,
+) -> Result<secp256k1::SecretKey, anyhow::Error> {
+    match info.algorithm.oids()? {
+        (OID_ECDSA, Some(OID_SECP256K1)) => Ok(secp256k1::SecretKey::from_slice(
+            sec1::EcPrivateKey::from_der(info.private_key)?.private_key,
+        )?),
         _ => Err(Error::ExpectedPrivateKey.into()),
     }
 }
 
+// Reads a secp256k1 key from a PEM or DER encoded PKCS #8 file
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn read_pkcs8_k1(path: &str) -> Result<secp256k1::SecretKey, anyhow::Error> {
+    let data = read_key_file(path, "PRIVATE KEY", Error::ExpectedPrivateKey)?;
+    k1_from_pkcs8(&data.decode_msg::<pkcs8::PrivateKeyInfo>()?)
+}
+
+// Returns the DER encoded PKCS #8 document for a secp256k1 key
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn k1_to_pkcs8(
+    key: &secp256k1::SecretKey,
+) -> Result<pkcs8::SecretDocument, anyhow::Error> {
+    let ec_key = Encode::to_der(&sec1::EcPrivateKey {
+        private_key: &key.secret_bytes(),
+        parameters: None,
+        public_key: None,
+    })?;
+    let info = pkcs8::PrivateKeyInfo {
+        algorithm: spki::AlgorithmIdentifier {
+            oid: OID_ECDSA,
+            parameters: Some(AnyRef::from(&OID_SECP256K1)),
+        },
+        private_key: &ec_key,
+        public_key: None,
+    };
+    Ok(pkcs8::SecretDocument::encode_msg(&info)?)
+}
+
 #[cfg(not(target_family = "wasm"))]
 fn load_pkcs11_public_key(key: &str) -> Result<Vec<u8>, anyhow::Error> {
     let mut uri = PKCS11URI::from_str(key)?;
@@ -876,6 +977,48 @@ pub struct AnyPrivateKey {
     key: Box<dyn Signer>,
 }
 
+#[cfg(not(target_family = "wasm"))]
+impl AnyPrivateKey {
+    /// Signs by running an external program, so that the private key
+    /// never has to be available to this process.
+    ///
+    /// `command` is run by the shell with one of the following appended:
+    /// - `public-key`: print the public key, in any form accepted by
+    ///   [AnyPublicKey]
+    /// - `sign <digest>`: `<digest>` is the hex-encoded sha256 of the packed
+    ///   transaction. Print the 64 byte compact (r, s) ECDSA signature in hex.
+    ///
+    /// The command is not run until the key is first used to sign, so
+    /// failures are reported as [K1Error::SignerFailed] by
+    /// [sign_transaction].
+    pub fn from_command(command: &str) -> Self {
+        Self {
+            key: Box::new(ExternalSigner {
+                command: command.to_string(),
+                claim: Mutex::new(None),
+            }),
+        }
+    }
+
+    // Keys from the keystore keep the claim of the form they were
+    // imported from: verifyk1 for PVT_K1_ keys, verify-sig for PKCS #8.
+    pub(crate) fn from_k1(key: secp256k1::SecretKey, pkcs8: bool) -> Self {
+        if pkcs8 {
+            Self {
+                key: Box::new(PKCS8PrivateKeyK1 { key }),
+            }
+        } else {
+            Self {
+                key: Box::new(PrivateKey::from(&key)),
+            }
+        }
+    }
+
+    pub(crate) fn claim(&self) -> Result<crate::Claim, K1Error> {
+        self.key.get_claim()
+    }
+}
+
 #[cfg(not(target_family = "wasm"))]
 impl FromStr for AnyPrivateKey {
     type Err = anyhow::Error;
@@ -914,7 +1057,12 @@ impl FromStr for AnyPublicKey {
             });
         }
 
-        if let Ok(pkey) = PublicKey::from_str(key) {
+        if key.starts_with("PVT_") {
+            return Err(Error::PrivateKeyNotPublic.into());
+        }
+
+        if key.starts_with("PUB_") {
+            let pkey = PublicKey::from_str(key)?;
             return Ok(Self {
                 key: crate::Claim {
                     service: AccountNumber::new(account_raw!("verifyk1")),
@@ -923,6 +1071,13 @@ impl FromStr for AnyPublicKey {
             });
         }
 
+        if !std::path::Path::new(key).is_file() {
+            return Err(Error::UnknownPublicKey {
+                key: key.to_string(),
+            }
+            .into());
+        }
+
         let data = read_key_file(key, "PUBLIC KEY", Error::ExpectedPublicKey)?;
 
         data.decode_msg::<spki::SubjectPublicKeyInfo<AnyRef, BitStringRef>>()?;
@@ -940,14 +1095,121 @@ pub fn sign_transaction(
     mut trx: crate::Transaction,
     keys: &[AnyPrivateKey],
 ) -> Result<crate::SignedTransaction, K1Error> {
-    trx.claims = keys.iter().map(|k| k.key.get_claim()).collect();
+    trx.claims = keys
+        .iter()
+        .map(|k| k.key.get_claim())
+        .collect::<Result<_, _>>()?;
     let transaction = fracpack::Pack::packed(&trx);
     let proofs = keys
         .iter()
-        .map(|k| k.key.sign(&transaction).into())
-        .collect();
+        .map(|k| Ok(k.key.sign(&transaction)?.into()))
+        .collect::<Result<_, K1Error>>()?;
     Ok(crate::SignedTransaction {
         transaction: transaction.into(),
         proofs,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_key_rejects_private_key() {
+        let err =
+            AnyPublicKey::from_str("PVT_K1_2bfGi9rYsXQSXXTvJbDAPhHLQUojjaNLomdm3cEJ1XTzMqUt3V")
+                .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::PrivateKeyNotPublic)
+        ));
+        assert!(err.to_string().contains("private key"));
+    }
+
+    #[test]
+    fn public_key_lists_formats() {
+        let err = AnyPublicKey::from_str("not-a-key").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::UnknownPublicKey { .. })
+        ));
+        let message = err.to_string();
+        assert!(message.contains("not-a-key"));
+        assert!(message.contains("PUB_K1_"));
+        assert!(message.contains("PKCS #11"));
+    }
+
+    #[test]
+    fn public_key_reports_bad_checksum() {
+        let err =
+            AnyPublicKey::from_str("PUB_K1_6MRyAjQq8ud7hVNYcfnVPJqcVpscN5So8BhtHuGYqET5BoDq64")
+                .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidKey)
+        ));
+    }
+
+    #[test]
+    fn external_signer_signs_digest() -> Result<(), anyhow::Error> {
+        let private_key =
+            PrivateKey::from_str("PVT_K1_2bfGi9rYsXQSXXTvJbDAPhHLQUojjaNLomdm3cEJ1XTzMqUt3V")?;
+        let public_key = PublicKey::from(&secp256k1::PublicKey::from_secret_key(
+            secp256k1::SECP256K1,
+            &private_key.into_k1()?,
+        ));
+        let dir = tempfile::tempdir()?;
+        let digest_file = dir.path().join("digest");
+        let script = dir.path().join("signer.sh");
+        std::fs::write(
+            &script,
+            format!(
+                "case \"$1\" in\n\
+                 public-key) echo {} ;;\n\
+                 sign) echo \"$2\" > {}; echo {} ;;\n\
+                 *) exit 1 ;;\n\
+                 esac\n",
+                public_key,
+                digest_file.display(),
+                crate::Hex::from([7u8; 64]),
+            ),
+        )?;
+
+        let key = AnyPrivateKey::from_command(&format!("sh {}", script.display()));
+        let signed = sign_transaction(crate::Transaction::default(), &[key])?;
+
+        let trx: crate::Transaction = fracpack::Unpack::unpacked(&signed.transaction)?;
+        assert_eq!(trx.claims.len(), 1);
+        assert_eq!(trx.claims[0].rawData.0, fracpack::Pack::packed(&public_key));
+        let digest: [u8; 32] = Sha256::digest(&signed.transaction[..]).into();
+        assert_eq!(
+            std::fs::read_to_string(&digest_file)?.trim(),
+            crate::Hex::from(digest).to_string()
+        );
+        assert_eq!(
+            signed.proofs[0].0,
+            fracpack::Pack::packed(&Signature {
+                data: SignatureEnum::K1([7; 64])
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn external_signer_reports_failure() {
+        let key = AnyPrivateKey::from_command("exit 3;");
+        let err = sign_transaction(crate::Transaction::default(), &[key]).unwrap_err();
+        assert!(matches!(err, K1Error::SignerFailed { .. }));
+    }
+
+    #[test]
+    fn external_signer_runs_on_first_use() -> Result<(), anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        let marker = dir.path().join("ran");
+        let key = AnyPrivateKey::from_command(&format!("touch {}; exit 3;", marker.display()));
+        assert!(!marker.exists());
+        assert!(key.claim().is_err());
+        assert!(marker.exists());
+        Ok(())
+    }
+}
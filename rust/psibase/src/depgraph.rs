@@ -1,7 +1,8 @@
 use crate::{version_match, Meta, PackageInfo, PackageRef, Version};
 use custom_error::custom_error;
+use log::debug;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use varisat::{ExtendFormula, Lit, Solver};
 
 // The following rules are hard requirements:
@@ -11,14 +12,22 @@ use varisat::{ExtendFormula, Lit, Solver};
 // - A package is only installed if it is required (transitively) to satisfy the request
 //
 // In addition, the solver prefers newer versions over older versions.
+//
+// A dependency on a name that no package has is a dependency on a virtual
+// package. It is satisfied by any package that provides a matching version
+// of it. When several packages could, the one that is requested, preferred,
+// or already installed is used. If that still leaves more than one, the
+// choice is up to the user.
 
 custom_error! {
     pub Error
         CannotResolvePackages          = "Cannot resolve packages",
     DependencyCycle = "Cycle in service dependencies",
+    Downgrade{package:String, installed:String, candidate:String} = "Refusing to downgrade {package} from {installed} to {candidate}",
+    AmbiguousProvider{name:String, providers:String} = "{name} is provided by several packages: {providers}",
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PackageDisposition {
     can_break: bool,
     can_remove: bool,
@@ -71,76 +80,227 @@ impl PackageDisposition {
 //
 //struct DepGraph
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum PackageOp {
     Install(PackageInfo),
     Replace(Meta, PackageInfo),
     Remove(Meta),
 }
 
+/// Options for [solve_dependencies]. The default only installs what
+/// is missing or out of date.
+#[derive(Debug, Clone, Default)]
+pub struct SolveOptions {
+    /// Replace the requested packages even if they are already installed
+    pub reinstall: bool,
+    /// With `reinstall`, also replace the dependencies of the requested
+    /// packages
+    pub reinstall_deps: bool,
+    /// Allow replacing an installed package with an older version
+    pub allow_downgrade: bool,
+    /// Packages to choose when more than one package provides a
+    /// dependency
+    pub prefer: Vec<String>,
+}
+
 pub fn solve_dependencies(
     packages: Vec<PackageInfo>,
     input: Vec<PackageRef>,
     existing: Vec<(Meta, PackageDisposition)>,
-    reinstall: bool,
+    options: &SolveOptions,
 ) -> Result<Vec<PackageOp>, anyhow::Error> {
-    let mut graph = DepGraph::new();
-    graph.reinstall = reinstall;
-    for package in packages {
-        graph.add(package);
+    let solve = |allow_downgrade: bool| {
+        let mut graph = DepGraph::new();
+        graph.reinstall = options.reinstall;
+        graph.reinstall_deps = options.reinstall_deps;
+        graph.allow_downgrade = allow_downgrade;
+        for name in &options.prefer {
+            graph.add_preferred(name.clone());
+        }
+        for package in &packages {
+            graph.add(package.clone());
+        }
+        for package in &input {
+            graph.add_input(package.clone());
+        }
+        for (package, disp) in &existing {
+            graph.add_existing(package.clone(), disp.clone());
+        }
+        graph.solve()
+    };
+    let result = solve(options.allow_downgrade);
+    if let Ok(ops) = &result {
+        for op in ops {
+            debug!("Resolved {:?}", op);
+        }
+    }
+    if let Err(e) = &result {
+        // Explain the failure if a downgrade would have fixed it
+        if !options.allow_downgrade
+            && matches!(e.downcast_ref(), Some(Error::CannotResolvePackages))
+        {
+            for op in solve(true).unwrap_or_default() {
+                if let PackageOp::Replace(old, new) = op {
+                    if Version::new(&new.version)? < Version::new(&old.version)? {
+                        Err(Error::Downgrade {
+                            package: old.name,
+                            installed: old.version,
+                            candidate: new.version,
+                        })?
+                    }
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Why an op is part of the result of [solve_dependencies]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PackageOpReason {
+    /// The package was one of the inputs
+    Requested,
+    /// The package is installed or replaced because this package
+    /// depends on it
+    DependencyOf(String),
+    /// An installed package that nothing in the plan depends on is
+    /// replaced, to satisfy the other installed packages
+    Upgrade,
+    /// No remaining package depends on it
+    Unused,
+}
+
+impl std::fmt::Display for PackageOpReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PackageOpReason::Requested => write!(f, "requested"),
+            PackageOpReason::DependencyOf(name) => write!(f, "dependency of {}", name),
+            PackageOpReason::Upgrade => write!(f, "upgrade of an installed package"),
+            PackageOpReason::Unused => write!(f, "no longer needed"),
+        }
     }
-    for package in input {
-        graph.add_input(package);
+}
+
+/// Gives the reason for each op returned by [solve_dependencies] for
+/// `input`. A dependency is attributed to the first package in the plan
+/// that depends on it.
+pub fn explain_ops(ops: &[PackageOp], input: &[PackageRef]) -> Vec<PackageOpReason> {
+    let requested: HashSet<&str> = input.iter().map(|r| r.name.as_str()).collect();
+    let mut providers: HashMap<&str, &str> = HashMap::new();
+    for op in ops {
+        if let PackageOp::Install(info) | PackageOp::Replace(_, info) = op {
+            for provided in &info.provides {
+                providers
+                    .entry(provided.name.as_str())
+                    .or_insert(info.name.as_str());
+            }
+        }
     }
-    for (package, disp) in existing {
-        graph.add_existing(package, disp);
+    let mut requester: HashMap<&str, &str> = HashMap::new();
+    for op in ops {
+        if let PackageOp::Install(info) | PackageOp::Replace(_, info) = op {
+            for dep in &info.depends {
+                let name = providers.get(dep.name.as_str()).copied();
+                let name = name.unwrap_or(dep.name.as_str());
+                requester.entry(name).or_insert(info.name.as_str());
+            }
+        }
     }
-    graph.solve()
+    ops.iter()
+        .map(|op| match op {
+            PackageOp::Install(info) | PackageOp::Replace(_, info) => {
+                if requested.contains(info.name.as_str()) {
+                    PackageOpReason::Requested
+                } else if let Some(name) = requester.get(info.name.as_str()) {
+                    PackageOpReason::DependencyOf(name.to_string())
+                } else {
+                    PackageOpReason::Upgrade
+                }
+            }
+            PackageOp::Remove(_) => PackageOpReason::Unused,
+        })
+        .collect()
 }
 
 pub struct DepGraph<'a> {
     packages: HashMap<String, HashMap<String, (PackageInfo, Lit)>>,
     request: HashMap<String, String>,
     existing: HashMap<String, (Meta, PackageDisposition, bool)>,
+    prefer: HashSet<String>,
+    // Packages with a dependency that several providers could satisfy,
+    // along with the dependency and the names of the providers
+    ambiguous: Vec<(Lit, String, Vec<String>)>,
     solver: Solver<'a>,
     upgrade_all: bool,
     reinstall: bool,
+    reinstall_deps: bool,
+    allow_downgrade: bool,
 }
 
 trait PackageDep {
     fn depends(&self) -> &[PackageRef];
+    fn provides(&self) -> &[PackageRef];
 }
 
 impl PackageDep for PackageInfo {
     fn depends(&self) -> &[PackageRef] {
         &self.depends
     }
+    fn provides(&self) -> &[PackageRef] {
+        &self.provides
+    }
 }
 
 impl PackageDep for Meta {
     fn depends(&self) -> &[PackageRef] {
         &self.depends
     }
+    fn provides(&self) -> &[PackageRef] {
+        &self.provides
+    }
+}
+
+// Maps each virtual package provided by one of packages to its provider.
+// Names that are also the names of packages are not virtual.
+fn virtual_names<'a, T: PackageDep + 'a>(
+    packages: impl Iterator<Item = (&'a String, &'a T)> + Clone,
+) -> HashMap<String, String> {
+    let names: HashSet<&String> = packages.clone().map(|(name, _)| name).collect();
+    let mut result = HashMap::new();
+    for (name, package) in packages {
+        for provided in package.provides() {
+            if !names.contains(&provided.name) {
+                result
+                    .entry(provided.name.clone())
+                    .or_insert_with(|| name.clone());
+            }
+        }
+    }
+    result
+}
+
+fn resolve_name<'a>(provided: &'a HashMap<String, String>, name: &'a String) -> &'a String {
+    provided.get(name).unwrap_or(name)
 }
 
 fn get_removed_impl(
     reg: &HashMap<String, PackageInfo>,
     names: &[PackageRef],
     existing: &HashMap<String, (Meta, PackageDisposition, bool)>,
+    provided: &HashMap<String, String>,
     found: &mut HashMap<String, bool>,
     result: &mut Vec<PackageOp>,
 ) {
     for name in names {
-        if !found.contains_key(&name.name) {
-            let (package, disp, broken) = existing.get(&name.name).unwrap();
-            found.insert(name.name.clone(), true);
-            let should_remove = !reg.contains_key(&name.name) && disp.can_remove;
+        let name = resolve_name(provided, &name.name);
+        if !found.contains_key(name) {
+            let (package, disp, broken) = existing.get(name).unwrap();
+            found.insert(name.clone(), true);
+            let should_remove = !reg.contains_key(name) && disp.can_remove;
             if !broken {
-                get_removed_impl(reg, &package.depends, existing, found, result);
+                get_removed_impl(reg, &package.depends, existing, provided, found, result);
                 if should_remove {
-                    result.push(PackageOp::Remove(
-                        existing.get(&name.name).unwrap().0.clone(),
-                    ));
+                    result.push(PackageOp::Remove(existing.get(name).unwrap().0.clone()));
                 }
             }
         }
@@ -152,26 +312,36 @@ fn get_installed_impl(
     reinstall: &HashMap<String, String>,
     names: &[PackageRef],
     existing: &mut HashMap<String, (Meta, PackageDisposition, bool)>,
+    provided: &HashMap<String, String>,
     found: &mut HashMap<String, bool>,
     result: &mut Vec<PackageOp>,
 ) -> Result<(), anyhow::Error> {
     for name in names {
-        if let Some(completed) = found.get(&name.name) {
+        let name = resolve_name(provided, &name.name);
+        if let Some(completed) = found.get(name) {
             if !completed {
                 Err(Error::DependencyCycle)?
             }
         } else {
-            let (nm, package) = reg.remove_entry(&name.name).unwrap();
+            let (nm, package) = reg.remove_entry(name).unwrap();
             found.insert(nm, false);
-            get_installed_impl(reg, reinstall, &package.depends, existing, found, result)?;
-            if let Some((meta, _, _)) = existing.remove(&name.name) {
-                if meta.version != package.version || reinstall.contains_key(&name.name) {
+            get_installed_impl(
+                reg,
+                reinstall,
+                &package.depends,
+                existing,
+                provided,
+                found,
+                result,
+            )?;
+            if let Some((meta, _, _)) = existing.remove(name) {
+                if meta.version != package.version || reinstall.contains_key(name) {
                     result.push(PackageOp::Replace(meta, package));
                 }
             } else {
                 result.push(PackageOp::Install(package));
             }
-            *found.get_mut(&name.name).unwrap() = true;
+            *found.get_mut(name).unwrap() = true;
         }
     }
     Ok(())
@@ -185,8 +355,7 @@ fn get_installed_impl(
 fn evaluate_changes(
     mut packages: HashMap<String, PackageInfo>,
     mut existing: HashMap<String, (Meta, PackageDisposition, bool)>,
-    request: HashMap<String, String>,
-    reinstall: bool,
+    reinstall: HashMap<String, String>,
 ) -> Result<Vec<PackageOp>, anyhow::Error> {
     let existing_refs: Vec<_> = existing
         .keys()
@@ -195,11 +364,13 @@ fn evaluate_changes(
             version: String::new(),
         })
         .collect();
+    let existing_provided = virtual_names(existing.iter().map(|(name, (meta, _, _))| (name, meta)));
     let mut result = vec![];
     get_removed_impl(
         &packages,
         &existing_refs,
         &existing,
+        &existing_provided,
         &mut HashMap::new(),
         &mut result,
     );
@@ -212,17 +383,38 @@ fn evaluate_changes(
             version: String::new(),
         })
         .collect();
+    let provided = virtual_names(packages.iter());
     get_installed_impl(
         &mut packages,
-        &if reinstall { request } else { HashMap::new() },
+        &reinstall,
         &installed_refs,
         &mut existing,
+        &provided,
         &mut HashMap::new(),
         &mut result,
     )?;
     Ok(result)
 }
 
+// Adds the transitive dependencies of names within the selected packages
+fn with_dependencies(
+    packages: &HashMap<String, PackageInfo>,
+    mut names: HashMap<String, String>,
+) -> HashMap<String, String> {
+    let mut stack: Vec<String> = names.keys().cloned().collect();
+    while let Some(name) = stack.pop() {
+        if let Some(package) = packages.get(&name) {
+            for dep in &package.depends {
+                if !names.contains_key(&dep.name) {
+                    names.insert(dep.name.clone(), dep.version.clone());
+                    stack.push(dep.name.clone());
+                }
+            }
+        }
+    }
+    names
+}
+
 fn get_selected_version<'a>(
     packages: &'a HashMap<String, (PackageInfo, Lit)>,
     model: &HashSet<Lit>,
@@ -241,9 +433,13 @@ impl<'a> DepGraph<'a> {
             packages: HashMap::new(),
             request: HashMap::new(),
             existing: HashMap::new(),
+            prefer: HashSet::new(),
+            ambiguous: vec![],
             solver: Solver::new(),
             upgrade_all: false,
             reinstall: false,
+            reinstall_deps: false,
+            allow_downgrade: false,
         }
     }
     pub fn add(&mut self, meta: PackageInfo) {
@@ -259,18 +455,23 @@ impl<'a> DepGraph<'a> {
     pub fn add_input(&mut self, input: PackageRef) {
         self.request.insert(input.name, input.version);
     }
+    /// Chooses `name` over the other providers of the same virtual package
+    pub fn add_preferred(&mut self, name: String) {
+        self.prefer.insert(name);
+    }
     pub fn add_existing(&mut self, meta: Meta, disposition: PackageDisposition) {
-        let is_known_package = self
+        let known = self
             .packages
             .get(&meta.name)
-            .map_or(false, |packages| packages.contains_key(&meta.version));
-        if !is_known_package {
+            .and_then(|packages| packages.get(&meta.version));
+        if known.is_none() {
             self.add(PackageInfo {
                 name: meta.name.clone(),
                 version: meta.version.clone(),
                 description: meta.description.clone(),
                 depends: meta.depends.clone(),
                 accounts: meta.accounts.clone(),
+                provides: meta.provides.clone(),
                 sha256: Default::default(),
                 file: String::new(),
             });
@@ -296,6 +497,14 @@ impl<'a> DepGraph<'a> {
                     }
                 }
                 if request_optimized && !self.improve_non_request_solution(&model) {
+                    for (var, name, providers) in &self.ambiguous {
+                        if model.contains(var) {
+                            Err(Error::AmbiguousProvider {
+                                name: name.clone(),
+                                providers: providers.join(", "),
+                            })?
+                        }
+                    }
                     let mut result = HashMap::new();
                     for (_, packages) in self.packages {
                         for (_, (meta, var)) in packages {
@@ -305,7 +514,14 @@ impl<'a> DepGraph<'a> {
                             }
                         }
                     }
-                    return evaluate_changes(result, self.existing, self.request, self.reinstall);
+                    let reinstall = if !self.reinstall {
+                        HashMap::new()
+                    } else if self.reinstall_deps {
+                        with_dependencies(&result, self.request)
+                    } else {
+                        self.request
+                    };
+                    return evaluate_changes(result, self.existing, reinstall);
                 }
             }
         }
@@ -334,7 +550,14 @@ impl<'a> DepGraph<'a> {
         }
         self.solver.add_clause(&negated);
         self.solver.assume(&[control]);
-        self.solver.solve().unwrap_or(false)
+        if self.solver.solve().unwrap_or(false) {
+            true
+        } else {
+            // The control assumption must not leak into the
+            // optimization of non-requested packages.
+            self.solver.assume(&[]);
+            false
+        }
     }
     // Optimizes packages that were not part of the request
     // - The current state of the package is preferred, unless upgrade_all is set
@@ -394,34 +617,79 @@ impl<'a> DepGraph<'a> {
         self.solver.add_clause(&negated);
         self.solver.solve().unwrap_or(false)
     }
-    fn get_matching(&self, pattern: &PackageRef) -> Result<Vec<Lit>, anyhow::Error> {
-        let mut result = vec![];
+    // Returns the packages that can satisfy pattern, grouped by name. If
+    // pattern names a virtual package, these are its providers, and
+    // there may be more than one name.
+    fn get_matching(
+        &self,
+        pattern: &PackageRef,
+    ) -> Result<BTreeMap<String, Vec<Lit>>, anyhow::Error> {
+        let mut result: BTreeMap<String, Vec<Lit>> = BTreeMap::new();
         if let Some(packages) = self.packages.get(&pattern.name) {
+            let group = result.entry(pattern.name.clone()).or_default();
             for (k, v) in packages {
                 if version_match(&pattern.version, k)? {
-                    result.push(v.1);
+                    group.push(v.1);
                 }
             }
+            return Ok(result);
+        }
+        for packages in self.packages.values() {
+            for (meta, var) in packages.values() {
+                for provided in &meta.provides {
+                    if provided.name == pattern.name
+                        && version_match(&pattern.version, &provided.version)?
+                    {
+                        result.entry(meta.name.clone()).or_default().push(*var);
+                    }
+                }
+            }
+        }
+        let chosen: BTreeMap<_, _> = result
+            .iter()
+            .filter(|(name, _)| {
+                self.request.contains_key(*name)
+                    || self.prefer.contains(*name)
+                    || self.existing.contains_key(*name)
+            })
+            .map(|(name, vars)| (name.clone(), vars.clone()))
+            .collect();
+        if !chosen.is_empty() {
+            result = chosen;
         }
         Ok(result)
     }
     fn add_depends(&mut self) -> Result<(), anyhow::Error> {
+        let mut ambiguous = vec![];
         for packages in self.packages.values() {
             for (meta, var) in packages.values() {
                 for dep in &meta.depends {
-                    let group = self.get_matching(dep)?;
+                    let matching = self.get_matching(dep)?;
+                    if matching.len() > 1 {
+                        let names = matching.keys().cloned().collect();
+                        ambiguous.push((*var, dep.name.clone(), names));
+                    }
+                    let group = matching.into_values().flatten().collect();
                     any_if(&mut self.solver, *var, group);
                 }
             }
         }
+        self.ambiguous = ambiguous;
         Ok(())
     }
     fn add_roots(&mut self) -> Result<(), anyhow::Error> {
         for (name, version) in &self.request {
-            let group = self.get_matching(&PackageRef {
+            let matching = self.get_matching(&PackageRef {
                 name: name.clone(),
                 version: version.clone(),
             })?;
+            if matching.len() > 1 {
+                Err(Error::AmbiguousProvider {
+                    name: name.clone(),
+                    providers: matching.keys().cloned().collect::<Vec<_>>().join(", "),
+                })?
+            }
+            let group: Vec<_> = matching.into_values().flatten().collect();
             any(&mut self.solver, &group);
         }
         Ok(())
@@ -448,17 +716,31 @@ impl<'a> DepGraph<'a> {
         let mut directly_broken = vec![];
         let mut reverse_graph: HashMap<String, Vec<String>> =
             self.existing.keys().map(|k| (k.clone(), vec![])).collect();
+        let provided = virtual_names(
+            self.existing
+                .iter()
+                .map(|(name, (meta, _, _))| (name, meta)),
+        );
         for (name, (meta, _, _)) in &self.existing {
             let mut broken = false;
             for dep in &meta.depends {
+                let dep_name = resolve_name(&provided, &dep.name);
                 if !broken {
-                    if let Some((package, _, _)) = self.existing.get(&dep.name) {
-                        broken = !version_match(&dep.version, &package.version)?;
+                    if let Some((package, _, _)) = self.existing.get(dep_name) {
+                        // A provider has the version that it provides
+                        let version = package
+                            .provides
+                            .iter()
+                            .find(|v| dep_name != &dep.name && v.name == dep.name)
+                            .map_or(&package.version, |v| &v.version);
+                        broken = !version_match(&dep.version, version)?;
                     } else {
                         broken = true;
                     }
                 }
-                reverse_graph.get_mut(&dep.name).unwrap().push(name.clone());
+                if let Some(rdepends) = reverse_graph.get_mut(dep_name) {
+                    rdepends.push(name.clone());
+                }
             }
             if broken {
                 directly_broken.push(name.clone());
@@ -485,7 +767,7 @@ impl<'a> DepGraph<'a> {
                 let mut allowed_versions = vec![];
                 let current_version = Version::new(&meta.version).unwrap();
                 for (k, v) in packages {
-                    if &Version::new(&k).unwrap() < &current_version {
+                    if !self.allow_downgrade && &Version::new(&k).unwrap() < &current_version {
                         self.solver.add_clause(&[!v.1]);
                     } else {
                         allowed_versions.push(v.1);
@@ -551,7 +833,7 @@ mod tests {
             name: "A".to_string(),
             version: "1.0.0".to_string(),
         });
-        assert_eq!(graph.solve()?, vec![a]);
+        assert_eq!(graph.solve()?, vec![PackageOp::Install(a)]);
         Ok(())
     }
 
@@ -571,7 +853,41 @@ mod tests {
             name: "B".to_string(),
             version: "1.0.0".to_string(),
         });
-        assert_eq!(graph.solve()?, packages);
+        assert_eq!(
+            graph.solve()?,
+            packages
+                .into_iter()
+                .map(PackageOp::Install)
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    // Once the requested packages are optimal, the dependencies must
+    // still be upgraded to their newest versions
+    #[test]
+    fn test_solve_newest_dependency() -> Result<(), anyhow::Error> {
+        let mut graph = DepGraph::new();
+        let mut packages: Vec<PackageInfo> = serde_json::from_str(
+            r#"[{"name":"A","description":"","version":"1.0.0","depends":[{"name":"B","version":"1.0.0"}],"accounts":[]}]"#,
+        )?;
+        for minor in (0..10).rev() {
+            packages.push(serde_json::from_str(&format!(
+                r#"{{"name":"B","description":"","version":"1.{}.0","depends":[],"accounts":[]}}"#,
+                minor
+            ))?);
+        }
+        for package in &packages {
+            graph.add(package.clone());
+        }
+        graph.add_input(PackageRef {
+            name: "A".to_string(),
+            version: "1.0.0".to_string(),
+        });
+        assert_eq!(
+            get_ids(&graph.solve()?),
+            vec![("B", "1.9.0"), ("A", "1.0.0")]
+        );
         Ok(())
     }
 
@@ -612,6 +928,185 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn explain_transitive_dependencies() -> Result<(), anyhow::Error> {
+        let packages: Vec<PackageInfo> = serde_json::from_str(
+            r#"[
+{"name":"A","description":"","version":"1.0.0","depends":[{"name":"B","version":"1.0.0"}],"accounts":[]},
+{"name":"B","description":"","version":"1.0.0","depends":[{"name":"C","version":"1.1.0"}],"accounts":[]},
+{"name":"C","description":"","version":"1.0.0","depends":[],"accounts":[]},
+{"name":"C","description":"","version":"1.1.0","depends":[],"accounts":[]}
+]"#,
+        )?;
+        let existing = vec![(
+            serde_json::from_str::<Meta>(r#"{"name":"C","version":"1.0.0"}"#)?,
+            PackageDisposition::upgradable("1.0.0"),
+        )];
+        let input = vec![PackageRef {
+            name: "A".to_string(),
+            version: "1.0.0".to_string(),
+        }];
+        let ops = solve_dependencies(packages, input.clone(), existing, &SolveOptions::default())?;
+        let explained: Vec<_> = ops
+            .iter()
+            .zip(explain_ops(&ops, &input))
+            .map(|(op, reason)| match op {
+                PackageOp::Install(info) => (info.name.as_str(), false, reason),
+                PackageOp::Replace(_, info) => (info.name.as_str(), true, reason),
+                PackageOp::Remove(meta) => panic!("unexpected removal of {}", meta.name),
+            })
+            .collect();
+        assert_eq!(
+            explained,
+            [
+                ("C", true, PackageOpReason::DependencyOf("B".to_string())),
+                ("B", false, PackageOpReason::DependencyOf("A".to_string())),
+                ("A", false, PackageOpReason::Requested),
+            ]
+        );
+        assert_eq!(explained[0].2.to_string(), "dependency of B");
+        Ok(())
+    }
+
+    #[test]
+    fn test_reinstall_deps() -> Result<(), anyhow::Error> {
+        let packages: Vec<PackageInfo> = serde_json::from_str(
+            r#"[
+{"name":"A","description":"","version":"1.0.0","depends":[{"name":"B","version":"1.0.0"}],"accounts":[]},
+{"name":"B","description":"","version":"1.0.0","depends":[{"name":"C","version":"1.0.0"}],"accounts":[]},
+{"name":"C","description":"","version":"1.0.0","depends":[],"accounts":[]},
+{"name":"D","description":"","version":"1.0.0","depends":[],"accounts":[]}
+]"#,
+        )?;
+        let existing = || -> Result<Vec<(Meta, PackageDisposition)>, anyhow::Error> {
+            let mut result = vec![];
+            for package in &packages {
+                let meta: Meta = serde_json::from_str(&serde_json::to_string(package)?)?;
+                result.push((meta, PackageDisposition::upgradable("1.0.0")));
+            }
+            Ok(result)
+        };
+        let input = || {
+            vec![PackageRef {
+                name: "A".to_string(),
+                version: "1.0.0".to_string(),
+            }]
+        };
+        let replaced = |ops: &[PackageOp]| -> Vec<String> {
+            ops.iter()
+                .map(|op| match op {
+                    PackageOp::Replace(_, info) => info.name.clone(),
+                    _ => panic!("unexpected op {:?}", op),
+                })
+                .collect()
+        };
+        let ops = solve_dependencies(
+            packages.clone(),
+            input(),
+            existing()?,
+            &SolveOptions {
+                reinstall: true,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(replaced(&ops), ["A"]);
+        let ops = solve_dependencies(
+            packages.clone(),
+            input(),
+            existing()?,
+            &SolveOptions {
+                reinstall: true,
+                reinstall_deps: true,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(replaced(&ops), ["C", "B", "A"]);
+        Ok(())
+    }
+
+    fn auth_packages() -> Result<Vec<PackageInfo>, anyhow::Error> {
+        Ok(serde_json::from_str(
+            r#"[
+{"name":"App","version":"1.0.0","depends":[{"name":"auth","version":"^1.0.0"}],"accounts":[]},
+{"name":"AuthSig","version":"1.0.0","depends":[],"accounts":[],"provides":[{"name":"auth","version":"1.2.0"}]},
+{"name":"AuthK1","version":"1.0.0","depends":[],"accounts":[],"provides":[{"name":"auth","version":"1.0.0"}]},
+{"name":"AuthOld","version":"1.0.0","depends":[],"accounts":[],"provides":[{"name":"auth","version":"0.9.0"}]}
+]"#,
+        )?)
+    }
+
+    fn app() -> Vec<PackageRef> {
+        vec![PackageRef {
+            name: "App".to_string(),
+            version: "1.0.0".to_string(),
+        }]
+    }
+
+    #[test]
+    fn test_single_provider() -> Result<(), anyhow::Error> {
+        let packages: Vec<_> = auth_packages()?
+            .into_iter()
+            .filter(|p| p.name != "AuthK1")
+            .collect();
+        let ops = solve_dependencies(packages, app(), vec![], &SolveOptions::default())?;
+        // The provider is installed first
+        assert_eq!(get_ids(&ops), [("AuthSig", "1.0.0"), ("App", "1.0.0")]);
+        assert_eq!(
+            explain_ops(&ops, &app())[0],
+            PackageOpReason::DependencyOf("App".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_providers() -> Result<(), anyhow::Error> {
+        let err = solve_dependencies(auth_packages()?, app(), vec![], &SolveOptions::default())
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::AmbiguousProvider { name, providers })
+                if name == "auth" && providers == "AuthK1, AuthSig"
+        ));
+
+        let ops = solve_dependencies(
+            auth_packages()?,
+            app(),
+            vec![],
+            &SolveOptions {
+                prefer: vec!["AuthK1".to_string()],
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(get_ids(&ops), [("AuthK1", "1.0.0"), ("App", "1.0.0")]);
+
+        // An installed provider is used without --prefer
+        let existing = vec![(
+            serde_json::from_str::<Meta>(
+                r#"{"name":"AuthSig","version":"1.0.0","provides":[{"name":"auth","version":"1.2.0"}]}"#,
+            )?,
+            PackageDisposition::upgradable("1.0.0"),
+        )];
+        let ops = solve_dependencies(auth_packages()?, app(), existing, &SolveOptions::default())?;
+        assert_eq!(get_ids(&ops), [("App", "1.0.0")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_provider() -> Result<(), anyhow::Error> {
+        // AuthOld provides a version that doesn't match
+        let packages: Vec<_> = auth_packages()?
+            .into_iter()
+            .filter(|p| p.name == "App" || p.name == "AuthOld")
+            .collect();
+        let err =
+            solve_dependencies(packages, app(), vec![], &SolveOptions::default()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::CannotResolvePackages)
+        ));
+        Ok(())
+    }
+
     #[test]
     fn test_version_select() -> Result<(), anyhow::Error> {
         let mut graph = DepGraph::new();
@@ -0,0 +1,658 @@
+use crate::reflect::Reflect;
+use crate::schema::{create_schema_with_root, BuildString, Definition, Field, TypeRef};
+use crate::{AccountNumber, MethodNumber};
+use anyhow::anyhow;
+use fracpack::Error;
+use std::collections::HashMap;
+
+/// One annotated region of packed data. See [dump_packed].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpEntry {
+    /// Start of the region within the packed data
+    pub offset: u32,
+
+    /// Number of bytes in the region
+    pub size: u32,
+
+    /// Where the region's value is within the top-level value, e.g.
+    /// `actions[0].sender`. Empty for the top-level value itself.
+    pub path: String,
+
+    /// What the region holds
+    pub description: String,
+}
+
+/// Annotate packed data of type `T`
+///
+/// This walks `src` the same way [fracpack::Unpack::verify_all]
+/// does, using `T`'s [Reflect] implementation for the layout, and
+/// returns the regions that it passes through, ordered by offset. The
+/// size headers of structs and tuples are annotated with where their
+/// fixed data and heap start and end. Each offset is annotated with
+/// its target.
+///
+/// Fails if `src` is not a valid `T`.
+///
+/// ```
+/// use psibase::{dump_packed, format_dump, Tapos, TimePointSec};
+/// use fracpack::Pack;
+///
+/// let packed = Tapos {
+///     expiration: TimePointSec { seconds: 10 },
+///     refBlockSuffix: 5,
+///     flags: 0,
+///     refBlockIndex: 3,
+/// }
+/// .packed();
+/// let entries = dump_packed::<Tapos>(&packed)?;
+/// assert_eq!(entries[0].path, "expiration.seconds");
+/// assert_eq!(entries[0].description, "u32 = 10");
+/// println!("{}", format_dump(&packed, &entries));
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub fn dump_packed<T: Reflect>(src: &[u8]) -> Result<Vec<DumpEntry>, anyhow::Error> {
+    let (schema, root) = create_schema_with_root::<T>();
+    let mut dumper = Dumper {
+        src,
+        types: schema
+            .userTypes
+            .iter()
+            .map(|def| (def.name.borrow().to_string(), def))
+            .collect(),
+        entries: Vec::new(),
+        heap_end_unknown: false,
+    };
+    let mut pos = 0;
+    dumper.walk(&root, "", &mut pos)?;
+    if pos as usize != src.len() {
+        if !dumper.heap_end_unknown {
+            return Err(Error::TrailingData.into());
+        }
+        dumper.push(
+            pos,
+            src.len() as u32 - pos,
+            "",
+            "heap data of unknown fields",
+        );
+    }
+    let mut entries = dumper.entries;
+    entries.sort_by_key(|entry| entry.offset);
+    Ok(entries)
+}
+
+/// Format the result of [dump_packed] with one line per entry: the
+/// offset, the first bytes of the region, and the annotation.
+pub fn format_dump(src: &[u8], entries: &[DumpEntry]) -> String {
+    const SHOWN: usize = 8;
+    let mut result = String::new();
+    for entry in entries {
+        let start = entry.offset as usize;
+        let end = start + entry.size as usize;
+        let mut bytes: Vec<_> = src[start..end.min(start + SHOWN)]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        if entry.size as usize > SHOWN {
+            bytes.push("..".to_string());
+        }
+        let label = if entry.path.is_empty() {
+            entry.description.clone()
+        } else {
+            format!("{}: {}", entry.path, entry.description)
+        };
+        result += &format!("{:06x}  {:<26}  {}\n", entry.offset, bytes.join(" "), label);
+    }
+    result
+}
+
+// Longest string shown in a description
+const MAX_SHOWN_STRING: usize = 40;
+
+struct Layout {
+    fixed_size: u32,
+    variable_size: bool,
+}
+
+struct Dumper<'a> {
+    src: &'a [u8],
+    types: HashMap<String, &'a Definition<BuildString>>,
+    entries: Vec<DumpEntry>,
+    heap_end_unknown: bool,
+}
+
+fn field_path(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+fn type_name(ty: &TypeRef<BuildString>) -> String {
+    match ty {
+        TypeRef::ty(name) | TypeRef::user(name) => name.borrow().to_string(),
+        TypeRef::vector(inner) => format!("vector of {}", type_name(inner)),
+        TypeRef::option(inner) => format!("optional {}", type_name(inner)),
+        TypeRef::tuple(_) => "tuple".to_string(),
+        TypeRef::array(inner, n) => format!("array of {} {}", n, type_name(inner)),
+        TypeRef::hex(n) => format!("{} bytes", n),
+    }
+}
+
+fn scalar_size(name: &str) -> Option<u32> {
+    match name {
+        "bool" | "u8" | "i8" => Some(1),
+        "u16" | "i16" => Some(2),
+        "u32" | "i32" | "f32" => Some(4),
+        "u64" | "i64" | "f64" => Some(8),
+        _ => None,
+    }
+}
+
+fn scalar_value(name: &str, bytes: &[u8]) -> String {
+    let mut buf = [0u8; 8];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    let raw = u64::from_le_bytes(buf);
+    match name {
+        "bool" => match raw {
+            0 => "false".to_string(),
+            1 => "true".to_string(),
+            _ => format!("{} (not a valid bool)", raw),
+        },
+        "i8" => (raw as i8).to_string(),
+        "i16" => (raw as i16).to_string(),
+        "i32" => (raw as i32).to_string(),
+        "i64" => (raw as i64).to_string(),
+        "f32" => f32::from_bits(raw as u32).to_string(),
+        "f64" => f64::from_bits(raw).to_string(),
+        _ => raw.to_string(),
+    }
+}
+
+impl<'a> Dumper<'a> {
+    fn push(&mut self, offset: u32, size: u32, path: &str, description: impl Into<String>) {
+        self.entries.push(DumpEntry {
+            offset,
+            size,
+            path: path.to_string(),
+            description: description.into(),
+        });
+    }
+
+    fn take(&self, pos: &mut u32, size: u32) -> Result<&'a [u8], Error> {
+        let start = *pos as usize;
+        let end = start + size as usize;
+        if end > self.src.len() {
+            return Err(Error::ReadPastEnd);
+        }
+        *pos = end as u32;
+        Ok(&self.src[start..end])
+    }
+
+    fn read_u32(&self, pos: &mut u32) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(pos, 4)?.try_into().unwrap()))
+    }
+
+    fn definition(&self, name: &BuildString) -> Result<&'a Definition<BuildString>, anyhow::Error> {
+        let name = name.borrow();
+        self.types
+            .get(name.as_ref())
+            .copied()
+            .ok_or_else(|| anyhow!("Type {} is not defined", name))
+    }
+
+    // Follows aliases, which pack the same as the type they name
+    fn resolve<'b>(
+        &self,
+        mut ty: &'b TypeRef<BuildString>,
+    ) -> Result<&'b TypeRef<BuildString>, anyhow::Error>
+    where
+        'a: 'b,
+    {
+        while let TypeRef::user(name) = ty {
+            match &self.definition(name)?.alias {
+                Some(alias) => ty = alias,
+                None => break,
+            }
+        }
+        Ok(ty)
+    }
+
+    fn layout(&self, ty: &TypeRef<BuildString>) -> Result<Layout, anyhow::Error> {
+        let variable = Layout {
+            fixed_size: 4,
+            variable_size: true,
+        };
+        Ok(match self.resolve(ty)? {
+            TypeRef::ty(name) => {
+                let name = name.borrow().to_string();
+                match scalar_size(&name) {
+                    Some(fixed_size) => Layout {
+                        fixed_size,
+                        variable_size: false,
+                    },
+                    None if name == "string" || name == "hex" => variable,
+                    None => return Err(anyhow!("Don't know how {} is packed", name)),
+                }
+            }
+            TypeRef::user(name) => {
+                let def = self.definition(name)?;
+                match (&def.structFields, def.definitionWillNotChange) {
+                    (Some(fields), Some(true)) => {
+                        let mut fixed_size = 0;
+                        for field in fields {
+                            let layout = self.layout(&field.ty)?;
+                            if layout.variable_size {
+                                return Ok(variable);
+                            }
+                            fixed_size += layout.fixed_size;
+                        }
+                        Layout {
+                            fixed_size,
+                            variable_size: false,
+                        }
+                    }
+                    _ => variable,
+                }
+            }
+            TypeRef::array(inner, n) => {
+                let inner = self.layout(inner)?;
+                if inner.variable_size {
+                    variable
+                } else {
+                    Layout {
+                        fixed_size: inner.fixed_size * n,
+                        variable_size: false,
+                    }
+                }
+            }
+            TypeRef::hex(n) => Layout {
+                fixed_size: *n,
+                variable_size: false,
+            },
+            TypeRef::vector(_) | TypeRef::option(_) | TypeRef::tuple(_) => variable,
+        })
+    }
+
+    // Walks a value that starts at pos, like Unpack::verify
+    fn walk(
+        &mut self,
+        ty: &TypeRef<BuildString>,
+        path: &str,
+        pos: &mut u32,
+    ) -> Result<(), anyhow::Error> {
+        let start = *pos;
+        match self.resolve(ty)? {
+            TypeRef::ty(name) => {
+                let name = name.borrow().to_string();
+                if let Some(size) = scalar_size(&name) {
+                    let value = scalar_value(&name, self.take(pos, size)?);
+                    self.push(start, size, path, format!("{} = {}", name, value));
+                } else {
+                    let len = self.read_u32(pos)?;
+                    let data_pos = *pos;
+                    let data = self.take(pos, len)?;
+                    if name == "string" {
+                        let s = std::str::from_utf8(data).map_err(|_| Error::BadUTF8)?;
+                        self.push(start, 4, path, format!("string, {} bytes", len));
+                        if len > 0 {
+                            let mut shown: String = s.chars().take(MAX_SHOWN_STRING).collect();
+                            if shown.len() < s.len() {
+                                shown += "...";
+                            }
+                            self.push(data_pos, len, path, format!("{:?}", shown));
+                        }
+                    } else {
+                        self.push(start, 4, path, format!("bytes, {} bytes", len));
+                        if len > 0 {
+                            self.push(data_pos, len, path, "data");
+                        }
+                    }
+                }
+            }
+            TypeRef::user(name) => {
+                let def = self.definition(name)?;
+                let name = def.name.borrow().to_string();
+                if let Some(fields) = &def.structFields {
+                    if (name == "AccountNumber" || name == "MethodNumber")
+                        && def.definitionWillNotChange == Some(true)
+                    {
+                        let value = u64::from_le_bytes(self.take(pos, 8)?.try_into().unwrap());
+                        let shown = if name == "AccountNumber" {
+                            AccountNumber::new(value).to_string()
+                        } else {
+                            MethodNumber::new(value).to_string()
+                        };
+                        self.push(start, 8, path, format!("{} = {}", name, shown));
+                    } else {
+                        let extensible = def.definitionWillNotChange != Some(true);
+                        let fields: Vec<_> = fields
+                            .iter()
+                            .map(|field| (field.name.borrow().to_string(), &field.ty))
+                            .collect();
+                        self.walk_fields(&name, &fields, extensible, path, pos)?;
+                    }
+                } else if let Some(variants) = &def.unionFields {
+                    self.walk_variant(&name, variants, path, pos)?;
+                } else {
+                    return Err(anyhow!("Don't know how {} is packed", name));
+                }
+            }
+            TypeRef::vector(inner) => {
+                let num_bytes = self.read_u32(pos)?;
+                let heap_start = *pos as u64 + num_bytes as u64;
+                if heap_start > self.src.len() as u64 {
+                    return Err(Error::ReadPastEnd.into());
+                }
+                let inner_name = type_name(inner);
+                if inner_name == "u8" || inner_name == "i8" {
+                    self.push(start, 4, path, format!("bytes, {} bytes", num_bytes));
+                    if num_bytes > 0 {
+                        self.push(*pos, num_bytes, path, "data");
+                    }
+                    *pos = heap_start as u32;
+                    return Ok(());
+                }
+                let fixed_size = self.layout(inner)?.fixed_size;
+                let len = match num_bytes.checked_rem(fixed_size) {
+                    Some(0) => num_bytes / fixed_size,
+                    None if num_bytes == 0 => 0,
+                    _ => return Err(Error::BadSize.into()),
+                };
+                self.push(
+                    start,
+                    4,
+                    path,
+                    format!("vector of {}, length {}", inner_name, len),
+                );
+                let mut heap_pos = heap_start as u32;
+                for i in 0..len {
+                    self.embedded(inner, &format!("{}[{}]", path, i), pos, &mut heap_pos)?;
+                }
+                *pos = heap_pos;
+            }
+            TypeRef::option(inner) => {
+                let mut fixed_pos = *pos;
+                *pos += 4;
+                self.embedded_option(inner, path, &mut fixed_pos, pos)?;
+            }
+            TypeRef::tuple(fields) => {
+                let names: Vec<_> = (0..fields.len()).map(|i| i.to_string()).collect();
+                let fields: Vec<_> = names.iter().cloned().zip(fields.iter()).collect();
+                self.walk_fields("tuple", &fields, true, path, pos)?;
+            }
+            TypeRef::array(inner, n) => {
+                let fixed_size = self.layout(inner)?.fixed_size;
+                let heap_start = *pos as u64 + fixed_size as u64 * *n as u64;
+                if heap_start > self.src.len() as u64 {
+                    return Err(Error::ReadPastEnd.into());
+                }
+                let mut heap_pos = heap_start as u32;
+                for i in 0..*n {
+                    self.embedded(inner, &format!("{}[{}]", path, i), pos, &mut heap_pos)?;
+                }
+                *pos = heap_pos;
+            }
+            TypeRef::hex(n) => {
+                self.take(pos, *n)?;
+                self.push(start, *n, path, format!("{} bytes", n));
+            }
+        }
+        Ok(())
+    }
+
+    fn walk_fields(
+        &mut self,
+        name: &str,
+        fields: &[(String, &TypeRef<BuildString>)],
+        extensible: bool,
+        path: &str,
+        pos: &mut u32,
+    ) -> Result<(), anyhow::Error> {
+        let start = *pos;
+        let fixed_size = if extensible {
+            u16::from_le_bytes(self.take(pos, 2)?.try_into().unwrap()) as u32
+        } else {
+            let mut size = 0;
+            for (_, ty) in fields {
+                size += self.layout(ty)?.fixed_size;
+            }
+            size
+        };
+        let heap_start = *pos + fixed_size;
+        let mut heap_pos = heap_start;
+        let header = self.entries.len();
+        if extensible {
+            self.push(start, 2, path, "");
+        }
+        for (field, ty) in fields {
+            self.embedded(ty, &field_path(path, field), pos, &mut heap_pos)?;
+        }
+        if extensible {
+            if *pos > heap_start || (heap_start - *pos) % 4 != 0 {
+                return Err(Error::BadSize.into());
+            }
+            while *pos < heap_start {
+                let field_pos = *pos;
+                let offset = self.read_u32(pos)?;
+                let description = if offset <= 1 {
+                    format!("unknown field, offset {}", offset)
+                } else {
+                    let target = field_pos as u64 + offset as u64;
+                    if target < heap_pos as u64 || target > self.src.len() as u64 {
+                        return Err(Error::BadOffset.into());
+                    }
+                    heap_pos = target as u32;
+                    self.heap_end_unknown = true;
+                    format!("unknown field, offset {} -> {:06x}", offset, target)
+                };
+                self.push(field_pos, 4, path, description);
+            }
+            self.entries[header].description = format!(
+                "{}, fixed data {:06x}..{:06x}, heap {:06x}..{:06x}",
+                name,
+                heap_start - fixed_size,
+                heap_start,
+                heap_start,
+                heap_pos
+            );
+        }
+        *pos = heap_pos;
+        Ok(())
+    }
+
+    fn walk_variant(
+        &mut self,
+        name: &str,
+        variants: &[Field<BuildString>],
+        path: &str,
+        pos: &mut u32,
+    ) -> Result<(), anyhow::Error> {
+        let start = *pos;
+        let index = self.take(pos, 1)?[0];
+        let size_pos = *pos;
+        let size = self.read_u32(pos)?;
+        let end = size_pos as u64 + 4 + size as u64;
+        if end > self.src.len() as u64 {
+            return Err(Error::ReadPastEnd.into());
+        }
+        let Some(variant) = variants.get(index as usize) else {
+            self.push(
+                start,
+                5,
+                path,
+                format!("{}, unknown variant {}", name, index),
+            );
+            if size > 0 {
+                self.push(*pos, size, path, "unknown variant data");
+            }
+            *pos = end as u32;
+            return Ok(());
+        };
+        let variant_name = variant.name.borrow().to_string();
+        self.push(
+            start,
+            5,
+            path,
+            format!("{}::{}, {} bytes", name, variant_name, size),
+        );
+        self.walk(&variant.ty, &field_path(path, &variant_name), pos)?;
+        if *pos as u64 != end {
+            return Err(Error::BadSize.into());
+        }
+        Ok(())
+    }
+
+    // Walks a value that is embedded in the fixed data of a struct,
+    // tuple, vector, or array, like Unpack::embedded_verify
+    fn embedded(
+        &mut self,
+        ty: &TypeRef<BuildString>,
+        path: &str,
+        fixed_pos: &mut u32,
+        heap_pos: &mut u32,
+    ) -> Result<(), anyhow::Error> {
+        if !self.layout(ty)?.variable_size {
+            return self.walk(ty, path, fixed_pos);
+        }
+        match self.resolve(ty)? {
+            TypeRef::option(inner) => self.embedded_option(inner, path, fixed_pos, heap_pos),
+            _ => self.embedded_variable(ty, path, fixed_pos, heap_pos),
+        }
+    }
+
+    fn embedded_option(
+        &mut self,
+        inner: &TypeRef<BuildString>,
+        path: &str,
+        fixed_pos: &mut u32,
+        heap_pos: &mut u32,
+    ) -> Result<(), anyhow::Error> {
+        let start = *fixed_pos;
+        let mut peek = start;
+        if self.read_u32(&mut peek)? == 1 {
+            *fixed_pos += 4;
+            self.push(start, 4, path, "offset 1, empty optional");
+            return Ok(());
+        }
+        self.embedded_variable(inner, path, fixed_pos, heap_pos)
+    }
+
+    fn embedded_variable(
+        &mut self,
+        ty: &TypeRef<BuildString>,
+        path: &str,
+        fixed_pos: &mut u32,
+        heap_pos: &mut u32,
+    ) -> Result<(), anyhow::Error> {
+        let start = *fixed_pos;
+        let offset = self.read_u32(fixed_pos)?;
+        if offset == 0 {
+            self.push(start, 4, path, "offset 0, empty container");
+            return Ok(());
+        }
+        let target = start as u64 + offset as u64;
+        self.push(
+            start,
+            4,
+            path,
+            format!("offset {} -> {:06x}", offset, target),
+        );
+        if target != *heap_pos as u64 {
+            if !self.heap_end_unknown || target < *heap_pos as u64 || target > self.src.len() as u64
+            {
+                return Err(Error::BadOffset.into());
+            }
+            self.push(
+                *heap_pos,
+                target as u32 - *heap_pos,
+                "",
+                "heap data of unknown fields",
+            );
+            *heap_pos = target as u32;
+        }
+        self.heap_end_unknown = false;
+        self.walk(ty, path, heap_pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Action, Tapos, TimePointSec, Transaction};
+    use fracpack::Pack;
+
+    fn transaction() -> Transaction {
+        Transaction {
+            tapos: Tapos {
+                expiration: TimePointSec { seconds: 1000 },
+                refBlockSuffix: 0x01020304,
+                flags: 0,
+                refBlockIndex: 7,
+            },
+            actions: vec![Action {
+                sender: AccountNumber::from("alice"),
+                service: AccountNumber::from("sites"),
+                method: MethodNumber::from("storeSys"),
+                rawData: vec![1, 2, 3].into(),
+            }],
+            claims: vec![],
+        }
+    }
+
+    #[test]
+    fn dump_transaction() -> Result<(), anyhow::Error> {
+        let packed = transaction().packed();
+        let entries = dump_packed::<Transaction>(&packed)?;
+        assert!(entries.windows(2).all(|w| w[0].offset <= w[1].offset));
+        let find = |path: &str| {
+            entries
+                .iter()
+                .filter(|entry| entry.path == path)
+                .map(|entry| (entry.offset, entry.size, entry.description.as_str()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            find(""),
+            [(
+                0,
+                2,
+                "Transaction, fixed data 000002..000015, heap 000015..000042"
+            )]
+        );
+        assert_eq!(find("tapos.refBlockIndex"), [(0x0c, 1, "u8 = 7")]);
+        assert_eq!(
+            find("actions"),
+            [
+                (0x0d, 4, "offset 8 -> 000015"),
+                (0x15, 4, "vector of Action, length 1")
+            ]
+        );
+        assert_eq!(find("claims"), [(0x11, 4, "offset 0, empty container")]);
+        assert_eq!(
+            find("actions[0].sender"),
+            [(0x1f, 8, "AccountNumber = alice")]
+        );
+        assert_eq!(
+            find("actions[0].rawData"),
+            [
+                (0x37, 4, "offset 4 -> 00003b"),
+                (0x3b, 4, "bytes, 3 bytes"),
+                (0x3f, 3, "data")
+            ]
+        );
+
+        let text = format_dump(&packed, &entries);
+        assert_eq!(text.lines().count(), entries.len());
+        assert!(text.contains("00001f  0f 31 66 00 00 00 00 00     actions[0].sender"));
+        Ok(())
+    }
+
+    #[test]
+    fn dump_rejects_invalid_data() {
+        let mut packed = transaction().packed();
+        assert!(dump_packed::<Transaction>(&packed[..packed.len() - 1]).is_err());
+        packed.push(0);
+        assert!(dump_packed::<Transaction>(&packed).is_err());
+    }
+}
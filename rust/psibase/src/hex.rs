@@ -173,6 +173,23 @@ where
     fn verify(src: &'a [u8], pos: &mut u32) -> crate::fracpack::Result<()> {
         <T as crate::fracpack::Unpack>::verify(src, pos)
     }
+    fn unpack_at(
+        src: &'a [u8],
+        pos: &mut u32,
+        known_end: &mut bool,
+        options: &crate::fracpack::UnpackOptions,
+    ) -> crate::fracpack::Result<Self> {
+        let value = <T as crate::fracpack::Unpack>::unpack_at(src, pos, known_end, options)?;
+        Ok(Self(value))
+    }
+    fn verify_at(
+        src: &'a [u8],
+        pos: &mut u32,
+        known_end: &mut bool,
+        options: &crate::fracpack::UnpackOptions,
+    ) -> crate::fracpack::Result<()> {
+        <T as crate::fracpack::Unpack>::verify_at(src, pos, known_end, options)
+    }
     fn new_empty_container() -> crate::fracpack::Result<Self> {
         Ok(Self(<T as crate::fracpack::Unpack>::new_empty_container()?))
     }
@@ -180,32 +197,47 @@ where
         src: &'a [u8],
         fixed_pos: &mut u32,
         heap_pos: &mut u32,
+        known_end: &mut bool,
+        options: &crate::fracpack::UnpackOptions,
     ) -> crate::fracpack::Result<Self> {
-        let value =
-            <T as crate::fracpack::Unpack>::embedded_variable_unpack(src, fixed_pos, heap_pos)?;
+        let value = <T as crate::fracpack::Unpack>::embedded_variable_unpack(
+            src, fixed_pos, heap_pos, known_end, options,
+        )?;
         Ok(Self(value))
     }
     fn embedded_unpack(
         src: &'a [u8],
         fixed_pos: &mut u32,
         heap_pos: &mut u32,
+        known_end: &mut bool,
+        options: &crate::fracpack::UnpackOptions,
     ) -> crate::fracpack::Result<Self> {
-        let value = <T as crate::fracpack::Unpack>::embedded_unpack(src, fixed_pos, heap_pos)?;
+        let value = <T as crate::fracpack::Unpack>::embedded_unpack(
+            src, fixed_pos, heap_pos, known_end, options,
+        )?;
         Ok(Self(value))
     }
     fn embedded_variable_verify(
         src: &'a [u8],
         fixed_pos: &mut u32,
         heap_pos: &mut u32,
+        known_end: &mut bool,
+        options: &crate::fracpack::UnpackOptions,
     ) -> crate::fracpack::Result<()> {
-        <T as crate::fracpack::Unpack>::embedded_variable_verify(src, fixed_pos, heap_pos)
+        <T as crate::fracpack::Unpack>::embedded_variable_verify(
+            src, fixed_pos, heap_pos, known_end, options,
+        )
     }
     fn embedded_verify(
         src: &'a [u8],
         fixed_pos: &mut u32,
         heap_pos: &mut u32,
+        known_end: &mut bool,
+        options: &crate::fracpack::UnpackOptions,
     ) -> crate::fracpack::Result<()> {
-        <T as crate::fracpack::Unpack>::embedded_verify(src, fixed_pos, heap_pos)
+        <T as crate::fracpack::Unpack>::embedded_verify(
+            src, fixed_pos, heap_pos, known_end, options,
+        )
     }
 }
 
@@ -6,18 +6,22 @@ mod boot;
 mod crypto;
 mod db;
 mod depgraph;
+mod dump;
 mod from_bin;
 mod graph_ql;
 mod hex;
 mod http;
 mod internal_macros;
 #[cfg(not(target_family = "wasm"))]
+mod keystore;
+#[cfg(not(target_family = "wasm"))]
 mod local_socket;
 mod method_number;
 pub mod native;
 pub mod native_raw;
 mod native_tables;
 mod package;
+mod progress;
 pub mod reflect;
 #[cfg(not(target_family = "wasm"))]
 mod rpc;
@@ -43,16 +47,20 @@ pub use boot::*;
 pub use crypto::*;
 pub use db::*;
 pub use depgraph::*;
+pub use dump::*;
 pub use from_bin::*;
 pub use graph_ql::*;
 pub use hex::*;
 pub use http::*;
 #[cfg(not(target_family = "wasm"))]
+pub use keystore::*;
+#[cfg(not(target_family = "wasm"))]
 pub use local_socket::*;
 pub use method_number::*;
 pub use native::*;
 pub use native_tables::*;
 pub use package::*;
+pub use progress::*;
 #[cfg(not(target_family = "wasm"))]
 pub use rpc::*;
 pub use schema::*;
@@ -67,6 +75,9 @@ pub use to_key::*;
 pub use trace::*;
 pub use web_services::*;
 
+// Several modules have an Error, so the globs above export none of them
+pub use depgraph::Error as SolveError;
+
 use internal_macros::*;
 
 pub use fracpack;
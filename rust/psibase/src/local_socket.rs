@@ -3,6 +3,10 @@ use tokio::net::UnixStream;
 //use core::net::SocketAddr;
 use reqwest::Url;
 
+/// Stops the proxy task started by [make_proxy] when dropped.
+///
+/// Clients built with a `unix:` proxy send every request through this
+/// task, so it must outlive all uses of the client, including clones.
 pub struct AutoAbort {
     handle: tokio::task::JoinHandle<()>,
 }
@@ -33,20 +37,31 @@ async fn make_tcp_proxy(
     Ok((task, format!("http://{}", endpoint)))
 }
 
-pub async fn apply_proxy(
-    builder: reqwest::ClientBuilder,
+/// Starts the proxy named by `proxy`, if any, and returns a
+/// [reqwest::Proxy] for it. The proxy can be shared by several clients.
+pub async fn make_proxy(
     proxy: &Option<Url>,
-) -> Result<(reqwest::ClientBuilder, Option<AutoAbort>), anyhow::Error> {
+) -> Result<(Option<reqwest::Proxy>, Option<AutoAbort>), anyhow::Error> {
     if let Some(url) = proxy {
         if url.scheme() == "unix" {
             let (task, endpoint) = make_tcp_proxy(url.path()).await?;
             return Ok((
-                builder.proxy(reqwest::Proxy::http(endpoint)?),
+                Some(reqwest::Proxy::http(endpoint)?),
                 Some(AutoAbort { handle: task }),
             ));
         } else {
-            return Ok((builder.proxy(reqwest::Proxy::http(url.as_str())?), None));
+            return Ok((Some(reqwest::Proxy::http(url.as_str())?), None));
         }
     }
-    return Ok((builder, None));
+    return Ok((None, None));
+}
+
+pub async fn apply_proxy(
+    builder: reqwest::ClientBuilder,
+    proxy: &Option<Url>,
+) -> Result<(reqwest::ClientBuilder, Option<AutoAbort>), anyhow::Error> {
+    match make_proxy(proxy).await? {
+        (Some(proxy), result) => Ok((builder.proxy(proxy), result)),
+        (None, result) => Ok((builder, result)),
+    }
 }
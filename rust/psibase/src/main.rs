@@ -1,30 +1,51 @@
 use anyhow::{anyhow, Context};
-use chrono::{Duration, Utc};
-use clap::{Parser, Subcommand};
-use fracpack::Pack;
-use futures::future::join_all;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use fracpack::{Pack, Unpack};
+use futures::{future, stream, Future, StreamExt};
 use hmac::{Hmac, Mac};
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
 use jwt::SignWithKey;
-use psibase::services::{accounts, auth_delegate, sites};
+use log::{debug, info, warn};
+use psibase::services::{accounts, auth_delegate, setcode, sites};
 use psibase::{
-    account, apply_proxy, as_json, create_boot_transactions, get_accounts_to_create,
-    get_installed_manifest, get_manifest, get_tapos_for_head, method, new_account_action,
-    push_transaction, push_transactions, reg_server, set_auth_service_action, set_code_action,
-    set_key_action, sign_transaction, AccountNumber, Action, AnyPrivateKey, AnyPublicKey,
-    AutoAbort, DirectoryRegistry, ExactAccountNumber, HTTPRegistry, JointRegistry, Meta,
-    PackageDataFile, PackageList, PackageOp, PackageOrigin, PackageRegistry, ServiceInfo,
-    SignedTransaction, Tapos, TaposRefBlock, TimePointSec, TraceFormat, Transaction,
-    TransactionBuilder, TransactionTrace,
+    account, as_json, check_chain_id, create_boot_transactions, dump_packed, explain_ops,
+    format_dump, get_accounts_to_create, get_auth_services, get_installed_manifest, get_manifest,
+    get_tapos_for_head, get_transaction_trace, gql_query, guess_content_type, make_proxy,
+    make_refs, method, new_account_action, ping, push_transaction, push_transactions_until,
+    redirect_policy, reg_server, resume_boot_transactions, rotate_key_actions,
+    set_auth_service_action, set_code_action, set_key_action, sign_transaction, solve_dependencies,
+    validate_dependencies, verify_labeled_transactions, verify_transactions, wait_for_node,
+    write_package_dir, AccountNumber, Action, AnyPrivateKey, AnyPublicKey, ChainUrl, Checksum256,
+    Claim, ContentTypeGuess, DirectoryRegistry, DumpEntry, ExactAccountNumber, HTTPRegistry, Hex,
+    JointRegistry, KeyStore, LockedPackage, Meta, PackageDataFile, PackageInfo, PackageList,
+    PackageManifest, PackageOp, PackageOrigin, PackageRef, PackageRegistry, PackagedService,
+    ProgressSink, ServiceInfo, SignedTransaction, SolveError, SolveOptions, Tapos, TaposRefBlock,
+    TimePointSec, TraceFormat, Transaction, TransactionBuilder, TransactionTrace,
+    DEFAULT_MAX_PACKAGE_SIZE, DEFAULT_MAX_REDIRECTS,
 };
 use regex::Regex;
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::fs::{metadata, read_dir, File};
-use std::io::BufReader;
+use std::fs::{metadata, read_dir, symlink_metadata, File};
+use std::io::{BufReader, Write};
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+const DEFAULT_API: &str = "http://psibase.127.0.0.1.sslip.io:8080/";
+
+// Transactions that are pushed right away don't need to live long
+const DEFAULT_EXPIRE_AFTER: u32 = 10;
+
+// The longest expiration that the chain accepts
+const MAX_EXPIRE_AFTER: u32 = 60 * 60;
 
 /// Interact with a running psinode
 #[derive(Parser, Debug)]
@@ -36,17 +57,59 @@ struct Args {
         long,
         value_name = "URL",
         env = "PSINODE_URL",
-        default_value = "http://psibase.127.0.0.1.sslip.io:8080/"
+        default_value = DEFAULT_API
     )]
     api: Url,
 
+    /// Look for a running psinode when --api is not set: first the
+    /// node configs under the current directory, then common local ports
+    #[clap(long)]
+    discover: bool,
+
     /// HTTP proxy
     #[clap(long, value_name = "URL")]
     proxy: Option<Url>,
 
-    /// Sign with this key (repeatable)
+    /// The running proxy for --proxy, shared by every client
+    #[clap(skip)]
+    proxy_server: Option<reqwest::Proxy>,
+
+    /// Sign with this key, or with this key from the keystore, given
+    /// by name or public key (repeatable)
     #[clap(short = 's', long, value_name = "KEY")]
-    sign: Vec<AnyPrivateKey>,
+    sign: Vec<String>,
+
+    /// Sign by running this command, which holds the key (repeatable)
+    #[clap(long, value_name = "CMD")]
+    signer: Vec<String>,
+
+    /// Directory of the keystore managed by `psibase key`
+    #[clap(long, value_name = "DIR")]
+    keystore: Option<PathBuf>,
+
+    /// The keys from --sign and --signer
+    #[clap(skip)]
+    keys: Vec<AnyPrivateKey>,
+
+    /// File that holds the tokens saved by `psibase login`
+    #[clap(long, value_name = "FILE")]
+    sessions: Option<PathBuf>,
+
+    /// The token saved by `psibase login` for --api, if any
+    #[clap(skip)]
+    session_token: Option<String>,
+
+    /// Print extra details, such as which content type was chosen for
+    /// a file whose extension has several, or which package needs each
+    /// account that install creates
+    #[clap(short = 'v', long)]
+    verbose: bool,
+
+    /// Log internal diagnostics, such as HTTP requests, at this level
+    /// and above to stderr: off, error, warn, info, debug, or trace.
+    /// Overrides RUST_LOG.
+    #[clap(long, value_name = "LEVEL")]
+    log_level: Option<log::LevelFilter>,
 
     /// Suppress "Ok" message
     #[clap(long)]
@@ -57,10 +120,88 @@ struct Args {
     #[clap(long, value_name = "FORMAT", default_value = "stack")]
     trace: TraceFormat,
 
+    /// With --trace json, print each trace on a single line
+    #[clap(long)]
+    compact: bool,
+
+    /// Don't show animated progress bars. They are also disabled when
+    /// stderr is not a terminal.
+    #[clap(long)]
+    no_progress: bool,
+
     /// Controls whether the transaction's console output is shown
     #[clap(long, action=clap::ArgAction::Set, min_values=0, require_equals=true, default_value="true", default_missing_value="true")]
     console: bool,
 
+    /// Verify the encoding of every transaction locally, in parallel,
+    /// before pushing any of them
+    #[clap(long)]
+    parallel_verify: bool,
+
+    /// Reference this block in new transactions instead of asking the
+    /// node for its head block. Either SUFFIX:INDEX or a file holding
+    /// the JSON from common/tapos/head.
+    #[clap(long, value_name = "SUFFIX:INDEX")]
+    tapos_block: Option<TaposRefBlock>,
+
+    /// New transactions expire this many seconds after they are built.
+    /// The chain accepts at most 3600.
+    #[clap(long, value_name = "SECONDS", default_value_t = DEFAULT_EXPIRE_AFTER)]
+    expire_after: u32,
+
+    /// Write the signed transaction to this file instead of pushing it.
+    /// Only create, modify, and set-owner support this. With
+    /// --tapos-block, they do not contact the node at all.
+    #[clap(long, value_name = "FILE")]
+    write_transaction: Option<PathBuf>,
+
+    /// Refuse to push transactions unless the node is on this chain
+    #[clap(long, value_name = "HASH")]
+    chain_id: Option<Checksum256>,
+
+    /// Approximate limit on the action data in each transaction pushed
+    /// by install and upload. Must be at least 4096.
+    #[clap(
+        long,
+        value_name = "BYTES",
+        default_value = "65536",
+        value_parser = clap::value_parser!(u32).range(4096..)
+    )]
+    max_transaction_size: u32,
+
+    /// Allow package sources that use plain http. Packages fetched over
+    /// http can be tampered with in transit.
+    #[clap(long)]
+    insecure_http: bool,
+
+    /// Largest package file to download from an http package source
+    #[clap(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_PACKAGE_SIZE)]
+    max_package_size: u64,
+
+    /// Directory that packages from http package sources are downloaded
+    /// to. They are removed once they are read.
+    #[clap(long, value_name = "DIR")]
+    download_dir: Option<PathBuf>,
+
+    /// Most HTTP redirects to follow for a single request to a package
+    /// source
+    #[clap(long, value_name = "N", default_value_t = DEFAULT_MAX_REDIRECTS)]
+    max_redirects: usize,
+
+    /// Follow HTTP redirects to a different origin. Without this, a
+    /// package source that redirects to another server is rejected.
+    #[clap(long)]
+    allow_cross_origin_redirect: bool,
+
+    /// A URL or path to a package repository whose packages shadow any
+    /// package of the same name in the other sources (repeatable)
+    #[clap(long, value_name = "URL")]
+    prefer_source: Vec<String>,
+
+    /// Set when the user presses Ctrl-C
+    #[clap(skip)]
+    interrupted: Arc<AtomicBool>,
+
     #[clap(subcommand)]
     command: Command,
 }
@@ -73,14 +214,46 @@ enum Command {
         #[clap(short = 'k', long, value_name = "KEY")]
         key: Option<AnyPublicKey>,
 
-        /// Sets the name of the block producer
-        #[clap(short = 'p', long, value_name = "PRODUCER")]
-        producer: ExactAccountNumber,
+        /// Sets the name of the block producer. Repeat to boot with
+        /// several producers.
+        #[clap(
+            short = 'p',
+            long,
+            value_name = "PRODUCER",
+            required_unless_present = "resume"
+        )]
+        producer: Vec<ExactAccountNumber>,
 
         /// A URL or path to a package repository (repeatable)
         #[clap(long, value_name = "URL")]
         package_source: Vec<String>,
 
+        /// Finish a boot that was interrupted after the genesis block
+        /// was pushed. Only the boot transactions that the chain has not
+        /// applied yet are pushed.
+        #[clap(long)]
+        resume: bool,
+
+        /// After booting, print new blocks as they are produced until
+        /// interrupted
+        #[clap(long)]
+        follow: bool,
+
+        /// Wait up to this long for the node to start accepting
+        /// requests before booting
+        #[clap(long, value_name = "SECONDS")]
+        wait_for_node: Option<u64>,
+
+        /// Boot exactly the package versions in this lockfile. Fails if
+        /// the package sources no longer have them.
+        #[clap(long, value_name = "FILE", conflicts_with = "resume")]
+        lock: Option<PathBuf>,
+
+        /// Write the exact package versions that were selected to this
+        /// lockfile
+        #[clap(long, value_name = "FILE", conflicts_with = "resume")]
+        lock_out: Option<PathBuf>,
+
         services: Vec<String>,
     },
 
@@ -101,6 +274,16 @@ enum Command {
         #[clap(short = 'i', long)]
         insecure: bool,
 
+        /// Authenticate the account with this auth service instead of
+        /// the one that matches --key. auth-k1 and auth-sig require
+        /// --key. auth-delegate requires --owner.
+        #[clap(long, value_name = "ACCOUNT", conflicts_with = "insecure")]
+        auth_service: Option<ExactAccountNumber>,
+
+        /// Account that owns this account when using --auth-service auth-delegate
+        #[clap(long, value_name = "ACCOUNT", requires = "auth-service")]
+        owner: Option<ExactAccountNumber>,
+
         /// Sender to use when creating the account.
         #[clap(short = 'S', long, value_name = "SENDER", default_value = "accounts")]
         sender: ExactAccountNumber,
@@ -121,15 +304,55 @@ enum Command {
         /// on production or public chains.
         #[clap(short = 'i', long)]
         insecure: bool,
+
+        /// Authenticate the account with this auth service instead of
+        /// the one that matches --key. auth-k1 and auth-sig require
+        /// --key. auth-delegate requires --owner.
+        #[clap(long, value_name = "ACCOUNT", conflicts_with = "insecure")]
+        auth_service: Option<ExactAccountNumber>,
+
+        /// Account that owns this account when using --auth-service auth-delegate
+        #[clap(long, value_name = "ACCOUNT", requires = "auth-service")]
+        owner: Option<ExactAccountNumber>,
+    },
+
+    /// Transfer an account that authenticates with auth-delegate to a
+    /// new owner. The transaction must be signed by the current owner.
+    SetOwner {
+        /// Account to transfer
+        account: ExactAccountNumber,
+
+        /// Account that will own it
+        new_owner: ExactAccountNumber,
     },
 
     /// Deploy a service
     Deploy {
         /// Account to deploy service on
-        account: ExactAccountNumber,
+        #[clap(required_unless_present_any = &["verify-only", "dir"])]
+        account: Option<ExactAccountNumber>,
 
         /// Filename containing the service
-        filename: String,
+        #[clap(required_unless_present_any = &["verify-only", "dir"])]
+        filename: Option<String>,
+
+        /// Check that a file contains a deployable service, then exit
+        /// without building or pushing a transaction
+        #[clap(
+            long,
+            value_name = "FILE",
+            conflicts_with_all = &["account", "filename", "safe-deploy"]
+        )]
+        verify_only: Option<String>,
+
+        /// Deploy each <ACCOUNT>.wasm in this directory to ACCOUNT. The flags
+        /// and server in <ACCOUNT>.json are applied if it exists.
+        #[clap(
+            long,
+            value_name = "DIR",
+            conflicts_with_all = &["account", "filename", "verify-only", "register-proxy", "safe-deploy"]
+        )]
+        dir: Option<PathBuf>,
 
         /// Create the account if it doesn't exist. Also set the account to
         /// authenticate using this key, even if the account already existed.
@@ -147,6 +370,12 @@ enum Command {
         #[clap(short = 'p', long)]
         register_proxy: bool,
 
+        /// Deploy to this scratch account first, in a separate transaction, and
+        /// only deploy to ACCOUNT if that succeeds. The scratch account is
+        /// created if it doesn't exist, and is not secured.
+        #[clap(long, value_name = "SHADOW")]
+        safe_deploy: Option<ExactAccountNumber>,
+
         /// Sender to use when creating the account.
         #[clap(short = 'S', long, value_name = "SENDER", default_value = "accounts")]
         sender: ExactAccountNumber,
@@ -174,6 +403,40 @@ enum Command {
         /// Sender to use; defaults to <SERVICE>
         #[clap(short = 'S', long, value_name = "SENDER")]
         sender: Option<ExactAccountNumber>,
+
+        /// Write a JSON manifest of the uploaded files to this file
+        #[clap(long, value_name = "FILE", requires = "recursive")]
+        manifest_out: Option<PathBuf>,
+
+        /// After uploading, remove the files under DEST that are stored on
+        /// chain but are not in SOURCE. Only supported for sites.
+        #[clap(long, requires = "recursive")]
+        prune: bool,
+
+        /// Don't ask for confirmation before removing files with --prune
+        #[clap(short = 'y', long, requires = "prune")]
+        yes: bool,
+
+        /// Remove this local directory from the start of SOURCE and upload
+        /// to the rest of its path under DEST. e.g. dist/app.js with
+        /// --strip-prefix dist and DEST /app is uploaded to /app/app.js.
+        #[clap(long, value_name = "LOCAL")]
+        strip_prefix: Option<PathBuf>,
+
+        /// Upload the targets of symlinks found in SOURCE. By default,
+        /// symlinks are skipped.
+        #[clap(long, requires = "recursive")]
+        follow_symlinks: bool,
+
+        /// Maximum number of transactions to have in flight at once
+        /// during a recursive upload
+        #[clap(long, value_name = "N", default_value = "8")]
+        concurrency: NonZeroUsize,
+
+        /// After uploading, fetch each file from the node and check that
+        /// it matches the local file
+        #[clap(long)]
+        verify: bool,
     },
 
     /// Install apps to the chain
@@ -191,13 +454,80 @@ enum Command {
         package_source: Vec<String>,
 
         /// Sender to use for installing. The packages and all accounts
-        /// that they create will be owned by this account.
-        #[clap(short = 'S', long, value_name = "SENDER", default_value = "root")]
-        sender: ExactAccountNumber,
+        /// that they create will be owned by this account. PACKAGE=SENDER
+        /// sets the owner of a single package (repeatable). Packages
+        /// without their own sender use the plain SENDER, or root.
+        #[clap(short = 'S', long, value_name = "[PACKAGE=]SENDER")]
+        sender: Vec<PackageSender>,
 
         /// Install the package even if it is already installed
         #[clap(long)]
         reinstall: bool,
+
+        /// With --reinstall, also reinstall all the dependencies of the
+        /// requested packages
+        #[clap(long, requires = "reinstall")]
+        reinstall_deps: bool,
+
+        /// Allow replacing an installed package with an older version
+        #[clap(long)]
+        allow_downgrade: bool,
+
+        /// When several packages provide a virtual package that is needed,
+        /// use this one (repeatable)
+        #[clap(long, value_name = "PACKAGE")]
+        prefer: Vec<String>,
+
+        /// Also set the existing accounts of the requested packages to
+        /// authenticate using --key. Accounts must be owned by the sender.
+        /// Accounts that use auth-delegate are not changed unless
+        /// --force-rotate is given.
+        #[clap(long, requires = "key")]
+        rotate_key: bool,
+
+        /// Allow --rotate-key to also switch the accounts it would leave
+        /// unchanged, replacing their current auth service
+        #[clap(long, requires = "rotate-key")]
+        force_rotate: bool,
+
+        /// Skip storing a data file when an earlier package in the same
+        /// install already stored identical content at the same path
+        #[clap(long)]
+        dedup_files: bool,
+
+        /// Write the planned package changes as JSON to this file before
+        /// applying them
+        #[clap(long, value_name = "FILE")]
+        plan_out: Option<PathBuf>,
+
+        /// Install exactly the package versions in this lockfile. Fails if
+        /// the package sources no longer have them.
+        #[clap(long, value_name = "FILE")]
+        lock: Option<PathBuf>,
+
+        /// Write the exact versions of every package that the chain will
+        /// have after the install, including kept dependencies, to this
+        /// lockfile
+        #[clap(long, value_name = "FILE")]
+        lock_out: Option<PathBuf>,
+
+        /// Don't ask for confirmation before large installs
+        #[clap(short = 'y', long)]
+        yes: bool,
+
+        /// Print why each package is installed, replaced, or removed
+        #[clap(long)]
+        explain: bool,
+
+        /// After installing, print new blocks as they are produced until
+        /// interrupted
+        #[clap(long)]
+        follow: bool,
+
+        /// Only create the accounts that the packages need. The packages
+        /// themselves are not installed.
+        #[clap(long, conflicts_with_all = &["rotate-key", "dedup-files"])]
+        only_accounts: bool,
     },
 
     /// Prints a list of apps
@@ -211,6 +541,12 @@ enum Command {
         /// List installed apps
         #[clap(long)]
         installed: bool,
+        /// Show the dependencies between installed apps
+        #[clap(long, conflicts_with_all = &["all", "available"])]
+        tree: bool,
+        /// Output format. Possible values are text, table, or json (--tree only)
+        #[clap(long, value_name = "FORMAT", default_value = "text")]
+        format: ListFormat,
 
         /// A URL or path to a package repository (repeatable)
         #[clap(long, value_name = "URL")]
@@ -223,6 +559,10 @@ enum Command {
         #[clap(required = true)]
         patterns: Vec<String>,
 
+        /// Output format. Possible values are text or table
+        #[clap(long, value_name = "FORMAT", default_value = "text")]
+        format: ListFormat,
+
         /// A URL or path to a package repository (repeatable)
         #[clap(long, value_name = "URL")]
         package_source: Vec<String>,
@@ -237,6 +577,81 @@ enum Command {
         /// A URL or path to a package repository (repeatable)
         #[clap(long, value_name = "URL")]
         package_source: Vec<String>,
+
+        /// Print the packed Meta and the manifest as hex instead of
+        /// listing the services
+        #[clap(long)]
+        raw: bool,
+    },
+
+    /// Build a package file from a directory
+    Package {
+        /// Directory holding meta.json, service/, data/, and script/
+        dir: PathBuf,
+
+        /// The package file to write
+        #[clap(short = 'o', long, value_name = "FILE")]
+        out: PathBuf,
+
+        /// A URL or path to a package repository (repeatable). The
+        /// dependencies of the package are loaded from here to check
+        /// the accounts that it uses.
+        #[clap(long, value_name = "URL")]
+        package_source: Vec<String>,
+    },
+
+    /// Write the index.json for a directory of package files
+    Index {
+        /// Directory holding the .psi files
+        dir: PathBuf,
+
+        /// Check the existing index.json against the package files
+        /// instead of replacing it
+        #[clap(long)]
+        verify: bool,
+    },
+
+    /// Show the layout of packed data, e.g. a transaction
+    Hexdump {
+        /// Type of the data: Action, Claim, SignedTransaction, Tapos, or
+        /// Transaction
+        #[clap(value_name = "TYPE")]
+        type_name: String,
+
+        /// The packed data in hex. If not given, it's read from stdin.
+        data: Option<String>,
+
+        /// The data is base64 instead of hex
+        #[clap(long)]
+        base64: bool,
+    },
+
+    /// Shows the trace of a transaction recently pushed to the node
+    Trace {
+        /// Id of the transaction: the sha256 of the packed transaction
+        txid: Checksum256,
+    },
+
+    /// Checks that the node is reachable and measures the round-trip time
+    Ping {
+        /// Number of requests to send
+        #[clap(
+            short = 'n',
+            long,
+            default_value = "4",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        count: u32,
+
+        /// How long to wait for each response
+        #[clap(long, value_name = "SECONDS", default_value = "10")]
+        timeout: u64,
+    },
+
+    /// Manage the keys in the local keystore
+    Key {
+        #[clap(subcommand)]
+        command: KeyCommand,
     },
 
     /// Create a bearer token that can be used to access a node
@@ -249,6 +664,41 @@ enum Command {
         #[clap(short = 'm', long, default_value = "rw")]
         mode: String,
     },
+
+    /// Create a token and use it for later commands against this node
+    Login {
+        /// The lifetime of the new token
+        #[clap(short = 'e', long, default_value = "3600", value_name = "SECONDS")]
+        expires_after: i64,
+
+        /// The access mode: "r" or "rw"
+        #[clap(short = 'm', long, default_value = "rw")]
+        mode: String,
+    },
+
+    /// Forget the token saved by login for this node
+    Logout,
+}
+
+#[derive(Subcommand, Debug)]
+enum KeyCommand {
+    /// Encrypt a private key with a password and add it to the keystore
+    Import {
+        /// Name of the key in the keystore
+        name: String,
+
+        /// A PVT_K1_ key or a PKCS #8 file. Prompts for the key if omitted.
+        key: Option<String>,
+    },
+
+    /// List the names and public keys of the keys in the keystore
+    List,
+
+    /// Remove a key from the keystore
+    Remove {
+        /// Name of the key
+        name: String,
+    },
 }
 
 #[allow(dead_code)] // TODO: move to lib if still needed
@@ -276,12 +726,23 @@ fn store_sys(
     )
 }
 
-fn with_tapos(tapos: &TaposRefBlock, actions: Vec<Action>) -> Transaction {
-    let now_plus_10secs = Utc::now() + Duration::seconds(10);
-    let expiration = TimePointSec {
-        seconds: now_plus_10secs.timestamp() as u32,
-    };
-    Transaction {
+// The tapos for new transactions: --tapos-block if it was given,
+// otherwise the node's head block
+async fn get_tapos(args: &Args, client: reqwest::Client) -> Result<TaposRefBlock, anyhow::Error> {
+    match &args.tapos_block {
+        Some(tapos) => Ok(tapos.clone()),
+        None => get_tapos_for_head(&args.api, client).await,
+    }
+}
+
+fn with_tapos(
+    args: &Args,
+    tapos: &TaposRefBlock,
+    actions: Vec<Action>,
+) -> Result<Transaction, anyhow::Error> {
+    let expiration =
+        TimePointSec::try_from(Utc::now() + Duration::seconds(args.expire_after.into()))?;
+    Ok(Transaction {
         tapos: Tapos {
             expiration,
             refBlockSuffix: tapos.ref_block_suffix,
@@ -290,69 +751,166 @@ fn with_tapos(tapos: &TaposRefBlock, actions: Vec<Action>) -> Transaction {
         },
         actions,
         claims: vec![],
-    }
+    })
 }
 
-async fn create(
+// With --write-transaction and --tapos-block, a command builds and signs
+// its transaction without asking the node anything
+fn is_offline(args: &Args) -> bool {
+    args.write_transaction.is_some() && args.tapos_block.is_some()
+}
+
+// Pushes a single transaction, or with --write-transaction, saves it so
+// that it can be pushed later
+async fn push_or_write(
     args: &Args,
     client: reqwest::Client,
+    trx: SignedTransaction,
+) -> Result<(), anyhow::Error> {
+    if let Some(path) = &args.write_transaction {
+        return std::fs::write(path, trx.packed())
+            .with_context(|| format!("Can not create {}", path.to_string_lossy()));
+    }
+    push_transaction(
+        &args.api,
+        client,
+        trx.packed(),
+        args.trace,
+        args.console,
+        None,
+    )
+    .await
+}
+
+// Returns the actions that make account authenticate with auth_service
+fn auth_service_actions(
+    account: AccountNumber,
+    auth_service: AccountNumber,
+    key: &Option<AnyPublicKey>,
+    owner: Option<AccountNumber>,
+) -> Result<Vec<Action>, anyhow::Error> {
+    let mut actions = Vec::new();
+    let uses_key = [account!("auth-k1"), account!("auth-sig")].contains(&auth_service);
+    match key {
+        Some(key) if uses_key => {
+            if key.auth_service() != auth_service {
+                return Err(anyhow!(
+                    "--key is for {}, not {}",
+                    key.auth_service(),
+                    auth_service
+                ));
+            }
+            actions.push(set_key_action(account, key));
+        }
+        Some(_) => return Err(anyhow!("--key cannot be used with {}", auth_service)),
+        None if uses_key => return Err(anyhow!("{} requires --key", auth_service)),
+        None => {}
+    }
+    if auth_service == auth_delegate::SERVICE {
+        let Some(owner) = owner else {
+            return Err(anyhow!("{} requires --owner", auth_service));
+        };
+        actions.push(auth_delegate::Wrapper::pack_from(account).setOwner(owner));
+    } else if owner.is_some() {
+        return Err(anyhow!(
+            "--owner can only be used with {}",
+            auth_delegate::SERVICE
+        ));
+    }
+    actions.push(set_auth_service_action(account, auth_service));
+    Ok(actions)
+}
+
+// The chain checks that the service can authenticate the account when
+// it is set. This catches a misspelled service before pushing anything.
+async fn check_auth_service_exists(
+    args: &Args,
+    mut client: reqwest::Client,
+    auth_service: AccountNumber,
+) -> Result<(), anyhow::Error> {
+    if !get_auth_services(&args.api, &mut client, &[auth_service])
+        .await?
+        .contains_key(&auth_service)
+    {
+        return Err(anyhow!("The auth service {} does not exist", auth_service));
+    }
+    Ok(())
+}
+
+fn create_actions(
     sender: AccountNumber,
     account: AccountNumber,
     key: &Option<AnyPublicKey>,
     insecure: bool,
-) -> Result<(), anyhow::Error> {
+    auth_service: Option<AccountNumber>,
+    owner: Option<AccountNumber>,
+) -> Result<Vec<Action>, anyhow::Error> {
     let mut actions: Vec<Action> = Vec::new();
 
     if key.is_some() && insecure {
         return Err(anyhow!("--key and --insecure cannot be used together"));
     }
-    if key.is_none() && !insecure {
-        return Err(anyhow!("either --key or --insecure must be used"));
+    if key.is_none() && !insecure && auth_service.is_none() {
+        return Err(anyhow!(
+            "either --key, --insecure, or --auth-service must be used"
+        ));
     }
 
     actions.push(new_account_action(sender, account));
 
-    if let Some(key) = key {
+    if let Some(auth_service) = auth_service {
+        actions.extend(auth_service_actions(account, auth_service, key, owner)?);
+    } else if let Some(key) = key {
         actions.push(set_key_action(account, key));
         actions.push(set_auth_service_action(account, key.auth_service()));
     }
+    Ok(actions)
+}
 
-    let trx = with_tapos(
-        &get_tapos_for_head(&args.api, client.clone()).await?,
-        actions,
-    );
-    push_transaction(
-        &args.api,
-        client,
-        sign_transaction(trx, &args.sign)?.packed(),
-        args.trace,
-        args.console,
-        None,
-    )
-    .await?;
+async fn create(
+    args: &Args,
+    client: reqwest::Client,
+    sender: AccountNumber,
+    account: AccountNumber,
+    key: &Option<AnyPublicKey>,
+    insecure: bool,
+    auth_service: Option<AccountNumber>,
+    owner: Option<AccountNumber>,
+) -> Result<(), anyhow::Error> {
+    let actions = create_actions(sender, account, key, insecure, auth_service, owner)?;
+    if let (Some(auth_service), false) = (auth_service, is_offline(args)) {
+        check_auth_service_exists(args, client.clone(), auth_service).await?;
+    }
+
+    let trx = with_tapos(args, &get_tapos(args, client.clone()).await?, actions)?;
+    push_or_write(args, client, sign_transaction(trx, &args.keys)?).await?;
     if !args.suppress_ok {
         println!("Ok");
     }
     Ok(())
 }
 
-async fn modify(
-    args: &Args,
-    client: reqwest::Client,
+fn modify_actions(
     account: AccountNumber,
     key: &Option<AnyPublicKey>,
     insecure: bool,
-) -> Result<(), anyhow::Error> {
+    auth_service: Option<AccountNumber>,
+    owner: Option<AccountNumber>,
+) -> Result<Vec<Action>, anyhow::Error> {
     let mut actions: Vec<Action> = Vec::new();
 
     if key.is_some() && insecure {
         return Err(anyhow!("--key and --insecure cannot be used together"));
     }
-    if key.is_none() && !insecure {
-        return Err(anyhow!("either --key or --insecure must be used"));
+    if key.is_none() && !insecure && auth_service.is_none() {
+        return Err(anyhow!(
+            "either --key, --insecure, or --auth-service must be used"
+        ));
     }
 
-    if let Some(key) = key {
+    if let Some(auth_service) = auth_service {
+        actions.extend(auth_service_actions(account, auth_service, key, owner)?);
+    } else if let Some(key) = key {
         actions.push(set_key_action(account, key));
         actions.push(set_auth_service_action(account, key.auth_service()));
     }
@@ -360,53 +918,168 @@ async fn modify(
     if insecure {
         actions.push(set_auth_service_action(account, account!("auth-any")));
     }
+    Ok(actions)
+}
 
-    let trx = with_tapos(
-        &get_tapos_for_head(&args.api, client.clone()).await?,
-        actions,
-    );
-    push_transaction(
-        &args.api,
-        client,
-        sign_transaction(trx, &args.sign)?.packed(),
-        args.trace,
-        args.console,
-        None,
-    )
-    .await?;
+async fn modify(
+    args: &Args,
+    client: reqwest::Client,
+    account: AccountNumber,
+    key: &Option<AnyPublicKey>,
+    insecure: bool,
+    auth_service: Option<AccountNumber>,
+    owner: Option<AccountNumber>,
+) -> Result<(), anyhow::Error> {
+    let actions = modify_actions(account, key, insecure, auth_service, owner)?;
+    if let (Some(auth_service), false) = (auth_service, is_offline(args)) {
+        check_auth_service_exists(args, client.clone(), auth_service).await?;
+    }
+
+    let trx = with_tapos(args, &get_tapos(args, client.clone()).await?, actions)?;
+    push_or_write(args, client, sign_transaction(trx, &args.keys)?).await?;
     if !args.suppress_ok {
         println!("Ok");
     }
     Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn deploy(
+fn set_owner_actions(
+    account: AccountNumber,
+    new_owner: AccountNumber,
+) -> Result<Vec<Action>, anyhow::Error> {
+    if account == new_owner {
+        return Err(anyhow!("{} cannot own itself", account));
+    }
+    // auth-delegate authorizes the account's actions with the current owner
+    Ok(vec![
+        auth_delegate::Wrapper::pack_from(account).setOwner(new_owner)
+    ])
+}
+
+// Only accounts that delegate to an owner have one to change, and giving
+// the account to a missing owner would leave nobody able to use it
+async fn check_set_owner(
     args: &Args,
-    client: reqwest::Client,
-    sender: AccountNumber,
+    mut client: reqwest::Client,
     account: AccountNumber,
-    filename: &str,
-    create_account: &Option<AnyPublicKey>,
-    create_insecure_account: bool,
-    register_proxy: bool,
+    new_owner: AccountNumber,
 ) -> Result<(), anyhow::Error> {
-    let wasm = std::fs::read(filename).with_context(|| format!("Can not read {}", filename))?;
-
-    let mut actions: Vec<Action> = Vec::new();
+    let auth_services = get_auth_services(&args.api, &mut client, &[account, new_owner]).await?;
+    match auth_services.get(&account) {
+        None => return Err(anyhow!("The account {} does not exist", account)),
+        Some(service) if *service != auth_delegate::SERVICE => {
+            return Err(anyhow!(
+                "{} authenticates with {}, not {}. Use modify --auth-service {} --owner to delegate it.",
+                account,
+                service,
+                auth_delegate::SERVICE,
+                auth_delegate::SERVICE
+            ))
+        }
+        _ => {}
+    }
+    if !auth_services.contains_key(&new_owner) {
+        return Err(anyhow!("The new owner {} does not exist", new_owner));
+    }
+    Ok(())
+}
 
-    if create_account.is_some() && create_insecure_account {
-        return Err(anyhow!(
-            "--create-account and --create-insecure-account cannot be used together"
-        ));
+async fn set_owner(
+    args: &Args,
+    client: reqwest::Client,
+    account: AccountNumber,
+    new_owner: AccountNumber,
+) -> Result<(), anyhow::Error> {
+    let actions = set_owner_actions(account, new_owner)?;
+    if !is_offline(args) {
+        check_set_owner(args, client.clone(), account, new_owner).await?;
     }
 
-    if create_account.is_some() || create_insecure_account {
-        actions.push(new_account_action(sender, account));
+    let trx = with_tapos(args, &get_tapos(args, client.clone()).await?, actions)?;
+    push_or_write(args, client, sign_transaction(trx, &args.keys)?).await?;
+    if !args.suppress_ok {
+        println!("Ok");
     }
+    Ok(())
+}
 
-    // This happens before the set_code as a safety measure.
-    // If the set_code was first, and the user didn't actually
+/// Checks that `wasm` looks like a service that psinode can run: a valid
+/// wasm module that exports the entry points psinode calls, imports only
+/// host functions, and fits in a transaction.
+fn verify_service_wasm(wasm: &[u8], size_limit: usize) -> Result<(), anyhow::Error> {
+    use wasmparser::{ExternalKind, Parser, Payload, Validator};
+
+    Validator::new()
+        .validate_all(wasm)
+        .map_err(|e| anyhow!("Not a valid wasm module: {}", e))?;
+    let mut exports = Vec::new();
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::ImportSection(imports) => {
+                for import in imports {
+                    let import = import?;
+                    if import.module != "env" {
+                        return Err(anyhow!(
+                            "Import {}.{} is not provided by psinode",
+                            import.module,
+                            import.name
+                        ));
+                    }
+                }
+            }
+            Payload::ExportSection(section) => {
+                for export in section {
+                    let export = export?;
+                    if export.kind == ExternalKind::Func {
+                        exports.push(export.name);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    for name in ["start", "called"] {
+        if !exports.contains(&name) {
+            return Err(anyhow!("Missing exported function \"{}\"", name));
+        }
+    }
+    if wasm.len() > size_limit {
+        return Err(anyhow!(
+            "Service is {} bytes, which exceeds the transaction size limit of {} bytes",
+            wasm.len(),
+            size_limit
+        ));
+    }
+    Ok(())
+}
+
+fn verify_deploy(args: &Args, filename: &str) -> Result<(), anyhow::Error> {
+    let wasm = std::fs::read(filename).with_context(|| format!("Can not read {}", filename))?;
+    verify_service_wasm(&wasm, args.max_transaction_size as usize)
+        .with_context(|| format!("{} failed verification", filename))?;
+    println!("{}: ok", filename);
+    Ok(())
+}
+
+fn push_create_actions(
+    actions: &mut Vec<Action>,
+    sender: AccountNumber,
+    account: AccountNumber,
+    create_account: &Option<AnyPublicKey>,
+    create_insecure_account: bool,
+) -> Result<(), anyhow::Error> {
+    if create_account.is_some() && create_insecure_account {
+        return Err(anyhow!(
+            "--create-account and --create-insecure-account cannot be used together"
+        ));
+    }
+
+    if create_account.is_some() || create_insecure_account {
+        actions.push(new_account_action(sender, account));
+    }
+
+    // This happens before the set_code as a safety measure.
+    // If the set_code was first, and the user didn't actually
     // have the matching private key, then the transaction would
     // lock out the user. Putting this before the set_code causes
     // the set_code to require the private key, failing the transaction
@@ -415,6 +1088,52 @@ async fn deploy(
         actions.push(set_key_action(account, key));
         actions.push(set_auth_service_action(account, key.auth_service()));
     }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn deploy(
+    args: &Args,
+    client: reqwest::Client,
+    sender: AccountNumber,
+    account: AccountNumber,
+    filename: &str,
+    create_account: &Option<AnyPublicKey>,
+    create_insecure_account: bool,
+    register_proxy: bool,
+    safe_deploy: Option<AccountNumber>,
+) -> Result<(), anyhow::Error> {
+    let wasm = std::fs::read(filename).with_context(|| format!("Can not read {}", filename))?;
+
+    if safe_deploy == Some(account) {
+        return Err(anyhow!(
+            "--safe-deploy needs an account other than {}",
+            account
+        ));
+    }
+
+    let mut actions: Vec<Action> = Vec::new();
+    push_create_actions(
+        &mut actions,
+        sender,
+        account,
+        create_account,
+        create_insecure_account,
+    )?;
+
+    // The shadow gets the same code and registration. If the chain
+    // rejects them there, the transaction for the real account is
+    // never pushed.
+    let shadow_actions = safe_deploy.map(|shadow| {
+        let mut shadow_actions = vec![
+            new_account_action(sender, shadow),
+            set_code_action(shadow, wasm.clone()),
+        ];
+        if register_proxy {
+            shadow_actions.push(reg_server(shadow, shadow));
+        }
+        (shadow, shadow_actions)
+    });
 
     actions.push(set_code_action(account, wasm));
 
@@ -422,14 +1141,31 @@ async fn deploy(
         actions.push(reg_server(account, account));
     }
 
-    let trx = with_tapos(
-        &get_tapos_for_head(&args.api, client.clone()).await?,
-        actions,
-    );
+    let tapos = get_tapos(args, client.clone()).await?;
+    if let Some((shadow, shadow_actions)) = shadow_actions {
+        let trx = with_tapos(args, &tapos, shadow_actions)?;
+        push_transaction(
+            &args.api,
+            client.clone(),
+            sign_transaction(trx, &args.keys)?.packed(),
+            args.trace,
+            args.console,
+            None,
+        )
+        .await
+        .with_context(|| {
+            format!(
+                "Deploying to {} failed; {} was not changed",
+                shadow, account
+            )
+        })?;
+    }
+
+    let trx = with_tapos(args, &tapos, actions)?;
     push_transaction(
         &args.api,
         client,
-        sign_transaction(trx, &args.sign)?.packed(),
+        sign_transaction(trx, &args.keys)?.packed(),
         args.trace,
         args.console,
         None,
@@ -441,6 +1177,111 @@ async fn deploy(
     Ok(())
 }
 
+// One group of actions for each <account>.wasm in dir, sorted by account
+fn deploy_dir_actions(
+    dir: &Path,
+    sender: AccountNumber,
+    create_account: &Option<AnyPublicKey>,
+    create_insecure_account: bool,
+) -> Result<Vec<(AccountNumber, Vec<Action>)>, anyhow::Error> {
+    let mut services = vec![];
+    for entry in read_dir(dir).with_context(|| format!("Can not read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.extension().map_or(true, |ext| ext != "wasm") || !path.is_file() {
+            continue;
+        }
+        let name = path.file_stem().unwrap().to_string_lossy();
+        let account = ExactAccountNumber::from_str(&name)
+            .with_context(|| format!("{} is not named after an account", path.display()))?;
+        services.push((AccountNumber::from(account), path));
+    }
+    if services.is_empty() {
+        return Err(anyhow!("{} contains no .wasm files", dir.display()));
+    }
+    services.sort_by_key(|(account, _)| account.to_string());
+
+    let mut result = vec![];
+    for (account, path) in services {
+        let info_path = path.with_extension("json");
+        let info = if info_path.exists() {
+            let file = File::open(&info_path)
+                .with_context(|| format!("Can not read {}", info_path.display()))?;
+            serde_json::from_reader(BufReader::new(file))
+                .with_context(|| format!("Invalid service info in {}", info_path.display()))?
+        } else {
+            ServiceInfo::default()
+        };
+        let wasm =
+            std::fs::read(&path).with_context(|| format!("Can not read {}", path.display()))?;
+
+        let mut actions = vec![];
+        push_create_actions(
+            &mut actions,
+            sender,
+            account,
+            create_account,
+            create_insecure_account,
+        )?;
+        actions.push(set_code_action(account, wasm));
+        let flags = info.flag_bits()?;
+        if flags != 0 {
+            actions.push(setcode::Wrapper::pack().setFlags(account, flags));
+        }
+        if let Some(server) = info.server {
+            actions.push(reg_server(account, server));
+        }
+        result.push((account, actions));
+    }
+    Ok(result)
+}
+
+async fn deploy_dir(
+    args: &Args,
+    client: reqwest::Client,
+    sender: AccountNumber,
+    dir: &Path,
+    create_account: &Option<AnyPublicKey>,
+    create_insecure_account: bool,
+) -> Result<(), anyhow::Error> {
+    let services = deploy_dir_actions(dir, sender, create_account, create_insecure_account)?;
+
+    let tapos = get_tapos(args, client.clone()).await?;
+    let build_transaction = |actions: Vec<Action>| -> Result<SignedTransaction, anyhow::Error> {
+        let actions = with_sender_first(sender, actions)?;
+        Ok(sign_transaction(
+            with_tapos(args, &tapos, actions)?,
+            &args.keys,
+        )?)
+    };
+    let mut trx_builder =
+        TransactionBuilder::new(args.max_transaction_size as usize, build_transaction);
+    for (account, actions) in services {
+        trx_builder.set_label(format!("Deploying {}", account));
+        trx_builder.push(actions)?;
+    }
+    let transactions = trx_builder.finish()?;
+
+    let progress = Progress::new(args, transactions.len() as u64, "services", true)?;
+    push_transactions_until(
+        &args.api,
+        client,
+        transactions,
+        args.trace,
+        args.console,
+        &progress,
+        &args.interrupted,
+    )
+    .await
+    .context("Failed to deploy services")?;
+
+    if !args.suppress_ok {
+        progress.finish_with_message("Ok");
+    } else {
+        progress.finish_and_clear();
+    }
+    Ok(())
+}
+
 async fn upload(
     args: &Args,
     client: reqwest::Client,
@@ -449,6 +1290,7 @@ async fn upload(
     dest: &Option<String>,
     content_type: &Option<String>,
     source: &str,
+    verify: bool,
 ) -> Result<(), anyhow::Error> {
     let sender = if let Some(s) = sender {
         s.into()
@@ -459,11 +1301,11 @@ async fn upload(
     let deduced_content_type = match content_type {
         Some(t) => t.clone(),
         None => {
-            let guess = mime_guess::from_path(source);
-            let Some(t) = guess.first() else {
+            let Some(guess) = guess_content_type(source) else {
                 return Err(anyhow!(format!("Unknown mime type: {}", source)));
             };
-            t.essence_str().to_string()
+            report_content_type(args.verbose, source, &guess);
+            guess.content_type
         }
     };
 
@@ -477,55 +1319,118 @@ async fn upload(
         "/".to_string() + Path::new(source).file_name().unwrap().to_str().unwrap()
     };
 
+    let content = std::fs::read(source).with_context(|| format!("Can not read {}", source))?;
     let actions = vec![store_sys(
         service,
         sender,
         &normalized_dest,
         &deduced_content_type,
-        &std::fs::read(source).with_context(|| format!("Can not read {}", source))?,
+        &content,
     )];
-    let trx = with_tapos(
-        &get_tapos_for_head(&args.api, client.clone()).await?,
-        actions,
-    );
+    let trx = with_tapos(args, &get_tapos(args, client.clone()).await?, actions)?;
 
     push_transaction(
         &args.api,
-        client,
-        sign_transaction(trx, &args.sign)?.packed(),
+        client.clone(),
+        sign_transaction(trx, &args.keys)?.packed(),
         args.trace,
         args.console,
         None,
     )
     .await?;
+    if verify {
+        let file = UploadedFile {
+            path: normalized_dest,
+            content_type: deduced_content_type,
+            sha256: <[u8; 32]>::from(Sha256::digest(&content)).into(),
+            size: content.len() as u64,
+        };
+        let host = content_host(service, sender);
+        verify_uploads(args, &client, host, &[file], NonZeroUsize::new(1).unwrap()).await?;
+    }
     if !args.suppress_ok {
         println!("Ok");
     }
     Ok(())
 }
 
+// Extensions such as .ttf have several content types. guess_content_type
+// picks one deterministically; --verbose says which.
+fn report_content_type(verbose: bool, source: &str, guess: &ContentTypeGuess) {
+    if verbose && !guess.alternatives.is_empty() {
+        eprintln!(
+            "{}: using content type {} (also possible: {})",
+            source,
+            guess.content_type,
+            guess.alternatives.join(", ")
+        );
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UploadedFile {
+    path: String,
+    content_type: String,
+    sha256: Checksum256,
+    size: u64,
+}
+
+// Symlinks below the top are skipped unless follow_symlinks is set.
+// visited holds the canonical paths of the directories that were already
+// walked, so that a symlink cycle is only walked once.
+#[allow(clippy::too_many_arguments)]
 fn fill_tree(
     service: AccountNumber,
     sender: AccountNumber,
     actions: &mut Vec<(String, Action)>,
+    manifest: &mut Vec<UploadedFile>,
     dest: &str,
     source: &str,
     top: bool,
+    follow_symlinks: bool,
+    visited: &mut HashSet<PathBuf>,
+    verbose: bool,
 ) -> Result<(), anyhow::Error> {
-    let md = metadata(source)?;
+    let md = symlink_metadata(source)?;
+    let md = if !md.is_symlink() || top {
+        metadata(source)?
+    } else if follow_symlinks {
+        match metadata(source) {
+            Ok(md) => md,
+            Err(_) => {
+                warn!("Skip broken symlink: {}", source);
+                return Ok(());
+            }
+        }
+    } else {
+        warn!(
+            "Skip symlink: {} (use --follow-symlinks to upload it)",
+            source
+        );
+        return Ok(());
+    };
     if md.is_file() {
-        let guess = mime_guess::from_path(source);
-        if let Some(t) = guess.first() {
-            println!("{} <=== {}   {}", dest, source, t.essence_str());
+        if let Some(guess) = guess_content_type(source) {
+            report_content_type(verbose, source, &guess);
+            let t = guess.content_type;
+            let content =
+                std::fs::read(source).with_context(|| format!("Can not read {}", source))?;
+            println!(
+                "{} <=== {}   {}   {}",
+                dest,
+                source,
+                t,
+                HumanBytes(content.len() as u64)
+            );
+            manifest.push(UploadedFile {
+                path: dest.to_owned(),
+                content_type: t.clone(),
+                sha256: <[u8; 32]>::from(Sha256::digest(&content)).into(),
+                size: content.len() as u64,
+            });
             actions.push((
                 dest.to_owned(),
-                store_sys(
-                    service,
-                    sender,
-                    dest,
-                    t.essence_str(),
-                    &std::fs::read(source).with_context(|| format!("Can not read {}", source))?,
-                ),
+                store_sys(service, sender, dest, &t, &content),
             ));
         } else {
             if top {
@@ -535,6 +1440,10 @@ fn fill_tree(
             }
         }
     } else if md.is_dir() {
+        if !visited.insert(std::fs::canonicalize(source)?) {
+            warn!("Skip {}: this directory was already uploaded", source);
+            return Ok(());
+        }
         for path in read_dir(source)? {
             let path = path?;
             let d = dest.to_owned() + "/" + path.file_name().to_str().unwrap();
@@ -542,9 +1451,13 @@ fn fill_tree(
                 service,
                 sender,
                 actions,
+                manifest,
                 &d,
                 path.path().to_str().unwrap(),
                 false,
+                follow_symlinks,
+                visited,
+                verbose,
             )?;
         }
     } else {
@@ -562,7 +1475,7 @@ async fn monitor_trx(
     client: &reqwest::Client,
     files: Vec<String>,
     trx: SignedTransaction,
-    progress: ProgressBar,
+    progress: &dyn ProgressSink,
     n: u64,
 ) -> Result<(), anyhow::Error> {
     let result = push_transaction(
@@ -571,17 +1484,18 @@ async fn monitor_trx(
         trx.packed(),
         args.trace,
         args.console,
-        Some(&progress),
+        Some(progress),
     )
     .await;
     if let Err(err) = result {
-        progress.suspend(|| {
-            println!("=====\n{:?}", err);
-            println!("-----\nThese files were in this failed transaction:");
-            for f in files {
-                println!("    {}", f);
-            }
-        });
+        let mut output = format!(
+            "=====\n{:?}\n-----\nThese files were in this failed transaction:\n",
+            err
+        );
+        for f in files {
+            output += &format!("    {}\n", f);
+        }
+        progress.write_output(&output);
         return Err(err);
     } else {
         progress.inc(n);
@@ -589,6 +1503,46 @@ async fn monitor_trx(
     Ok(())
 }
 
+fn parse_packed(text: &str, base64: bool) -> Result<Vec<u8>, anyhow::Error> {
+    let text: String = text.split_whitespace().collect();
+    if base64 {
+        Ok(base64::engine::general_purpose::STANDARD
+            .decode(text)
+            .context("The data is not valid base64")?)
+    } else {
+        Ok(text
+            .parse::<Hex<Vec<u8>>>()
+            .map_err(|_| anyhow!("The data is not valid hex"))?
+            .0)
+    }
+}
+
+fn dump_type(type_name: &str, src: &[u8]) -> Result<Vec<DumpEntry>, anyhow::Error> {
+    match type_name {
+        "Action" => dump_packed::<Action>(src),
+        "Claim" => dump_packed::<Claim>(src),
+        "SignedTransaction" => dump_packed::<SignedTransaction>(src),
+        "Tapos" => dump_packed::<Tapos>(src),
+        "Transaction" => dump_packed::<Transaction>(src),
+        _ => {
+            return Err(anyhow!(
+                "Unknown type {}. Use Action, Claim, SignedTransaction, Tapos, or Transaction",
+                type_name
+            ))
+        }
+    }
+    .with_context(|| format!("The data is not a valid {}", type_name))
+}
+
+fn hexdump(type_name: &str, data: &Option<String>, base64: bool) -> Result<(), anyhow::Error> {
+    let src = match data {
+        Some(data) => parse_packed(data, base64)?,
+        None => parse_packed(&std::io::read_to_string(std::io::stdin())?, base64)?,
+    };
+    print!("{}", format_dump(&src, &dump_type(type_name, &src)?));
+    Ok(())
+}
+
 fn data_directory() -> Result<PathBuf, anyhow::Error> {
     let exe = std::env::current_exe()?.canonicalize()?;
     let Some(parent) = exe.parent() else {
@@ -604,21 +1558,172 @@ fn data_directory() -> Result<PathBuf, anyhow::Error> {
     Ok(base.join("share/psibase"))
 }
 
+// Files that belong to the current user are kept under $<var>/psibase,
+// or ~/<default_suffix>/psibase if it is not set, instead of the shared
+// install directory: data such as saved boot transactions under
+// XDG_DATA_HOME (.local/share), and settings and secrets such as the
+// keystore and login sessions under XDG_CONFIG_HOME (.config)
+fn xdg_dir(var: &str, default_suffix: &str) -> Result<PathBuf, anyhow::Error> {
+    if let Some(dir) = std::env::var_os(var).filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir).join("psibase"));
+    }
+    let Some(home) = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")) else {
+        return Err(anyhow!("Cannot find the home directory"));
+    };
+    Ok(PathBuf::from(home).join(default_suffix).join("psibase"))
+}
+
+// Creates dir and its parents, readable only by the current user
+fn create_private_dir(dir: &Path) -> Result<(), anyhow::Error> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder
+        .create(dir)
+        .with_context(|| format!("Cannot create {}", dir.display()))
+}
+
+fn check_package_source(source: &str, insecure_http: bool) -> Result<(), anyhow::Error> {
+    if source.starts_with("http:") && !insecure_http {
+        return Err(anyhow!(
+            "Refusing to fetch packages over plain http from {}. Use an https source, or pass --insecure-http to allow it anyway.",
+            source
+        ));
+    }
+    Ok(())
+}
+
+async fn push_package_source(
+    args: &Args,
+    registry: &mut JointRegistry<BufReader<File>>,
+    source: &str,
+    preferred: bool,
+) -> Result<(), anyhow::Error> {
+    if source.starts_with("http:") || source.starts_with("https:") {
+        let url = Url::parse(source)?;
+        let client = build_registry_client(args, &url)?;
+        let mut source =
+            HTTPRegistry::new_with_max_package_size(url, client, args.max_package_size).await?;
+        if let Some(dir) = &args.download_dir {
+            source = source.with_temp_dir(dir.clone());
+        }
+        if preferred {
+            registry.push_preferred(source)
+        } else {
+            registry.push(source)
+        }
+    } else {
+        let source = DirectoryRegistry::open(source.into())?;
+        if preferred {
+            registry.push_preferred(source)
+        } else {
+            registry.push(source)
+        }
+    }
+}
+
 async fn get_package_registry(
+    args: &Args,
     sources: &Vec<String>,
-    client: reqwest::Client,
 ) -> Result<JointRegistry<BufReader<File>>, anyhow::Error> {
     let mut result = JointRegistry::new();
+    for source in sources.iter().chain(&args.prefer_source) {
+        check_package_source(source, args.insecure_http)?;
+    }
+    for source in &args.prefer_source {
+        push_package_source(args, &mut result, source, true).await?;
+    }
     if sources.is_empty() {
         result.push(DirectoryRegistry::new(data_directory()?.join("packages")))?;
     } else {
         for source in sources {
-            if source.starts_with("http:") || source.starts_with("https:") {
-                result.push(HTTPRegistry::new(Url::parse(source)?, client.clone()).await?)?;
-            } else {
-                result.push(DirectoryRegistry::new(source.into()))?;
+            push_package_source(args, &mut result, source, false).await?;
+        }
+    }
+    Ok(result)
+}
+
+// A lockfile is a JSON array of the exact packages that were selected
+fn read_lockfile(path: &Path) -> Result<Vec<LockedPackage>, anyhow::Error> {
+    let file =
+        File::open(path).with_context(|| format!("Can not open {}", path.to_string_lossy()))?;
+    serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("{} is not a valid lockfile", path.to_string_lossy()))
+}
+
+fn write_lockfile<'a>(
+    path: &Path,
+    packages: impl IntoIterator<Item = &'a PackageInfo>,
+) -> Result<(), anyhow::Error> {
+    let lock: Vec<LockedPackage> = packages.into_iter().map(LockedPackage::from).collect();
+    let file =
+        File::create(path).with_context(|| format!("Can not create {}", path.to_string_lossy()))?;
+    serde_json::to_writer_pretty(file, &lock)?;
+    Ok(())
+}
+
+// The lockfile for an install has every package that the chain will have
+// afterwards. Installed packages that are kept, such as dependencies that
+// are already satisfied, are locked to their entries in the package sources.
+fn install_lock(
+    installed: &PackageList,
+    registry: &JointRegistry<BufReader<File>>,
+    ops: &[PackageOp],
+) -> Result<Vec<PackageInfo>, anyhow::Error> {
+    let changed: HashSet<_> = ops
+        .iter()
+        .filter_map(|op| match op {
+            PackageOp::Install(_) => None,
+            PackageOp::Replace(meta, _) | PackageOp::Remove(meta) => {
+                Some((&meta.name, &meta.version))
             }
+        })
+        .collect();
+    // Kept packages come first, since they are installed before the rest
+    let mut result = vec![];
+    for (meta, _) in installed.clone().into_info() {
+        if changed.contains(&(&meta.name, &meta.version)) {
+            continue;
         }
+        let Some(info) = registry.get_info(&meta.name, &format!("={}", meta.version))? else {
+            return Err(anyhow!(
+                "{}-{} is installed, but is not in the package sources, so it cannot be locked",
+                meta.name,
+                meta.version
+            ));
+        };
+        result.push(info);
+    }
+    result.extend(ops.iter().filter_map(|op| match op {
+        PackageOp::Install(info) | PackageOp::Replace(_, info) => Some(info.clone()),
+        PackageOp::Remove(_) => None,
+    }));
+    Ok(result)
+}
+
+async fn get_locked_registry(
+    args: &Args,
+    sources: &Vec<String>,
+    lock: &Option<PathBuf>,
+) -> Result<JointRegistry<BufReader<File>>, anyhow::Error> {
+    let mut result = get_package_registry(args, sources).await?;
+    if let Some(path) = lock {
+        result
+            .lock(&read_lockfile(path)?)
+            .with_context(|| format!("Cannot use {}", path.to_string_lossy()))?;
+    }
+    Ok(result)
+}
+
+// The names of the sources that get_package_registry adds, in the order
+// that JointRegistry numbers them
+fn package_source_names(args: &Args, sources: &[String]) -> Result<Vec<String>, anyhow::Error> {
+    let mut result = args.prefer_source.clone();
+    if sources.is_empty() {
+        result.push(data_directory()?.join("packages").display().to_string());
+    } else {
+        result.extend(sources.iter().cloned());
     }
     Ok(result)
 }
@@ -627,31 +1732,59 @@ async fn boot(
     args: &Args,
     client: reqwest::Client,
     key: &Option<AnyPublicKey>,
-    producer: ExactAccountNumber,
+    producers: &[ExactAccountNumber],
     package_source: &Vec<String>,
     services: &Vec<String>,
+    lock: &Option<PathBuf>,
+    lock_out: &Option<PathBuf>,
 ) -> Result<(), anyhow::Error> {
-    let now_plus_120secs = Utc::now() + Duration::seconds(120);
-    let expiration = TimePointSec {
-        seconds: now_plus_120secs.timestamp() as u32,
-    };
+    if is_booted(args, client.clone()).await? {
+        return Err(anyhow!(
+            "The chain is already booted. Use --resume to finish an interrupted boot."
+        ));
+    }
+    let expiration = TimePointSec::try_from(Utc::now() + Duration::seconds(120))?;
+    let interrupted = &*args.interrupted;
     let default_services = vec!["Default".to_string()];
-    let package_registry = get_package_registry(package_source, client.clone()).await?;
-    let mut packages = package_registry
-        .resolve(if services.is_empty() {
-            &default_services[..]
-        } else {
-            &services[..]
-        })
-        .await?;
+    let package_registry =
+        unless_interrupted(interrupted, get_locked_registry(args, package_source, lock)).await?;
+    let services = if services.is_empty() {
+        &default_services[..]
+    } else {
+        &services[..]
+    };
+    let infos = package_registry.resolve_info(services)?;
+    if infos.is_empty() {
+        return Err(anyhow!("Nothing to boot: no packages were selected"));
+    }
+    if let Some(path) = lock_out {
+        write_lockfile(path, &infos)?;
+    }
+    let mut packages = vec![];
+    for info in &infos {
+        packages.push(unless_interrupted(interrupted, package_registry.get_by_info(info)).await?);
+    }
+    let producers: Vec<AccountNumber> = producers.iter().map(|p| (*p).into()).collect();
     let (boot_transactions, transactions) =
-        create_boot_transactions(key, producer.into(), true, expiration, &mut packages)?;
+        create_boot_transactions(key, &producers, true, expiration, &mut packages)?;
+
+    // The chain only accepts exactly these transactions until the boot
+    // is finished, so keep them around for --resume
+    let pending = pending_boot_path(&args.api)?;
+    write_pending_boot(&pending, &transactions.packed())?;
 
-    let progress = ProgressBar::new((transactions.len() + 1) as u64)
-        .with_style(ProgressStyle::with_template("{wide_bar} {pos}/{len}")?);
+    let progress = Progress::new(args, (transactions.len() + 1) as u64, "", false)?;
     push_boot(args, &client, boot_transactions.packed(), &progress).await?;
     progress.inc(1);
     for transaction in transactions {
+        if args.interrupted.load(Ordering::Relaxed) {
+            progress.abandon();
+            return Err(anyhow!(
+                "Interrupted after pushing {} of {} boot transactions",
+                progress.position(),
+                progress.length()
+            ));
+        }
         push_transaction(
             &args.api,
             client.clone(),
@@ -663,42 +1796,382 @@ async fn boot(
         .await?;
         progress.inc(1)
     }
+    let _ = std::fs::remove_file(&pending);
     if !args.suppress_ok {
         println!("Ok");
     }
     Ok(())
 }
 
-async fn push_boot(
-    args: &Args,
-    client: &reqwest::Client,
-    packed: Vec<u8>,
-    progress: &ProgressBar,
-) -> Result<(), anyhow::Error> {
-    let trace: TransactionTrace =
-        as_json(client.post(args.api.join("native/push_boot")?).body(packed)).await?;
-    if args.console {
-        progress.suspend(|| print!("{}", trace.console()));
-    }
-    args.trace
-        .error_for_trace(trace, Some(progress))
-        .context("Failed to boot")
+// Where boot keeps the transactions that follow the genesis block
+fn pending_boot_path(api: &Url) -> Result<PathBuf, anyhow::Error> {
+    let host = format!(
+        "{}-{}",
+        api.host_str().unwrap_or_default(),
+        api.port_or_known_default().unwrap_or_default()
+    );
+    let host: String = host
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    Ok(xdg_dir("XDG_DATA_HOME", ".local/share")?
+        .join("boot")
+        .join(format!("{}.bin", host)))
 }
 
-fn normalize_upload_path(path: &Option<String>) -> String {
-    let mut result = String::new();
-    if let Some(s) = path {
-        if !s.starts_with('/') {
-            result.push('/');
-        }
-        result.push_str(s);
-        while result.ends_with('/') {
-            result.pop();
+// The chain is not booted, so a file left by an earlier boot of the same
+// node is stale. The transactions are signed, so only the user may read them.
+fn write_pending_boot(pending: &Path, packed: &[u8]) -> Result<(), anyhow::Error> {
+    if let Some(dir) = pending.parent() {
+        create_private_dir(dir)?;
+    }
+    match std::fs::remove_file(pending) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("Cannot remove {}", pending.display()))
+        }
+        _ => {}
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(pending)
+        .and_then(|mut file| file.write_all(packed))
+        .with_context(|| format!("Cannot write {}", pending.display()))
+}
+
+// Boot transactions cannot be re-signed with a later expiration, because
+// the genesis transaction commits to their exact contents
+fn check_boot_expiration(
+    transactions: &[SignedTransaction],
+    now: DateTime<Utc>,
+) -> Result<(), anyhow::Error> {
+    let Some(first) = transactions.first() else {
+        return Ok(());
+    };
+    let expiration: DateTime<Utc> = Transaction::unpacked(&first.transaction)?
+        .tapos
+        .expiration
+        .into();
+    if expiration <= now {
+        return Err(anyhow!(
+            "The boot transactions expired at {}. A chain whose boot transactions have expired cannot be finished and must be booted again from scratch.",
+            expiration
+        ));
+    }
+    Ok(())
+}
+
+async fn is_booted(args: &Args, client: reqwest::Client) -> Result<bool, anyhow::Error> {
+    match get_tapos_for_head(&args.api, client).await {
+        Ok(_) => Ok(true),
+        Err(e) if is_unbooted_error(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+async fn resume_boot(args: &Args, client: reqwest::Client) -> Result<(), anyhow::Error> {
+    if !is_booted(args, client.clone()).await? {
+        return Err(anyhow!(
+            "The chain has not been booted yet. Run boot without --resume."
+        ));
+    }
+    let pending = pending_boot_path(&args.api)?;
+    let packed = match std::fs::read(&pending) {
+        Ok(packed) => packed,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(anyhow!("No interrupted boot was found for {}", args.api));
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("Cannot read {}", pending.to_string_lossy()))
+        }
+    };
+    // Reject a damaged file instead of pushing whatever part of it parses
+    <Vec<SignedTransaction>>::verify_all(&packed)
+        .with_context(|| format!("{} is damaged", pending.to_string_lossy()))?;
+    let transactions = <Vec<SignedTransaction>>::unpacked(&packed)?;
+    check_boot_expiration(&transactions, Utc::now())?;
+    let progress = Progress::new(args, transactions.len() as u64, "", false)?;
+    resume_boot_transactions(
+        &args.api,
+        client,
+        transactions,
+        args.trace,
+        args.console,
+        &progress,
+    )
+    .await
+    .context("Failed to resume boot")?;
+    progress.finish_and_clear();
+    let _ = std::fs::remove_file(&pending);
+    if !args.suppress_ok {
+        println!("Ok");
+    }
+    Ok(())
+}
+
+// How often a progress line is printed when progress bars are disabled
+const PROGRESS_LINE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Shows progress as an animated bar when stderr is a terminal. Otherwise,
+// redrawing the bar would fill logs with control sequences, so plain
+// lines are printed instead.
+enum Progress {
+    Bar(ProgressBar),
+    Lines(LineProgress),
+}
+
+impl Progress {
+    fn new(args: &Args, len: u64, unit: &str, show_message: bool) -> Result<Self, anyhow::Error> {
+        if args.no_progress || !console::Term::stderr().is_term() {
+            return Ok(Progress::Lines(LineProgress::new(
+                len,
+                unit,
+                PROGRESS_LINE_INTERVAL,
+                Box::new(std::io::stderr()),
+            )));
+        }
+        let mut template = "{wide_bar} {pos}/{len}".to_string();
+        if !unit.is_empty() {
+            template = template + " " + unit;
+        }
+        if show_message {
+            template += "\n{msg}";
+        }
+        Ok(Progress::Bar(
+            ProgressBar::new(len).with_style(ProgressStyle::with_template(&template)?),
+        ))
+    }
+    fn position(&self) -> u64 {
+        match self {
+            Progress::Bar(bar) => bar.position(),
+            Progress::Lines(lines) => lines.pos.load(Ordering::Relaxed),
+        }
+    }
+    fn length(&self) -> u64 {
+        match self {
+            Progress::Bar(bar) => bar.length().unwrap_or(0),
+            Progress::Lines(lines) => lines.len,
+        }
+    }
+    fn finish_with_message(&self, msg: &'static str) {
+        match self {
+            Progress::Bar(bar) => bar.finish_with_message(msg),
+            Progress::Lines(lines) => lines.write_line(msg),
+        }
+    }
+    fn finish_and_clear(&self) {
+        if let Progress::Bar(bar) = self {
+            bar.finish_and_clear()
+        }
+    }
+}
+
+impl ProgressSink for Progress {
+    fn inc(&self, delta: u64) {
+        match self {
+            Progress::Bar(bar) => bar.inc(delta),
+            Progress::Lines(lines) => lines.inc(delta),
+        }
+    }
+    fn set_message(&self, msg: String) {
+        match self {
+            Progress::Bar(bar) => bar.set_message(msg),
+            Progress::Lines(lines) => *lines.message.lock().unwrap() = msg,
+        }
+    }
+    fn abandon(&self) {
+        match self {
+            Progress::Bar(bar) => bar.abandon(),
+            Progress::Lines(lines) => lines.report(),
+        }
+    }
+    fn suspend(&self, f: &mut dyn FnMut()) {
+        match self {
+            Progress::Bar(bar) => bar.suspend(f),
+            Progress::Lines(_) => f(),
+        }
+    }
+}
+
+// Prints the position at most once per interval, and when done
+struct LineProgress {
+    len: u64,
+    unit: String,
+    interval: std::time::Duration,
+    pos: std::sync::atomic::AtomicU64,
+    message: std::sync::Mutex<String>,
+    last: std::sync::Mutex<Option<std::time::Instant>>,
+    out: std::sync::Mutex<Box<dyn std::io::Write + Send>>,
+}
+
+impl LineProgress {
+    fn new(
+        len: u64,
+        unit: &str,
+        interval: std::time::Duration,
+        out: Box<dyn std::io::Write + Send>,
+    ) -> Self {
+        LineProgress {
+            len,
+            unit: unit.to_string(),
+            interval,
+            pos: Default::default(),
+            message: Default::default(),
+            last: Default::default(),
+            out: std::sync::Mutex::new(out),
+        }
+    }
+    fn inc(&self, delta: u64) {
+        let pos = self.pos.fetch_add(delta, Ordering::Relaxed) + delta;
+        let now = std::time::Instant::now();
+        let mut last = self.last.lock().unwrap();
+        if pos >= self.len || last.map_or(true, |last| now - last >= self.interval) {
+            *last = Some(now);
+            drop(last);
+            self.report();
+        }
+    }
+    fn report(&self) {
+        let mut line = format!("{}/{}", self.pos.load(Ordering::Relaxed), self.len);
+        if !self.unit.is_empty() {
+            line = line + " " + &self.unit;
+        }
+        let message = self.message.lock().unwrap();
+        if !message.is_empty() {
+            line = line + ": " + &message;
+        }
+        drop(message);
+        self.write_line(&line);
+    }
+    fn write_line(&self, line: &str) {
+        let _ = writeln!(self.out.lock().unwrap(), "{}", line);
+    }
+}
+
+async fn push_boot(
+    args: &Args,
+    client: &reqwest::Client,
+    packed: Vec<u8>,
+    progress: &dyn ProgressSink,
+) -> Result<(), anyhow::Error> {
+    let trace: TransactionTrace =
+        as_json(client.post(args.api.join("native/push_boot")?).body(packed)).await?;
+    args.trace
+        .report_trace(trace, args.console, Some(progress))
+        .context("Failed to boot")
+}
+
+async fn ping_node(
+    args: &Args,
+    client: reqwest::Client,
+    count: u32,
+    timeout: u64,
+) -> Result<(), anyhow::Error> {
+    let timeout = std::time::Duration::from_secs(timeout);
+    let mut latencies = Vec::new();
+    for _ in 0..count {
+        let reply = ping(&args.api, &client, timeout).await?;
+        let ms = reply.latency.as_secs_f64() * 1000.0;
+        match reply.remote_addr {
+            Some(addr) => println!("reply from {}: time={:.1} ms", addr, ms),
+            None => println!("reply from {}: time={:.1} ms", args.api, ms),
+        }
+        latencies.push(ms);
+    }
+    let min = latencies.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = latencies.iter().cloned().fold(0.0, f64::max);
+    let avg = latencies.iter().sum::<f64>() / latencies.len() as f64;
+    println!("min/avg/max = {:.1}/{:.1}/{:.1} ms", min, avg, max);
+    Ok(())
+}
+
+async fn show_trace(
+    args: &Args,
+    client: reqwest::Client,
+    id: &Checksum256,
+) -> Result<(), anyhow::Error> {
+    let Some(trace) = get_transaction_trace(&args.api, client, id).await? else {
+        return Err(anyhow!(
+            "No trace for transaction {}. Only recent transactions pushed to this node are kept.",
+            id
+        ));
+    };
+    args.trace.report_trace(trace, args.console, None)?;
+    if !args.suppress_ok {
+        println!("Ok");
+    }
+    Ok(())
+}
+
+fn normalize_upload_path(path: &Option<String>) -> String {
+    let mut result = String::new();
+    if let Some(s) = path {
+        if !s.starts_with('/') {
+            result.push('/');
+        }
+        result.push_str(s);
+        while result.ends_with('/') {
+            result.pop();
         }
     }
     result
 }
 
+// The destination of source when the local prefix is replaced by dest
+fn strip_upload_prefix(
+    dest: &Option<String>,
+    source: &str,
+    prefix: &Path,
+) -> Result<String, anyhow::Error> {
+    let without_dots = |path: &Path| -> PathBuf {
+        path.components()
+            .filter(|c| !matches!(c, std::path::Component::CurDir))
+            .collect()
+    };
+    let source_path = without_dots(Path::new(source));
+    let rest = source_path
+        .strip_prefix(without_dots(prefix))
+        .map_err(|_| {
+            anyhow!(
+                "{} is not under --strip-prefix {}",
+                source,
+                prefix.display()
+            )
+        })?;
+    let mut result = normalize_upload_path(dest);
+    for part in rest {
+        result.push('/');
+        result.push_str(
+            part.to_str()
+                .ok_or_else(|| anyhow!("{} is not valid UTF-8", source))?,
+        );
+    }
+    Ok(result)
+}
+
+// Runs at most limit futures at a time. Once interrupted, the futures that
+// haven't started yet are dropped.
+async fn run_bounded<F: Future>(
+    futures: Vec<F>,
+    limit: NonZeroUsize,
+    interrupted: &AtomicBool,
+) -> Vec<F::Output> {
+    stream::iter(futures)
+        .take_while(|_| future::ready(!interrupted.load(Ordering::Relaxed)))
+        .buffer_unordered(limit.get())
+        .collect()
+        .await
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn upload_tree(
     args: &Args,
     client: reqwest::Client,
@@ -706,6 +2179,12 @@ async fn upload_tree(
     sender: Option<ExactAccountNumber>,
     dest: &Option<String>,
     source: &str,
+    manifest_out: &Option<PathBuf>,
+    prune: bool,
+    yes: bool,
+    follow_symlinks: bool,
+    concurrency: NonZeroUsize,
+    verify: bool,
 ) -> Result<(), anyhow::Error> {
     let sender = if let Some(s) = sender {
         s.into()
@@ -713,46 +2192,63 @@ async fn upload_tree(
         service
     };
 
+    if prune && service != sites::SERVICE {
+        return Err(anyhow!(
+            "--prune needs to list the stored files, which only {} supports",
+            sites::SERVICE
+        ));
+    }
+
     let normalized_dest = normalize_upload_path(dest);
 
     let mut actions = Vec::new();
+    let mut manifest = Vec::new();
     fill_tree(
         service,
         sender,
         &mut actions,
+        &mut manifest,
         &normalized_dest,
         source,
         true,
+        follow_symlinks,
+        &mut HashSet::new(),
+        args.verbose,
     )?;
 
-    let tapos = get_tapos_for_head(&args.api, client.clone()).await?;
-    let mut running = Vec::new();
-    let progress = ProgressBar::new(actions.len() as u64).with_style(ProgressStyle::with_template(
-        "{wide_bar} {pos}/{len} files",
-    )?);
+    let tapos = get_tapos(args, client.clone()).await?;
+    let progress = Progress::new(args, actions.len() as u64, "files", false)?;
 
-    while !actions.is_empty() {
-        let mut n = 0;
-        let mut size = 0;
-        while n < actions.len() && n < 10 && size < 64 * 1024 {
-            size += actions[n].1.rawData.len();
-            n += 1;
-        }
+    let batches = batch_uploads(actions, args.max_transaction_size as usize);
+    let summary = upload_summary(&manifest, batches.len());
 
-        let (selected_files, selected_actions) = actions.drain(..n).unzip();
-        let trx = with_tapos(&tapos, selected_actions);
-        running.push(monitor_trx(
-            args,
-            &client,
-            selected_files,
-            sign_transaction(trx, &args.sign)?,
-            progress.clone(),
-            n as u64,
-        ));
+    let mut transactions = Vec::new();
+    for (selected_files, selected_actions) in batches {
+        let trx = with_tapos(args, &tapos, selected_actions)?;
+        transactions.push((selected_files, sign_transaction(trx, &args.keys)?));
+    }
+
+    if args.parallel_verify {
+        let labels: Vec<String> = transactions
+            .iter()
+            .map(|(files, _)| files.join(", "))
+            .collect();
+        let labeled: Vec<(&str, &SignedTransaction)> = labels
+            .iter()
+            .zip(&transactions)
+            .map(|(label, (_, trx))| (label.as_str(), trx))
+            .collect();
+        verify_labeled_transactions(&labeled)?;
+    }
+
+    let mut running = Vec::new();
+    for (files, trx) in transactions {
+        let n = files.len() as u64;
+        running.push(monitor_trx(args, &client, files, trx, &progress, n));
     }
 
     let num_trx = running.len();
-    let num_failed = join_all(running)
+    let num_failed = run_bounded(running, concurrency, &args.interrupted)
         .await
         .iter()
         .filter(|x| x.is_err())
@@ -761,439 +2257,2268 @@ async fn upload_tree(
         progress.abandon();
         return Err(anyhow!("{}/{} failed transactions", num_failed, num_trx));
     }
+    // Transactions that were in flight when interrupted still finish,
+    // so the interrupt is only reported after they resolve.
+    if args.interrupted.load(Ordering::Relaxed) {
+        progress.abandon();
+        return Err(anyhow!(
+            "Interrupted after uploading {} of {} files",
+            progress.position(),
+            progress.length()
+        ));
+    }
 
+    if let Some(path) = manifest_out {
+        manifest.sort_by(|a, b| a.path.cmp(&b.path));
+        let file = File::create(path)
+            .with_context(|| format!("Can not create {}", path.to_string_lossy()))?;
+        serde_json::to_writer_pretty(file, &manifest)?;
+    }
+
+    progress.finish_and_clear();
     if !args.suppress_ok {
-        println!("Ok");
+        println!("{}", summary);
     }
-    Ok(())
-}
 
-fn create_accounts<F: Fn(Vec<Action>) -> Result<SignedTransaction, anyhow::Error>>(
-    accounts: Vec<AccountNumber>,
-    out: &mut TransactionBuilder<F>,
-    sender: AccountNumber,
-) -> Result<(), anyhow::Error> {
-    for account in accounts {
-        out.set_label(format!("Creating {}", account));
-        let group = vec![
-            accounts::Wrapper::pack().newAccount(account, account!("auth-any"), true),
-            auth_delegate::Wrapper::pack_from(account).setOwner(sender),
-            set_auth_service_action(account, auth_delegate::SERVICE),
-        ];
-        out.push(group)?;
+    if verify {
+        let host = content_host(service, sender);
+        verify_uploads(args, &client, host, &manifest, concurrency).await?;
     }
-    Ok(())
-}
 
-async fn apply_packages<
-    R: PackageRegistry,
-    F: Fn(Vec<Action>) -> Result<SignedTransaction, anyhow::Error>,
->(
-    base_url: &reqwest::Url,
-    client: &mut reqwest::Client,
-    reg: &R,
-    ops: Vec<PackageOp>,
-    accounts: &mut Vec<AccountNumber>,
-    out: &mut TransactionBuilder<F>,
-    sender: AccountNumber,
-    key: &Option<AnyPublicKey>,
-) -> Result<(), anyhow::Error> {
-    for op in ops {
-        match op {
-            PackageOp::Install(info) => {
-                // TODO: verify ownership of existing accounts
-                let mut package = reg.get_by_info(&info).await?;
-                accounts.extend_from_slice(package.get_accounts());
-                out.set_label(format!("Installing {}-{}", &info.name, &info.version));
-                let mut account_actions = vec![];
-                package.install_accounts(&mut account_actions, sender, key)?;
-                out.push_all(account_actions)?;
-                let mut actions = vec![];
-                package.install(&mut actions, sender, true)?;
-                out.push_all(actions)?;
+    if prune {
+        let mut client = client;
+        let stored = site_files(&args.api, &mut client, sender, &normalized_dest).await?;
+        let removals = prune_actions(service, sender, &normalized_dest, stored, &manifest);
+        if !removals.is_empty() {
+            for (path, _) in &removals {
+                println!("{} (removed)", path);
             }
-            PackageOp::Replace(meta, info) => {
-                let mut package = reg.get_by_info(&info).await?;
-                accounts.extend_from_slice(package.get_accounts());
-                // TODO: skip unmodified files (?)
-                out.set_label(format!(
-                    "Updating {}-{} -> {}-{}",
-                    &meta.name, &meta.version, &info.name, &info.version
+            if !yes {
+                confirm_prune(removals.len())?;
+            }
+            let num_files = removals.len();
+            let mut transactions = vec![];
+            for (selected_files, selected_actions) in
+                batch_uploads(removals, args.max_transaction_size as usize)
+            {
+                let trx = with_tapos(args, &tapos, selected_actions)?;
+                transactions.push((
+                    selected_files.join(", "),
+                    vec![sign_transaction(trx, &args.keys)?],
+                    false,
                 ));
-                // Remove out-dated files. This needs to happen before installing
-                // new files, to handle the case where a service that stores
-                // data files is replaced by a service that does not provide
-                // removeSys.
-                let old_manifest =
-                    get_installed_manifest(base_url, client, &meta.name, sender).await?;
-                old_manifest.upgrade(package.manifest(), out)?;
-                // Install the new package
-                let mut account_actions = vec![];
-                package.install_accounts(&mut account_actions, sender, key)?;
-                out.push_all(account_actions)?;
-                let mut actions = vec![];
-                package.install(&mut actions, sender, true)?;
-                out.push_all(actions)?;
             }
-            PackageOp::Remove(meta) => {
-                out.set_label(format!("Removing {}", &meta.name));
-                let old_manifest =
-                    get_installed_manifest(base_url, client, &meta.name, sender).await?;
-                old_manifest.remove(out)?;
+            let progress = Progress::new(args, transactions.len() as u64, "removals", false)?;
+            push_transactions_until(
+                &args.api,
+                client,
+                transactions,
+                args.trace,
+                args.console,
+                &progress,
+                &args.interrupted,
+            )
+            .await
+            .context("Failed to remove stale files")?;
+            progress.finish_and_clear();
+            if !args.suppress_ok {
+                println!("Removed {} files", num_files);
             }
         }
     }
+
+    if !args.suppress_ok {
+        println!("Ok");
+    }
     Ok(())
 }
 
-async fn install(
-    args: &Args,
-    mut client: reqwest::Client,
-    packages: &[String],
-    sender: AccountNumber,
-    key: &Option<AnyPublicKey>,
-    sources: &Vec<String>,
-    reinstall: bool,
-) -> Result<(), anyhow::Error> {
-    let installed = PackageList::installed(&args.api, &mut client).await?;
-    let package_registry = get_package_registry(sources, client.clone()).await?;
-    let to_install = installed
-        .resolve_changes(&package_registry, packages, reinstall)
-        .await?;
-
-    let tapos = get_tapos_for_head(&args.api, client.clone()).await?;
-
-    let build_transaction = |mut actions: Vec<Action>| -> Result<SignedTransaction, anyhow::Error> {
-        if actions.first().unwrap().sender != sender {
-            actions.insert(
-                0,
-                Action {
-                    sender,
-                    service: account!("nop"),
-                    method: method!("nop"),
-                    rawData: Default::default(),
-                },
-            );
-        }
-        Ok(sign_transaction(with_tapos(&tapos, actions), &args.sign)?)
-    };
+#[derive(Deserialize)]
+struct SiteFile {
+    account: AccountNumber,
+    path: String,
+}
 
-    let action_limit: usize = 64 * 1024;
+#[derive(Deserialize)]
+struct SiteFileEdge {
+    node: SiteFile,
+}
 
-    let mut account_builder = TransactionBuilder::new(action_limit, build_transaction);
-    let mut new_accounts = vec![];
+#[allow(non_snake_case)]
+#[derive(Deserialize)]
+struct SiteFilePageInfo {
+    hasNextPage: bool,
+    endCursor: String,
+}
 
-    let mut trx_builder = TransactionBuilder::new(action_limit, build_transaction);
-    apply_packages(
-        &args.api,
-        &mut client,
-        &package_registry,
-        to_install,
-        &mut new_accounts,
-        &mut trx_builder,
-        sender,
-        key,
-    )
-    .await?;
+#[allow(non_snake_case)]
+#[derive(Deserialize)]
+struct SiteFiles {
+    pageInfo: SiteFilePageInfo,
+    edges: Vec<SiteFileEdge>,
+}
 
-    new_accounts = get_accounts_to_create(&args.api, &mut client, &new_accounts, sender).await?;
-    create_accounts(new_accounts, &mut account_builder, sender)?;
+#[derive(Deserialize)]
+struct SiteFilesQuery {
+    content: SiteFiles,
+}
 
-    let account_transactions = account_builder.finish()?;
-    let transactions = trx_builder.finish()?;
+fn is_under(path: &str, dest: &str) -> bool {
+    path.strip_prefix(dest)
+        .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+}
 
-    {
-        let progress = ProgressBar::new(account_transactions.len() as u64).with_style(
-            ProgressStyle::with_template("{wide_bar} {pos}/{len} accounts\n{msg}")?,
-        );
-        push_transactions(
-            &args.api,
-            client.clone(),
-            account_transactions,
-            args.trace,
-            args.console,
-            &progress,
+// Lists the paths that sites stores for account at or under dest
+async fn site_files(
+    base_url: &Url,
+    client: &mut reqwest::Client,
+    account: AccountNumber,
+    dest: &str,
+) -> Result<Vec<String>, anyhow::Error> {
+    let mut end_cursor: Option<String> = None;
+    let mut result = vec![];
+    loop {
+        let data: SiteFilesQuery = gql_query(
+            base_url,
+            client,
+            sites::SERVICE,
+            format!(
+                "query {{ content(ge: {{ account: {}, path: {} }}, first: 100, after: {}) {{ pageInfo {{ hasNextPage endCursor }} edges {{ node {{ account path }} }} }} }}",
+                serde_json::to_string(&account.to_string())?,
+                serde_json::to_string(dest)?,
+                serde_json::to_string(&end_cursor)?,
+            ),
         )
-        .await?;
-        progress.finish_and_clear();
+        .await
+        .with_context(|| format!("Failed to list the files of {}", account))?;
+        // Rows are sorted by account, then path, so the paths under
+        // dest are contiguous. Paths that share a prefix with dest,
+        // e.g. /dest-old for /dest, may be mixed in.
+        let mut done = !data.content.pageInfo.hasNextPage;
+        for edge in data.content.edges {
+            if edge.node.account != account || !edge.node.path.starts_with(dest) {
+                done = true;
+                break;
+            }
+            if is_under(&edge.node.path, dest) {
+                result.push(edge.node.path);
+            }
+        }
+        if done {
+            return Ok(result);
+        }
+        end_cursor = Some(data.content.pageInfo.endCursor);
     }
+}
 
-    let progress = ProgressBar::new(transactions.len() as u64).with_style(
-        ProgressStyle::with_template("{wide_bar} {pos}/{len} packages\n{msg}")?,
-    );
-
-    push_transactions(
-        &args.api,
-        client.clone(),
-        transactions,
-        args.trace,
-        args.console,
-        &progress,
-    )
-    .await?;
-
-    if !args.suppress_ok {
-        progress.finish_with_message("Ok");
+// sites serves each account's files from the account's subdomain. Other
+// services serve the files stored with them from their own subdomain.
+fn content_host(service: AccountNumber, sender: AccountNumber) -> AccountNumber {
+    if service == sites::SERVICE {
+        sender
     } else {
-        progress.finish_and_clear();
+        service
     }
-
-    Ok(())
 }
 
-async fn list(
-    args: &Args,
-    mut client: reqwest::Client,
-    all: bool,
-    available: bool,
-    installed: bool,
-    sources: &Vec<String>,
-) -> Result<(), anyhow::Error> {
-    if all || (installed && available) || (!all & !installed && !available) {
-        let installed = handle_unbooted(PackageList::installed(&args.api, &mut client).await)?;
-        let package_registry = get_package_registry(sources, client.clone()).await?;
-        let reglist = PackageList::from_registry(&package_registry)?;
-        for name in installed.union(reglist).into_vec() {
-            println!("{}", name);
-        }
-    } else if installed {
-        let installed = handle_unbooted(PackageList::installed(&args.api, &mut client).await)?;
-        for name in installed.into_vec() {
-            println!("{}", name);
-        }
-    } else if available {
-        let installed = handle_unbooted(PackageList::installed(&args.api, &mut client).await)?;
-        let package_registry = get_package_registry(sources, client.clone()).await?;
-        let reglist = PackageList::from_registry(&package_registry)?;
-        for name in reglist.difference(installed).into_vec() {
-            println!("{}", name);
-        }
+// Returns why the file that the node serves at file.path doesn't match
+// file, or None if it matches
+async fn check_upload(
+    client: &reqwest::Client,
+    base_url: &Url,
+    file: &UploadedFile,
+) -> Option<String> {
+    let url = match base_url.join(&file.path) {
+        Ok(url) => url,
+        Err(e) => return Some(e.to_string()),
+    };
+    let response = match client.get(url).send().await {
+        Ok(response) => response,
+        Err(e) => return Some(e.to_string()),
+    };
+    if !response.status().is_success() {
+        return Some(format!("the node returned {}", response.status()));
     }
-    Ok(())
+    let content = match response.bytes().await {
+        Ok(content) => content,
+        Err(e) => return Some(e.to_string()),
+    };
+    let sha256: Checksum256 = <[u8; 32]>::from(Sha256::digest(&content)).into();
+    if sha256 != file.sha256 {
+        return Some(format!(
+            "stored {} bytes with SHA-256 {}, expected {} bytes with SHA-256 {}",
+            content.len(),
+            sha256,
+            file.size,
+            file.sha256
+        ));
+    }
+    None
 }
 
-async fn search(
-    _args: &Args,
-    client: reqwest::Client,
-    patterns: &Vec<String>,
-    sources: &Vec<String>,
+// Fetches the uploaded files from host's subdomain and fails if any of
+// them don't match
+async fn verify_uploads(
+    args: &Args,
+    client: &reqwest::Client,
+    host: AccountNumber,
+    files: &[UploadedFile],
+    concurrency: NonZeroUsize,
 ) -> Result<(), anyhow::Error> {
-    let mut compiled = vec![];
-    for pattern in patterns {
-        compiled.push(Regex::new(&("(?i)".to_string() + pattern))?);
+    let base_url = &host.url(&args.api)?;
+    let checks = files
+        .iter()
+        .map(|file| async move {
+            let mismatch = check_upload(client, base_url, file).await;
+            (file, mismatch)
+        })
+        .collect();
+    let results = run_bounded(checks, concurrency, &args.interrupted).await;
+    if results.len() < files.len() {
+        return Err(anyhow!(
+            "Interrupted after verifying {} of {} files",
+            results.len(),
+            files.len()
+        ));
     }
-    // TODO: search installed packages as well
-    let package_registry = get_package_registry(sources, client.clone()).await?;
-    let mut primary_matches = vec![];
-    let mut secondary_matches = vec![];
-    for info in package_registry.index()? {
-        let mut name_matched = 0;
-        let mut description_matched = 0;
-        for re in &compiled[..] {
-            if re.is_match(&info.name) {
-                name_matched += 1;
-            } else if re.is_match(&info.description) {
-                description_matched += 1;
-            } else {
-                break;
-            }
-        }
-        if name_matched == compiled.len() {
-            primary_matches.push(info);
-        } else if name_matched + description_matched == compiled.len() {
-            secondary_matches.push(info);
+    let mut num_mismatched = 0;
+    for (file, mismatch) in results {
+        if let Some(reason) = mismatch {
+            println!("{}: {}", file.path, reason);
+            num_mismatched += 1;
         }
     }
-    primary_matches.sort_unstable_by(|a, b| a.name.cmp(&b.name));
-    secondary_matches.sort_unstable_by(|a, b| a.name.cmp(&b.name));
-    for result in primary_matches {
-        println!("{}", result.name);
-    }
-    for result in secondary_matches {
-        println!("{}", result.name);
+    if num_mismatched > 0 {
+        return Err(anyhow!(
+            "{}/{} uploaded files don't match the local files",
+            num_mismatched,
+            files.len()
+        ));
     }
     Ok(())
 }
 
-struct ServicePrinter<'a> {
+// removeSys for each stored file under dest that was not just uploaded
+fn prune_actions(
     service: AccountNumber,
-    info: Option<&'a ServiceInfo>,
-    data: &'a [PackageDataFile],
+    sender: AccountNumber,
+    dest: &str,
+    stored: Vec<String>,
+    uploaded: &[UploadedFile],
+) -> Vec<(String, Action)> {
+    let current: HashSet<&str> = uploaded.iter().map(|file| file.path.as_str()).collect();
+    stored
+        .into_iter()
+        .filter(|path| is_under(path, dest) && !current.contains(path.as_str()))
+        .map(|path| {
+            let action = sites::Wrapper::pack_from_to(sender, service).removeSys(path.clone());
+            (path, action)
+        })
+        .collect()
 }
 
-impl fmt::Display for ServicePrinter<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if let Some(info) = &self.info {
-            writeln!(f, "service: {}", self.service)?;
-            if !info.flags.is_empty() {
-                write!(f, "  flags:")?;
-                for flag in &info.flags {
-                    write!(f, " {}", flag)?;
-                }
-                writeln!(f, "")?;
-            }
-            if let Some(server) = &info.server {
-                writeln!(f, "  server: {}", server)?;
-            }
-        } else {
-            writeln!(f, "account: {}", self.service)?;
-        }
-        if !self.data.is_empty() {
-            writeln!(f, "  files:")?;
-            for file in self.data {
-                writeln!(f, "    {}", &file.filename)?;
-            }
+fn confirm_prune(files: usize) -> Result<(), anyhow::Error> {
+    let term = console::Term::stderr();
+    if !term.is_term() {
+        return Err(anyhow!(
+            "--prune would remove {} files. Use --yes to confirm.",
+            files
+        ));
+    }
+    term.write_str(&format!("Remove {} files? [y/N] ", files))?;
+    if !matches!(term.read_line()?.trim(), "y" | "Y" | "yes") {
+        return Err(anyhow!("Prune cancelled"));
+    }
+    Ok(())
+}
+
+/// Groups uploads into transactions of at most 10 files and `max_size`
+/// bytes. A file that is larger than `max_size` gets its own transaction.
+fn batch_uploads(
+    mut actions: Vec<(String, Action)>,
+    max_size: usize,
+) -> Vec<(Vec<String>, Vec<Action>)> {
+    let mut batches = Vec::new();
+    while !actions.is_empty() {
+        let mut n = 0;
+        let mut size = 0;
+        while n < actions.len()
+            && n < 10
+            && (n == 0 || size + actions[n].1.rawData.len() <= max_size)
+        {
+            size += actions[n].1.rawData.len();
+            n += 1;
         }
-        Ok(())
+        batches.push(actions.drain(..n).unzip());
     }
+    batches
 }
 
-fn get_service_data(s: &[PackageDataFile], account: AccountNumber) -> &[PackageDataFile] {
-    let key = account.to_string();
-    let lower = s.partition_point(|file| &file.account.to_string() < &key);
-    let upper = s.partition_point(|file| &file.account.to_string() <= &key);
-    &s[lower..upper]
+// Counts the file contents, not the packed actions that carry them
+fn upload_summary(files: &[UploadedFile], transactions: usize) -> String {
+    let bytes: u64 = files.iter().map(|file| file.size).sum();
+    format!(
+        "Uploaded {} files, {} in {} transactions",
+        files.len(),
+        HumanBytes(bytes),
+        transactions
+    )
 }
 
-async fn show_package<T: PackageRegistry + ?Sized>(
-    reg: &T,
-    base_url: &reqwest::Url,
-    client: &mut reqwest::Client,
-    package: &Meta,
-    origin: &PackageOrigin,
-) -> Result<(), anyhow::Error> {
-    let mut manifest = get_manifest(reg, base_url, client, package, origin).await?;
-    println!("name: {}-{}", &package.name, &package.version);
-    println!("description: {}", &package.description);
-    let mut services: Vec<_> = manifest.services.into_iter().collect();
-    services.sort_by(|lhs, rhs| lhs.0.to_string().cmp(&rhs.0.to_string()));
-    manifest.data.sort_by(|lhs, rhs| {
-        (
-            lhs.account.to_string(),
-            lhs.service.to_string(),
-            &lhs.filename,
-        )
-            .cmp(&(
-                rhs.account.to_string(),
-                rhs.service.to_string(),
-                &rhs.filename,
-            ))
-    });
+// An account that install creates, the sender that will own it, and the
+// package that needs it
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct NewAccount {
+    account: AccountNumber,
+    owner: AccountNumber,
+    package: String,
+}
 
-    for account in &package.accounts {
-        let info = services
-            .binary_search_by(|service| service.0.to_string().cmp(&account.to_string()))
-            .map_or(None, |idx| Some(&services[idx].1));
-        print!(
-            "{}",
-            &ServicePrinter {
-                service: *account,
-                info,
-                data: get_service_data(&manifest.data, *account)
-            }
-        );
+// Creates each account, delegating its authentication to its owner.
+// With verbose, the label also says which package needs the account
+fn create_accounts<F: Fn(Vec<Action>) -> Result<SignedTransaction, anyhow::Error>>(
+    accounts: Vec<NewAccount>,
+    verbose: bool,
+    out: &mut TransactionBuilder<F>,
+) -> Result<(), anyhow::Error> {
+    for NewAccount {
+        account,
+        owner,
+        package,
+    } in accounts
+    {
+        if verbose {
+            out.set_label(format!("Creating {} for {}", account, package));
+        } else {
+            out.set_label(format!("Creating {}", account));
+        }
+        let group = vec![
+            accounts::Wrapper::pack().newAccount(account, account!("auth-any"), true),
+            auth_delegate::Wrapper::pack_from(account).setOwner(owner),
+            set_auth_service_action(account, auth_delegate::SERVICE),
+        ];
+        out.push(group)?;
     }
     Ok(())
 }
 
-// an unbooted chain has no packages installed
-fn handle_unbooted(list: Result<PackageList, anyhow::Error>) -> Result<PackageList, anyhow::Error> {
-    if let Err(e) = &list {
-        if e.root_cause()
-            .to_string()
-            .contains("Need genesis block; use 'psibase boot' to boot chain")
-        {
-            return Ok(PackageList::new());
+// The accounts of the packages that ops installs or replaces, paired
+// with the sender that will own them. The accounts are taken from the
+// registry index so that the packages are not downloaded.
+fn package_accounts(ops: &[PackageOp], senders: &PackageSenders) -> Vec<NewAccount> {
+    let mut result = vec![];
+    for op in ops {
+        if let PackageOp::Install(info) | PackageOp::Replace(_, info) = op {
+            add_accounts(&mut result, &info.accounts, &info.name, senders);
         }
     }
-    list
+    result
 }
 
-async fn package_info(
-    args: &Args,
-    mut client: reqwest::Client,
-    packages: &Vec<String>,
-    sources: &Vec<String>,
-) -> Result<(), anyhow::Error> {
-    let installed = handle_unbooted(PackageList::installed(&args.api, &mut client).await)?;
-    let package_registry = get_package_registry(sources, client.clone()).await?;
-    let reglist = PackageList::from_registry(&package_registry)?;
-
-    for package in packages {
-        if let Some((meta, origin)) = installed.get_by_name(package)? {
-            show_package(&package_registry, &args.api, &mut client, meta, origin).await?;
-        } else if let Some((meta, origin)) = reglist.get_by_name(package)? {
-            show_package(&package_registry, &args.api, &mut client, meta, origin).await?;
-        } else {
-            eprintln!("Package {} not found", package);
+// Accounts shared by several packages belong to the first one
+fn add_accounts(
+    result: &mut Vec<NewAccount>,
+    accounts: &[AccountNumber],
+    package: &str,
+    senders: &PackageSenders,
+) {
+    for account in accounts {
+        if !result.iter().any(|existing| existing.account == *account) {
+            result.push(NewAccount {
+                account: *account,
+                owner: senders.get(package),
+                package: package.to_string(),
+            });
         }
     }
-
-    Ok(())
 }
 
-#[derive(Serialize, Deserialize)]
-struct TokenData<'a> {
-    exp: i64,
-    mode: &'a str,
+// Filters out the accounts that already exist. Each owner is queried
+// separately, which fails if an existing account is owned by someone else.
+async fn new_accounts_to_create(
+    base_url: &reqwest::Url,
+    client: &mut reqwest::Client,
+    accounts: &[NewAccount],
+) -> Result<Vec<NewAccount>, anyhow::Error> {
+    let mut owners: Vec<AccountNumber> = vec![];
+    for new in accounts {
+        if !owners.contains(&new.owner) {
+            owners.push(new.owner);
+        }
+    }
+    let mut result = vec![];
+    for owner in owners {
+        let owned: Vec<_> = accounts.iter().filter(|new| new.owner == owner).collect();
+        let names: Vec<_> = owned.iter().map(|new| new.account).collect();
+        let missing = get_accounts_to_create(base_url, client, &names, owner).await?;
+        result.extend(
+            owned
+                .into_iter()
+                .filter(|new| missing.contains(&new.account))
+                .cloned(),
+        );
+    }
+    Ok(result)
 }
 
-fn create_token(expires_after: Duration, mode: &str) -> Result<(), anyhow::Error> {
-    let key_text = rpassword::prompt_password("Enter Key: ")?;
-    let claims = TokenData {
-        exp: (Utc::now() + expires_after).timestamp(),
-        mode: mode,
-    };
-    let key: Hmac<Sha256> = Hmac::new_from_slice(key_text.as_bytes())?;
-    let token = claims.sign_with_key(&key)?;
-    println!("{}", token);
-    Ok(())
+// The content of the data files that were stored by earlier packages
+// in the same install, keyed by (account, service, path)
+#[derive(Default)]
+struct StoredFiles {
+    files: HashMap<(AccountNumber, AccountNumber, String), [u8; 32]>,
 }
 
-async fn build_client(args: &Args) -> Result<(reqwest::Client, Option<AutoAbort>), anyhow::Error> {
-    let (builder, result) = apply_proxy(reqwest::Client::builder(), &args.proxy).await?;
-    Ok((builder.gzip(true).build()?, result))
+impl StoredFiles {
+    // Drops stores of files that already have the same content
+    fn dedup(&mut self, actions: Vec<Action>) -> Result<Vec<Action>, anyhow::Error> {
+        let mut result = Vec::with_capacity(actions.len());
+        for act in actions {
+            if act.method == method!("storeSys") {
+                let args = sites::action_structs::storeSys::unpacked(&act.rawData)?;
+                let hash: [u8; 32] = Sha256::digest(&act.rawData).into();
+                let key = (act.sender, act.service, args.path);
+                if self.files.get(&key) == Some(&hash) {
+                    continue;
+                }
+                self.files.insert(key, hash);
+            }
+            result.push(act);
+        }
+        Ok(result)
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), anyhow::Error> {
-    let args = Args::parse();
-    let (client, _proxy) = build_client(&args).await?;
-    match &args.command {
-        Command::Boot {
-            key,
-            producer,
-            package_source,
-            services,
-        } => boot(&args, client, key, *producer, package_source, services).await?,
-        Command::Create {
-            account,
-            key,
-            insecure,
-            sender,
-        } => {
-            create(
-                &args,
-                client,
-                (*sender).into(),
-                (*account).into(),
-                key,
-                *insecure,
-            )
-            .await?
-        }
-        Command::Modify {
-            account,
-            key,
-            insecure,
-        } => modify(&args, client, (*account).into(), key, *insecure).await?,
-        Command::Deploy {
+async fn apply_packages<
+    R: PackageRegistry,
+    F: Fn(Vec<Action>) -> Result<SignedTransaction, anyhow::Error>,
+>(
+    base_url: &reqwest::Url,
+    client: &mut reqwest::Client,
+    reg: &R,
+    ops: Vec<PackageOp>,
+    accounts: &mut Vec<NewAccount>,
+    package_size: &mut u64,
+    out: &mut TransactionBuilder<F>,
+    senders: &PackageSenders,
+    key: &Option<AnyPublicKey>,
+    dedup_files: bool,
+) -> Result<(), anyhow::Error> {
+    // Remove out-dated files before any package is replaced or removed.
+    // This needs to happen before installing new files, to handle the
+    // case where a service that stores data files is replaced by a
+    // service that does not provide removeSys. Packages may store files
+    // in a dependency's service, and dependencies are replaced first,
+    // so the files of every package are removed up front.
+    let mut replaced = Vec::with_capacity(ops.len());
+    for op in &ops {
+        let (old_manifest, new_package, name, stale) = match op {
+            PackageOp::Install(_) => {
+                replaced.push(None);
+                continue;
+            }
+            PackageOp::Replace(meta, info) => {
+                let mut package = reg.get_by_info(info).await?;
+                let old_manifest =
+                    get_installed_manifest(base_url, client, &meta.name, senders.get(&info.name))
+                        .await?;
+                let mut stale = vec![];
+                old_manifest.remove_stale_data(&package.manifest(), &mut stale)?;
+                (old_manifest, Some(package), meta.name.as_str(), stale)
+            }
+            PackageOp::Remove(meta) => {
+                let old_manifest =
+                    get_installed_manifest(base_url, client, &meta.name, senders.get(&meta.name))
+                        .await?;
+                let mut stale = vec![];
+                old_manifest.remove_stale_data(&PackageManifest::default(), &mut stale)?;
+                (old_manifest, None, meta.name.as_str(), stale)
+            }
+        };
+        if !stale.is_empty() {
+            out.set_label(format!("Removing out-dated files of {}", name));
+            out.push_all(stale)?;
+        }
+        replaced.push(Some((old_manifest, new_package)));
+    }
+    let mut stored = StoredFiles::default();
+    for (op, replaced) in ops.into_iter().zip(replaced) {
+        match op {
+            PackageOp::Install(info) => {
+                // TODO: verify ownership of existing accounts
+                let sender = senders.get(&info.name);
+                let mut package = reg.get_by_info(&info).await?;
+                add_accounts(accounts, package.get_accounts(), &info.name, senders);
+                *package_size += package.total_size();
+                out.set_label(format!("Installing {}-{}", &info.name, &info.version));
+                let mut account_actions = vec![];
+                package.install_accounts(&mut account_actions, sender, key)?;
+                out.push_all(account_actions)?;
+                let mut actions = vec![];
+                package.install(&mut actions, sender, true)?;
+                if dedup_files {
+                    actions = stored.dedup(actions)?;
+                }
+                out.push_all(actions)?;
+            }
+            PackageOp::Replace(meta, info) => {
+                let sender = senders.get(&info.name);
+                let (old_manifest, package) = replaced.unwrap();
+                let mut package = package.unwrap();
+                add_accounts(accounts, package.get_accounts(), &info.name, senders);
+                *package_size += package.total_size();
+                // TODO: skip unmodified files (?)
+                out.set_label(format!(
+                    "Updating {}-{} -> {}-{}",
+                    &meta.name, &meta.version, &info.name, &info.version
+                ));
+                old_manifest.upgrade_services(&package.manifest(), out)?;
+                // Install the new package
+                let mut account_actions = vec![];
+                package.install_accounts(&mut account_actions, sender, key)?;
+                out.push_all(account_actions)?;
+                let mut actions = vec![];
+                package.install(&mut actions, sender, true)?;
+                if dedup_files {
+                    actions = stored.dedup(actions)?;
+                }
+                out.push_all(actions)?;
+            }
+            PackageOp::Remove(meta) => {
+                out.set_label(format!("Removing {}", &meta.name));
+                let (old_manifest, _) = replaced.unwrap();
+                old_manifest.remove_services(out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn rotate_keys<F: Fn(Vec<Action>) -> Result<SignedTransaction, anyhow::Error>>(
+    base_url: &reqwest::Url,
+    client: &mut reqwest::Client,
+    installed: &PackageList,
+    packages: &[String],
+    out: &mut TransactionBuilder<F>,
+    senders: &PackageSenders,
+    key: &AnyPublicKey,
+    force: bool,
+) -> Result<(), anyhow::Error> {
+    let mut owners = vec![];
+    for package in packages {
+        if let Some((meta, _)) = installed.get_by_name(package)? {
+            add_accounts(&mut owners, &meta.accounts, &meta.name, senders);
+        }
+    }
+    // Fails if any of the existing accounts is not owned by its sender
+    let missing = new_accounts_to_create(base_url, client, &owners).await?;
+    let accounts: Vec<_> = owners.iter().map(|new| (new.account, new.owner)).collect();
+    let owned: Vec<_> = owners
+        .iter()
+        .map(|new| new.account)
+        .filter(|account| !missing.iter().any(|m| m.account == *account))
+        .collect();
+    let auth_services = get_auth_services(base_url, client, &owned).await?;
+    let (groups, skipped) = rotate_key_actions(&accounts, &owned, &auth_services, key, force);
+    if !skipped.is_empty() {
+        warn!(
+            "Leaving the key of {} unchanged, because replacing its auth service would stop later installs from accepting it. Use --force-rotate to rotate it anyway",
+            skipped
+                .iter()
+                .map(|account| account.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    for group in groups {
+        out.set_label(format!("Rotating key for {}", group[0].sender));
+        out.push(group)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum PlannedOp {
+    Install {
+        name: String,
+        version: String,
+        sha256: Checksum256,
+    },
+    Replace {
+        name: String,
+        old_version: String,
+        version: String,
+        sha256: Checksum256,
+    },
+    Remove {
+        name: String,
+        version: String,
+    },
+}
+
+impl fmt::Display for PlannedOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlannedOp::Install { name, version, .. } => write!(f, "install {}-{}", name, version),
+            PlannedOp::Replace {
+                name,
+                old_version,
+                version,
+                ..
+            } => write!(
+                f,
+                "replace {}-{} with {}-{}",
+                name, old_version, name, version
+            ),
+            PlannedOp::Remove { name, version } => write!(f, "remove {}-{}", name, version),
+        }
+    }
+}
+
+fn install_plan(ops: &[PackageOp]) -> Vec<PlannedOp> {
+    ops.iter()
+        .map(|op| match op {
+            PackageOp::Install(info) => PlannedOp::Install {
+                name: info.name.clone(),
+                version: info.version.clone(),
+                sha256: info.sha256.clone(),
+            },
+            PackageOp::Replace(meta, info) => PlannedOp::Replace {
+                name: info.name.clone(),
+                old_version: meta.version.clone(),
+                version: info.version.clone(),
+                sha256: info.sha256.clone(),
+            },
+            PackageOp::Remove(meta) => PlannedOp::Remove {
+                name: meta.name.clone(),
+                version: meta.version.clone(),
+            },
+        })
+        .collect()
+}
+
+// Installs that push more transactions than this ask for confirmation
+const CONFIRM_TRANSACTIONS: usize = 20;
+
+#[derive(Debug, Default, PartialEq)]
+struct InstallSummary {
+    install: usize,
+    replace: usize,
+    remove: usize,
+    // Uncompressed size of the packages to install or replace
+    package_size: u64,
+    accounts: usize,
+    transactions: usize,
+    bytes: u64,
+}
+
+impl InstallSummary {
+    fn new(
+        plan: &[PlannedOp],
+        package_size: u64,
+        accounts: usize,
+        groups: &[&[(String, Vec<SignedTransaction>, bool)]],
+    ) -> Self {
+        let mut result = InstallSummary {
+            package_size,
+            accounts,
+            ..Default::default()
+        };
+        for op in plan {
+            match op {
+                PlannedOp::Install { .. } => result.install += 1,
+                PlannedOp::Replace { .. } => result.replace += 1,
+                PlannedOp::Remove { .. } => result.remove += 1,
+            }
+        }
+        for (_, transactions, _) in groups.iter().copied().flatten() {
+            result.transactions += transactions.len();
+            result.bytes += transactions
+                .iter()
+                .map(|trx| trx.packed().len() as u64)
+                .sum::<u64>();
+        }
+        result
+    }
+}
+
+impl fmt::Display for InstallSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Packages:     {} to install, {} to replace, {} to remove",
+            self.install, self.replace, self.remove
+        )?;
+        writeln!(f, "Package size: {}", HumanBytes(self.package_size))?;
+        writeln!(f, "Accounts:     {} to create", self.accounts)?;
+        write!(
+            f,
+            "Transactions: {}, {}",
+            self.transactions,
+            HumanBytes(self.bytes)
+        )
+    }
+}
+
+fn confirm_install(summary: &InstallSummary) -> Result<(), anyhow::Error> {
+    let term = console::Term::stderr();
+    if !term.is_term() {
+        return Err(anyhow!(
+            "This install pushes {} transactions. Use --yes to confirm.",
+            summary.transactions
+        ));
+    }
+    term.write_str("Continue? [y/N] ")?;
+    if !matches!(term.read_line()?.trim(), "y" | "Y" | "yes") {
+        return Err(anyhow!("Install cancelled"));
+    }
+    Ok(())
+}
+
+// A --sender argument for install, optionally restricted to one package
+#[derive(Debug, Clone)]
+struct PackageSender {
+    package: Option<String>,
+    sender: ExactAccountNumber,
+}
+
+impl FromStr for PackageSender {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, anyhow::Error> {
+        match s.split_once('=') {
+            Some((package, sender)) => Ok(PackageSender {
+                package: Some(package.to_string()),
+                sender: sender.parse()?,
+            }),
+            None => Ok(PackageSender {
+                package: None,
+                sender: s.parse()?,
+            }),
+        }
+    }
+}
+
+// The account that owns each package that install touches
+#[derive(Debug)]
+struct PackageSenders {
+    default: AccountNumber,
+    overrides: HashMap<String, AccountNumber>,
+}
+
+impl PackageSenders {
+    fn new(args: &[PackageSender]) -> Result<Self, anyhow::Error> {
+        let mut default = None;
+        let mut overrides = HashMap::new();
+        for arg in args {
+            let sender = arg.sender.into();
+            if let Some(package) = &arg.package {
+                if overrides.insert(package.clone(), sender).is_some() {
+                    return Err(anyhow!("--sender was given twice for {}", package));
+                }
+            } else if default.replace(sender).is_some() {
+                return Err(anyhow!("Only one --sender may apply to all packages"));
+            }
+        }
+        Ok(PackageSenders {
+            default: default.unwrap_or(account!("root")),
+            overrides,
+        })
+    }
+    fn get(&self, package: &str) -> AccountNumber {
+        self.overrides.get(package).copied().unwrap_or(self.default)
+    }
+}
+
+// Returns once interrupted
+async fn wait_for_interrupt(interrupted: &AtomicBool) {
+    while !interrupted.load(Ordering::Relaxed) {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+}
+
+// Runs a phase that has not pushed anything yet, so that it can be
+// dropped part way through when interrupted
+async fn unless_interrupted<T>(
+    interrupted: &AtomicBool,
+    phase: impl Future<Output = Result<T, anyhow::Error>>,
+) -> Result<T, anyhow::Error> {
+    tokio::select! {
+        result = phase => result,
+        _ = wait_for_interrupt(interrupted) => Err(anyhow!("Interrupted")),
+    }
+}
+
+// The first action of an install transaction must come from sender,
+// which pays for it
+fn with_sender_first(
+    sender: AccountNumber,
+    mut actions: Vec<Action>,
+) -> Result<Vec<Action>, anyhow::Error> {
+    let Some(first) = actions.first() else {
+        return Err(anyhow!("Cannot build a transaction with no actions"));
+    };
+    if first.sender != sender {
+        actions.insert(
+            0,
+            Action {
+                sender,
+                service: account!("nop"),
+                method: method!("nop"),
+                rawData: Default::default(),
+            },
+        );
+    }
+    Ok(actions)
+}
+
+async fn install(
+    args: &Args,
+    mut client: reqwest::Client,
+    packages: &[String],
+    senders: &PackageSenders,
+    key: &Option<AnyPublicKey>,
+    sources: &Vec<String>,
+    options: &SolveOptions,
+    rotate_key: bool,
+    force_rotate: bool,
+    dedup_files: bool,
+    plan_out: &Option<PathBuf>,
+    lock: &Option<PathBuf>,
+    lock_out: &Option<PathBuf>,
+    yes: bool,
+    explain: bool,
+    only_accounts: bool,
+) -> Result<(), anyhow::Error> {
+    let interrupted = &*args.interrupted;
+    let installed =
+        unless_interrupted(interrupted, PackageList::installed(&args.api, &mut client)).await?;
+    let package_registry =
+        unless_interrupted(interrupted, get_locked_registry(args, sources, lock)).await?;
+    let to_install = unless_interrupted(
+        interrupted,
+        installed.resolve_changes(&package_registry, packages, options),
+    )
+    .await
+    .map_err(with_solve_hint)?;
+
+    let plan = install_plan(&to_install);
+    if let Some(path) = plan_out {
+        let file = File::create(path)
+            .with_context(|| format!("Can not create {}", path.to_string_lossy()))?;
+        serde_json::to_writer_pretty(file, &plan)?;
+    }
+    if let Some(path) = lock_out {
+        write_lockfile(
+            path,
+            &install_lock(&installed, &package_registry, &to_install)?,
+        )?;
+    }
+
+    if explain {
+        for (op, reason) in plan
+            .iter()
+            .zip(explain_ops(&to_install, &make_refs(packages)?))
+        {
+            println!("{}: {}", op, reason);
+        }
+    }
+
+    if to_install.is_empty() && !(rotate_key && key.is_some()) {
+        if !args.suppress_ok {
+            println!("Nothing to do: all packages are already installed");
+        }
+        return Ok(());
+    }
+
+    let tapos = unless_interrupted(interrupted, get_tapos(args, client.clone())).await?;
+
+    let build_transaction = |actions: Vec<Action>| -> Result<SignedTransaction, anyhow::Error> {
+        let actions = with_sender_first(senders.default, actions)?;
+        Ok(sign_transaction(
+            with_tapos(args, &tapos, actions)?,
+            &args.keys,
+        )?)
+    };
+
+    let action_limit = args.max_transaction_size as usize;
+
+    let mut account_builder = TransactionBuilder::new(action_limit, build_transaction);
+    let mut new_accounts = vec![];
+    let mut package_size = 0;
+
+    let mut trx_builder = TransactionBuilder::new(action_limit, build_transaction);
+    if only_accounts {
+        new_accounts = package_accounts(&to_install, senders);
+    } else {
+        unless_interrupted(
+            interrupted,
+            apply_packages(
+                &args.api,
+                &mut client,
+                &package_registry,
+                to_install,
+                &mut new_accounts,
+                &mut package_size,
+                &mut trx_builder,
+                senders,
+                key,
+                dedup_files,
+            ),
+        )
+        .await?;
+    }
+
+    new_accounts = unless_interrupted(
+        interrupted,
+        new_accounts_to_create(&args.api, &mut client, &new_accounts),
+    )
+    .await?;
+    if args.verbose {
+        for new in &new_accounts {
+            eprintln!(
+                "{}: creating for {}, owned by {}",
+                new.account, new.package, new.owner
+            );
+        }
+    }
+    let num_accounts = new_accounts.len();
+    create_accounts(new_accounts, args.verbose, &mut account_builder)?;
+
+    if let (true, Some(key)) = (rotate_key, key) {
+        unless_interrupted(
+            interrupted,
+            rotate_keys(
+                &args.api,
+                &mut client,
+                &installed,
+                packages,
+                &mut account_builder,
+                senders,
+                key,
+                force_rotate,
+            ),
+        )
+        .await?;
+    }
+
+    let account_transactions = account_builder.finish()?;
+    let transactions = trx_builder.finish()?;
+
+    if args.parallel_verify {
+        verify_transactions(&account_transactions)?;
+        verify_transactions(&transactions)?;
+    }
+
+    let summary = InstallSummary::new(
+        &plan,
+        package_size,
+        num_accounts,
+        &[&account_transactions, &transactions],
+    );
+    println!("{}", summary);
+    if summary.transactions > CONFIRM_TRANSACTIONS && !yes {
+        confirm_install(&summary)?;
+    }
+    if interrupted.load(Ordering::Relaxed) {
+        return Err(anyhow!("Interrupted"));
+    }
+
+    {
+        let progress = Progress::new(args, account_transactions.len() as u64, "accounts", true)?;
+        push_transactions_until(
+            &args.api,
+            client.clone(),
+            account_transactions,
+            args.trace,
+            args.console,
+            &progress,
+            &args.interrupted,
+        )
+        .await
+        .context("Failed to create accounts")?;
+        progress.finish_and_clear();
+    }
+
+    let progress = Progress::new(args, transactions.len() as u64, "packages", true)?;
+
+    push_transactions_until(
+        &args.api,
+        client.clone(),
+        transactions,
+        args.trace,
+        args.console,
+        &progress,
+        &args.interrupted,
+    )
+    .await
+    .context("Failed to install packages")?;
+
+    if !args.suppress_ok {
+        progress.finish_with_message("Ok");
+    } else {
+        progress.finish_and_clear();
+    }
+
+    Ok(())
+}
+
+const FOLLOW_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+const FOLLOW_MAX_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(16);
+const FOLLOW_PAGE_SIZE: u32 = 100;
+
+// How often boot --wait-for-node checks whether the node has started
+const NODE_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+#[derive(Deserialize)]
+struct FollowedAction {
+    sender: String,
+    service: String,
+    method: String,
+}
+
+#[derive(Deserialize)]
+struct FollowedTransaction {
+    actions: Vec<FollowedAction>,
+}
+
+#[derive(Deserialize)]
+struct FollowedSignedTransaction {
+    transaction: FollowedTransaction,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FollowedHeader {
+    block_num: u32,
+    time: String,
+}
+
+#[derive(Deserialize)]
+struct FollowedBlock {
+    header: FollowedHeader,
+    #[serde(default)]
+    transactions: Vec<FollowedSignedTransaction>,
+}
+
+#[derive(Deserialize)]
+struct FollowedEdge {
+    node: FollowedBlock,
+}
+
+#[derive(Deserialize)]
+struct FollowedBlocks {
+    edges: Vec<FollowedEdge>,
+}
+
+#[derive(Deserialize)]
+struct FollowedBlocksQuery {
+    blocks: FollowedBlocks,
+}
+
+impl fmt::Display for FollowedBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "block {} {}", self.header.block_num, self.header.time)?;
+        for trx in &self.transactions {
+            for act in &trx.transaction.actions {
+                writeln!(f, "  {} => {}::{}", act.sender, act.service, act.method)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn query_blocks(
+    args: &Args,
+    client: &mut reqwest::Client,
+    range: String,
+) -> Result<Vec<FollowedBlock>, anyhow::Error> {
+    let result: FollowedBlocksQuery = gql_query(
+        &args.api,
+        client,
+        account!("explorer"),
+        format!(
+            "query {{ blocks({}) {{ edges {{ node {{ header {{ blockNum time }} transactions {{ transaction {{ actions {{ sender service method }} }} }} }} }} }} }}",
+            range
+        ),
+    )
+    .await?;
+    Ok(result.blocks.edges.into_iter().map(|e| e.node).collect())
+}
+
+// Returns false if interrupted before the delay is over
+async fn sleep_unless_interrupted(delay: std::time::Duration, interrupted: &AtomicBool) -> bool {
+    let step = std::time::Duration::from_millis(50);
+    let mut remaining = delay;
+    while !interrupted.load(Ordering::Relaxed) {
+        if remaining.is_zero() {
+            return true;
+        }
+        let next = remaining.min(step);
+        tokio::time::sleep(next).await;
+        remaining -= next;
+    }
+    false
+}
+
+/// Polls explorer for blocks after the current head and writes each one
+/// to `out` until interrupted. When the node can't be reached, this
+/// keeps retrying with a growing delay and continues from the last
+/// block that it printed.
+async fn follow_blocks(
+    args: &Args,
+    mut client: reqwest::Client,
+    out: &mut dyn std::io::Write,
+    poll_interval: std::time::Duration,
+) -> Result<(), anyhow::Error> {
+    let mut next = query_blocks(args, &mut client, "last: 1".to_string())
+        .await
+        .context("Failed to get the head block")?
+        .last()
+        .map_or(1, |block| block.header.block_num + 1);
+    let mut retry_delay: Option<std::time::Duration> = None;
+    loop {
+        let delay = match query_blocks(
+            args,
+            &mut client,
+            format!("ge: {}, first: {}", next, FOLLOW_PAGE_SIZE),
+        )
+        .await
+        {
+            Ok(blocks) => {
+                if retry_delay.take().is_some() {
+                    info!("Reconnected to {}", args.api);
+                }
+                let caught_up = blocks.len() < FOLLOW_PAGE_SIZE as usize;
+                for block in blocks {
+                    next = block.header.block_num + 1;
+                    write!(out, "{}", block)?;
+                }
+                out.flush()?;
+                if caught_up {
+                    poll_interval
+                } else {
+                    std::time::Duration::ZERO
+                }
+            }
+            Err(e) => {
+                debug!("Failed to get blocks after {}: {:#}", next, e);
+                let delay = match retry_delay {
+                    None => {
+                        warn!("Lost connection to {}: {:#}; retrying", args.api, e);
+                        poll_interval
+                    }
+                    Some(delay) => (delay * 2).min(FOLLOW_MAX_RETRY_DELAY),
+                };
+                debug!("Retrying in {:?}", delay);
+                retry_delay = Some(delay);
+                delay
+            }
+        };
+        if !sleep_unless_interrupted(delay, &args.interrupted).await {
+            return Ok(());
+        }
+    }
+}
+
+async fn list(
+    args: &Args,
+    mut client: reqwest::Client,
+    all: bool,
+    available: bool,
+    installed: bool,
+    sources: &Vec<String>,
+    format: ListFormat,
+) -> Result<(), anyhow::Error> {
+    if all || (installed && available) || (!all & !installed && !available) {
+        let installed = handle_unbooted(PackageList::installed(&args.api, &mut client).await)?;
+        let package_registry = get_package_registry(args, sources).await?;
+        let reglist = PackageList::from_registry(&package_registry)?;
+        let names = installed.clone().union(reglist.clone()).into_vec();
+        print_packages(names, &installed, &reglist, format)?;
+    } else if installed {
+        let installed = handle_unbooted(PackageList::installed(&args.api, &mut client).await)?;
+        let names = installed.clone().into_vec();
+        print_packages(names, &installed, &PackageList::new(), format)?;
+    } else if available {
+        let installed = handle_unbooted(PackageList::installed(&args.api, &mut client).await)?;
+        let package_registry = get_package_registry(args, sources).await?;
+        let reglist = PackageList::from_registry(&package_registry)?;
+        let names = reglist.clone().difference(installed.clone()).into_vec();
+        print_packages(names, &installed, &reglist, format)?;
+    }
+    Ok(())
+}
+
+fn print_packages(
+    names: Vec<String>,
+    installed: &PackageList,
+    available: &PackageList,
+    format: ListFormat,
+) -> Result<(), anyhow::Error> {
+    match format {
+        ListFormat::Text => {
+            for name in names {
+                println!("{}", name);
+            }
+        }
+        ListFormat::Table => {
+            let rows = package_rows(&names, installed, available)?;
+            let width = console::Term::stdout()
+                .size_checked()
+                .map(|(_, cols)| cols as usize);
+            print!("{}", format_table(&rows, width));
+        }
+        ListFormat::Json => return Err(anyhow!("--format json requires --tree")),
+    }
+    Ok(())
+}
+
+// Returns the name, installed version, available version, and description of each package
+fn package_rows(
+    names: &[String],
+    installed: &PackageList,
+    available: &PackageList,
+) -> Result<Vec<[String; 4]>, anyhow::Error> {
+    let mut rows = Vec::new();
+    for name in names {
+        let installed = installed.get_by_name(name)?.map(|(meta, _)| meta);
+        let available = available.get_by_name(name)?.map(|(meta, _)| meta);
+        let description = available
+            .or(installed)
+            .map_or(String::new(), |meta| meta.description.clone());
+        rows.push([
+            name.clone(),
+            installed.map_or(String::new(), |meta| meta.version.clone()),
+            available.map_or(String::new(), |meta| meta.version.clone()),
+            description,
+        ]);
+    }
+    Ok(rows)
+}
+
+// Aligns the columns of rows under a header. If width is given, the last
+// column is truncated so that lines fit within it.
+fn format_table(rows: &[[String; 4]], width: Option<usize>) -> String {
+    const HEADER: [&str; 4] = ["NAME", "INSTALLED", "AVAILABLE", "DESCRIPTION"];
+    const SEPARATOR: &str = "  ";
+    let mut widths = HEADER.map(|h| h.len());
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+    let prefix_len: usize = widths[..3].iter().map(|w| w + SEPARATOR.len()).sum();
+    let mut result = String::new();
+    let header = HEADER.map(|h| h.to_string());
+    for row in std::iter::once(&header).chain(rows) {
+        let mut line = String::new();
+        for (cell, w) in row[..3].iter().zip(widths) {
+            line += &format!("{:<w$}{}", cell, SEPARATOR, w = w);
+        }
+        let description = &row[3];
+        match width {
+            Some(width) if prefix_len + description.chars().count() > width => {
+                let room = width.saturating_sub(prefix_len + 3);
+                let truncated: String = description.chars().take(room).collect();
+                line += truncated.trim_end();
+                line += "...";
+            }
+            _ => line += description,
+        }
+        result += line.trim_end();
+        result.push('\n');
+    }
+    result
+}
+
+#[derive(Debug, Copy, Clone)]
+enum ListFormat {
+    Text,
+    Json,
+    Table,
+}
+
+impl FromStr for ListFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, anyhow::Error> {
+        match s {
+            "text" => Ok(ListFormat::Text),
+            "json" => Ok(ListFormat::Json),
+            "table" => Ok(ListFormat::Table),
+            _ => Err(anyhow!("Unknown format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PackageTree {
+    name: String,
+    version: String,
+    /// Set when this package already appears higher up the same branch
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    cycle: bool,
+    /// Set when this package's dependencies were already shown earlier
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    repeated: bool,
+    depends: Vec<PackageTree>,
+}
+
+// Each package's dependencies are only expanded the first time it is
+// reached, so that shared dependencies don't make the tree exponential
+fn build_package_tree(
+    name: &str,
+    packages: &HashMap<&str, &Meta>,
+    path: &mut Vec<String>,
+    expanded: &mut HashSet<String>,
+) -> Option<PackageTree> {
+    let meta = packages.get(name)?;
+    let has_depends = meta
+        .depends
+        .iter()
+        .any(|dep| packages.contains_key(dep.name.as_str()));
+    let mut result = PackageTree {
+        name: meta.name.clone(),
+        version: meta.version.clone(),
+        cycle: path.iter().any(|p| p == name),
+        repeated: false,
+        depends: vec![],
+    };
+    if !result.cycle {
+        if !expanded.insert(name.to_string()) {
+            result.repeated = has_depends;
+            return Some(result);
+        }
+        path.push(name.to_string());
+        for dep in &meta.depends {
+            if let Some(child) = build_package_tree(&dep.name, packages, path, expanded) {
+                result.depends.push(child);
+            }
+        }
+        path.pop();
+    }
+    Some(result)
+}
+
+/// Arranges packages into a forest rooted at the packages that nothing
+/// else depends on. Packages that are only reachable through a cycle
+/// become roots as well, so that every package is shown.
+fn package_forest(installed: &[Meta]) -> Vec<PackageTree> {
+    let packages: HashMap<&str, &Meta> = installed
+        .iter()
+        .map(|meta| (meta.name.as_str(), meta))
+        .collect();
+    let mut names: Vec<&str> = packages.keys().copied().collect();
+    names.sort_unstable();
+    let depended_on: HashSet<&str> = installed
+        .iter()
+        .flat_map(|meta| meta.depends.iter().map(|dep| dep.name.as_str()))
+        .collect();
+
+    let mut result = vec![];
+    let mut expanded = HashSet::new();
+    let roots = names.iter().filter(|name| !depended_on.contains(*name));
+    let rest = names.iter().filter(|name| depended_on.contains(*name));
+    for name in roots.chain(rest) {
+        if expanded.contains(*name) {
+            continue;
+        }
+        result.push(build_package_tree(name, &packages, &mut vec![], &mut expanded).unwrap());
+    }
+    result
+}
+
+fn format_package_forest(forest: &[PackageTree]) -> String {
+    fn format_tree(tree: &PackageTree, depth: usize, out: &mut String) {
+        *out += &format!("{}{}-{}", "  ".repeat(depth), tree.name, tree.version);
+        if tree.cycle {
+            *out += " (cycle)";
+        } else if tree.repeated {
+            *out += " (see above)";
+        }
+        *out += "\n";
+        for child in &tree.depends {
+            format_tree(child, depth + 1, out);
+        }
+    }
+    let mut result = String::new();
+    for tree in forest {
+        format_tree(tree, 0, &mut result);
+    }
+    result
+}
+
+async fn list_tree(
+    args: &Args,
+    mut client: reqwest::Client,
+    format: ListFormat,
+) -> Result<(), anyhow::Error> {
+    let installed = handle_unbooted(PackageList::installed(&args.api, &mut client).await)?;
+    let installed: Vec<Meta> = installed
+        .into_info()
+        .into_iter()
+        .map(|(meta, _)| meta)
+        .collect();
+    let forest = package_forest(&installed);
+    match format {
+        ListFormat::Text => print!("{}", format_package_forest(&forest)),
+        ListFormat::Json => println!("{}", serde_json::to_string_pretty(&forest)?),
+        ListFormat::Table => return Err(anyhow!("--format table cannot be used with --tree")),
+    }
+    Ok(())
+}
+
+async fn search(
+    args: &Args,
+    client: reqwest::Client,
+    patterns: &Vec<String>,
+    sources: &Vec<String>,
+    format: ListFormat,
+) -> Result<(), anyhow::Error> {
+    // JSON is only available for list --tree
+    if let ListFormat::Json = format {
+        return Err(anyhow!("search does not support --format json"));
+    }
+    let mut compiled = vec![];
+    for pattern in patterns {
+        compiled.push(Regex::new(&("(?i)".to_string() + pattern))?);
+    }
+    // TODO: search installed packages as well
+    let package_registry = get_package_registry(args, sources).await?;
+    let mut primary_matches = vec![];
+    let mut secondary_matches = vec![];
+    for info in package_registry.index()? {
+        let mut name_matched = 0;
+        let mut description_matched = 0;
+        for re in &compiled[..] {
+            if re.is_match(&info.name) {
+                name_matched += 1;
+            } else if re.is_match(&info.description) {
+                description_matched += 1;
+            } else {
+                break;
+            }
+        }
+        if name_matched == compiled.len() {
+            primary_matches.push(info);
+        } else if name_matched + description_matched == compiled.len() {
+            secondary_matches.push(info);
+        }
+    }
+    primary_matches.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    secondary_matches.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    let mut names = vec![];
+    let mut available = PackageList::new();
+    for result in primary_matches.into_iter().chain(secondary_matches) {
+        names.push(result.name.clone());
+        available.insert_info(result);
+    }
+    let installed = if let ListFormat::Table = format {
+        let mut client = client;
+        handle_unbooted(PackageList::installed(&args.api, &mut client).await)?
+    } else {
+        PackageList::new()
+    };
+    print_packages(names, &installed, &available, format)
+}
+
+struct ServicePrinter<'a> {
+    service: AccountNumber,
+    info: Option<&'a ServiceInfo>,
+    data: &'a [PackageDataFile],
+}
+
+impl fmt::Display for ServicePrinter<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(info) = &self.info {
+            writeln!(f, "service: {}", self.service)?;
+            if !info.flags.is_empty() {
+                write!(f, "  flags:")?;
+                for flag in &info.flags {
+                    write!(f, " {}", flag)?;
+                }
+                writeln!(f, "")?;
+            }
+            if let Some(server) = &info.server {
+                writeln!(f, "  server: {}", server)?;
+            }
+        } else {
+            writeln!(f, "account: {}", self.service)?;
+        }
+        if !self.data.is_empty() {
+            writeln!(f, "  files:")?;
+            for file in self.data {
+                writeln!(f, "    {}", &file.filename)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn get_service_data(s: &[PackageDataFile], account: AccountNumber) -> &[PackageDataFile] {
+    let key = account.to_string();
+    let lower = s.partition_point(|file| &file.account.to_string() < &key);
+    let upper = s.partition_point(|file| &file.account.to_string() <= &key);
+    &s[lower..upper]
+}
+
+async fn show_package<T: PackageRegistry + ?Sized>(
+    reg: &T,
+    base_url: &reqwest::Url,
+    client: &mut reqwest::Client,
+    package: &Meta,
+    origin: &PackageOrigin,
+    raw: bool,
+) -> Result<(), anyhow::Error> {
+    // Only packages from a registry have an archive to measure
+    let (mut manifest, size) = match origin {
+        PackageOrigin::Repo { sha256, file } => {
+            let mut archive = reg
+                .get_by_info(&package.info(sha256.clone(), file.clone()))
+                .await?;
+            (archive.manifest(), Some(archive.total_size()))
+        }
+        PackageOrigin::Installed { .. } => (
+            get_manifest(reg, base_url, client, package, origin).await?,
+            None,
+        ),
+    };
+    if raw {
+        print!("{}", raw_package_info(package, &manifest)?);
+        return Ok(());
+    }
+    println!("name: {}-{}", &package.name, &package.version);
+    println!("description: {}", &package.description);
+    if let Some(size) = size {
+        println!("size: {}", HumanBytes(size));
+    }
+    let mut services: Vec<_> = manifest.services.into_iter().collect();
+    services.sort_by(|lhs, rhs| lhs.0.to_string().cmp(&rhs.0.to_string()));
+    manifest.data.sort_by(|lhs, rhs| {
+        (
+            lhs.account.to_string(),
+            lhs.service.to_string(),
+            &lhs.filename,
+        )
+            .cmp(&(
+                rhs.account.to_string(),
+                rhs.service.to_string(),
+                &rhs.filename,
+            ))
+    });
+
+    for account in &package.accounts {
+        let info = services
+            .binary_search_by(|service| service.0.to_string().cmp(&account.to_string()))
+            .map_or(None, |idx| Some(&services[idx].1));
+        print!(
+            "{}",
+            &ServicePrinter {
+                service: *account,
+                info,
+                data: get_service_data(&manifest.data, *account)
+            }
+        );
+    }
+    Ok(())
+}
+
+// The manifest is the JSON that postinstall stores on chain, before compression
+fn raw_package_info(package: &Meta, manifest: &PackageManifest) -> Result<String, anyhow::Error> {
+    Ok(format!(
+        "name: {}-{}\nmeta: {}\nmanifest: {}\n",
+        &package.name,
+        &package.version,
+        Hex::from(package.packed()),
+        Hex::from(serde_json::to_vec(manifest)?)
+    ))
+}
+
+fn is_unbooted_error(e: &anyhow::Error) -> bool {
+    e.root_cause()
+        .to_string()
+        .contains("Need genesis block; use 'psibase boot' to boot chain")
+}
+
+// an unbooted chain has no packages installed
+fn handle_unbooted(list: Result<PackageList, anyhow::Error>) -> Result<PackageList, anyhow::Error> {
+    if let Err(e) = &list {
+        if is_unbooted_error(e) {
+            return Ok(PackageList::new());
+        }
+    }
+    list
+}
+
+fn verify_index(args: &Args, dir: &Path) -> Result<(), anyhow::Error> {
+    let errors = DirectoryRegistry::new(dir.to_path_buf()).verify_index()?;
+    for err in &errors {
+        eprintln!("{}", err);
+    }
+    if !errors.is_empty() {
+        return Err(anyhow!(
+            "{} is stale. Run psibase index {} to regenerate it.",
+            dir.join("index.json").to_string_lossy(),
+            dir.to_string_lossy()
+        ));
+    }
+    if !args.suppress_ok {
+        println!("Ok");
+    }
+    Ok(())
+}
+
+// Writes the package to a temporary file next to out, so that out is
+// only replaced once the package is known to be valid
+async fn build_package(
+    args: &Args,
+    dir: &Path,
+    out: &Path,
+    sources: &Vec<String>,
+) -> Result<(), anyhow::Error> {
+    let out_dir = match out.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(out_dir)
+        .with_context(|| format!("Can not create {}", out.to_string_lossy()))?;
+    write_package_dir(dir, file.as_file_mut())?;
+    let package = PackagedService::new(BufReader::new(file.reopen()?))?;
+    let mut packages = vec![];
+    let depends = package.meta().depends.clone();
+    if !depends.is_empty() {
+        let registry = get_package_registry(args, sources).await?;
+        for op in solve_dependencies(registry.index()?, depends, vec![], &SolveOptions::default())?
+        {
+            let PackageOp::Install(info) = op else {
+                return Err(anyhow!(
+                    "Resolving the dependencies of {} returned {:?}, but only installs \
+                     are expected when there are no existing packages",
+                    package.name(),
+                    op
+                ));
+            };
+            packages.push(registry.get_by_info(&info).await?);
+        }
+    }
+    packages.push(package);
+    validate_dependencies(&mut packages)?;
+    file.persist(out)
+        .with_context(|| format!("Can not create {}", out.to_string_lossy()))?;
+    Ok(())
+}
+
+async fn package_info(
+    args: &Args,
+    mut client: reqwest::Client,
+    packages: &Vec<String>,
+    sources: &Vec<String>,
+    raw: bool,
+) -> Result<(), anyhow::Error> {
+    let installed = handle_unbooted(PackageList::installed(&args.api, &mut client).await)?;
+    // Only built if a package is not installed
+    let mut package_registry = None;
+
+    for (package, PackageRef { name, version }) in packages.iter().zip(make_refs(packages)?) {
+        if let Some((meta, origin)) = installed.get_by_name(package)? {
+            // The manifests of installed packages come from the chain
+            let no_registry = JointRegistry::<BufReader<File>>::new();
+            show_package(&no_registry, &args.api, &mut client, meta, origin, raw).await?;
+            continue;
+        }
+        if package_registry.is_none() {
+            package_registry = Some(get_package_registry(args, sources).await?);
+        }
+        let package_registry = package_registry.as_ref().unwrap();
+        if let Some(info) = package_registry.get_info(&name, &version)? {
+            let origin = PackageOrigin::Repo {
+                sha256: info.sha256.clone(),
+                file: info.file.clone(),
+            };
+            show_package(
+                package_registry,
+                &args.api,
+                &mut client,
+                &info.meta(),
+                &origin,
+                raw,
+            )
+            .await?;
+            if !raw {
+                write_source_versions(
+                    &mut std::io::stdout(),
+                    package_registry,
+                    &package_source_names(args, sources)?,
+                    &name,
+                )?;
+            }
+        } else {
+            eprintln!("Package {} not found", package);
+        }
+    }
+
+    Ok(())
+}
+
+// Lists each version of the package in each source, since mirrors and
+// Names the option that gets past a dependency error
+fn with_solve_hint(e: anyhow::Error) -> anyhow::Error {
+    let hint = match e.downcast_ref() {
+        Some(SolveError::Downgrade { .. }) => "pass --allow-downgrade to allow it",
+        Some(SolveError::AmbiguousProvider { .. }) => "choose one with --prefer",
+        _ => return e,
+    };
+    anyhow!("{}; {}", e, hint)
+}
+
+// --prefer-source can make the version shown differ from what one
+// source alone would give
+fn write_source_versions<T: std::io::Read + std::io::Seek>(
+    out: &mut dyn std::io::Write,
+    registry: &JointRegistry<T>,
+    source_names: &[String],
+    name: &str,
+) -> Result<(), anyhow::Error> {
+    for version in registry.versions(name)? {
+        write!(
+            out,
+            "available: {}-{} from {}",
+            name, version.info.version, source_names[version.source]
+        )?;
+        if version.shadowed {
+            write!(out, " (shadowed by --prefer-source)")?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn open_keystore(args: &Args) -> Result<KeyStore, anyhow::Error> {
+    Ok(KeyStore::new(match &args.keystore {
+        Some(dir) => dir.clone(),
+        None => xdg_dir("XDG_CONFIG_HOME", ".config")?.join("keys"),
+    }))
+}
+
+// --sign takes a key, or the name or public key of a key in the keystore
+fn load_keys(args: &Args) -> Result<Vec<AnyPrivateKey>, anyhow::Error> {
+    let mut result = Vec::new();
+    if !args.sign.is_empty() {
+        let keystore = open_keystore(args)?;
+        for key in &args.sign {
+            if let Some(entry) = keystore.find(key)? {
+                let password =
+                    rpassword::prompt_password(format!("Enter password for {}: ", entry.name))?;
+                result.push(keystore.load(&entry.name, &password)?);
+            } else {
+                result
+                    .push(AnyPrivateKey::from_str(key).with_context(|| format!("--sign {}", key))?);
+            }
+        }
+    }
+    for command in &args.signer {
+        result.push(AnyPrivateKey::from_command(command));
+    }
+    Ok(result)
+}
+
+fn manage_keys(keystore: &KeyStore, command: &KeyCommand) -> Result<(), anyhow::Error> {
+    match command {
+        KeyCommand::Import { name, key } => {
+            let key = match key {
+                Some(key) => key.clone(),
+                None => rpassword::prompt_password("Enter key: ")?,
+            };
+            let password = rpassword::prompt_password("Enter password: ")?;
+            if rpassword::prompt_password("Repeat password: ")? != password {
+                return Err(anyhow!("Passwords do not match"));
+            }
+            let entry = keystore.import(name, &key, &password)?;
+            println!("{} {}", entry.name, entry.public_key);
+        }
+        KeyCommand::List => {
+            for entry in keystore.list()? {
+                println!("{} {}", entry.name, entry.public_key);
+            }
+        }
+        KeyCommand::Remove { name } => keystore.remove(name)?,
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+struct TokenData<'a> {
+    exp: i64,
+    mode: &'a str,
+}
+
+fn sign_token(key_text: &str, exp: i64, mode: &str) -> Result<String, anyhow::Error> {
+    let claims = TokenData { exp, mode };
+    let key: Hmac<Sha256> = Hmac::new_from_slice(key_text.as_bytes())?;
+    Ok(claims.sign_with_key(&key)?)
+}
+
+fn create_token(expires_after: Duration, mode: &str) -> Result<(), anyhow::Error> {
+    let key_text = rpassword::prompt_password("Enter Key: ")?;
+    let token = sign_token(&key_text, (Utc::now() + expires_after).timestamp(), mode)?;
+    println!("{}", token);
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Session {
+    token: String,
+    expires: i64,
+}
+
+fn sessions_file(args: &Args) -> Result<PathBuf, anyhow::Error> {
+    match &args.sessions {
+        Some(file) => Ok(file.clone()),
+        None => Ok(xdg_dir("XDG_CONFIG_HOME", ".config")?.join("sessions.json")),
+    }
+}
+
+// Sessions are keyed by the origin of --api, so that e.g. a trailing
+// slash doesn't matter
+fn session_key(api: &Url) -> String {
+    api.origin().ascii_serialization()
+}
+
+fn read_sessions(file: &Path) -> Result<HashMap<String, Session>, anyhow::Error> {
+    match std::fs::read(file) {
+        Ok(contents) => Ok(serde_json::from_slice(&contents)
+            .with_context(|| format!("Invalid sessions file {}", file.display()))?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e).with_context(|| format!("Can not read {}", file.display())),
+    }
+}
+
+// Drops expired sessions. The file holds bearer tokens, so only its
+// owner may read it. The mode is set on every write, because a file
+// that already exists keeps its permissions when it is opened.
+fn write_sessions(
+    file: &Path,
+    sessions: &mut HashMap<String, Session>,
+) -> Result<(), anyhow::Error> {
+    let now = Utc::now().timestamp();
+    sessions.retain(|_, session| session.expires > now);
+    if let Some(dir) = file.parent() {
+        create_private_dir(dir)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let out = options
+        .open(file)
+        .with_context(|| format!("Can not write {}", file.display()))?;
+    #[cfg(unix)]
+    out.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))
+        .with_context(|| format!("Can not set the permissions of {}", file.display()))?;
+    serde_json::to_writer_pretty(out, sessions)?;
+    Ok(())
+}
+
+// Only login and logout need a readable sessions file; other commands
+// continue without a token
+fn load_session(args: &Args) -> Option<String> {
+    let file = sessions_file(args).ok()?;
+    let mut sessions = match read_sessions(&file) {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            warn!("Ignoring sessions file: {:#}", e);
+            return None;
+        }
+    };
+    let now = Utc::now().timestamp();
+    sessions
+        .remove(&session_key(&args.api))
+        .filter(|session| session.expires > now)
+        .map(|session| session.token)
+}
+
+fn login(
+    args: &Args,
+    key_text: &str,
+    expires_after: Duration,
+    mode: &str,
+) -> Result<(), anyhow::Error> {
+    let file = sessions_file(args)?;
+    let mut sessions = read_sessions(&file)?;
+    let expires = (Utc::now() + expires_after).timestamp();
+    sessions.insert(
+        session_key(&args.api),
+        Session {
+            token: sign_token(key_text, expires, mode)?,
+            expires,
+        },
+    );
+    write_sessions(&file, &mut sessions)
+}
+
+fn logout(args: &Args) -> Result<(), anyhow::Error> {
+    let file = sessions_file(args)?;
+    let mut sessions = read_sessions(&file)?;
+    if sessions.remove(&session_key(&args.api)).is_none() {
+        return Err(anyhow!("Not logged in to {}", session_key(&args.api)));
+    }
+    write_sessions(&file, &mut sessions)
+}
+
+// Builds the one client used for the whole command. Clones share its
+// connection pool, so pass clones around instead of building new clients.
+// Requests to the node carry the session token; requests to other
+// servers only do if `with_session` is set
+fn client_builder(
+    args: &Args,
+    with_session: bool,
+) -> Result<reqwest::ClientBuilder, anyhow::Error> {
+    let mut builder = reqwest::Client::builder().gzip(true);
+    if let Some(proxy) = &args.proxy_server {
+        builder = builder.proxy(proxy.clone());
+    }
+    if let (true, Some(token)) = (with_session, &args.session_token) {
+        let mut value = reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token))?;
+        value.set_sensitive(true);
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(reqwest::header::AUTHORIZATION, value);
+        builder = builder.default_headers(headers);
+    }
+    Ok(builder)
+}
+
+fn build_client(args: &Args) -> Result<reqwest::Client, anyhow::Error> {
+    Ok(client_builder(args, true)?.build()?)
+}
+
+// Package sources get their own client, which limits redirects
+fn build_registry_client(args: &Args, url: &Url) -> Result<reqwest::Client, anyhow::Error> {
+    Ok(client_builder(args, is_node_host(&args.api, url))?
+        .redirect(redirect_policy(
+            args.max_redirects,
+            args.allow_cross_origin_redirect,
+        ))
+        .build()?)
+}
+
+// Ports that a local psinode commonly listens on
+const DISCOVERY_PORTS: [u16; 3] = [8080, 8081, 8090];
+
+// How long discovery waits for each candidate to respond
+const DISCOVERY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+// Returns the URLs where a local node might be, with where each one
+// came from. The configs of node databases in `dir`, or `dir` itself,
+// come first, since they name the host and port that the node uses.
+fn discovery_candidates(dir: &Path) -> Vec<(Url, String)> {
+    let mut configs = vec![dir.join("config")];
+    if let Ok(entries) = read_dir(dir) {
+        let mut subdirs: Vec<_> = entries
+            .filter_map(|entry| Some(entry.ok()?.path().join("config")))
+            .collect();
+        subdirs.sort();
+        configs.extend(subdirs);
+    }
+    let mut result = vec![];
+    for path in configs {
+        let Ok(text) = std::fs::read_to_string(&path) else {
+            continue;
+        };
+        for url in node_config_urls(&text) {
+            result.push((url, path.display().to_string()));
+        }
+    }
+    let default = Url::parse(DEFAULT_API).unwrap();
+    for port in DISCOVERY_PORTS {
+        let mut url = default.clone();
+        url.set_port(Some(port)).unwrap();
+        result.push((url, format!("port {}", port)));
+    }
+    result
+}
+
+// Reads the `host` and TCP `listen` settings from a psinode config file
+fn node_config_urls(config: &str) -> Vec<Url> {
+    let mut host = None;
+    let mut ports = vec![];
+    for line in config.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            // Later sections configure loggers
+            break;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        match key.trim() {
+            "host" => host = Some(value.trim().to_string()),
+            "listen" => {
+                // Either PORT or ADDRESS:PORT. Local sockets are skipped.
+                let value = value.trim();
+                let port = value.rsplit_once(':').map_or(value, |(_, port)| port);
+                ports.extend(port.parse::<u16>().ok());
+            }
+            _ => (),
+        }
+    }
+    let Some(host) = host else {
+        return vec![];
+    };
+    ports
+        .into_iter()
+        .filter_map(|port| Url::parse(&format!("http://{}:{}/", host, port)).ok())
+        .collect()
+}
+
+// Returns the first candidate that responds
+async fn discover_api(
+    candidates: &[(Url, String)],
+    client: &reqwest::Client,
+) -> Result<(Url, String), anyhow::Error> {
+    for (url, source) in candidates {
+        match ping(url, client, DISCOVERY_TIMEOUT).await {
+            Ok(_) => return Ok((url.clone(), source.clone())),
+            Err(e) => debug!("{} from {}: {}", url, source, e),
+        }
+    }
+    let tried: Vec<_> = candidates.iter().map(|(url, _)| url.as_str()).collect();
+    Err(anyhow!(
+        "No running psinode was found. Tried {}. Use --api to name the node.",
+        tried.join(", ")
+    ))
+}
+
+// The session token is only for the node. Package sources on other
+// hosts get a client without it.
+fn is_node_host(api: &Url, url: &Url) -> bool {
+    match (api.host_str(), url.host_str()) {
+        (Some(api), Some(host)) => {
+            host == api
+                || host
+                    .strip_suffix(api)
+                    .map_or(false, |sub| sub.ends_with('.'))
+        }
+        _ => false,
+    }
+}
+
+// The first Ctrl-C asks long running commands to stop after the
+// transactions that are in flight. A second Ctrl-C exits immediately.
+fn handle_interrupt(interrupted: Arc<AtomicBool>) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!(
+                "Interrupted; waiting for pending transactions. Press Ctrl-C again to exit now."
+            );
+            interrupted.store(true, Ordering::Relaxed);
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        }
+    });
+}
+
+// Diagnostics go to stderr, so they don't mix with the output on stdout
+fn init_logging(level: Option<log::LevelFilter>) {
+    let mut builder = match level {
+        Some(level) => {
+            let mut builder = env_logger::Builder::new();
+            builder.filter_level(level);
+            builder
+        }
+        None => env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")),
+    };
+    builder.init();
+}
+
+// An explicit --api or PSINODE_URL takes precedence, even when it names
+// the default URL
+fn should_discover(args: &Args, matches: &clap::ArgMatches) -> bool {
+    args.discover && matches.value_source("api") == Some(clap::ValueSource::DefaultValue)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    init_logging(args.log_level);
+    if should_discover(&args, &matches) {
+        let candidates = discovery_candidates(&std::env::current_dir()?);
+        let (url, source) = discover_api(&candidates, &reqwest::Client::new()).await?;
+        info!("Using psinode at {} ({})", url, source);
+        args.api = url;
+    }
+    if args.compact {
+        if !matches!(args.trace, TraceFormat::Json) {
+            return Err(anyhow!("--compact requires --trace json"));
+        }
+        args.trace = TraceFormat::CompactJson;
+    }
+    if args.expire_after == 0 || args.expire_after > MAX_EXPIRE_AFTER {
+        return Err(anyhow!(
+            "--expire-after must be from 1 to {} seconds",
+            MAX_EXPIRE_AFTER
+        ));
+    }
+    if args.write_transaction.is_some()
+        && !matches!(
+            args.command,
+            Command::Create { .. } | Command::Modify { .. } | Command::SetOwner { .. }
+        )
+    {
+        return Err(anyhow!(
+            "--write-transaction only works with create, modify, and set-owner"
+        ));
+    }
+    args.keys = load_keys(&args)?;
+    if !matches!(args.command, Command::Login { .. } | Command::Logout) {
+        args.session_token = load_session(&args);
+    }
+    if matches!(
+        args.command,
+        Command::Boot { .. }
+            | Command::Install { .. }
+            | Command::Upload { .. }
+            | Command::Deploy { dir: Some(_), .. }
+    ) {
+        handle_interrupt(args.interrupted.clone());
+    }
+    // _proxy must live until main returns; dropping it stops the proxy
+    let (proxy_server, _proxy) = make_proxy(&args.proxy).await?;
+    args.proxy_server = proxy_server;
+    let client = build_client(&args)?;
+    if let Some(chain_id) = &args.chain_id {
+        // Boot creates the chain, so there is nothing to compare against
+        // yet, but --resume continues an existing one
+        if matches!(
+            args.command,
+            Command::Create { .. }
+                | Command::Modify { .. }
+                | Command::SetOwner { .. }
+                | Command::Deploy {
+                    verify_only: None,
+                    ..
+                }
+                | Command::Upload { .. }
+                | Command::Install { .. }
+                | Command::Boot { resume: true, .. }
+        ) {
+            check_chain_id(&args.api, client.clone(), chain_id).await?;
+        }
+    }
+    match &args.command {
+        Command::Boot {
+            key,
+            producer,
+            package_source,
+            resume,
+            follow,
+            wait_for_node: wait,
+            lock,
+            lock_out,
+            services,
+        } => {
+            if let Some(seconds) = wait {
+                unless_interrupted(
+                    &args.interrupted,
+                    wait_for_node(
+                        &args.api,
+                        &client,
+                        std::time::Duration::from_secs(*seconds),
+                        NODE_POLL_INTERVAL,
+                    ),
+                )
+                .await?;
+            }
+            if !*resume {
+                boot(
+                    &args,
+                    client.clone(),
+                    key,
+                    producer,
+                    package_source,
+                    services,
+                    lock,
+                    lock_out,
+                )
+                .await?
+            } else {
+                resume_boot(&args, client.clone()).await?
+            }
+            if *follow {
+                follow_blocks(&args, client, &mut std::io::stdout(), FOLLOW_POLL_INTERVAL).await?
+            }
+        }
+        Command::Create {
+            account,
+            key,
+            insecure,
+            auth_service,
+            owner,
+            sender,
+        } => {
+            create(
+                &args,
+                client,
+                (*sender).into(),
+                (*account).into(),
+                key,
+                *insecure,
+                auth_service.map(Into::into),
+                owner.map(Into::into),
+            )
+            .await?
+        }
+        Command::Modify {
             account,
-            filename,
+            key,
+            insecure,
+            auth_service,
+            owner,
+        } => {
+            modify(
+                &args,
+                client,
+                (*account).into(),
+                key,
+                *insecure,
+                auth_service.map(Into::into),
+                owner.map(Into::into),
+            )
+            .await?
+        }
+        Command::SetOwner { account, new_owner } => {
+            set_owner(&args, client, (*account).into(), (*new_owner).into()).await?
+        }
+        Command::Deploy {
+            verify_only: Some(filename),
+            ..
+        } => verify_deploy(&args, filename)?,
+        Command::Deploy {
+            dir: Some(dir),
+            create_account,
+            create_insecure_account,
+            sender,
+            ..
+        } => {
+            deploy_dir(
+                &args,
+                client,
+                (*sender).into(),
+                dir,
+                create_account,
+                *create_insecure_account,
+            )
+            .await?
+        }
+        Command::Deploy {
+            account: Some(account),
+            filename: Some(filename),
             create_account,
             create_insecure_account,
             register_proxy,
+            safe_deploy,
             sender,
+            ..
         } => {
             deploy(
                 &args,
@@ -1204,72 +4529,2241 @@ async fn main() -> Result<(), anyhow::Error> {
                 create_account,
                 *create_insecure_account,
                 *register_proxy,
+                safe_deploy.map(Into::into),
+            )
+            .await?
+        }
+        Command::Deploy { .. } => unreachable!("clap requires account and filename"),
+        Command::Upload {
+            service,
+            source,
+            dest,
+            content_type,
+            recursive,
+            sender,
+            manifest_out,
+            prune,
+            yes,
+            strip_prefix,
+            follow_symlinks,
+            concurrency,
+            verify,
+        } => {
+            let dest = match strip_prefix {
+                Some(prefix) => Some(strip_upload_prefix(dest, source, prefix)?),
+                None => dest.clone(),
+            };
+            let dest = &dest;
+            if *recursive {
+                if content_type.is_some() {
+                    return Err(anyhow!("--recursive is incompatible with --content-type"));
+                }
+                upload_tree(
+                    &args,
+                    client,
+                    (*service).into(),
+                    *sender,
+                    dest,
+                    source,
+                    manifest_out,
+                    *prune,
+                    *yes,
+                    *follow_symlinks,
+                    *concurrency,
+                    *verify,
+                )
+                .await?
+            } else {
+                upload(
+                    &args,
+                    client,
+                    (*service).into(),
+                    *sender,
+                    dest,
+                    content_type,
+                    source,
+                    *verify,
+                )
+                .await?
+            }
+        }
+        Command::Install {
+            packages,
+            key,
+            package_source,
+            sender,
+            reinstall,
+            reinstall_deps,
+            allow_downgrade,
+            prefer,
+            rotate_key,
+            force_rotate,
+            dedup_files,
+            plan_out,
+            lock,
+            lock_out,
+            yes,
+            explain,
+            follow,
+            only_accounts,
+        } => {
+            install(
+                &args,
+                client.clone(),
+                packages,
+                &PackageSenders::new(sender)?,
+                key,
+                package_source,
+                &SolveOptions {
+                    reinstall: *reinstall,
+                    reinstall_deps: *reinstall_deps,
+                    allow_downgrade: *allow_downgrade,
+                    prefer: prefer.clone(),
+                },
+                *rotate_key,
+                *force_rotate,
+                *dedup_files,
+                plan_out,
+                lock,
+                lock_out,
+                *yes,
+                *explain,
+                *only_accounts,
+            )
+            .await?;
+            if *follow {
+                follow_blocks(&args, client, &mut std::io::stdout(), FOLLOW_POLL_INTERVAL).await?
+            }
+        }
+        Command::List {
+            all,
+            available,
+            installed,
+            tree,
+            format,
+            package_source,
+        } => {
+            if *tree {
+                list_tree(&args, client, *format).await?
+            } else {
+                list(
+                    &args,
+                    client,
+                    *all,
+                    *available,
+                    *installed,
+                    package_source,
+                    *format,
+                )
+                .await?
+            }
+        }
+        Command::Search {
+            patterns,
+            format,
+            package_source,
+        } => search(&args, client, patterns, package_source, *format).await?,
+        Command::Info {
+            packages,
+            package_source,
+            raw,
+        } => package_info(&args, client, packages, package_source, *raw).await?,
+        Command::Package {
+            dir,
+            out,
+            package_source,
+        } => build_package(&args, dir, out, package_source).await?,
+        Command::Index { dir, verify: false } => {
+            let index = DirectoryRegistry::write_index(dir)?;
+            if !args.suppress_ok {
+                println!("Indexed {} packages", index.len());
+            }
+        }
+        Command::Index { dir, verify: true } => verify_index(&args, dir)?,
+        Command::Hexdump {
+            type_name,
+            data,
+            base64,
+        } => hexdump(type_name, data, *base64)?,
+        Command::Trace { txid } => show_trace(&args, client, txid).await?,
+        Command::Ping { count, timeout } => ping_node(&args, client, *count, *timeout).await?,
+        Command::CreateToken {
+            expires_after,
+            mode,
+        } => create_token(Duration::seconds(*expires_after), mode)?,
+        Command::Login {
+            expires_after,
+            mode,
+        } => {
+            let key_text = rpassword::prompt_password("Enter Key: ")?;
+            login(&args, &key_text, Duration::seconds(*expires_after), mode)?;
+            if !args.suppress_ok {
+                println!("Logged in to {}", session_key(&args.api));
+            }
+        }
+        Command::Logout => logout(&args)?,
+        Command::Key { command } => manage_keys(&open_keystore(&args)?, command)?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use psibase::{solve_dependencies, PackageDisposition, PackageInfo};
+    use std::fs::{create_dir, write};
+
+    const TEST_KEY: &str = "PVT_K1_2bfGi9rYsXQSXXTvJbDAPhHLQUojjaNLomdm3cEJ1XTzMqUt3V";
+
+    #[tokio::test]
+    async fn interrupt_cancels_phase_in_progress() -> Result<(), anyhow::Error> {
+        let interrupted = Arc::new(AtomicBool::new(false));
+        assert_eq!(unless_interrupted(&interrupted, async { Ok(1) }).await?, 1);
+
+        let flag = interrupted.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            flag.store(true, Ordering::Relaxed);
+        });
+        let never = future::pending::<Result<(), anyhow::Error>>();
+        let err = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            unless_interrupted(&interrupted, never),
+        )
+        .await?
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Interrupted");
+        Ok(())
+    }
+
+    #[test]
+    fn pending_boot_is_private_and_replaces_stale_file() -> Result<(), anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        let pending = dir.path().join("boot").join("psibase.test-8080.bin");
+        write_pending_boot(&pending, b"old")?;
+        write_pending_boot(&pending, b"new")?;
+        assert_eq!(std::fs::read(&pending)?, b"new");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &Path| metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&pending), 0o600);
+            assert_eq!(mode(pending.parent().unwrap()), 0o700);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn expired_boot_transactions_are_not_resumed() -> Result<(), anyhow::Error> {
+        let created = Utc::now();
+        let trx = Transaction {
+            tapos: Tapos {
+                expiration: TimePointSec::try_from(created + Duration::seconds(120))?,
+                refBlockSuffix: 0,
+                flags: 0,
+                refBlockIndex: 0,
+            },
+            actions: vec![],
+            claims: vec![],
+        };
+        let transactions = vec![SignedTransaction {
+            transaction: trx.packed().into(),
+            proofs: vec![],
+        }];
+        check_boot_expiration(&transactions, created)?;
+        let err =
+            check_boot_expiration(&transactions, created + Duration::seconds(121)).unwrap_err();
+        assert!(err.to_string().contains("must be booted again"));
+        check_boot_expiration(&[], created)?;
+        Ok(())
+    }
+
+    #[test]
+    fn upload_summary_counts_all_files() {
+        let service = account!("sites");
+        let actions: Vec<_> = (0..25)
+            .map(|i| {
+                let path = format!("/file{}.txt", i);
+                let action = store_sys(service, service, &path, "text/plain", &[0; 1024]);
+                (path, action)
+            })
+            .collect();
+        let files: Vec<_> = actions
+            .iter()
+            .map(|(path, _)| UploadedFile {
+                path: path.clone(),
+                content_type: "text/plain".to_string(),
+                sha256: <[u8; 32]>::from(Sha256::digest([0; 1024])).into(),
+                size: 1024,
+            })
+            .collect();
+
+        let batches = batch_uploads(actions, 64 * 1024);
+        assert_eq!(batches.len(), 3);
+        assert_eq!(
+            upload_summary(&files, batches.len()),
+            "Uploaded 25 files, 25.00 KiB in 3 transactions"
+        );
+    }
+
+    #[test]
+    fn upload_batches_honor_max_transaction_size() {
+        let service = account!("sites");
+        let actions: Vec<_> = (0..6)
+            .map(|i| {
+                let path = format!("/file{}.txt", i);
+                let action = store_sys(service, service, &path, "text/plain", &[0; 3000]);
+                (path, action)
+            })
+            .collect();
+        let size = actions[0].1.rawData.len();
+
+        let batches = batch_uploads(actions.clone(), 2 * size);
+        assert_eq!(batches.len(), 3);
+        for (_, batch) in &batches {
+            let bytes: usize = batch.iter().map(|a| a.rawData.len()).sum();
+            assert!(bytes <= 2 * size);
+        }
+
+        // Files larger than the limit are still uploaded, one at a time
+        let batches = batch_uploads(actions, size - 1);
+        assert_eq!(batches.len(), 6);
+    }
+
+    #[test]
+    fn strip_prefix_replaces_local_directory() -> Result<(), anyhow::Error> {
+        let app = Some("/app".to_string());
+        assert_eq!(
+            strip_upload_prefix(&app, "./dist/index.html", Path::new("dist"))?,
+            "/app/index.html"
+        );
+        assert_eq!(
+            strip_upload_prefix(&None, "dist/assets/", Path::new("./dist"))?,
+            "/assets"
+        );
+        assert!(strip_upload_prefix(&app, "build/index.html", Path::new("dist")).is_err());
+        // Only whole directory names are stripped
+        assert!(strip_upload_prefix(&app, "distro/index.html", Path::new("dist")).is_err());
+
+        // The rest of the tree keeps its layout under the new prefix
+        let dir = tempfile::tempdir()?;
+        let dist = dir.path().join("dist");
+        create_dir(&dist)?;
+        create_dir(dist.join("assets"))?;
+        write(dist.join("index.html"), "<html/>")?;
+        write(dist.join("assets").join("app.js"), "")?;
+        let source = dist.join("assets").to_str().unwrap().to_string();
+        let dest = strip_upload_prefix(&app, &source, &dist)?;
+        let mut actions = vec![];
+        let mut manifest = vec![];
+        let service = account!("sites");
+        fill_tree(
+            service,
+            service,
+            &mut actions,
+            &mut manifest,
+            &dest,
+            &source,
+            true,
+            false,
+            &mut HashSet::new(),
+            false,
+        )?;
+        let paths: Vec<_> = manifest.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["/app/assets/app.js"]);
+        Ok(())
+    }
+
+    #[test]
+    fn upload_manifest_matches_files() -> Result<(), anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        write(dir.path().join("index.html"), "<html></html>")?;
+        create_dir(dir.path().join("js"))?;
+        write(dir.path().join("js/main.js"), "main();")?;
+
+        let service = account!("sites");
+        let mut actions = Vec::new();
+        let mut manifest = Vec::new();
+        fill_tree(
+            service,
+            service,
+            &mut actions,
+            &mut manifest,
+            "",
+            dir.path().to_str().unwrap(),
+            true,
+            false,
+            &mut HashSet::new(),
+            false,
+        )?;
+        manifest.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut uploaded: Vec<_> = actions.iter().map(|(path, _)| path.clone()).collect();
+        uploaded.sort();
+        let paths: Vec<_> = manifest.iter().map(|file| file.path.clone()).collect();
+        assert_eq!(paths, uploaded);
+        assert_eq!(paths, vec!["/index.html", "/js/main.js"]);
+
+        assert_eq!(manifest[0].content_type, "text/html");
+        assert_eq!(manifest[0].size, 13);
+        assert_eq!(
+            manifest[0].sha256,
+            <[u8; 32]>::from(Sha256::digest(b"<html></html>")).into()
+        );
+        assert_eq!(manifest[1].content_type, "application/javascript");
+        assert_eq!(manifest[1].size, 7);
+        Ok(())
+    }
+
+    #[test]
+    fn hexdump_parses_hex_and_base64() -> Result<(), anyhow::Error> {
+        let packed = Tapos {
+            expiration: TimePointSec { seconds: 1 },
+            refBlockSuffix: 2,
+            flags: 0,
+            refBlockIndex: 3,
+        }
+        .packed();
+        let hex = "01000000 02000000\n0000 03\n";
+        assert_eq!(parse_packed(hex, false)?, packed);
+        assert_eq!(parse_packed("AQAAAAIAAAAAAAM=", true)?, packed);
+        assert!(parse_packed("AQAAAAIAAAAAAAM=", false).is_err());
+
+        let entries = dump_type("Tapos", &packed)?;
+        assert_eq!(entries.len(), 4);
+        assert!(dump_type("Tapos", &packed[1..]).is_err());
+        assert!(dump_type("Block", &packed).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_bounded_limits_in_flight() {
+        use std::sync::atomic::AtomicUsize;
+        let in_flight = AtomicUsize::new(0);
+        let max_in_flight = AtomicUsize::new(0);
+        let task = |i: usize| {
+            let (in_flight, max_in_flight) = (&in_flight, &max_in_flight);
+            async move {
+                let n = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(n, Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis((i % 3) as u64)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                i
+            }
+        };
+        let limit = NonZeroUsize::new(4).unwrap();
+        let interrupted = AtomicBool::new(false);
+        let mut done = run_bounded((0..50).map(task).collect(), limit, &interrupted).await;
+        done.sort();
+        assert_eq!(done, (0..50).collect::<Vec<_>>());
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 4);
+
+        // Nothing new is started after an interrupt
+        interrupted.store(true, Ordering::Relaxed);
+        assert!(
+            run_bounded((0..50).map(task).collect(), limit, &interrupted)
+                .await
+                .is_empty()
+        );
+    }
+
+    #[cfg(unix)]
+    fn upload_paths(source: &Path, follow_symlinks: bool) -> Result<Vec<String>, anyhow::Error> {
+        let service = account!("sites");
+        let mut actions = Vec::new();
+        let mut manifest = Vec::new();
+        fill_tree(
+            service,
+            service,
+            &mut actions,
+            &mut manifest,
+            "",
+            source.to_str().unwrap(),
+            true,
+            follow_symlinks,
+            &mut HashSet::new(),
+            false,
+        )?;
+        let mut paths: Vec<_> = manifest.into_iter().map(|file| file.path).collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn upload_skips_symlinks() -> Result<(), anyhow::Error> {
+        use std::os::unix::fs::symlink;
+        let dir = tempfile::tempdir()?;
+        let shared = dir.path().join("shared");
+        create_dir(&shared)?;
+        write(shared.join("style.css"), "")?;
+        let site = dir.path().join("site");
+        create_dir(&site)?;
+        write(site.join("index.html"), "<html></html>")?;
+        symlink(&shared, site.join("shared"))?;
+        symlink(site.join("index.html"), site.join("home.html"))?;
+
+        assert_eq!(upload_paths(&site, false)?, ["/index.html"]);
+        assert_eq!(
+            upload_paths(&site, true)?,
+            ["/home.html", "/index.html", "/shared/style.css"]
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn upload_symlink_cycle_terminates() -> Result<(), anyhow::Error> {
+        use std::os::unix::fs::symlink;
+        let dir = tempfile::tempdir()?;
+        create_dir(dir.path().join("js"))?;
+        write(dir.path().join("js/main.js"), "main();")?;
+        symlink(dir.path(), dir.path().join("js/root"))?;
+
+        assert_eq!(upload_paths(dir.path(), true)?, ["/js/main.js"]);
+        Ok(())
+    }
+
+    fn meta(name: &str, depends: &[&str]) -> Meta {
+        Meta {
+            name: name.to_string(),
+            version: "1.0.0".to_string(),
+            description: String::new(),
+            depends: depends
+                .iter()
+                .map(|dep| PackageRef {
+                    name: dep.to_string(),
+                    version: "^1.0.0".to_string(),
+                })
+                .collect(),
+            accounts: vec![],
+            provides: vec![],
+        }
+    }
+
+    // Builds transactions without tapos or signatures, so that tests can
+    // look at the actions that would be pushed
+    fn unsigned_builder(
+        limit: usize,
+    ) -> TransactionBuilder<impl Fn(Vec<Action>) -> Result<SignedTransaction, anyhow::Error>> {
+        TransactionBuilder::new(limit, |actions: Vec<Action>| {
+            Ok(SignedTransaction {
+                transaction: Transaction {
+                    actions,
+                    ..Default::default()
+                }
+                .packed()
+                .into(),
+                proofs: vec![],
+            })
+        })
+    }
+
+    // The actions of every transaction in the groups, in order
+    fn pushed_actions(groups: &[(String, Vec<SignedTransaction>, bool)]) -> Vec<Action> {
+        groups
+            .iter()
+            .flat_map(|(_, trxs, _)| trxs)
+            .flat_map(|trx| Transaction::unpacked(&trx.transaction).unwrap().actions)
+            .collect()
+    }
+
+    #[test]
+    fn package_tree_shows_dependencies() {
+        let installed = vec![
+            meta("App", &["Lib", "Util"]),
+            meta("Lib", &["Util"]),
+            meta("Util", &[]),
+            meta("Other", &[]),
+            meta("X", &["Y"]),
+            meta("Y", &["X"]),
+        ];
+        let forest = package_forest(&installed);
+        assert_eq!(
+            format_package_forest(&forest),
+            "\
+App-1.0.0
+  Lib-1.0.0
+    Util-1.0.0
+  Util-1.0.0
+Other-1.0.0
+X-1.0.0
+  Y-1.0.0
+    X-1.0.0 (cycle)
+"
+        );
+        let json = serde_json::to_value(&forest).unwrap();
+        assert_eq!(json[0]["depends"][0]["name"], "Lib");
+        assert_eq!(json[2]["depends"][0]["depends"][0]["cycle"], true);
+        assert!(json[0].get("cycle").is_none());
+    }
+
+    #[tokio::test]
+    async fn search_rejects_json_format() {
+        let args = Args::parse_from(["psibase", "search", "--format", "json", "x"]);
+        let err = search(
+            &args,
+            reqwest::Client::new(),
+            &vec!["x".to_string()],
+            &vec![],
+            ListFormat::Json,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "search does not support --format json");
+    }
+
+    #[test]
+    fn package_tree_shows_shared_dependencies_once() {
+        // Each layer depends on the next one through two packages, which
+        // would double the size of the tree at every layer
+        let mut installed = vec![];
+        let names: Vec<(String, String, String)> = (0..40)
+            .map(|i| (format!("N{}", i), format!("L{}", i), format!("R{}", i)))
+            .collect();
+        for (i, (node, left, right)) in names.iter().enumerate() {
+            let next = names.get(i + 1).map(|(next, _, _)| next.as_str());
+            let deps: Vec<&str> = next.into_iter().collect();
+            installed.push(meta(node, &[left, right]));
+            installed.push(meta(left, &deps));
+            installed.push(meta(right, &deps));
+        }
+        let forest = package_forest(&installed);
+        let text = format_package_forest(&forest);
+        assert_eq!(text.lines().count(), installed.len() + 39);
+        assert!(text.starts_with(
+            "\
+N0-1.0.0
+  L0-1.0.0
+    N1-1.0.0
+"
+        ));
+        assert!(text.contains("\n  R0-1.0.0\n    N1-1.0.0 (see above)\n"));
+        let json = serde_json::to_value(&forest).unwrap();
+        assert_eq!(json[0]["depends"][1]["depends"][0]["repeated"], true);
+        assert!(json[0].get("repeated").is_none());
+    }
+
+    #[test]
+    fn install_plan_matches_resolved_ops() -> Result<(), anyhow::Error> {
+        let packages: Vec<PackageInfo> = serde_json::from_str(
+            r#"[
+{"name":"A","version":"1.0.0","depends":[],"accounts":[]},
+{"name":"A","version":"1.1.0","depends":[{"name":"B","version":"^1.0.0"}],"accounts":[]},
+{"name":"B","version":"1.0.0","depends":[],"accounts":[]}
+]"#,
+        )?;
+        let existing = vec![(
+            serde_json::from_str::<Meta>(r#"{"name":"A","version":"1.0.0"}"#)?,
+            PackageDisposition::upgradable("1.0.0"),
+        )];
+        let input = vec![PackageRef {
+            name: "A".to_string(),
+            version: "1.1.0".to_string(),
+        }];
+        let ops = solve_dependencies(packages, input, existing, &SolveOptions::default())?;
+        let plan = install_plan(&ops);
+        assert_eq!(plan.len(), ops.len());
+        for (planned, op) in plan.iter().zip(&ops) {
+            match (planned, op) {
+                (PlannedOp::Install { name, version, .. }, PackageOp::Install(info)) => {
+                    assert_eq!((name, version), (&info.name, &info.version));
+                }
+                (
+                    PlannedOp::Replace {
+                        name,
+                        old_version,
+                        version,
+                        ..
+                    },
+                    PackageOp::Replace(meta, info),
+                ) => {
+                    assert_eq!(
+                        (name, old_version, version),
+                        (&info.name, &meta.version, &info.version)
+                    );
+                }
+                _ => panic!("{:?} does not match {:?}", planned, op),
+            }
+        }
+
+        let json = serde_json::to_value(&plan)?;
+        assert_eq!(json[0]["op"], "install");
+        assert_eq!(json[0]["name"], "B");
+        assert_eq!(json[1]["op"], "replace");
+        assert_eq!(json[1]["old_version"], "1.0.0");
+        assert_eq!(json[1]["version"], "1.1.0");
+        assert_eq!(serde_json::from_value::<Vec<PlannedOp>>(json)?, plan);
+        assert_eq!(plan[0].to_string(), "install B-1.0.0");
+        assert_eq!(plan[1].to_string(), "replace A-1.0.0 with A-1.1.0");
+        Ok(())
+    }
+
+    #[test]
+    fn dedup_identical_data_files() -> Result<(), anyhow::Error> {
+        let store = |sender: AccountNumber, path: &str, content: &[u8]| {
+            sites::Wrapper::pack_from(sender).storeSys(
+                path.to_string(),
+                "text/plain".to_string(),
+                content.to_vec().into(),
+            )
+        };
+        let alice = account!("alice");
+        let mut stored = StoredFiles::default();
+        // The first package stores a file
+        let first = stored.dedup(vec![store(alice, "/font.woff", b"font")])?;
+        assert_eq!(first.len(), 1);
+        // The second package stores the same file and a different one
+        let second = stored.dedup(vec![
+            store(alice, "/font.woff", b"font"),
+            store(alice, "/font.woff2", b"font"),
+            store(account!("bob"), "/font.woff", b"font"),
+            new_account_action(alice, account!("carol")),
+        ])?;
+        let paths: Vec<_> = second.iter().map(|act| (act.sender, act.method)).collect();
+        assert_eq!(
+            paths,
+            [
+                (alice, method!("storeSys")),
+                (account!("bob"), method!("storeSys")),
+                (alice, method!("newAccount")),
+            ]
+        );
+        // Changed content at the same path is stored again
+        assert_eq!(
+            stored
+                .dedup(vec![store(alice, "/font.woff", b"other")])?
+                .len(),
+            1
+        );
+        assert_eq!(
+            stored
+                .dedup(vec![store(alice, "/font.woff", b"font")])?
+                .len(),
+            1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn install_transactions_start_with_sender() -> Result<(), anyhow::Error> {
+        assert!(with_sender_first(account!("alice"), vec![]).is_err());
+        let act = Action {
+            sender: account!("bob"),
+            service: account!("nop"),
+            method: method!("nop"),
+            rawData: Default::default(),
+        };
+        let actions = with_sender_first(account!("alice"), vec![act.clone()])?;
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[0].sender, account!("alice"));
+        let actions = with_sender_first(account!("bob"), vec![act])?;
+        assert_eq!(actions.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn only_accounts_creates_accounts() -> Result<(), anyhow::Error> {
+        let packages: Vec<PackageInfo> = serde_json::from_str(
+            r#"[
+{"name":"A","version":"1.0.0","depends":[],"accounts":["alpha"]},
+{"name":"A","version":"1.1.0","depends":[{"name":"B","version":"^1.0.0"}],"accounts":["alpha","alpha-2"]},
+{"name":"B","version":"1.0.0","depends":[],"accounts":["beta","alpha"]}
+]"#,
+        )?;
+        let existing = vec![(
+            serde_json::from_str::<Meta>(r#"{"name":"A","version":"1.0.0"}"#)?,
+            PackageDisposition::upgradable("1.0.0"),
+        )];
+        let input = vec![PackageRef {
+            name: "A".to_string(),
+            version: "1.1.0".to_string(),
+        }];
+        let ops = solve_dependencies(packages, input, existing, &SolveOptions::default())?;
+        let accounts = package_accounts(&ops, &PackageSenders::new(&[])?);
+        assert_eq!(
+            accounts
+                .iter()
+                .map(|new| (new.account, new.owner))
+                .collect::<Vec<_>>(),
+            vec![
+                (account!("beta"), account!("root")),
+                (account!("alpha"), account!("root")),
+                (account!("alpha-2"), account!("root"))
+            ]
+        );
+
+        let mut builder = unsigned_builder(1000);
+        create_accounts(accounts, false, &mut builder)?;
+        let methods: Vec<_> = pushed_actions(&builder.finish()?)
+            .into_iter()
+            .map(|act| act.method)
+            .collect();
+        assert_eq!(methods.len(), 9);
+        assert!(methods.iter().all(|m| [
+            method!("newAccount"),
+            method!("setOwner"),
+            method!("setAuthServ")
+        ]
+        .contains(m)));
+        Ok(())
+    }
+
+    #[test]
+    fn verbose_account_creation_names_package() -> Result<(), anyhow::Error> {
+        let packages: Vec<PackageInfo> = serde_json::from_str(
+            r#"[
+{"name":"A","version":"1.0.0","depends":[{"name":"B","version":"^1.0.0"}],"accounts":["alpha","shared"]},
+{"name":"B","version":"1.0.0","depends":[],"accounts":["beta","shared"]}
+]"#,
+        )?;
+        let input = vec![PackageRef {
+            name: "A".to_string(),
+            version: "1.0.0".to_string(),
+        }];
+        let ops = solve_dependencies(packages, input, vec![], &SolveOptions::default())?;
+        let accounts = package_accounts(&ops, &PackageSenders::new(&[])?);
+
+        let labels = |verbose| -> Result<Vec<String>, anyhow::Error> {
+            let mut builder = unsigned_builder(1000);
+            create_accounts(accounts.clone(), verbose, &mut builder)?;
+            Ok(builder
+                .finish()?
+                .into_iter()
+                .map(|(label, _, _)| label)
+                .collect())
+        };
+        // A shared account is attributed to the first package that needs it
+        assert_eq!(
+            labels(true)?,
+            [
+                "Creating beta for B",
+                "Creating shared for B",
+                "Creating alpha for A"
+            ]
+        );
+        assert_eq!(
+            labels(false)?,
+            ["Creating beta", "Creating shared", "Creating alpha"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn install_sender_per_package() -> Result<(), anyhow::Error> {
+        let packages: Vec<PackageInfo> = serde_json::from_str(
+            r#"[
+{"name":"A","version":"1.0.0","depends":[{"name":"B","version":"^1.0.0"}],"accounts":["alpha"]},
+{"name":"B","version":"1.0.0","depends":[],"accounts":["beta"]}
+]"#,
+        )?;
+        let input = vec![PackageRef {
+            name: "A".to_string(),
+            version: "1.0.0".to_string(),
+        }];
+        let ops = solve_dependencies(packages, input, vec![], &SolveOptions::default())?;
+
+        let args = Args::parse_from(["psibase", "install", "A", "-S", "alice", "-S", "B=bob"]);
+        let Command::Install { sender, .. } = &args.command else {
+            panic!("expected install");
+        };
+        let senders = PackageSenders::new(sender)?;
+        assert_eq!(senders.get("A"), account!("alice"));
+        assert_eq!(senders.get("B"), account!("bob"));
+
+        let accounts = package_accounts(&ops, &senders);
+        assert_eq!(
+            accounts
+                .iter()
+                .map(|new| (new.account, new.owner))
+                .collect::<Vec<_>>(),
+            vec![
+                (account!("beta"), account!("bob")),
+                (account!("alpha"), account!("alice"))
+            ]
+        );
+
+        let mut builder = unsigned_builder(1000);
+        create_accounts(accounts, false, &mut builder)?;
+        let owners: Vec<_> = pushed_actions(&builder.finish()?)
+            .into_iter()
+            .filter(|act| act.method == method!("setOwner"))
+            .map(|act| {
+                let args = auth_delegate::action_structs::setOwner::unpacked(&act.rawData).unwrap();
+                (act.sender, args.owner)
+            })
+            .collect();
+        assert_eq!(
+            owners,
+            vec![
+                (account!("beta"), account!("bob")),
+                (account!("alpha"), account!("alice"))
+            ]
+        );
+
+        let twice = Args::parse_from(["psibase", "install", "A", "-S", "alice", "-S", "bob"]);
+        let Command::Install { sender, .. } = &twice.command else {
+            panic!("expected install");
+        };
+        assert!(PackageSenders::new(sender).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http_package_source_requires_insecure_http() -> Result<(), anyhow::Error> {
+        let source = vec!["http://127.0.0.1:1/".to_string()];
+
+        let args = Args::parse_from(["psibase", "list"]);
+        let Err(err) = get_package_registry(&args, &source).await else {
+            panic!("http source should be rejected");
+        };
+        assert!(err.to_string().contains("--insecure-http"));
+
+        // With the flag, the source is used (and fails because nothing is listening)
+        let args = Args::parse_from(["psibase", "--insecure-http", "list"]);
+        let Err(err) = get_package_registry(&args, &source).await else {
+            panic!("nothing should be listening");
+        };
+        assert!(!err.to_string().contains("--insecure-http"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn https_and_directory_sources_are_allowed() -> Result<(), anyhow::Error> {
+        check_package_source("https://example.com/packages/", false)?;
+        let dir = tempfile::tempdir()?;
+        write(dir.path().join("index.json"), "[]")?;
+        let source = vec![dir.path().to_str().unwrap().to_string()];
+        let args = Args::parse_from(["psibase", "list"]);
+        get_package_registry(&args, &source).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn info_lists_versions_by_source() -> Result<(), anyhow::Error> {
+        let entry = |version: &str| {
+            format!(
+                r#"{{"name":"A","version":"{}","description":"","depends":[],"accounts":[],"sha256":"0000000000000000000000000000000000000000000000000000000000000000","file":"A-{}.psi"}}"#,
+                version, version
+            )
+        };
+        let mut dirs = vec![];
+        for versions in [&["1.0.0"][..], &["1.1.0", "1.0.0"], &["0.9.0"]] {
+            let dir = tempfile::tempdir()?;
+            let entries: Vec<_> = versions.iter().map(|v| entry(v)).collect();
+            write(
+                dir.path().join("index.json"),
+                format!("[{}]", entries.join(",")),
+            )?;
+            dirs.push(dir);
+        }
+        let names: Vec<String> = dirs
+            .iter()
+            .map(|dir| dir.path().display().to_string())
+            .collect();
+        let sources = names[..2].to_vec();
+
+        let args = Args::parse_from(["psibase", "info", "A"]);
+        let registry = get_package_registry(&args, &sources).await?;
+        let mut out = Vec::new();
+        let source_names = package_source_names(&args, &sources)?;
+        write_source_versions(&mut out, &registry, &source_names, "A")?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            format!(
+                "available: A-1.0.0 from {0}\navailable: A-1.0.0 from {1}\navailable: A-1.1.0 from {1}\n",
+                names[0], names[1]
+            )
+        );
+
+        let args = Args::parse_from(["psibase", "--prefer-source", &names[2], "info", "A"]);
+        let registry = get_package_registry(&args, &sources).await?;
+        let mut out = Vec::new();
+        let source_names = package_source_names(&args, &sources)?;
+        write_source_versions(&mut out, &registry, &source_names, "A")?;
+        let out = String::from_utf8(out)?;
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[0], format!("available: A-0.9.0 from {}", names[2]));
+        assert_eq!(
+            lines[3],
+            format!(
+                "available: A-1.1.0 from {} (shadowed by --prefer-source)",
+                names[1]
+            )
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lockfile_pins_versions() -> Result<(), anyhow::Error> {
+        let entry = |name: &str, version: &str, sha: char, depends: &str| {
+            format!(
+                r#"{{"name":"{0}","version":"{1}","description":"","depends":[{3}],"accounts":[],"sha256":"{2}","file":"{0}-{1}.psi"}}"#,
+                name,
+                version,
+                sha.to_string().repeat(64),
+                depends
+            )
+        };
+        let dep = r#"{"name":"B","version":"^1.0.0"}"#;
+        let dir = tempfile::tempdir()?;
+        let index = dir.path().join("index.json");
+        write(
+            &index,
+            format!(
+                "[{},{}]",
+                entry("A", "1.0.0", '1', dep),
+                entry("B", "1.0.0", '2', "")
+            ),
+        )?;
+        let sources = vec![dir.path().display().to_string()];
+        let packages = vec!["A".to_string()];
+        let lockfile = dir.path().join("psibase.lock");
+        let args = Args::parse_from(["psibase", "install", "A"]);
+
+        let registry = get_package_registry(&args, &sources).await?;
+        let resolved = registry.resolve_info(&packages)?;
+        write_lockfile(&lockfile, &resolved)?;
+        let lock = read_lockfile(&lockfile)?;
+        assert_eq!(
+            lock,
+            resolved.iter().map(LockedPackage::from).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            lock.iter()
+                .map(|p| format!("{}-{}", p.name, p.version))
+                .collect::<Vec<_>>(),
+            ["B-1.0.0", "A-1.0.0"]
+        );
+
+        // An install locks the installed dependencies that it keeps
+        let mut installed = PackageList::new();
+        installed.insert(
+            meta("B", &[]),
+            PackageOrigin::Installed {
+                owner: account!("root"),
+            },
+        );
+        let ops = installed
+            .resolve_changes(&registry, &packages, &SolveOptions::default())
+            .await?;
+        assert_eq!(ops.len(), 1);
+        assert_eq!(install_lock(&installed, &registry, &ops)?, resolved);
+        installed.insert(
+            meta("C", &[]),
+            PackageOrigin::Installed {
+                owner: account!("root"),
+            },
+        );
+        let err = install_lock(&installed, &registry, &ops).err().unwrap();
+        assert!(err.to_string().contains("C-1.0.0 is installed"));
+
+        // A newer version is ignored while locked
+        write(
+            &index,
+            format!(
+                "[{},{},{}]",
+                entry("A", "1.0.0", '1', dep),
+                entry("A", "1.1.0", '3', dep),
+                entry("B", "1.0.0", '2', "")
+            ),
+        )?;
+        let lock_path = Some(lockfile.clone());
+        let registry = get_package_registry(&args, &sources).await?;
+        assert_eq!(registry.resolve_info(&packages)?[1].version, "1.1.0");
+        let registry = get_locked_registry(&args, &sources, &lock_path).await?;
+        assert_eq!(registry.resolve_info(&packages)?, resolved);
+
+        // The locked version was republished with different content
+        write(
+            &index,
+            format!(
+                "[{},{}]",
+                entry("A", "1.0.0", '4', dep),
+                entry("B", "1.0.0", '2', "")
+            ),
+        )?;
+        let Err(err) = get_locked_registry(&args, &sources, &lock_path).await else {
+            panic!("a changed package should be rejected");
+        };
+        assert!(
+            format!("{:#}", err).contains("A-1.0.0 in the package sources has a different sha256")
+        );
+
+        // The locked version is gone
+        write(
+            &index,
+            format!(
+                "[{},{}]",
+                entry("A", "1.1.0", '3', dep),
+                entry("B", "1.0.0", '2', "")
+            ),
+        )?;
+        let Err(err) = get_locked_registry(&args, &sources, &lock_path).await else {
+            panic!("a missing package should be rejected");
+        };
+        assert!(format!("{:#}", err).contains("A-1.0.0 is not in the package sources"));
+        Ok(())
+    }
+
+    #[test]
+    fn package_table_aligns_columns() -> Result<(), anyhow::Error> {
+        let mut installed = PackageList::new();
+        let mut available = PackageList::new();
+        let mut tokens = meta("Tokens", &[]);
+        tokens.description = "Fungible tokens".to_string();
+        installed.insert(
+            tokens.clone(),
+            PackageOrigin::Installed {
+                owner: account!("root"),
+            },
+        );
+        tokens.version = "1.1.0".to_string();
+        available.insert(
+            tokens,
+            PackageOrigin::Repo {
+                sha256: Default::default(),
+                file: String::new(),
+            },
+        );
+        let mut explorer = meta("Explorer", &[]);
+        explorer.description = "Block explorer for browsing the chain".to_string();
+        available.insert(
+            explorer,
+            PackageOrigin::Repo {
+                sha256: Default::default(),
+                file: String::new(),
+            },
+        );
+        let names = vec!["Explorer".to_string(), "Tokens".to_string()];
+        let rows = package_rows(&names, &installed, &available)?;
+        assert_eq!(
+            format_table(&rows, None),
+            "NAME      INSTALLED  AVAILABLE  DESCRIPTION\n\
+             Explorer             1.0.0      Block explorer for browsing the chain\n\
+             Tokens    1.0.0      1.1.0      Fungible tokens\n"
+        );
+        assert_eq!(
+            format_table(&rows, Some(50)),
+            "NAME      INSTALLED  AVAILABLE  DESCRIPTION\n\
+             Explorer             1.0.0      Block explorer...\n\
+             Tokens    1.0.0      1.1.0      Fungible tokens\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn create_with_auth_delegate() -> Result<(), anyhow::Error> {
+        let actions = create_actions(
+            account!("accounts"),
+            account!("alice"),
+            &None,
+            false,
+            Some(auth_delegate::SERVICE),
+            Some(account!("bob")),
+        )?;
+        let calls: Vec<_> = actions
+            .iter()
+            .map(|act| (act.sender, act.service, act.method))
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                (
+                    account!("accounts"),
+                    accounts::SERVICE,
+                    method!("newAccount")
+                ),
+                (
+                    account!("alice"),
+                    auth_delegate::SERVICE,
+                    method!("setOwner")
+                ),
+                (account!("alice"), accounts::SERVICE, method!("setAuthServ")),
+            ]
+        );
+        assert_eq!(
+            actions[2].rawData,
+            set_auth_service_action(account!("alice"), auth_delegate::SERVICE).rawData
+        );
+
+        let err = create_actions(
+            account!("accounts"),
+            account!("alice"),
+            &None,
+            false,
+            Some(auth_delegate::SERVICE),
+            None,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("--owner"));
+        Ok(())
+    }
+
+    #[test]
+    fn auth_service_requires_matching_key() -> Result<(), anyhow::Error> {
+        let key =
+            AnyPublicKey::from_str("PUB_K1_6MRyAjQq8ud7hVNYcfnVPJqcVpscN5So8BhtHuGYqET5BoDq63")?;
+        let actions = modify_actions(
+            account!("alice"),
+            &Some(key),
+            false,
+            Some(account!("auth-k1")),
+            None,
+        )?;
+        assert_eq!(actions.len(), 2);
+        assert!(modify_actions(
+            account!("alice"),
+            &None,
+            false,
+            Some(account!("auth-k1")),
+            None
+        )
+        .is_err());
+        let key =
+            AnyPublicKey::from_str("PUB_K1_6MRyAjQq8ud7hVNYcfnVPJqcVpscN5So8BhtHuGYqET5BoDq63")?;
+        assert!(modify_actions(
+            account!("alice"),
+            &Some(key),
+            false,
+            Some(account!("auth-sig")),
+            None
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_owner_sends_from_account() -> Result<(), anyhow::Error> {
+        let actions = set_owner_actions(account!("alice"), account!("bob"))?;
+        assert_eq!(actions.len(), 1);
+        assert_eq!(
+            (actions[0].sender, actions[0].service, actions[0].method),
+            (
+                account!("alice"),
+                auth_delegate::SERVICE,
+                method!("setOwner")
             )
-            .await?
+        );
+        let data = auth_delegate::action_structs::setOwner::unpacked(&actions[0].rawData)?;
+        assert_eq!(data.owner, account!("bob"));
+        assert!(set_owner_actions(account!("alice"), account!("alice")).is_err());
+
+        const ALICE: &str =
+            r#"{"data":{"accounts":[{"accountNum":"alice","authService":"auth-delegate"}]}}"#;
+        const BOTH: &str = r#"{"data":{"accounts":[{"accountNum":"alice","authService":"auth-delegate"},{"accountNum":"carol","authService":"auth-sig"}]}}"#;
+        let (api, client) = serve_json(vec![ALICE, BOTH, BOTH]).await;
+        let mut args = Args::parse_from(["psibase", "set-owner", "alice", "bob"]);
+        args.api = api;
+        let err = check_set_owner(&args, client.clone(), account!("alice"), account!("bob"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "The new owner bob does not exist");
+        check_set_owner(&args, client.clone(), account!("alice"), account!("carol")).await?;
+        assert!(
+            check_set_owner(&args, client, account!("carol"), account!("alice"))
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn explicit_tapos_signs_without_node() -> Result<(), anyhow::Error> {
+        // Nothing listens on port 1, so any request would fail
+        let mut args = Args::parse_from([
+            "psibase",
+            "-a",
+            "http://127.0.0.1:1/",
+            "--sign",
+            TEST_KEY,
+            "--tapos-block",
+            "0x01020304:133",
+            "set-owner",
+            "alice",
+            "bob",
+        ]);
+        args.keys = load_keys(&args)?;
+        let tapos = get_tapos(&args, reqwest::Client::new()).await?;
+        let actions = set_owner_actions(account!("alice"), account!("bob"))?;
+        let trx = sign_transaction(with_tapos(&args, &tapos, actions)?, &args.keys)?;
+        assert_eq!(trx.proofs.len(), 1);
+        let trx = Transaction::unpacked(&trx.transaction)?;
+        assert_eq!(trx.tapos.refBlockSuffix, 0x01020304);
+        assert_eq!(trx.tapos.refBlockIndex, 133);
+
+        // The signed transaction is written instead of pushed
+        let dir = tempfile::tempdir()?;
+        let out = dir.path().join("trx.bin");
+        let mut args = Args::parse_from([
+            "psibase",
+            "-a",
+            "http://127.0.0.1:1/",
+            "--sign",
+            TEST_KEY,
+            "--tapos-block",
+            "0x01020304:133",
+            "--expire-after",
+            "3600",
+            "--write-transaction",
+            out.to_str().unwrap(),
+            "set-owner",
+            "alice",
+            "bob",
+        ]);
+        args.keys = load_keys(&args)?;
+        let before = Utc::now();
+        set_owner(
+            &args,
+            reqwest::Client::new(),
+            account!("alice"),
+            account!("bob"),
+        )
+        .await?;
+        let trx = SignedTransaction::unpacked(&std::fs::read(&out)?)?;
+        assert_eq!(trx.proofs.len(), 1);
+        let trx = Transaction::unpacked(&trx.transaction)?;
+        assert_eq!(trx.tapos.refBlockSuffix, 0x01020304);
+        assert!(
+            i64::from(trx.tapos.expiration.seconds) >= before.timestamp() + 3600,
+            "{:?}",
+            trx.tapos.expiration
+        );
+
+        let path = dir.path().join("tapos.json");
+        write(&path, r#"{"refBlockSuffix":7,"refBlockIndex":2}"#)?;
+        let args = Args::parse_from([
+            "psibase",
+            "--tapos-block",
+            path.to_str().unwrap(),
+            "set-owner",
+            "alice",
+            "bob",
+        ]);
+        let tapos = args.tapos_block.unwrap();
+        assert_eq!((tapos.ref_block_suffix, tapos.ref_block_index), (7, 2));
+
+        for bad in ["1:256", "x:1", "0x1:-1", "missing-file.json"] {
+            assert!(
+                Args::try_parse_from(["psibase", "--tapos-block", bad, "set-owner", "a", "b"])
+                    .is_err(),
+                "{}",
+                bad
+            );
         }
-        Command::Upload {
-            service,
+        Ok(())
+    }
+
+    // A module with no imports that exports empty "start" and "called" functions
+    const MINIMAL_SERVICE: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // types: () -> ()
+        0x03, 0x02, 0x01, 0x00, // functions
+        0x07, 0x12, 0x02, // exports
+        0x05, b's', b't', b'a', b'r', b't', 0x00, 0x00, //
+        0x06, b'c', b'a', b'l', b'l', b'e', b'd', 0x00, 0x00, //
+        0x0a, 0x04, 0x01, 0x02, 0x00, 0x0b, // code
+    ];
+
+    #[test]
+    fn verify_service_wasm_accepts_service() {
+        verify_service_wasm(MINIMAL_SERVICE, 65536).unwrap();
+    }
+
+    #[test]
+    fn verify_service_wasm_rejects_oversized_module() {
+        let err = verify_service_wasm(MINIMAL_SERVICE, MINIMAL_SERVICE.len() - 1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Service is {} bytes, which exceeds the transaction size limit of {} bytes",
+                MINIMAL_SERVICE.len(),
+                MINIMAL_SERVICE.len() - 1
+            )
+        );
+        verify_service_wasm(MINIMAL_SERVICE, MINIMAL_SERVICE.len()).unwrap();
+    }
+
+    #[test]
+    fn verify_service_wasm_rejects_other_files() {
+        let err = verify_service_wasm(b"#!/bin/sh\necho hello\n", 65536).unwrap_err();
+        assert!(err.to_string().starts_with("Not a valid wasm module"));
+
+        let mut no_called = MINIMAL_SERVICE.to_vec();
+        let pos = no_called.windows(6).position(|w| w == b"called").unwrap();
+        no_called[pos..pos + 6].copy_from_slice(b"caller");
+        let err = verify_service_wasm(&no_called, 65536).unwrap_err();
+        assert_eq!(err.to_string(), "Missing exported function \"called\"");
+    }
+
+    #[test]
+    fn account_args_report_name_errors() {
+        for (name, reason) in [
+            ("Alice", "'A' is not allowed"),
+            ("1alice", "must begin with a letter"),
+            ("abcdefghijklmnopqrs", "at most 18 characters"),
+        ] {
+            let err =
+                Args::try_parse_from(["psibase", "deploy", name, "service.wasm"]).unwrap_err();
+            assert!(err.to_string().contains(reason), "{}", err);
+            let err =
+                Args::try_parse_from(["psibase", "deploy", "-S", name, "alice", "service.wasm"])
+                    .unwrap_err();
+            assert!(err.to_string().contains(reason), "{}", err);
+        }
+    }
+
+    #[test]
+    fn raw_package_info_decodes_to_meta() {
+        let meta = Meta {
+            name: "Example".to_string(),
+            version: "1.2.0".to_string(),
+            description: "An example package".to_string(),
+            depends: vec![PackageRef {
+                name: "Sites".to_string(),
+                version: "^1.0.0".to_string(),
+            }],
+            accounts: vec![account!("example")],
+            provides: vec![],
+        };
+        let manifest = PackageManifest {
+            services: HashMap::from([(
+                account!("example"),
+                ServiceInfo {
+                    flags: vec!["isSubjective".to_string()],
+                    server: None,
+                },
+            )]),
+            data: vec![],
+        };
+        let text = raw_package_info(&meta, &manifest).unwrap();
+        let field = |name: &str| {
+            let prefix = format!("{}: ", name);
+            let line = text.lines().find(|l| l.starts_with(&prefix)).unwrap();
+            line[prefix.len()..].parse::<Hex<Vec<u8>>>().unwrap()
+        };
+        assert_eq!(Meta::unpacked(&field("meta")).unwrap(), meta);
+        let decoded: PackageManifest = serde_json::from_slice(&field("manifest")).unwrap();
+        assert_eq!(
+            decoded.services[&account!("example")].flags,
+            ["isSubjective"]
+        );
+    }
+
+    // Answers each connection with the next of `responses`. With `repeat`,
+    // the last response keeps being served once the others are used up.
+    // The text of each request is sent to the returned receiver.
+    async fn serve_http(
+        responses: Vec<String>,
+        repeat: bool,
+    ) -> (
+        std::net::SocketAddr,
+        tokio::sync::mpsc::UnboundedReceiver<String>,
+    ) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, requests) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for i in 0.. {
+                let response = match responses.get(i) {
+                    Some(response) => response,
+                    None if repeat && !responses.is_empty() => responses.last().unwrap(),
+                    None => break,
+                };
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    break;
+                };
+                let mut buf = [0u8; 4096];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                let _ = sender.send(String::from_utf8_lossy(&buf[..n]).into_owned());
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (addr, requests)
+    }
+
+    fn json_response(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    // Serves each JSON body to a separate connection. Returns the node's
+    // url and a client that resolves its service subdomains to the server.
+    async fn serve_json(bodies: Vec<&'static str>) -> (Url, reqwest::Client) {
+        let (addr, _) = serve_http(bodies.into_iter().map(json_response).collect(), false).await;
+        let url = Url::parse(&format!("http://psibase.test:{}/", addr.port())).unwrap();
+        let client = reqwest::Client::builder()
+            .resolve("psibase.test", addr)
+            .resolve("accounts.psibase.test", addr)
+            .resolve("explorer.psibase.test", addr)
+            .resolve("sites.psibase.test", addr)
+            .resolve("packages.psibase.test", addr)
+            .build()
+            .unwrap();
+        (url, client)
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn progress_without_terminal_prints_plain_lines() -> Result<(), anyhow::Error> {
+        let args = Args::parse_from(["psibase", "--no-progress", "list"]);
+        assert!(matches!(
+            Progress::new(&args, 3, "files", false)?,
+            Progress::Lines(_)
+        ));
+
+        let buf = SharedBuf::default();
+        let progress = Progress::Lines(LineProgress::new(
+            3,
+            "packages",
+            std::time::Duration::from_secs(3600),
+            Box::new(buf.clone()),
+        ));
+        progress.set_message("Installing a-1.0.0".to_string());
+        progress.inc(1);
+        // Too soon after the previous line
+        progress.inc(1);
+        progress.suspend(&mut || ());
+        progress.inc(1);
+        progress.finish_and_clear();
+        progress.finish_with_message("Ok");
+        let out = String::from_utf8(buf.0.lock().unwrap().clone())?;
+        assert_eq!(
+            out,
+            "1/3 packages: Installing a-1.0.0\n3/3 packages: Installing a-1.0.0\nOk\n"
+        );
+        assert!(!out.contains('\x1b'));
+        Ok(())
+    }
+
+    // Records every message logged by the tests
+    struct CaptureLogger;
+
+    static LOGGED: std::sync::Mutex<Vec<(log::Level, String)>> = std::sync::Mutex::new(vec![]);
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &log::Record) {
+            LOGGED
+                .lock()
+                .unwrap()
+                .push((record.level(), record.args().to_string()));
+        }
+        fn flush(&self) {}
+    }
+
+    #[tokio::test]
+    async fn requests_are_logged_at_debug() -> Result<(), anyhow::Error> {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CaptureLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Debug);
+        });
+        let args = Args::parse_from(["psibase", "--log-level", "debug", "list"]);
+        assert_eq!(args.log_level, Some(log::LevelFilter::Debug));
+
+        let (url, client) = serve_json(vec![r#"{"refBlockSuffix":1,"refBlockIndex":2}"#]).await;
+        get_tapos_for_head(&url, client).await?;
+        let expected = format!("GET {}common/tapos/head", url);
+        assert!(LOGGED
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(level, message)| *level == log::Level::Debug && *message == expected));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_uploads_reports_corrupted_files() -> Result<(), anyhow::Error> {
+        let file = |path: &str, content: &str| UploadedFile {
+            path: path.to_string(),
+            content_type: "text/html".to_string(),
+            sha256: <[u8; 32]>::from(Sha256::digest(content)).into(),
+            size: content.len() as u64,
+        };
+        let files = [file("/index.html", "<html></html>"), file("/a.html", "a")];
+        let sites = account!("sites");
+        assert_eq!(content_host(sites, account!("alice")), account!("alice"));
+        assert_eq!(content_host(account!("other"), sites), account!("other"));
+        let one = NonZeroUsize::new(1).unwrap();
+
+        let (url, client) = serve_json(vec!["<html></html>", "a"]).await;
+        let args = Args::parse_from(["psibase", "-a", url.as_str(), "list"]);
+        verify_uploads(&args, &client, sites, &files, one).await?;
+
+        let (url, client) = serve_json(vec!["<html></html>", "b"]).await;
+        let args = Args::parse_from(["psibase", "-a", url.as_str(), "list"]);
+        let err = verify_uploads(&args, &client, sites, &files, one)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "1/2 uploaded files don't match the local files"
+        );
+        Ok(())
+    }
+
+    const INSTALLED: &str = r#"{"data":{"installed":{"pageInfo":{"hasNextPage":false,"endCursor":""},"edges":[{"node":{"name":"A","version":"1.0.0","description":"","depends":[],"accounts":[],"owner":"root"}}]}}}"#;
+
+    #[test]
+    fn solve_errors_name_the_option() {
+        let err = with_solve_hint(
+            SolveError::Downgrade {
+                package: "A".to_string(),
+                installed: "1.1.0".to_string(),
+                candidate: "1.0.0".to_string(),
+            }
+            .into(),
+        );
+        assert_eq!(
+            err.to_string(),
+            "Refusing to downgrade A from 1.1.0 to 1.0.0; pass --allow-downgrade to allow it"
+        );
+        let err = with_solve_hint(
+            SolveError::AmbiguousProvider {
+                name: "V".to_string(),
+                providers: "A, B".to_string(),
+            }
+            .into(),
+        );
+        assert_eq!(
+            err.to_string(),
+            "V is provided by several packages: A, B; choose one with --prefer"
+        );
+        let err = with_solve_hint(SolveError::CannotResolvePackages.into());
+        assert_eq!(err.to_string(), "Cannot resolve packages");
+    }
+
+    #[test]
+    fn discover_only_without_explicit_api() -> Result<(), anyhow::Error> {
+        let discover = |argv: &[&str]| -> Result<bool, anyhow::Error> {
+            let matches = Args::command().try_get_matches_from(argv)?;
+            Ok(should_discover(
+                &Args::from_arg_matches(&matches)?,
+                &matches,
+            ))
+        };
+        if std::env::var_os("PSINODE_URL").is_none() {
+            assert!(discover(&["psibase", "--discover", "list"])?);
+        }
+        assert!(!discover(&["psibase", "list"])?);
+        assert!(!discover(&[
+            "psibase",
+            "--discover",
+            "-a",
+            DEFAULT_API,
+            "list"
+        ])?);
+        assert!(!discover(&[
+            "psibase",
+            "--discover",
+            "-a",
+            "http://127.0.0.1:8080/",
+            "list"
+        ])?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn discover_finds_node_from_config() -> Result<(), anyhow::Error> {
+        let (url, client) = serve_json(vec!["\"\""]).await;
+        let port = url.port().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let closed = listener.local_addr()?.port();
+        drop(listener);
+
+        // Nothing listens on the port in the first config
+        let dir = tempfile::tempdir()?;
+        for (name, port) in [("a-stale", closed), ("db", port)] {
+            create_dir(dir.path().join(name))?;
+            write(
+                dir.path().join(name).join("config"),
+                format!(
+                    "p2p      = off\nhost     = psibase.test\nlisten   = 127.0.0.1:{}\nlisten   = /tmp/psinode.sock\n\n[logger.stderr]\nhost = other\n",
+                    port
+                ),
+            )?;
+        }
+        let candidates = discovery_candidates(dir.path());
+        let urls: Vec<_> = candidates.iter().map(|(url, _)| url.to_string()).collect();
+        assert_eq!(
+            urls,
+            [
+                format!("http://psibase.test:{}/", closed),
+                format!("http://psibase.test:{}/", port),
+                "http://psibase.127.0.0.1.sslip.io:8080/".to_string(),
+                "http://psibase.127.0.0.1.sslip.io:8081/".to_string(),
+                "http://psibase.127.0.0.1.sslip.io:8090/".to_string(),
+            ]
+        );
+
+        let (found, source) = discover_api(&candidates[..2], &client).await?;
+        assert_eq!(found, url);
+        assert_eq!(
             source,
-            dest,
-            content_type,
-            recursive,
-            sender,
-        } => {
-            if *recursive {
-                if content_type.is_some() {
-                    return Err(anyhow!("--recursive is incompatible with --content-type"));
-                }
-                upload_tree(&args, client, (*service).into(), *sender, dest, source).await?
-            } else {
-                upload(
+            dir.path().join("db").join("config").display().to_string()
+        );
+
+        let err = discover_api(&candidates[..1], &client).await.unwrap_err();
+        assert!(err.to_string().starts_with("No running psinode was found"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replace_removes_files_before_dependencies() -> Result<(), anyhow::Error> {
+        // P stores files in D's service. D is replaced first, so P's
+        // stale files must be removed while D still has its old code.
+        let dir = tempfile::tempdir()?;
+        let write_package = |meta: Meta, files: &[(&str, &[u8])]| -> Result<_, anyhow::Error> {
+            let src = tempfile::tempdir()?;
+            write(src.path().join("meta.json"), serde_json::to_vec(&meta)?)?;
+            for (name, contents) in files {
+                let path = src.path().join(name);
+                std::fs::create_dir_all(path.parent().unwrap())?;
+                write(path, contents)?;
+            }
+            let file = format!("{}-{}.psi", meta.name, meta.version);
+            write_package_dir(src.path(), File::create(dir.path().join(&file))?)?;
+            Ok(meta.info(Checksum256::default(), file))
+        };
+        let meta = |name: &str, version: &str, depends: &[&str], accounts: &[AccountNumber]| Meta {
+            name: name.to_string(),
+            version: version.to_string(),
+            description: String::new(),
+            depends: depends
+                .iter()
+                .map(|dep| PackageRef {
+                    name: dep.to_string(),
+                    version: "*".to_string(),
+                })
+                .collect(),
+            accounts: accounts.to_vec(),
+            provides: vec![],
+        };
+        let (dsvc, psvc, other) = (account!("dsvc"), account!("psvc"), account!("other"));
+        let d = write_package(
+            meta("D", "1.1.0", &[], &[dsvc]),
+            &[("service/dsvc.wasm", b"")],
+        )?;
+        let p = write_package(
+            meta("P", "1.1.0", &["D"], &[psvc, other]),
+            &[
+                ("service/psvc.wasm", b""),
+                ("data/psvc@dsvc/kept.html", b"<html/>"),
+                ("data/other@dsvc/moved.html", b"<html/>"),
+            ],
+        )?;
+        let ops = vec![
+            PackageOp::Replace(meta("D", "1.0.0", &[], &[dsvc]), d),
+            PackageOp::Replace(meta("P", "1.0.0", &["D"], &[psvc, other]), p),
+        ];
+        let (url, mut client) = serve_json(vec![
+            r#"{"services":{"dsvc":{"flags":[],"server":null}},"data":[]}"#,
+            r#"{"services":{"psvc":{"flags":[],"server":"psvc"}},"data":[
+                {"account":"psvc","service":"dsvc","filename":"/stale.html"},
+                {"account":"psvc","service":"dsvc","filename":"/kept.html"},
+                {"account":"psvc","service":"dsvc","filename":"/moved.html"}]}"#,
+        ])
+        .await;
+        let registry = DirectoryRegistry::new(dir.path().to_path_buf());
+        let mut builder = unsigned_builder(1 << 20);
+        apply_packages(
+            &url,
+            &mut client,
+            &registry,
+            ops,
+            &mut vec![],
+            &mut 0,
+            &mut builder,
+            &PackageSenders::new(&[])?,
+            &None,
+            false,
+        )
+        .await?;
+
+        let groups = builder.finish()?;
+        assert_eq!(groups[0].0, "Removing out-dated files of P");
+        let actions: Vec<(AccountNumber, AccountNumber, String)> = pushed_actions(&groups)
+            .into_iter()
+            .map(|act| {
+                let detail = if act.method == method!("removeSys") {
+                    sites::action_structs::removeSys::unpacked(&act.rawData)
+                        .unwrap()
+                        .path
+                } else if act.method == method!("storeSys") {
+                    sites::action_structs::storeSys::unpacked(&act.rawData)
+                        .unwrap()
+                        .path
+                } else {
+                    String::new()
+                };
+                (
+                    act.sender,
+                    act.service,
+                    format!("{} {}", act.method, detail),
+                )
+            })
+            .collect();
+        let position = |entry: (AccountNumber, AccountNumber, &str)| {
+            let found: Vec<_> = actions
+                .iter()
+                .enumerate()
+                .filter(|(_, (sender, service, detail))| {
+                    (*sender, *service, detail.trim_end()) == entry
+                })
+                .map(|(i, _)| i)
+                .collect();
+            assert_eq!(found.len(), 1, "{:?} in {:?}", entry, actions);
+            found[0]
+        };
+        let new_code = position((dsvc, account!("setcode"), "setcode"));
+        assert!(position((psvc, dsvc, "removesys /stale.html")) < new_code);
+        assert!(position((psvc, dsvc, "removesys /moved.html")) < new_code);
+        assert!(position((other, dsvc, "storesys /moved.html")) > new_code);
+        // psvc no longer has a server
+        position((psvc, account!("http-server"), "registerserver"));
+        assert!(!actions
+            .iter()
+            .any(|(_, _, detail)| detail == "removesys /kept.html"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn installed_queries_skip_package_registry() -> Result<(), anyhow::Error> {
+        // Building a registry from this source would fail
+        let dir = tempfile::tempdir()?;
+        let sources = vec![dir.path().join("missing").display().to_string()];
+
+        let (url, client) = serve_json(vec![INSTALLED]).await;
+        let args = Args::parse_from(["psibase", "-a", url.as_str(), "list"]);
+        list(
+            &args,
+            client,
+            false,
+            false,
+            true,
+            &sources,
+            ListFormat::Text,
+        )
+        .await?;
+
+        let (url, client) = serve_json(vec![INSTALLED, r#"{"services":{},"data":[]}"#]).await;
+        let args = Args::parse_from(["psibase", "-a", url.as_str(), "info", "A"]);
+        package_info(&args, client, &vec!["A".to_string()], &sources, false).await?;
+
+        // A package that is not installed needs the registry
+        let (url, client) = serve_json(vec![INSTALLED]).await;
+        let args = Args::parse_from(["psibase", "-a", url.as_str(), "info", "A"]);
+        let err = package_info(&args, client, &vec!["B".to_string()], &sources, false)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("missing"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn sign_resolves_keystore_names() -> Result<(), anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        let keystore_dir = dir.path().to_str().unwrap();
+        let args = Args::parse_from([
+            "psibase",
+            "--keystore",
+            keystore_dir,
+            "--sign",
+            TEST_KEY,
+            "key",
+            "remove",
+            "alice",
+        ]);
+        // Keys that are not in the keystore are used directly
+        assert_eq!(load_keys(&args)?.len(), 1);
+
+        let keystore = open_keystore(&args)?;
+        assert_eq!(keystore.dir(), dir.path());
+        keystore.import("alice", TEST_KEY, "password")?;
+        assert_eq!(keystore.list()?.len(), 1);
+        let Command::Key { command } = &args.command else {
+            panic!("expected key command")
+        };
+        manage_keys(&keystore, command)?;
+        assert!(keystore.list()?.is_empty());
+        assert!(manage_keys(&keystore, command).is_err());
+
+        let args = Args::parse_from(["psibase", "--sign", "alice", "key", "list"]);
+        assert!(load_keys(&args).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn install_summary_counts_plan() -> Result<(), anyhow::Error> {
+        let plan = vec![
+            PlannedOp::Install {
+                name: "B".to_string(),
+                version: "1.0.0".to_string(),
+                sha256: Default::default(),
+            },
+            PlannedOp::Replace {
+                name: "A".to_string(),
+                old_version: "1.0.0".to_string(),
+                version: "1.1.0".to_string(),
+                sha256: Default::default(),
+            },
+            PlannedOp::Install {
+                name: "C".to_string(),
+                version: "1.0.0".to_string(),
+                sha256: Default::default(),
+            },
+            PlannedOp::Remove {
+                name: "D".to_string(),
+                version: "1.0.0".to_string(),
+            },
+        ];
+        let trx = SignedTransaction {
+            transaction: Transaction::default().packed().into(),
+            proofs: vec![],
+        };
+        let size = trx.packed().len() as u64;
+        let accounts = vec![("Creating accounts".to_string(), vec![trx.clone()], false)];
+        let packages = vec![
+            ("Installing B-1.0.0".to_string(), vec![trx.clone()], false),
+            (
+                "Updating A-1.0.0 -> A-1.1.0".to_string(),
+                vec![trx.clone(), trx.clone()],
+                false,
+            ),
+        ];
+
+        let summary = InstallSummary::new(&plan, 5000, 3, &[&accounts, &packages]);
+        assert_eq!(
+            summary,
+            InstallSummary {
+                install: 2,
+                replace: 1,
+                remove: 1,
+                package_size: 5000,
+                accounts: 3,
+                transactions: 4,
+                bytes: 4 * size,
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            format!(
+                "Packages:     2 to install, 1 to replace, 1 to remove\n\
+                 Package size: 4.88 KiB\n\
+                 Accounts:     3 to create\n\
+                 Transactions: 4, {}",
+                HumanBytes(4 * size)
+            )
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn safe_deploy_stops_when_shadow_fails() -> Result<(), anyhow::Error> {
+        const TAPOS: &str = r#"{"refBlockSuffix":0,"refBlockIndex":0}"#;
+        const OK: &str = r#"{"actionTraces":[],"error":null}"#;
+        const FAILED: &str = r#"{"actionTraces":[],"error":"setCode: invalid wasm"}"#;
+        let dir = tempfile::tempdir()?;
+        let wasm = dir.path().join("service.wasm");
+        std::fs::write(&wasm, b"\0asm\x01\0\0\0")?;
+        let wasm = wasm.to_str().unwrap();
+        let deploy_with = |url: &Url, client| {
+            let args =
+                Args::parse_from(["psibase", "-a", url.as_str(), "--trace", "error", "list"]);
+            let wasm = wasm.to_string();
+            async move {
+                deploy(
                     &args,
                     client,
-                    (*service).into(),
-                    *sender,
-                    dest,
-                    content_type,
-                    source,
+                    account!("accounts"),
+                    account!("alice"),
+                    &wasm,
+                    &None,
+                    false,
+                    false,
+                    Some(account!("alice-shadow")),
                 )
-                .await?
+                .await
+            }
+        };
+
+        let (url, client) = serve_json(vec![TAPOS, OK, OK]).await;
+        deploy_with(&url, client).await?;
+
+        // The deploy to alice would succeed, but is never pushed
+        let (url, client) = serve_json(vec![TAPOS, FAILED, OK]).await;
+        let err = deploy_with(&url, client).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Deploying to alice-shadow failed; alice was not changed"
+        );
+        assert!(format!("{:#}", err).contains("setCode: invalid wasm"));
+
+        let (url, client) = serve_json(vec![]).await;
+        let args = Args::parse_from(["psibase", "-a", url.as_str(), "list"]);
+        let err = deploy(
+            &args,
+            client,
+            account!("accounts"),
+            account!("alice"),
+            wasm,
+            &None,
+            false,
+            false,
+            Some(account!("alice")),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--safe-deploy needs an account other than alice"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn deploy_dir_sets_code_for_each_service() -> Result<(), anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("beta.wasm"), b"beta")?;
+        std::fs::write(dir.path().join("alpha.wasm"), b"alpha")?;
+        std::fs::write(
+            dir.path().join("alpha.json"),
+            r#"{"flags":["allowSudo"],"server":"alpha"}"#,
+        )?;
+        std::fs::write(dir.path().join("README.md"), b"not a service")?;
+
+        let services = deploy_dir_actions(dir.path(), account!("accounts"), &None, true)?;
+        assert_eq!(
+            services.iter().map(|(a, _)| *a).collect::<Vec<_>>(),
+            vec![account!("alpha"), account!("beta")]
+        );
+        let set_code: Vec<_> = services
+            .iter()
+            .flat_map(|(_, actions)| actions)
+            .filter(|act| act.method == method!("setCode"))
+            .map(|act| act.packed())
+            .collect();
+        assert_eq!(
+            set_code,
+            vec![
+                set_code_action(account!("alpha"), b"alpha".to_vec()).packed(),
+                set_code_action(account!("beta"), b"beta".to_vec()).packed(),
+            ]
+        );
+        let methods = |actions: &[Action]| actions.iter().map(|act| act.method).collect::<Vec<_>>();
+        assert_eq!(
+            methods(&services[0].1),
+            vec![
+                method!("newAccount"),
+                method!("setCode"),
+                method!("setFlags"),
+                method!("registerServer"),
+            ]
+        );
+        assert_eq!(
+            methods(&services[1].1),
+            vec![method!("newAccount"), method!("setCode")]
+        );
+
+        std::fs::write(dir.path().join("Not_An_Account.wasm"), b"")?;
+        assert!(deploy_dir_actions(dir.path(), account!("accounts"), &None, false).is_err());
+        Ok(())
+    }
+
+    // Interrupts the follow loop once it has printed `last`
+    struct StopAfter {
+        out: Vec<u8>,
+        last: &'static str,
+        interrupted: Arc<AtomicBool>,
+    }
+
+    impl std::io::Write for StopAfter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.out.extend_from_slice(buf);
+            if String::from_utf8_lossy(&self.out).contains(self.last) {
+                self.interrupted.store(true, Ordering::Relaxed);
             }
+            Ok(buf.len())
         }
-        Command::Install {
-            packages,
-            key,
-            package_source,
-            sender,
-            reinstall,
-        } => {
-            install(
-                &args,
-                client,
-                packages,
-                (*sender).into(),
-                key,
-                package_source,
-                *reinstall,
-            )
-            .await?
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
         }
-        Command::List {
-            all,
-            available,
-            installed,
-            package_source,
-        } => list(&args, client, *all, *available, *installed, package_source).await?,
-        Command::Search {
-            patterns,
-            package_source,
-        } => search(&args, client, patterns, package_source).await?,
-        Command::Info {
-            packages,
-            package_source,
-        } => package_info(&args, client, packages, package_source).await?,
-        Command::CreateToken {
-            expires_after,
-            mode,
-        } => create_token(Duration::seconds(*expires_after), mode)?,
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn follow_prints_new_blocks_across_reconnects() -> Result<(), anyhow::Error> {
+        const HEAD: &str = r#"{"data":{"blocks":{"edges":[{"node":{"header":{"blockNum":10,"time":"2024-01-01T00:00:10.000Z"},"transactions":[]}}]}}}"#;
+        const FIRST: &str = r#"{"data":{"blocks":{"edges":[{"node":{"header":{"blockNum":11,"time":"2024-01-01T00:00:11.000Z"},"transactions":[{"transaction":{"actions":[{"sender":"alice","service":"tokens","method":"credit"}]}}]}},{"node":{"header":{"blockNum":12,"time":"2024-01-01T00:00:12.000Z"},"transactions":[]}}]}}}"#;
+        const EMPTY: &str = r#"{"data":{"blocks":{"edges":[]}}}"#;
+        const BROKEN: &str = "<html>";
+        const SECOND: &str = r#"{"data":{"blocks":{"edges":[{"node":{"header":{"blockNum":13,"time":"2024-01-01T00:00:13.000Z"},"transactions":[]}}]}}}"#;
+
+        let (url, client) = serve_json(vec![HEAD, FIRST, EMPTY, BROKEN, SECOND]).await;
+        let args = Args::parse_from(["psibase", "-a", url.as_str(), "list"]);
+        let mut out = StopAfter {
+            out: vec![],
+            last: "block 13",
+            interrupted: args.interrupted.clone(),
+        };
+        follow_blocks(
+            &args,
+            client,
+            &mut out,
+            std::time::Duration::from_millis(10),
+        )
+        .await?;
+        assert_eq!(
+            String::from_utf8(out.out)?,
+            "block 11 2024-01-01T00:00:11.000Z\n  alice => tokens::credit\nblock 12 2024-01-01T00:00:12.000Z\nblock 13 2024-01-01T00:00:13.000Z\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prune_removes_only_orphaned_files() -> Result<(), anyhow::Error> {
+        const PAGE1: &str = r#"{"data":{"content":{"pageInfo":{"hasNextPage":true,"endCursor":"c1"},"edges":[{"node":{"account":"alice","path":"/app"}},{"node":{"account":"alice","path":"/app-old/index.html"}},{"node":{"account":"alice","path":"/app/index.html"}}]}}}"#;
+        const PAGE2: &str = r#"{"data":{"content":{"pageInfo":{"hasNextPage":true,"endCursor":"c2"},"edges":[{"node":{"account":"alice","path":"/app/old.js"}},{"node":{"account":"alice","path":"/other.html"}}]}}}"#;
+
+        let (url, mut client) = serve_json(vec![PAGE1, PAGE2]).await;
+        let stored = site_files(&url, &mut client, account!("alice"), "/app").await?;
+        assert_eq!(stored, vec!["/app", "/app/index.html", "/app/old.js"]);
+
+        let uploaded = vec![UploadedFile {
+            path: "/app/index.html".to_string(),
+            content_type: "text/html".to_string(),
+            sha256: Checksum256::default(),
+            size: 0,
+        }];
+        let removals = prune_actions(sites::SERVICE, account!("alice"), "/app", stored, &uploaded);
+        let paths: Vec<_> = removals.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, vec!["/app", "/app/old.js"]);
+        for (path, act) in &removals {
+            assert_eq!(act.sender, account!("alice"));
+            assert_eq!(act.method, method!("removeSys"));
+            let args = sites::action_structs::removeSys::unpacked(&act.rawData)?;
+            assert_eq!(&args.path, path);
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_sessions_makes_existing_file_private() -> Result<(), anyhow::Error> {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("sessions.json");
+        std::fs::write(&file, "{}")?;
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o644))?;
+        write_sessions(&file, &mut HashMap::new())?;
+        assert_eq!(
+            std::fs::metadata(&file)?.permissions().mode() & 0o777,
+            0o600
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn login_token_is_sent_to_node() -> Result<(), anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        let sessions = dir.path().join("sessions.json");
+        let (addr, mut requests) = serve_http(vec![json_response("{}")], false).await;
+        let api = format!("http://{}/", addr);
+        let parse = |command: &str| {
+            Args::parse_from([
+                "psibase",
+                "-a",
+                &api,
+                "--sessions",
+                sessions.to_str().unwrap(),
+                command,
+            ])
+        };
+
+        login(&parse("login"), "secret", Duration::seconds(60), "r")?;
+        let saved = read_sessions(&sessions)?;
+        let session = &saved[&session_key(&Url::parse(&api)?)];
+        assert_eq!(session.token, sign_token("secret", session.expires, "r")?);
+
+        let mut args = parse("list");
+        args.session_token = load_session(&args);
+        assert_eq!(args.session_token.as_ref(), Some(&session.token));
+        let client = build_client(&args)?;
+        let _ = client.get(args.api.join("status")?).send().await;
+        let request = requests.recv().await.unwrap().to_lowercase();
+        assert!(request.contains(&format!(
+            "authorization: bearer {}",
+            session.token.to_lowercase()
+        )));
+
+        let node = Url::parse("http://psibase.test:8080/")?;
+        assert!(is_node_host(
+            &node,
+            &Url::parse("https://packages.psibase.test/")?
+        ));
+        assert!(!is_node_host(
+            &node,
+            &Url::parse("https://notpsibase.test/")?
+        ));
+
+        logout(&parse("logout"))?;
+        assert!(load_session(&parse("list")).is_none());
+        assert!(logout(&parse("logout")).is_err());
+
+        std::fs::write(&sessions, "not json")?;
+        assert!(load_session(&parse("list")).is_none());
+        assert!(login(&parse("login"), "secret", Duration::seconds(60), "r").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_package_sources_limit_redirects() -> Result<(), anyhow::Error> {
+        let (target, _) = serve_http(
+            vec!["HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok".to_string()],
+            true,
+        )
+        .await;
+        let target = Url::parse(&format!("http://{}/", target))?;
+        let (origin, _) = serve_http(
+            vec![format!(
+                "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                target
+            )],
+            true,
+        )
+        .await;
+        let origin = Url::parse(&format!("http://{}/", origin))?;
+
+        let args = Args::parse_from(["psibase", "-a", origin.as_str(), "list"]);
+        let response = build_client(&args)?.get(origin.clone()).send().await?;
+        assert_eq!(response.url(), &target);
+
+        let err = build_registry_client(&args, &origin)?
+            .get(origin.clone())
+            .send()
+            .await
+            .err()
+            .unwrap();
+        assert!(err.is_redirect());
+        Ok(())
+    }
 }
@@ -2,7 +2,7 @@ use crate::services::{accounts, auth_delegate, http_server, packages, setcode, s
 use crate::{
     new_account_action, reg_server, set_auth_service_action, set_code_action, set_key_action,
     solve_dependencies, version_match, AccountNumber, Action, AnyPublicKey, Checksum256,
-    GenesisService, Pack, PackageDisposition, PackageOp, Reflect, Unpack, Version,
+    GenesisService, Pack, PackageDisposition, PackageOp, Reflect, SolveOptions, Unpack, Version,
 };
 use anyhow::Context;
 use custom_error::custom_error;
@@ -18,32 +18,53 @@ use zip::ZipArchive;
 use async_trait::async_trait;
 use std::fs::File;
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[cfg(not(target_family = "wasm"))]
 use crate::ChainUrl;
 #[cfg(not(target_family = "wasm"))]
+use flate2::read::GzDecoder;
+#[cfg(not(target_family = "wasm"))]
+use log::debug;
+#[cfg(not(target_family = "wasm"))]
 use sha2::{Digest, Sha256};
 #[cfg(not(target_family = "wasm"))]
 use std::io::Write;
 #[cfg(not(target_family = "wasm"))]
-use tempfile::tempfile;
+use tempfile::NamedTempFile;
 
 custom_error! {
     pub Error
         MissingMeta          = "Service does not contain meta.json",
-    InvalidFlags = "Invalid service flags",
-    DependencyCycle = "Cycle in service dependencies",
+    InvalidFlags{flag: String, closest: Option<String>} = @{
+        match closest {
+            Some(closest) => format!("Invalid service flag {}. Did you mean {}?", flag, closest),
+            None => format!("Invalid service flag {}. Valid flags are: {}", flag, known_flags().join(", ")),
+        }
+    },
+    DependencyCycle{cycle: String} = "Cycle in service dependencies: {cycle}",
     UnknownFileType{path:String} = "Cannot determine Mime-Type for {path}",
     UnknownAccount{name:AccountNumber} = "Account {name} not defined in meta.json",
+    NoAccounts{package: String, names: String} = "The package {package} has files for {names}, but the accounts in its meta.json are empty. Add each of these accounts to `accounts`.",
     AccountConflict{name: AccountNumber, old: String, new: String} = "The account {name} is defined by more than one package: {old}, {new}",
     MissingDepAccount{name: AccountNumber, package: String} = "The account {name} required by {package} is not defined by any package",
     MissingDepPackage{name: String, dep: String} = "The package {name} uses {dep} but does not depend on it",
     PackageNotFound{package: String} = "The package {package} was not found",
+    LockedPackageMissing{package: String} = "The locked package {package} is not in the package sources",
+    LockedPackageChanged{package: String} = "The locked package {package} in the package sources has a different sha256 from the lockfile",
     DuplicatePackage{package: String} = "The package {package} was declared multiple times in the package index",
     PackageDigestFailure{package: String} = "The package file for {package} does not match the package index",
-    PackageMetaMismatch{package: String} = "The package metadata for {package} does not match the package index",
+    PackageMetaMismatch{package: String, fields: String} = "The package metadata for {package} does not match the package index: {fields} differ",
     CrossOriginFile{file: String} = "The package file {file} has a different origin from the package index",
+    CrossOriginRedirect{from: String, to: String} = "Refusing to follow a redirect from {from} to {to}, which has a different origin",
+    TooManyRedirects{url: String, limit: usize} = "Too many redirects fetching {url}: the limit is {limit}",
+    PackageSourceNotFound{path: String} = "The package source {path} is not a directory",
+    MissingPackageIndex{path: String} = "No package index found at {path}",
+    InvalidPackageIndex{path: String, message: String} = "The package index {path} is malformed: {message}",
+    PackageTooLarge{file: String, limit: u64} = "The package file {file} is larger than the limit of {limit} bytes",
+    IndexTooLarge{url: String, limit: u64} = "The package index {url} is larger than the limit of {limit} bytes when decompressed",
+    UnexpectedPackageOp{op: String} = "Only installs are expected when there are no existing packages, but the dependency solver returned: {op}",
+    UnexpectedPackageFile{path: String} = "{path} does not belong in a package. A package contains meta.json, service/<account>.wasm, service/<account>.json, data/<account>/..., data/<account>@<service>/..., and script/postinstall.json",
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, Pack, Unpack, Reflect)]
@@ -66,6 +87,11 @@ pub struct Meta {
     pub depends: Vec<PackageRef>,
     #[serde(default)]
     pub accounts: Vec<AccountNumber>,
+    /// Virtual packages that this package can stand in for. A dependency
+    /// on a name that no package has is satisfied by a package that
+    /// provides a matching version of it.
+    #[serde(default)]
+    pub provides: Vec<PackageRef>,
 }
 
 impl Meta {
@@ -76,12 +102,31 @@ impl Meta {
             description: self.description.clone(),
             depends: self.depends.clone(),
             accounts: self.accounts.clone(),
+            provides: self.provides.clone(),
             sha256,
             file,
         };
     }
 }
 
+/// One exact package in a lockfile
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LockedPackage {
+    pub name: String,
+    pub version: String,
+    pub sha256: Checksum256,
+}
+
+impl From<&PackageInfo> for LockedPackage {
+    fn from(info: &PackageInfo) -> Self {
+        LockedPackage {
+            name: info.name.clone(),
+            version: info.version.clone(),
+            sha256: info.sha256.clone(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
 pub struct PackageInfo {
     pub name: String,
@@ -93,21 +138,61 @@ pub struct PackageInfo {
     #[serde(default)]
     pub accounts: Vec<AccountNumber>,
     #[serde(default)]
+    pub provides: Vec<PackageRef>,
+    #[serde(default)]
     pub sha256: Checksum256,
     #[serde(default)]
     pub file: String,
 }
 
 impl PackageInfo {
-    fn meta(&self) -> Meta {
+    pub fn meta(&self) -> Meta {
         Meta {
             name: self.name.clone(),
             version: self.version.clone(),
             description: self.description.clone(),
             depends: self.depends.clone(),
             accounts: self.accounts.clone(),
+            provides: self.provides.clone(),
         }
     }
+    /// Checks that this index entry matches the meta.json of the package
+    /// file that it refers to. The order of `depends`, `accounts`, and
+    /// `provides` does not matter.
+    pub fn check_meta(&self, meta: &Meta) -> Result<(), Error> {
+        fn sorted<T: Clone, K: Ord>(items: &[T], key: impl FnMut(&T) -> K) -> Vec<T> {
+            let mut result = items.to_vec();
+            result.sort_by_key(key);
+            result
+        }
+        let dep_key = |dep: &PackageRef| (dep.name.clone(), dep.version.clone());
+        let mut fields = vec![];
+        if self.name != meta.name {
+            fields.push("name");
+        }
+        if self.version != meta.version {
+            fields.push("version");
+        }
+        if self.description != meta.description {
+            fields.push("description");
+        }
+        if sorted(&self.depends, dep_key) != sorted(&meta.depends, dep_key) {
+            fields.push("depends");
+        }
+        if sorted(&self.accounts, |a| a.value) != sorted(&meta.accounts, |a| a.value) {
+            fields.push("accounts");
+        }
+        if sorted(&self.provides, dep_key) != sorted(&meta.provides, dep_key) {
+            fields.push("provides");
+        }
+        if !fields.is_empty() {
+            Err(Error::PackageMetaMismatch {
+                package: format!("{}-{}", self.name, self.version),
+                fields: fields.join(", "),
+            })?
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
@@ -118,6 +203,9 @@ pub struct InstalledPackageInfo {
     pub depends: Vec<PackageRef>,
     pub accounts: Vec<AccountNumber>,
     pub owner: AccountNumber,
+    /// Packages installed before the chain recorded `provides` have none
+    #[serde(default)]
+    pub provides: Option<Vec<PackageRef>>,
 }
 
 impl InstalledPackageInfo {
@@ -128,6 +216,7 @@ impl InstalledPackageInfo {
             description: self.description.clone(),
             depends: self.depends.clone(),
             accounts: self.accounts.clone(),
+            provides: self.provides.clone().unwrap_or_default(),
         }
     }
 }
@@ -151,26 +240,122 @@ pub struct ServiceInfo {
     pub server: Option<AccountNumber>,
 }
 
+impl ServiceInfo {
+    /// The bits in CodeRow::flags that correspond to `flags`
+    pub fn flag_bits(&self) -> Result<u64, Error> {
+        translate_flags(&self.flags)
+    }
+}
+
 pub struct PackagedService<R: Read + Seek> {
     archive: zip::read::ZipArchive<R>,
     meta: Meta,
     services: Vec<(AccountNumber, usize, ServiceInfo)>,
-    data: Vec<(AccountNumber, usize)>,
+    // The account that owns each file, the service named after `@` in
+    // its directory, if any, and the file's index in the archive
+    data: Vec<(AccountNumber, Option<AccountNumber>, usize)>,
+    size: u64,
+}
+
+// Matches data/<account>/<path> and data/<account>@<service>/<path>.
+// The groups are the account, the service, and the path.
+const DATA_FILE_RE: &str = r"^data/([-a-zA-Z0-9]*)(?:@([-a-zA-Z0-9]+))?(/.*)$";
+
+// The position of each flag is its bit in CodeRow::flags
+const KNOWN_FLAGS: [&str; 7] = [
+    "allowSudo",
+    "allowWriteNative",
+    "isSubjective",
+    "allowWriteSubjective",
+    "canNotTimeOut",
+    "canSetTimeLimit",
+    "isAuthService",
+];
+
+/// The flags that may appear in the `flags` of `service/<name>.json`
+pub fn known_flags() -> &'static [&'static str] {
+    &KNOWN_FLAGS
+}
+
+// Extensions that have several registered content types, and the type
+// used for each. Pinning them keeps uploads stable if mime_guess
+// reorders its candidates.
+const AMBIGUOUS_CONTENT_TYPES: [(&str, &str); 3] = [
+    ("md", "text/markdown"),
+    ("mp2", "audio/mpeg"),
+    ("ttf", "font/ttf"),
+];
+
+/// The content type chosen for a file stored in sites
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentTypeGuess {
+    pub content_type: String,
+    /// The other types registered for the file's extension
+    pub alternatives: Vec<String>,
+}
+
+/// Chooses a content type from the extension of `path`.
+///
+/// Most extensions have one registered type. Of the ones that have
+/// several, `md` is `text/markdown`, `mp2` is `audio/mpeg`, and `ttf` is
+/// `font/ttf`. Any others use the first candidate from mime_guess.
+pub fn guess_content_type(path: &str) -> Option<ContentTypeGuess> {
+    let mut candidates: Vec<String> = mime_guess::from_path(path)
+        .iter()
+        .map(|t| t.essence_str().to_string())
+        .collect();
+    if candidates.is_empty() {
+        return None;
+    }
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    let pinned = AMBIGUOUS_CONTENT_TYPES
+        .iter()
+        .find(|(ext, _)| Some(*ext) == extension.as_deref())
+        .and_then(|(_, t)| candidates.iter().position(|c| c == t));
+    let content_type = candidates.remove(pinned.unwrap_or(0));
+    Some(ContentTypeGuess {
+        content_type,
+        alternatives: candidates,
+    })
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = prev[j] + (ca != *cb) as usize;
+            cur.push(substitute.min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+// Only suggest flags that are a plausible typo
+fn closest_flag(flag: &str) -> Option<String> {
+    KNOWN_FLAGS
+        .iter()
+        .map(|known| (edit_distance(flag, known), known))
+        .min()
+        .filter(|(distance, _)| *distance <= 3)
+        .map(|(_, known)| known.to_string())
 }
 
 fn translate_flags(flags: &[String]) -> Result<u64, Error> {
     let mut result = 0;
     for flag in flags {
-        result |= match flag.as_str() {
-            "allowSudo" => 1 << 0,
-            "allowWriteNative" => 1 << 1,
-            "isSubjective" => 1 << 2,
-            "allowWriteSubjective" => 1 << 3,
-            "canNotTimeOut" => 1 << 4,
-            "canSetTimeLimit" => 1 << 5,
-            "isAuthService" => 1 << 6,
-            _ => Err(Error::InvalidFlags)?,
+        let Some(bit) = KNOWN_FLAGS.iter().position(|known| known == flag) else {
+            Err(Error::InvalidFlags {
+                flag: flag.clone(),
+                closest: closest_flag(flag),
+            })?
         };
+        result |= 1 << bit;
     }
     Ok(result)
 }
@@ -188,8 +373,9 @@ impl<R: Read + Seek> PackagedService<R> {
         let mut service_files: Vec<(AccountNumber, usize)> = vec![];
         let mut data = vec![];
         let mut meta_index = None;
+        let mut size = 0;
         let service_re = Regex::new(r"^service/([-a-zA-Z0-9]*)\.(wasm|json)$")?;
-        let data_re = Regex::new(r"^data/([-a-zA-Z0-9]*)/.*$")?;
+        let data_re = Regex::new(DATA_FILE_RE)?;
         for index in 0..archive.len() {
             let raw_file = archive.by_index_raw(index)?;
             let filename = raw_file.name();
@@ -198,6 +384,7 @@ impl<R: Read + Seek> PackagedService<R> {
             } else if let Some(captures) = service_re.captures(filename) {
                 match captures.extract() {
                     (_, [name, "wasm"]) => {
+                        size += raw_file.size();
                         service_files.push((AccountNumber::from_str(name)?, index));
                     }
                     (_, [name, "json"]) => {
@@ -207,14 +394,33 @@ impl<R: Read + Seek> PackagedService<R> {
                 }
             } else if let Some(captures) = data_re.captures(filename) {
                 if raw_file.is_file() {
-                    let (_, [name]) = captures.extract();
-                    data.push((AccountNumber::from_str(name)?, index));
+                    let name = AccountNumber::from_str(&captures[1])?;
+                    let target = match captures.get(2) {
+                        Some(target) => Some(AccountNumber::from_str(target.as_str())?),
+                        None => None,
+                    };
+                    size += raw_file.size();
+                    data.push((name, target, index));
                 }
             }
         }
         let meta_contents =
             std::io::read_to_string(archive.by_index(meta_index.ok_or(Error::MissingMeta)?)?)?;
         let meta: Meta = serde_json::de::from_str(&meta_contents)?;
+        if meta.accounts.is_empty() && !(service_files.is_empty() && data.is_empty()) {
+            let mut names: Vec<String> = service_files
+                .iter()
+                .map(|(account, _)| account)
+                .chain(data.iter().map(|(account, _, _)| account))
+                .map(|account| account.to_string())
+                .collect();
+            names.sort();
+            names.dedup();
+            Err(Error::NoAccounts {
+                package: meta.name.clone(),
+                names: names.join(", "),
+            })?
+        }
         let mut services = vec![];
         for (account, file) in service_files {
             if !meta.accounts.contains(&account) {
@@ -229,7 +435,7 @@ impl<R: Read + Seek> PackagedService<R> {
             };
             services.push((account, file, info));
         }
-        for (account, _file) in &data[..] {
+        for (account, _target, _file) in &data[..] {
             if !meta.accounts.contains(&account) {
                 Err(Error::UnknownAccount { name: *account })?
             }
@@ -239,11 +445,20 @@ impl<R: Read + Seek> PackagedService<R> {
             meta: meta,
             services: services,
             data: data,
+            size,
         })
     }
     pub fn name(&self) -> &str {
         &self.meta.name
     }
+    pub fn meta(&self) -> &Meta {
+        &self.meta
+    }
+    /// The uncompressed size of the service wasms and data files, as
+    /// recorded in the archive's index
+    pub fn total_size(&self) -> u64 {
+        self.size
+    }
     pub fn get_genesis(&mut self, services: &mut Vec<GenesisService>) -> Result<(), anyhow::Error> {
         for (account, index, info) in &self.services {
             services.push(GenesisService {
@@ -264,25 +479,32 @@ impl<R: Read + Seek> PackagedService<R> {
         }
         false
     }
+    // Data goes to the service named in its directory, then to the
+    // account's own service, then to sites
+    fn data_service(&self, sender: AccountNumber, target: Option<AccountNumber>) -> AccountNumber {
+        if let Some(target) = target {
+            target
+        } else if self.has_service(sender) {
+            sender
+        } else {
+            sites::SERVICE
+        }
+    }
     pub fn store_data(&mut self, actions: &mut Vec<Action>) -> Result<(), anyhow::Error> {
-        let data_re = Regex::new(r"^data/[-a-zA-Z0-9]*(/.*)$")?;
-        for (sender, index) in &self.data {
-            let service = if self.has_service(*sender) {
-                *sender
-            } else {
-                sites::SERVICE
-            };
+        let data_re = Regex::new(DATA_FILE_RE)?;
+        for (sender, target, index) in &self.data {
+            let service = self.data_service(*sender, *target);
             let mut file = self.archive.by_index(*index)?;
             let path = data_re
                 .captures(file.name())
                 .unwrap()
-                .get(1)
+                .get(3)
                 .unwrap()
                 .as_str();
-            if let Some(t) = mime_guess::from_path(path).first() {
+            if let Some(t) = guess_content_type(path) {
                 actions.push(sites::Wrapper::pack_from_to(*sender, service).storeSys(
                     path.to_string(),
-                    t.essence_str().to_string(),
+                    t.content_type,
                     read(&mut file)?.into(),
                 ));
             } else {
@@ -323,18 +545,14 @@ impl<R: Read + Seek> PackagedService<R> {
 
     fn manifest_data(&mut self) -> Vec<PackageDataFile> {
         let mut manifest = vec![];
-        let data_re = Regex::new(r"^data/[-a-zA-Z0-9]*(/.*)$").unwrap();
-        for (sender, index) in &self.data {
-            let service = if self.has_service(*sender) {
-                *sender
-            } else {
-                sites::SERVICE
-            };
+        let data_re = Regex::new(DATA_FILE_RE).unwrap();
+        for (sender, target, index) in &self.data {
+            let service = self.data_service(*sender, *target);
             let file = self.archive.by_index(*index).unwrap();
             let path = data_re
                 .captures(file.name())
                 .unwrap()
-                .get(1)
+                .get(3)
                 .unwrap()
                 .as_str();
             manifest.push(PackageDataFile {
@@ -399,7 +617,7 @@ impl<R: Read + Seek> PackagedService<R> {
                 *account,
                 read(&mut self.archive.by_index(*index)?)?.into(),
             ));
-            let flags = translate_flags(&info.flags)?;
+            let flags = info.flag_bits()?;
             if flags != 0 {
                 group.push(setcode::Wrapper::pack().setFlags(*account, flags));
             }
@@ -450,6 +668,10 @@ impl<R: Read + Seek> PackagedService<R> {
             }
         }
 
+        for (_, target, _) in &self.data {
+            result.extend(target);
+        }
+
         if let Ok(file) = self.archive.by_name("script/postinstall.json") {
             let actions: Vec<Action> = serde_json::de::from_str(&std::io::read_to_string(file)?)?;
             for act in actions {
@@ -503,15 +725,36 @@ impl PackageManifest {
         other: PackageManifest,
         out: &mut T,
     ) -> Result<(), anyhow::Error> {
-        let new_files: HashSet<_> = other.data.into_iter().collect();
+        self.remove_stale_data(&other, out)?;
+        self.upgrade_services(&other, out)
+    }
+    /// Removes the data files that are not in `other`. The files must be
+    /// removed before any of the services that stored them are replaced,
+    /// because the new code might not provide removeSys.
+    pub fn remove_stale_data<T: ActionSink>(
+        &self,
+        other: &PackageManifest,
+        out: &mut T,
+    ) -> Result<(), anyhow::Error> {
+        let new_files: HashSet<_> = other.data.iter().collect();
+        let mut removed = HashSet::new();
         for file in &self.data {
-            if !new_files.contains(file) {
+            if !new_files.contains(file) && removed.insert(file) {
                 out.push_action(
                     sites::Wrapper::pack_from_to(file.account, file.service)
                         .removeSys(file.filename.clone()),
                 )?;
             }
         }
+        Ok(())
+    }
+    /// Undoes the server registrations, flags, and code of services
+    /// that are not replaced by `other`
+    pub fn upgrade_services<T: ActionSink>(
+        &self,
+        other: &PackageManifest,
+        out: &mut T,
+    ) -> Result<(), anyhow::Error> {
         for (service, info) in &self.services {
             let other_info = other.services.get(service);
             if info.server.is_some() && other_info.map_or(true, |i| i.server.is_none()) {
@@ -527,12 +770,10 @@ impl PackageManifest {
         Ok(())
     }
     pub fn remove<T: ActionSink>(&self, out: &mut T) -> Result<(), anyhow::Error> {
-        for file in &self.data {
-            out.push_action(
-                sites::Wrapper::pack_from_to(file.account, file.service)
-                    .removeSys(file.filename.clone()),
-            )?;
-        }
+        self.remove_stale_data(&PackageManifest::default(), out)?;
+        self.remove_services(out)
+    }
+    pub fn remove_services<T: ActionSink>(&self, out: &mut T) -> Result<(), anyhow::Error> {
         for (service, info) in &self.services {
             if info.server.is_some() {
                 out.push_action(reg_server(*service, sites::SERVICE))?;
@@ -546,13 +787,157 @@ impl PackageManifest {
     }
 }
 
+// Returns the first cycle found as a path that starts and ends with the same package
+fn find_dependency_cycle(packages: &[&Meta]) -> Option<Vec<String>> {
+    fn visit(
+        i: usize,
+        packages: &[&Meta],
+        by_name: &HashMap<&str, usize>,
+        done: &mut Vec<bool>,
+        path: &mut Vec<usize>,
+    ) -> Option<Vec<String>> {
+        if let Some(start) = path.iter().position(|&p| p == i) {
+            let mut cycle: Vec<String> = path[start..]
+                .iter()
+                .map(|&p| packages[p].name.clone())
+                .collect();
+            cycle.push(packages[i].name.clone());
+            return Some(cycle);
+        }
+        if done[i] {
+            return None;
+        }
+        path.push(i);
+        for dep in &packages[i].depends {
+            // Dependencies outside this set of packages can't form a cycle with it
+            if let Some(&d) = by_name.get(dep.name.as_str()) {
+                if let Some(cycle) = visit(d, packages, by_name, done, path) {
+                    return Some(cycle);
+                }
+            }
+        }
+        path.pop();
+        done[i] = true;
+        None
+    }
+    let by_name: HashMap<&str, usize> = packages
+        .iter()
+        .enumerate()
+        .map(|(i, meta)| (meta.name.as_str(), i))
+        .collect();
+    let mut done = vec![false; packages.len()];
+    for i in 0..packages.len() {
+        if let Some(cycle) = visit(i, packages, &by_name, &mut done, &mut vec![]) {
+            return Some(cycle);
+        }
+    }
+    None
+}
+
+// Lists the files under dir, relative to it, with / as the separator
+fn package_dir_files(
+    dir: &Path,
+    prefix: &str,
+    files: &mut Vec<(String, PathBuf)>,
+) -> Result<(), anyhow::Error> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Cannot read {}", dir.to_string_lossy()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            Err(Error::UnexpectedPackageFile {
+                path: entry.path().to_string_lossy().to_string(),
+            })?
+        };
+        let path = format!("{}{}", prefix, name);
+        if entry.file_type()?.is_dir() {
+            package_dir_files(&entry.path(), &format!("{}/", path), files)?;
+        } else {
+            files.push((path, entry.path()));
+        }
+    }
+    Ok(())
+}
+
+/// Builds a package from a directory with the same layout as the package:
+/// `meta.json`, `service/<account>.wasm`, `service/<account>.json`,
+/// `data/<account>/...`, `data/<account>@<service>/...`, and
+/// `script/postinstall.json`.
+///
+/// The JSON files are checked before anything is written, including the
+/// service flags. The result should be loaded with [PackagedService::new]
+/// to check the accounts.
+pub fn write_package_dir<W: std::io::Write + Seek>(dir: &Path, out: W) -> Result<W, anyhow::Error> {
+    let service_re = Regex::new(r"^service/([-a-zA-Z0-9]*)\.(wasm|json)$")?;
+    let data_re = Regex::new(DATA_FILE_RE)?;
+    let mut files = vec![];
+    package_dir_files(dir, "", &mut files)?;
+    // meta.json comes first
+    files.sort_by_key(|(name, _)| name != "meta.json");
+    if files.first().map(|(name, _)| name.as_str()) != Some("meta.json") {
+        Err(Error::MissingMeta)?
+    }
+    let mut contents = vec![];
+    for (name, path) in files {
+        let data = std::fs::read(&path)
+            .with_context(|| format!("Cannot read {}", path.to_string_lossy()))?;
+        let context = || format!("Invalid {}", path.to_string_lossy());
+        if name == "meta.json" {
+            serde_json::from_slice::<Meta>(&data).with_context(context)?;
+        } else if name == "script/postinstall.json" {
+            serde_json::from_slice::<Vec<Action>>(&data).with_context(context)?;
+        } else if let Some(captures) = service_re.captures(&name) {
+            let (_, [account, kind]) = captures.extract();
+            AccountNumber::from_str(account).with_context(context)?;
+            if kind == "json" {
+                serde_json::from_slice::<ServiceInfo>(&data)
+                    .with_context(context)?
+                    .flag_bits()?;
+            }
+        } else if let Some(captures) = data_re.captures(&name) {
+            AccountNumber::from_str(&captures[1]).with_context(context)?;
+            if let Some(target) = captures.get(2) {
+                AccountNumber::from_str(target.as_str()).with_context(context)?;
+            }
+        } else {
+            Err(Error::UnexpectedPackageFile {
+                path: path.to_string_lossy().to_string(),
+            })?
+        }
+        contents.push((name, data));
+    }
+    let mut writer = zip::ZipWriter::new(out);
+    for (name, data) in contents {
+        writer.start_file(name, zip::write::FileOptions::default())?;
+        std::io::Write::write_all(&mut writer, &data)?;
+    }
+    Ok(writer.finish()?)
+}
+
+// Packages shall not depend on each other in a cycle
 // Two packages shall not create the same account
 // Accounts used in any way during installation must be part of the package or
 // its direct dependencies
 pub fn validate_dependencies<T: Read + Seek>(
     packages: &mut [PackagedService<T>],
 ) -> Result<(), anyhow::Error> {
+    let metas: Vec<&Meta> = packages.iter().map(|p| &p.meta).collect();
+    if let Some(cycle) = find_dependency_cycle(&metas) {
+        Err(Error::DependencyCycle {
+            cycle: cycle.join(" -> "),
+        })?
+    }
     let mut accounts: HashMap<AccountNumber, String> = HashMap::new();
+    // A dependency on a virtual package counts as a dependency on its providers
+    let provides: HashMap<String, Vec<String>> = packages
+        .iter()
+        .map(|p| {
+            let names = p.meta.provides.iter().map(|v| v.name.clone()).collect();
+            (p.meta.name.clone(), names)
+        })
+        .collect();
     for p in &packages[..] {
         for account in p.get_accounts() {
             match accounts.entry(*account) {
@@ -568,8 +953,10 @@ pub fn validate_dependencies<T: Read + Seek>(
     for p in &mut packages[..] {
         for account in p.get_required_accounts()? {
             if let Some(package) = accounts.get(&account) {
-                if &p.meta.name != package && !p.meta.depends.iter().any(|dep| &dep.name == package)
-                {
+                let provided = provides.get(package).map_or(&[][..], |names| &names[..]);
+                let depends_on =
+                    |dep: &PackageRef| &dep.name == package || provided.contains(&dep.name);
+                if &p.meta.name != package && !p.meta.depends.iter().any(depends_on) {
                     Err(Error::MissingDepPackage {
                         name: p.meta.name.clone(),
                         dep: package.clone(),
@@ -586,7 +973,7 @@ pub fn validate_dependencies<T: Read + Seek>(
     Ok(())
 }
 
-fn make_refs(packages: &[String]) -> Result<Vec<PackageRef>, anyhow::Error> {
+pub fn make_refs(packages: &[String]) -> Result<Vec<PackageRef>, anyhow::Error> {
     let re = Regex::new(r"^(.*?)(?:-(\d+\.\d+\.\d+(?:-[0-9a-zA-Z-.]+)?(?:\+[0-9a-zA-Z-.]+)?))?$")?;
     let mut refs = vec![];
     for package in packages {
@@ -608,28 +995,115 @@ fn make_refs(packages: &[String]) -> Result<Vec<PackageRef>, anyhow::Error> {
 pub trait PackageRegistry {
     type R: Read + Seek;
     fn index(&self) -> Result<Vec<PackageInfo>, anyhow::Error>;
+    // Returns the newest version of the package that matches version_req
+    fn get_info(
+        &self,
+        name: &str,
+        version_req: &str,
+    ) -> Result<Option<PackageInfo>, anyhow::Error> {
+        newest_match(self.index()?, name, version_req)
+    }
     async fn get_by_info(
         &self,
         info: &PackageInfo,
     ) -> Result<PackagedService<Self::R>, anyhow::Error>;
-    // Returns a set of packages and all dependencies
+    // Returns the index entries of a set of packages and all dependencies
     // The result is ordered by dependency so that if A depends on B, then B appears before A.
+    fn resolve_info(&self, packages: &[String]) -> Result<Vec<PackageInfo>, anyhow::Error> {
+        only_installs(solve_dependencies(
+            self.index()?,
+            make_refs(packages)?,
+            vec![],
+            &SolveOptions::default(),
+        )?)
+    }
+    // Returns a set of packages and all dependencies, in the order of resolve_info
     async fn resolve(
         &self,
         packages: &[String],
     ) -> Result<Vec<PackagedService<Self::R>>, anyhow::Error> {
         let mut result = vec![];
-        for op in solve_dependencies(self.index()?, make_refs(packages)?, vec![], false)? {
-            let PackageOp::Install(info) = op else {
-                panic!("Only install is expected when there are no existing packages");
-            };
+        for info in self.resolve_info(packages)? {
             result.push(self.get_by_info(&info).await?);
         }
-
         Ok(result)
     }
 }
 
+// With no existing packages, the solver can only install packages
+fn only_installs(ops: Vec<PackageOp>) -> Result<Vec<PackageInfo>, anyhow::Error> {
+    let mut result = vec![];
+    for op in ops {
+        match op {
+            PackageOp::Install(info) => result.push(info),
+            PackageOp::Replace(meta, info) => Err(Error::UnexpectedPackageOp {
+                op: format!(
+                    "replace {}-{} with {}-{}",
+                    meta.name, meta.version, info.name, info.version
+                ),
+            })?,
+            PackageOp::Remove(meta) => Err(Error::UnexpectedPackageOp {
+                op: format!("remove {}-{}", meta.name, meta.version),
+            })?,
+        }
+    }
+    Ok(result)
+}
+
+// Sorts by name, then version. Versions that are not valid semver sort
+// after the valid ones, in string order, so that the order is total even
+// for a malformed index. The sort is stable, so entries with the same
+// name and version keep their order.
+fn sort_index(index: &mut Vec<PackageInfo>) {
+    // Each version is parsed once. Version borrows from the entry, so
+    // the entries are sorted by position and then moved into place.
+    let order = {
+        let versions: Vec<_> = index
+            .iter()
+            .map(|info| Version::new(&info.version).ok())
+            .collect();
+        let mut order: Vec<usize> = (0..index.len()).collect();
+        order.sort_by(|&a, &b| {
+            index[a]
+                .name
+                .cmp(&index[b].name)
+                .then_with(|| match (&versions[a], &versions[b]) {
+                    (Some(x), Some(y)) => x.cmp(y),
+                    (Some(_), None) => std::cmp::Ordering::Less,
+                    (None, Some(_)) => std::cmp::Ordering::Greater,
+                    (None, None) => index[a].version.cmp(&index[b].version),
+                })
+        });
+        order
+    };
+    let mut entries: Vec<_> = std::mem::take(index).into_iter().map(Some).collect();
+    *index = order
+        .into_iter()
+        .map(|i| entries[i].take().unwrap())
+        .collect();
+}
+
+fn newest_match(
+    packages: impl IntoIterator<Item = PackageInfo>,
+    name: &str,
+    version_req: &str,
+) -> Result<Option<PackageInfo>, anyhow::Error> {
+    let mut found: Option<PackageInfo> = None;
+    for info in packages {
+        if info.name != name || !version_match(version_req, &info.version)? {
+            continue;
+        }
+        let newer = match &found {
+            Some(prev) => Version::new(&prev.version)? < Version::new(&info.version)?,
+            None => true,
+        };
+        if newer {
+            found = Some(info)
+        }
+    }
+    Ok(found)
+}
+
 pub struct DirectoryRegistry {
     dir: PathBuf,
 }
@@ -638,17 +1112,103 @@ impl DirectoryRegistry {
     pub fn new(dir: PathBuf) -> Self {
         DirectoryRegistry { dir }
     }
+    /// Like [DirectoryRegistry::new], but fails immediately if `dir`
+    /// does not contain a valid `index.json`
+    pub fn open(dir: PathBuf) -> Result<Self, anyhow::Error> {
+        let result = DirectoryRegistry { dir };
+        result.index()?;
+        Ok(result)
+    }
+    /// Writes an `index.json` that lists every `.psi` file in `dir`,
+    /// replacing the existing index. Returns the new index.
+    #[cfg(not(target_family = "wasm"))]
+    pub fn write_index(dir: &Path) -> Result<Vec<PackageInfo>, anyhow::Error> {
+        let mut result = vec![];
+        let mut seen = HashSet::new();
+        let mut entries = std::fs::read_dir(dir)
+            .with_context(|| format!("Cannot read {}", dir.to_string_lossy()))?
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            if !entry.file_type()?.is_file() || path.extension() != Some("psi".as_ref()) {
+                continue;
+            }
+            let contents = std::fs::read(&path)
+                .with_context(|| format!("Cannot read {}", path.to_string_lossy()))?;
+            let package = PackagedService::new(std::io::Cursor::new(&contents[..]))
+                .with_context(|| format!("Invalid package {}", path.to_string_lossy()))?;
+            let meta = package.meta();
+            if !seen.insert((meta.name.clone(), meta.version.clone())) {
+                Err(Error::DuplicatePackage {
+                    package: format!("{}-{}", meta.name, meta.version),
+                })?
+            }
+            let hash: [u8; 32] = Sha256::digest(&contents).into();
+            result.push(meta.info(
+                Checksum256::from(hash),
+                entry.file_name().to_string_lossy().to_string(),
+            ));
+        }
+        sort_index(&mut result);
+        let path = dir.join("index.json");
+        let f = File::create(&path)
+            .with_context(|| format!("Cannot create {}", path.to_string_lossy()))?;
+        serde_json::to_writer_pretty(f, &result)?;
+        Ok(result)
+    }
+    /// Checks each entry of the index against the package file that it
+    /// refers to. Returns the entries whose checksum or metadata don't
+    /// match, e.g. because a package was rebuilt without updating the
+    /// index.
+    #[cfg(not(target_family = "wasm"))]
+    pub fn verify_index(&self) -> Result<Vec<Error>, anyhow::Error> {
+        let mut result = vec![];
+        for info in self.index()? {
+            let path = self.dir.join(&info.file);
+            let contents = std::fs::read(&path)
+                .with_context(|| format!("Cannot read {}", path.to_string_lossy()))?;
+            let hash: [u8; 32] = Sha256::digest(&contents).into();
+            if Checksum256::from(hash) != info.sha256 {
+                result.push(Error::PackageDigestFailure {
+                    package: format!("{}-{}", info.name, info.version),
+                });
+                continue;
+            }
+            let package = PackagedService::new(std::io::Cursor::new(&contents[..]))
+                .with_context(|| format!("Invalid package {}", path.to_string_lossy()))?;
+            if let Err(e) = info.check_meta(package.meta()) {
+                result.push(e);
+            }
+        }
+        Ok(result)
+    }
 }
 
 #[async_trait(?Send)]
 impl PackageRegistry for DirectoryRegistry {
     type R = BufReader<File>;
     fn index(&self) -> Result<Vec<PackageInfo>, anyhow::Error> {
+        if !self.dir.is_dir() {
+            Err(Error::PackageSourceNotFound {
+                path: self.dir.to_string_lossy().to_string(),
+            })?
+        }
         let path = self.dir.join("index.json");
+        if !path.is_file() {
+            Err(Error::MissingPackageIndex {
+                path: path.to_string_lossy().to_string(),
+            })?
+        }
         let f =
             File::open(&path).with_context(|| format!("Cannot open {}", path.to_string_lossy()))?;
         let contents = std::io::read_to_string(f)?;
-        let result: Vec<PackageInfo> = serde_json::de::from_str(&contents)?;
+        let mut result: Vec<PackageInfo> =
+            serde_json::de::from_str(&contents).map_err(|e| Error::InvalidPackageIndex {
+                path: path.to_string_lossy().to_string(),
+                message: e.to_string(),
+            })?;
+        sort_index(&mut result);
         Ok(result)
     }
     async fn get_by_info(
@@ -667,6 +1227,45 @@ pub struct HTTPRegistry {
     index_url: reqwest::Url,
     client: reqwest::Client,
     index: HashMap<String, PackageInfo>,
+    temp_dir: PathBuf,
+    max_package_size: u64,
+}
+
+/// The default limit on the size of a package downloaded by [HTTPRegistry]
+pub const DEFAULT_MAX_PACKAGE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// The default limit on the number of redirects followed by [redirect_policy]
+pub const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+/// A redirect policy for clients that fetch packages. It follows at
+/// most `max_redirects` redirects, and unless `allow_cross_origin` is
+/// set, refuses any redirect to a different origin, so that a package
+/// source cannot hand off its index to another server.
+#[cfg(not(target_family = "wasm"))]
+pub fn redirect_policy(
+    max_redirects: usize,
+    allow_cross_origin: bool,
+) -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(move |attempt| {
+        let Some(prev) = attempt.previous().last() else {
+            return attempt.follow();
+        };
+        if attempt.previous().len() > max_redirects {
+            let error = Error::TooManyRedirects {
+                url: attempt.previous()[0].to_string(),
+                limit: max_redirects,
+            };
+            attempt.error(error)
+        } else if !allow_cross_origin && attempt.url().origin() != prev.origin() {
+            let error = Error::CrossOriginRedirect {
+                from: prev.to_string(),
+                to: attempt.url().to_string(),
+            };
+            attempt.error(error)
+        } else {
+            attempt.follow()
+        }
+    })
 }
 
 #[cfg(not(target_family = "wasm"))]
@@ -674,6 +1273,16 @@ impl HTTPRegistry {
     pub async fn new(
         url: reqwest::Url,
         client: reqwest::Client,
+    ) -> Result<HTTPRegistry, anyhow::Error> {
+        Self::new_with_max_package_size(url, client, DEFAULT_MAX_PACKAGE_SIZE).await
+    }
+    /// Like [new](Self::new), but with a different limit on the size
+    /// of package files. The limit also applies to the decompressed
+    /// size of `index.json.gz`.
+    pub async fn new_with_max_package_size(
+        url: reqwest::Url,
+        client: reqwest::Client,
+        max_package_size: u64,
     ) -> Result<HTTPRegistry, anyhow::Error> {
         let mut index_url = url.clone();
         index_url
@@ -681,8 +1290,20 @@ impl HTTPRegistry {
             .unwrap()
             .pop_if_empty()
             .push("index.json");
+        // If the index was redirected, package files are resolved
+        // relative to, and must have the same origin as, where it was
+        // actually found.
+        let (index_url, packages) =
+            match crate::rpc::as_json_with_url(client.get(index_url.clone())).await {
+                Err(e) if crate::rpc::is_not_found(&e) => {
+                    Self::get_gz_index(&client, &index_url, max_package_size)
+                        .await
+                        .with_context(|| format!("{} was not found", index_url))?
+                }
+                result => result?,
+            };
         let mut index = HashMap::new();
-        for package in crate::as_json::<Vec<PackageInfo>>(client.get(index_url.clone())).await? {
+        for package in packages {
             if let Some(prev) = index.insert(package.name.clone(), package) {
                 Err(Error::DuplicatePackage { package: prev.name })?
             }
@@ -691,8 +1312,50 @@ impl HTTPRegistry {
             index_url,
             client,
             index,
+            temp_dir: std::env::temp_dir(),
+            max_package_size,
         })
     }
+    /// Sets the directory that downloaded packages are stored in
+    /// until they are used. The default is the system temp directory.
+    pub fn with_temp_dir(mut self, dir: PathBuf) -> Self {
+        self.temp_dir = dir;
+        self
+    }
+    // Fallback for repositories that only provide a compressed index
+    async fn get_gz_index(
+        client: &reqwest::Client,
+        index_url: &reqwest::Url,
+        limit: u64,
+    ) -> Result<(reqwest::Url, Vec<PackageInfo>), anyhow::Error> {
+        let gz_url = index_url.join("index.json.gz")?;
+        debug!("GET {}", gz_url);
+        let response = client
+            .get(gz_url.clone())
+            .send()
+            .await?
+            .error_for_status()?;
+        let url = response.url().join("index.json")?;
+        let body = response.bytes().await?;
+        // Servers may mark .gz files with Content-Encoding: gzip,
+        // in which case reqwest has already decompressed it.
+        if body.starts_with(&[0x1f, 0x8b]) {
+            // A small file can decompress to a huge one
+            let mut index = vec![];
+            GzDecoder::new(&body[..])
+                .take(limit.saturating_add(1))
+                .read_to_end(&mut index)?;
+            if index.len() as u64 > limit {
+                Err(Error::IndexTooLarge {
+                    url: gz_url.to_string(),
+                    limit,
+                })?
+            }
+            Ok((url, serde_json::from_slice(&index)?))
+        } else {
+            Ok((url, serde_json::from_slice(&body)?))
+        }
+    }
     async fn download(&self, filename: &str) -> Result<(File, Checksum256), anyhow::Error> {
         let url = self.index_url.join(filename)?;
         if url.origin() != self.index_url.origin() {
@@ -700,16 +1363,48 @@ impl HTTPRegistry {
                 file: filename.to_string(),
             })?;
         }
-        let mut response = self.client.get(url).send().await?.error_for_status()?;
+        debug!("GET {}", url);
+        let response = self.client.get(url).send().await?.error_for_status()?;
+        let too_large = || Error::PackageTooLarge {
+            file: filename.to_string(),
+            limit: self.max_package_size,
+        };
+        if response
+            .content_length()
+            .map_or(false, |len| len > self.max_package_size)
+        {
+            Err(too_large())?
+        }
+        // Dropping the file removes it, so nothing is left behind if
+        // the download fails part way through. Once it is complete, the
+        // name is removed and only the open handle remains.
+        let mut f = NamedTempFile::new_in(&self.temp_dir)?;
+        // The server may not send a length, or may send more than it claims
+        let hash = Self::write_response(response, f.as_file_mut(), self.max_package_size)
+            .await?
+            .ok_or_else(too_large)?;
+        let mut f = f.into_file();
+        f.rewind()?;
+        Ok((f, hash))
+    }
+    // Returns None if the response is longer than limit
+    async fn write_response(
+        mut response: reqwest::Response,
+        f: &mut File,
+        limit: u64,
+    ) -> Result<Option<Checksum256>, anyhow::Error> {
         let mut hasher = Sha256::new();
-        let mut f = tempfile()?;
+        let mut size = 0u64;
         while let Some(chunk) = response.chunk().await? {
+            size += chunk.len() as u64;
+            if size > limit {
+                return Ok(None);
+            }
             f.write_all(&chunk)?;
             hasher.update(&chunk);
         }
         let hash: [u8; 32] = hasher.finalize().into();
-        f.rewind()?;
-        Ok((f, Checksum256::from(hash)))
+        Ok(Some(Checksum256::from(hash)))
     }
 }
 
@@ -718,12 +1413,20 @@ impl HTTPRegistry {
 impl PackageRegistry for HTTPRegistry {
     type R = BufReader<File>;
     fn index(&self) -> Result<Vec<PackageInfo>, anyhow::Error> {
-        let mut result = Vec::new();
-        for (_k, v) in &self.index {
-            result.push(v.clone());
-        }
+        let mut result: Vec<PackageInfo> = self.index.values().cloned().collect();
+        sort_index(&mut result);
         Ok(result)
     }
+    fn get_info(
+        &self,
+        name: &str,
+        version_req: &str,
+    ) -> Result<Option<PackageInfo>, anyhow::Error> {
+        match self.index.get(name) {
+            Some(info) if version_match(version_req, &info.version)? => Ok(Some(info.clone())),
+            _ => Ok(None),
+        }
+    }
     async fn get_by_info(
         &self,
         info: &PackageInfo,
@@ -735,22 +1438,69 @@ impl PackageRegistry for HTTPRegistry {
             })?
         }
         let result = PackagedService::new(BufReader::new(f))?;
-        if result.meta != info.meta() {
-            Err(Error::PackageMetaMismatch {
-                package: info.name.clone(),
-            })?
-        }
+        info.check_meta(&result.meta)?;
         Ok(result)
     }
 }
 
+/// A version of a package offered by one source of a [JointRegistry]
+#[derive(Debug, Clone)]
+pub struct SourceVersion {
+    /// The position of the source. The sources added with
+    /// [push_preferred](JointRegistry::push_preferred) come first, in
+    /// the order they were added, followed by the other sources.
+    pub source: usize,
+    pub info: PackageInfo,
+    /// A preferred source has a package of the same name, so this
+    /// version is never used
+    pub shadowed: bool,
+}
+
+/// Combines several package registries.
+///
+/// When more than one source has a package, the newest version that
+/// matches the request wins. If several sources have that version, the
+/// one added first wins. Sources added with
+/// [push_preferred](Self::push_preferred) take precedence: any package
+/// that a preferred source has, in any version, is hidden in all other
+/// sources.
 pub struct JointRegistry<T: Read + Seek> {
     sources: Vec<(PackageList, Box<dyn PackageRegistry<R = T>>)>,
+    // The first `preferred` sources were added by push_preferred
+    preferred: usize,
+    // Set by lock. Only these packages are offered.
+    locked: Option<Vec<PackageInfo>>,
 }
 
 impl<T: Read + Seek> JointRegistry<T> {
     pub fn new() -> Self {
-        Self { sources: vec![] }
+        Self {
+            sources: vec![],
+            preferred: 0,
+            locked: None,
+        }
+    }
+    /// Restricts the registry to the exact packages of a lockfile. Fails
+    /// if the sources no longer have one of them, or if its sha256 has
+    /// changed. This should be called after all sources are added.
+    pub fn lock(&mut self, lock: &[LockedPackage]) -> Result<(), anyhow::Error> {
+        let index = self.index()?;
+        let mut locked = vec![];
+        for entry in lock {
+            let package = format!("{}-{}", entry.name, entry.version);
+            let Some(info) = index
+                .iter()
+                .find(|info| info.name == entry.name && info.version == entry.version)
+            else {
+                Err(Error::LockedPackageMissing { package })?
+            };
+            if info.sha256 != entry.sha256 {
+                Err(Error::LockedPackageChanged { package })?
+            }
+            locked.push(info.clone());
+        }
+        self.locked = Some(locked);
+        Ok(())
     }
     pub fn push<U: PackageRegistry<R = T> + 'static>(
         &mut self,
@@ -760,30 +1510,91 @@ impl<T: Read + Seek> JointRegistry<T> {
         self.sources.push((list, Box::new(source)));
         Ok(())
     }
+    /// Adds a source whose packages shadow those of the same name in
+    /// sources that were added with [push](Self::push)
+    pub fn push_preferred<U: PackageRegistry<R = T> + 'static>(
+        &mut self,
+        source: U,
+    ) -> Result<(), anyhow::Error> {
+        let list = PackageList::from_registry(&source)?;
+        self.sources
+            .insert(self.preferred, (list, Box::new(source)));
+        self.preferred += 1;
+        Ok(())
+    }
+    /// Lists every version of the package in every source, including
+    /// versions that are shadowed or duplicated by an earlier source
+    pub fn versions(&self, name: &str) -> Result<Vec<SourceVersion>, anyhow::Error> {
+        let mut result = vec![];
+        for (i, (_, reg)) in self.sources.iter().enumerate() {
+            let shadowed = self.is_shadowed(i, name);
+            let mut index = reg.index()?;
+            index.retain(|info| info.name == name);
+            sort_index(&mut index);
+            result.extend(index.into_iter().map(|info| SourceVersion {
+                source: i,
+                info,
+                shadowed,
+            }));
+        }
+        Ok(result)
+    }
+    fn is_shadowed(&self, source: usize, name: &str) -> bool {
+        source >= self.preferred
+            && self.sources[..self.preferred]
+                .iter()
+                .any(|(list, _)| list.contains_name(name))
+    }
 }
 
 #[async_trait(?Send)]
 impl<T: Read + Seek> PackageRegistry for JointRegistry<T> {
     type R = T;
     fn index(&self) -> Result<Vec<PackageInfo>, anyhow::Error> {
+        if let Some(locked) = &self.locked {
+            let mut result = locked.clone();
+            sort_index(&mut result);
+            return Ok(result);
+        }
         let mut result = Vec::new();
         let mut found = PackageList::new();
-        for (_, reg) in &self.sources {
+        for (i, (_, reg)) in self.sources.iter().enumerate() {
             for entry in reg.index()? {
-                if !found.contains_version(&entry.name, &entry.version) {
+                if !self.is_shadowed(i, &entry.name)
+                    && !found.contains_version(&entry.name, &entry.version)
+                {
                     found.insert_info(entry.clone());
                     result.push(entry);
                 }
             }
         }
+        sort_index(&mut result);
         Ok(result)
     }
+    fn get_info(
+        &self,
+        name: &str,
+        version_req: &str,
+    ) -> Result<Option<PackageInfo>, anyhow::Error> {
+        if let Some(locked) = &self.locked {
+            return newest_match(locked.iter().cloned(), name, version_req);
+        }
+        // Earlier sources win when they have the same version
+        let mut candidates = Vec::new();
+        for (i, (_, reg)) in self.sources.iter().enumerate() {
+            if !self.is_shadowed(i, name) {
+                candidates.extend(reg.get_info(name, version_req)?);
+            }
+        }
+        newest_match(candidates, name, version_req)
+    }
     async fn get_by_info(
         &self,
         info: &PackageInfo,
     ) -> Result<PackagedService<Self::R>, anyhow::Error> {
-        for (list, reg) in &self.sources {
-            if list.contains_version(&info.name, &info.version) {
+        for (i, (list, reg)) in self.sources.iter().enumerate() {
+            if !self.is_shadowed(i, &info.name) && list.contains_version(&info.name, &info.version)
+            {
                 return reg.get_by_info(info).await;
             }
         }
@@ -793,11 +1604,13 @@ impl<T: Read + Seek> PackageRegistry for JointRegistry<T> {
     }
 }
 
+#[derive(Clone)]
 pub enum PackageOrigin {
     Installed { owner: AccountNumber },
     Repo { sha256: Checksum256, file: String },
 }
 
+#[derive(Clone)]
 pub struct PackageList {
     packages: HashMap<String, HashMap<String, (Meta, PackageOrigin)>>,
 }
@@ -853,6 +1666,82 @@ pub async fn get_accounts_to_create(
     Ok(result.newAccounts)
 }
 
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AccountRow {
+    accountNum: AccountNumber,
+    authService: AccountNumber,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct AccountsQuery {
+    accounts: Vec<AccountRow>,
+}
+
+/// Looks up the auth service currently used by each of `accounts`.
+/// Accounts that do not exist are omitted from the result.
+#[cfg(not(target_family = "wasm"))]
+pub async fn get_auth_services(
+    base_url: &reqwest::Url,
+    client: &mut reqwest::Client,
+    accounts: &[AccountNumber],
+) -> Result<HashMap<AccountNumber, AccountNumber>, anyhow::Error> {
+    let result: AccountsQuery = crate::gql_query(
+        base_url,
+        client,
+        accounts::SERVICE,
+        format!(
+            "query {{ accounts(names: {}) {{ accountNum authService }} }}",
+            serde_json::to_string(accounts)?,
+        ),
+    )
+    .await?;
+    Ok(result
+        .accounts
+        .into_iter()
+        .map(|row| (row.accountNum, row.authService))
+        .collect())
+}
+
+/// Returns the actions that switch existing accounts over to `key`,
+/// and the accounts that were left unchanged.
+///
+/// `accounts` pairs each account with its owner. Only accounts in
+/// `owned` are modified, and by default only in ways that installing
+/// packages still accepts: accounts that use `auth-delegate` are
+/// controlled by the owner's key and are left alone, and other
+/// accounts may only change their auth service if they are the owner.
+/// `force` switches the accounts that would be left alone as well.
+pub fn rotate_key_actions(
+    accounts: &[(AccountNumber, AccountNumber)],
+    owned: &[AccountNumber],
+    auth_services: &HashMap<AccountNumber, AccountNumber>,
+    key: &AnyPublicKey,
+    force: bool,
+) -> (Vec<Vec<Action>>, Vec<AccountNumber>) {
+    let mut result = vec![];
+    let mut skipped = vec![];
+    for (account, owner) in accounts {
+        if !owned.contains(account) {
+            continue;
+        }
+        let Some(auth_service) = auth_services.get(account) else {
+            continue;
+        };
+        if *auth_service == key.auth_service() {
+            result.push(vec![set_key_action(*account, key)]);
+        } else if force || (account == owner && *auth_service != auth_delegate::SERVICE) {
+            result.push(vec![
+                set_key_action(*account, key),
+                set_auth_service_action(*account, key.auth_service()),
+            ]);
+        } else {
+            skipped.push(*account);
+        }
+    }
+    (result, skipped)
+}
+
 #[cfg(not(target_family = "wasm"))]
 pub async fn get_installed_manifest(
     base_url: &reqwest::Url,
@@ -902,7 +1791,7 @@ impl PackageList {
         let mut result = PackageList::new();
         loop {
             let data = crate::gql_query::<InstalledQuery>(base_url, client, packages::SERVICE,
-                                        format!("query {{ installed(first: 100, after: {}) {{ pageInfo {{ hasNextPage endCursor }} edges {{ node {{ name version description depends {{ name version }}  accounts owner }} }} }} }}", serde_json::to_string(&end_cursor)?))
+                                        format!("query {{ installed(first: 100, after: {}) {{ pageInfo {{ hasNextPage endCursor }} edges {{ node {{ name version description depends {{ name version }}  accounts owner provides {{ name version }} }} }} }} }}", serde_json::to_string(&end_cursor)?))
                 .await.with_context(|| "Failed to list installed packages")?;
             for edge in data.installed.edges {
                 result.insert_installed(edge.node);
@@ -942,6 +1831,9 @@ impl PackageList {
         self.insert(info.meta(), PackageOrigin::Installed { owner: info.owner });
     }
 
+    fn contains_name(&self, name: &str) -> bool {
+        self.packages.contains_key(name)
+    }
     fn contains_version(&self, name: &str, version: &str) -> bool {
         if let Some(packages) = self.packages.get(name) {
             return packages.contains_key(version);
@@ -961,13 +1853,13 @@ impl PackageList {
         &self,
         reg: &T,
         packages: &[String],
-        reinstall: bool,
+        options: &SolveOptions,
     ) -> Result<Vec<PackageOp>, anyhow::Error> {
         solve_dependencies(
             reg.index()?,
             make_refs(packages)?,
             self.as_upgradable(),
-            reinstall,
+            options,
         )
     }
     pub fn into_info(self) -> Vec<(Meta, PackageOrigin)> {
@@ -1041,6 +1933,1026 @@ pub fn js_resolve_packages(
         index,
         js_err(make_refs(&packages))?,
         pinned,
-        false,
+        &SolveOptions::default(),
     ))?)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::*;
+    use crate::{
+        push_transaction, AccountNumber, MethodNumber, SignedTransaction, TraceFormat, Transaction,
+    };
+    use fracpack::{Pack as _, Unpack as _};
+    use std::io::{Cursor, Write};
+
+    #[test]
+    fn ambiguous_content_types() {
+        let guess = guess_content_type("/fonts/a.TTF").unwrap();
+        assert_eq!(guess.content_type, "font/ttf");
+        assert!(guess
+            .alternatives
+            .contains(&"application/x-font-ttf".to_string()));
+        for (ext, expected) in AMBIGUOUS_CONTENT_TYPES {
+            let guess = guess_content_type(&format!("/file.{}", ext)).unwrap();
+            assert_eq!(guess.content_type, expected);
+            assert!(!guess.alternatives.is_empty(), "{} is not ambiguous", ext);
+        }
+
+        let guess = guess_content_type("/data.bin").unwrap();
+        assert_eq!(guess.content_type, "application/octet-stream");
+        assert!(guess.alternatives.is_empty());
+        assert_eq!(guess_content_type("/README"), None);
+    }
+
+    const INDEX: &str = r#"[{"name":"A","version":"1.0.0","description":"","depends":[],"accounts":[],"sha256":"0000000000000000000000000000000000000000000000000000000000000000","file":"A.psi"}]"#;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[tokio::test]
+    async fn http_registry_decompresses_index() -> Result<(), anyhow::Error> {
+        let url = mock_server_bytes(vec![http_response_bytes(
+            "200 OK",
+            "application/json",
+            &[("Content-Encoding", "gzip")],
+            &gzip(INDEX.as_bytes()),
+        )])
+        .await?;
+        let registry = HTTPRegistry::new(url, reqwest::Client::new()).await?;
+        assert_eq!(registry.index()?[0].name, "A");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn registry_and_push_share_connections() -> Result<(), anyhow::Error> {
+        let (url, connections) = mock_keep_alive_server(vec![
+            INDEX.to_string(),
+            r#"{"actionTraces":[],"error":null}"#.to_string(),
+        ])
+        .await?;
+        let client = reqwest::Client::new();
+        let registry = HTTPRegistry::new(url.clone(), client.clone()).await?;
+        assert_eq!(registry.index()?[0].name, "A");
+        let trx = SignedTransaction {
+            transaction: Transaction::default().packed().into(),
+            proofs: vec![],
+        };
+        push_transaction(&url, client, trx.packed(), TraceFormat::Error, false, None).await?;
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http_registry_falls_back_to_gz_index() -> Result<(), anyhow::Error> {
+        let url = mock_server_bytes(vec![
+            http_response("404 Not Found", "text/plain", "Not Found").into_bytes(),
+            http_response_bytes("200 OK", "application/gzip", &[], &gzip(INDEX.as_bytes())),
+        ])
+        .await?;
+        let registry = HTTPRegistry::new(url, reqwest::Client::new()).await?;
+        assert_eq!(registry.index()?[0].name, "A");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http_registry_limits_gz_index_size() -> Result<(), anyhow::Error> {
+        let gz_index = || {
+            vec![
+                http_response("404 Not Found", "text/plain", "Not Found").into_bytes(),
+                http_response_bytes("200 OK", "application/gzip", &[], &gzip(INDEX.as_bytes())),
+            ]
+        };
+        let limit = INDEX.len() as u64;
+        let url = mock_server_bytes(gz_index()).await?;
+        HTTPRegistry::new_with_max_package_size(url, reqwest::Client::new(), limit).await?;
+
+        let url = mock_server_bytes(gz_index()).await?;
+        let err = HTTPRegistry::new_with_max_package_size(url, reqwest::Client::new(), limit - 1)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::IndexTooLarge { .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http_registry_index_is_sorted() -> Result<(), anyhow::Error> {
+        let index = index_json(&[
+            ("C", "1.0.0", "C.psi"),
+            ("A", "1.0.0", "A.psi"),
+            ("D", "1.0.0", "D.psi"),
+            ("B", "1.0.0", "B.psi"),
+        ]);
+        let url = mock_server(http_response("200 OK", "application/json", &index)).await?;
+        let registry = HTTPRegistry::new(url, reqwest::Client::new()).await?;
+        let names = |index: Vec<PackageInfo>| -> Vec<String> {
+            index.into_iter().map(|info| info.name).collect()
+        };
+        let first = names(registry.index()?);
+        assert_eq!(first, ["A", "B", "C", "D"]);
+        for _ in 0..10 {
+            assert_eq!(names(registry.index()?), first);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sort_index_compares_versions() -> Result<(), anyhow::Error> {
+        let mut index: Vec<PackageInfo> = serde_json::from_str(&index_json(&[
+            ("B", "1.10.0", "B-1.10.0.psi"),
+            ("A", "2.0.0", "A-2.0.0.psi"),
+            ("B", "1.9.0", "B-1.9.0.psi"),
+            ("B", "1.9.0", "B-1.9.0-copy.psi"),
+        ]))?;
+        sort_index(&mut index);
+        let files: Vec<_> = index.iter().map(|info| info.file.as_str()).collect();
+        assert_eq!(
+            files,
+            [
+                "A-2.0.0.psi",
+                "B-1.9.0.psi",
+                "B-1.9.0-copy.psi",
+                "B-1.10.0.psi"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sort_index_puts_invalid_versions_last() -> Result<(), anyhow::Error> {
+        // Mixing semver and string order here would not be a total order:
+        // 1.9.0 < 1.10.0 < 1.5 < 1.9.0
+        let mut index: Vec<PackageInfo> = serde_json::from_str(&index_json(&[
+            ("A", "1.5", "A-1.5.psi"),
+            ("A", "1.10.0", "A-1.10.0.psi"),
+            ("A", "latest", "A-latest.psi"),
+            ("A", "1.9.0", "A-1.9.0.psi"),
+        ]))?;
+        sort_index(&mut index);
+        let files: Vec<_> = index.iter().map(|info| info.file.as_str()).collect();
+        assert_eq!(
+            files,
+            ["A-1.9.0.psi", "A-1.10.0.psi", "A-1.5.psi", "A-latest.psi"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_info_rejects_other_ops() -> Result<(), anyhow::Error> {
+        let old = meta_with_deps("A", &[]);
+        let mut new = old.info(Checksum256::default(), "A.psi".to_string());
+        new.version = "1.1.0".to_string();
+        assert_eq!(
+            only_installs(vec![PackageOp::Install(new.clone())])?,
+            [new.clone()]
+        );
+        for op in [
+            PackageOp::Replace(old.clone(), new.clone()),
+            PackageOp::Remove(old.clone()),
+        ] {
+            let err = only_installs(vec![PackageOp::Install(new.clone()), op])
+                .err()
+                .unwrap();
+            assert!(matches!(
+                err.downcast_ref(),
+                Some(Error::UnexpectedPackageOp { .. })
+            ));
+        }
+        let err = only_installs(vec![PackageOp::Remove(old)]).err().unwrap();
+        assert!(err.to_string().ends_with("returned: remove A-1.0.0"));
+        Ok(())
+    }
+
+    fn index_json(packages: &[(&str, &str, &str)]) -> String {
+        let entries: Vec<_> = packages
+            .iter()
+            .map(|(name, version, file)| {
+                format!(
+                    r#"{{"name":"{}","version":"{}","description":"","depends":[],"accounts":[],"sha256":"0000000000000000000000000000000000000000000000000000000000000000","file":"{}"}}"#,
+                    name, version, file
+                )
+            })
+            .collect();
+        format!("[{}]", entries.join(","))
+    }
+
+    fn directory_registry(
+        packages: &[(&str, &str, &str)],
+    ) -> Result<(tempfile::TempDir, DirectoryRegistry), anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("index.json"), index_json(packages))?;
+        let registry = DirectoryRegistry::new(dir.path().to_path_buf());
+        Ok((dir, registry))
+    }
+
+    #[test]
+    fn directory_registry_get_info() -> Result<(), anyhow::Error> {
+        let (_dir, registry) = directory_registry(&[
+            ("A", "1.0.0", "A-1.0.0.psi"),
+            ("A", "1.2.0", "A-1.2.0.psi"),
+            ("B", "2.0.0", "B.psi"),
+        ])?;
+        assert_eq!(registry.get_info("A", "*")?.unwrap().version, "1.2.0");
+        assert_eq!(registry.get_info("A", "=1.0.0")?.unwrap().version, "1.0.0");
+        assert!(registry.get_info("A", "=2.0.0")?.is_none());
+        assert!(registry.get_info("C", "*")?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resolve_empty_service_list() -> Result<(), anyhow::Error> {
+        let (_dir, registry) = directory_registry(&[("A", "1.0.0", "A.psi")])?;
+        assert!(registry.resolve(&[]).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resolve_changes_skips_installed_packages() -> Result<(), anyhow::Error> {
+        let (_dir, registry) = directory_registry(&[("A", "1.0.0", "A.psi")])?;
+        let mut installed = PackageList::new();
+        installed.insert(
+            serde_json::from_str(r#"{"name":"A","version":"1.0.0"}"#)?,
+            PackageOrigin::Installed {
+                owner: AccountNumber::from("root"),
+            },
+        );
+        let packages = ["A".to_string()];
+        assert!(installed
+            .resolve_changes(&registry, &packages, &SolveOptions::default())
+            .await?
+            .is_empty());
+        assert_eq!(
+            installed
+                .resolve_changes(
+                    &registry,
+                    &packages,
+                    &SolveOptions {
+                        reinstall: true,
+                        ..Default::default()
+                    },
+                )
+                .await?
+                .len(),
+            1
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resolve_changes_refuses_downgrade() -> Result<(), anyhow::Error> {
+        let (_dir, registry) = directory_registry(&[("A", "1.0.0", "A.psi")])?;
+        let mut installed = PackageList::new();
+        installed.insert(
+            serde_json::from_str(r#"{"name":"A","version":"1.1.0"}"#)?,
+            PackageOrigin::Installed {
+                owner: AccountNumber::from("root"),
+            },
+        );
+        // The installed version is kept when no version is requested
+        let packages = ["A".to_string()];
+        assert!(installed
+            .resolve_changes(&registry, &packages, &SolveOptions::default())
+            .await?
+            .is_empty());
+        let packages = ["A-1.0.0".to_string()];
+        let err = installed
+            .resolve_changes(&registry, &packages, &SolveOptions::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(crate::depgraph::Error::Downgrade { .. })
+        ));
+        assert!(err
+            .to_string()
+            .starts_with("Refusing to downgrade A from 1.1.0 to 1.0.0"));
+        let ops = installed
+            .resolve_changes(
+                &registry,
+                &packages,
+                &SolveOptions {
+                    allow_downgrade: true,
+                    ..Default::default()
+                },
+            )
+            .await?;
+        assert!(matches!(&ops[..], [PackageOp::Replace(old, new)]
+            if old.version == "1.1.0" && new.version == "1.0.0"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_index_lists_packages() -> Result<(), anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        let package =
+            |name: &str, version: &str, depends: &[&str]| -> Result<Vec<u8>, anyhow::Error> {
+                let meta = Meta {
+                    name: name.to_string(),
+                    version: version.to_string(),
+                    description: format!("{} package", name),
+                    accounts: vec![AccountNumber::from_str(&name.to_lowercase())?],
+                    ..meta_with_deps(name, depends)
+                };
+                package_bytes(&meta, &[])
+            };
+        let b = package("B", "1.0.0", &[])?;
+        std::fs::write(dir.path().join("A.psi"), package("A", "1.1.0", &["B"])?)?;
+        std::fs::write(dir.path().join("B-1.0.0.psi"), &b)?;
+        std::fs::write(dir.path().join("notes.txt"), b"not a package")?;
+
+        let written = DirectoryRegistry::write_index(dir.path())?;
+        let registry = DirectoryRegistry::open(dir.path().to_path_buf())?;
+        assert_eq!(registry.index()?, written);
+        let info = registry.get_info("B", "*")?.unwrap();
+        assert_eq!(info.file, "B-1.0.0.psi");
+        assert_eq!(info.description, "B package");
+        assert_eq!(info.accounts, [AccountNumber::from("b")]);
+        let hash: [u8; 32] = Sha256::digest(&b).into();
+        assert_eq!(info.sha256, Checksum256::from(hash));
+        let info = registry.get_info("A", "*")?.unwrap();
+        assert_eq!(info.depends[0].name, "B");
+        let resolved = registry.resolve(&["A".to_string()]).await?;
+        let names: Vec<_> = resolved.iter().map(|p| p.name()).collect();
+        assert_eq!(names, ["B", "A"]);
+
+        std::fs::write(dir.path().join("B-copy.psi"), &b)?;
+        let err = DirectoryRegistry::write_index(dir.path()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::DuplicatePackage { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn verify_index_reports_stale_entries() -> Result<(), anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        let meta = Meta {
+            accounts: vec![AccountNumber::from("a"), AccountNumber::from("a-2")],
+            ..meta_with_deps("A", &["B", "C"])
+        };
+        std::fs::write(dir.path().join("A.psi"), package_bytes(&meta, &[])?)?;
+        let mut index = DirectoryRegistry::write_index(dir.path())?;
+        let registry = DirectoryRegistry::new(dir.path().to_path_buf());
+        assert!(registry.verify_index()?.is_empty());
+
+        // Order doesn't matter
+        index[0].depends.reverse();
+        index[0].accounts.reverse();
+        std::fs::write(dir.path().join("index.json"), serde_json::to_vec(&index)?)?;
+        assert!(registry.verify_index()?.is_empty());
+
+        // An entry for an older build of the package
+        index[0].depends.pop();
+        index[0].accounts.push(AccountNumber::from("b"));
+        std::fs::write(dir.path().join("index.json"), serde_json::to_vec(&index)?)?;
+        let errors = registry.verify_index()?;
+        assert_eq!(errors.len(), 1);
+        let Error::PackageMetaMismatch { package, fields } = &errors[0] else {
+            panic!("Unexpected error: {}", errors[0]);
+        };
+        assert_eq!(package, "A-1.0.0");
+        assert_eq!(fields, "depends, accounts");
+
+        index[0].sha256 = Checksum256::default();
+        std::fs::write(dir.path().join("index.json"), serde_json::to_vec(&index)?)?;
+        assert!(matches!(
+            &registry.verify_index()?[..],
+            [Error::PackageDigestFailure { .. }]
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn directory_registry_open_errors() -> Result<(), anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        let missing = dir.path().join("missing");
+        let err = DirectoryRegistry::open(missing).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::PackageSourceNotFound { .. })
+        ));
+
+        let err = DirectoryRegistry::open(dir.path().to_path_buf())
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::MissingPackageIndex { .. })
+        ));
+        assert!(err.to_string().starts_with("No package index found at "));
+
+        std::fs::write(dir.path().join("index.json"), "[{")?;
+        let err = DirectoryRegistry::open(dir.path().to_path_buf())
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::InvalidPackageIndex { .. })
+        ));
+
+        std::fs::write(dir.path().join("index.json"), INDEX)?;
+        DirectoryRegistry::open(dir.path().to_path_buf())?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http_registry_interrupted_download_leaves_no_files() -> Result<(), anyhow::Error> {
+        let url = mock_server_bytes(vec![
+            http_response("200 OK", "application/json", INDEX).into_bytes(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 1000\r\nConnection: close\r\n\r\npartial".to_vec(),
+        ])
+        .await?;
+        let dir = tempfile::tempdir()?;
+        let registry = HTTPRegistry::new(url, reqwest::Client::new())
+            .await?
+            .with_temp_dir(dir.path().to_path_buf());
+        let info = registry.index()?.pop().unwrap();
+        assert!(registry.get_by_info(&info).await.is_err());
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+
+        // A complete download is not left in the directory either
+        let url = mock_server_bytes(vec![
+            http_response("200 OK", "application/json", INDEX).into_bytes(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\ndata".to_vec(),
+        ])
+        .await?;
+        let registry = HTTPRegistry::new(url, reqwest::Client::new())
+            .await?
+            .with_temp_dir(dir.path().to_path_buf());
+        let (mut f, _) = registry.download(&info.file).await?;
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+        let mut content = String::new();
+        f.read_to_string(&mut content)?;
+        assert_eq!(content, "data");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http_registry_limits_package_size() -> Result<(), anyhow::Error> {
+        let index = || http_response("200 OK", "application/json", INDEX).into_bytes();
+        let is_too_large =
+            |err: anyhow::Error| matches!(err.downcast_ref(), Some(Error::PackageTooLarge { .. }));
+
+        // The advertised length is checked before reading the body
+        let url = mock_server_bytes(vec![
+            index(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 1000000\r\nConnection: close\r\n\r\n".to_vec(),
+        ])
+        .await?;
+        let registry =
+            HTTPRegistry::new_with_max_package_size(url, reqwest::Client::new(), 1000).await?;
+        let info = registry.index()?.pop().unwrap();
+        assert!(is_too_large(
+            registry.get_by_info(&info).await.err().unwrap()
+        ));
+
+        // Without a length, the download stops once it passes the limit
+        let mut response = b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n".to_vec();
+        response.extend_from_slice(&[0; 2000]);
+        let url = mock_server_bytes(vec![index(), response]).await?;
+        let dir = tempfile::tempdir()?;
+        let registry = HTTPRegistry::new_with_max_package_size(url, reqwest::Client::new(), 1000)
+            .await?
+            .with_temp_dir(dir.path().to_path_buf());
+        let info = registry.index()?.pop().unwrap();
+        let err = registry.get_by_info(&info).await.err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "The package file A.psi is larger than the limit of 1000 bytes"
+        );
+        assert!(is_too_large(err));
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http_registry_cross_origin_redirect() -> Result<(), anyhow::Error> {
+        async fn redirect_to(target: &reqwest::Url) -> Result<reqwest::Url, anyhow::Error> {
+            let location = target.join("index.json")?;
+            mock_server_bytes(vec![http_response_bytes(
+                "302 Found",
+                "text/plain",
+                &[("Location", location.as_str())],
+                b"",
+            )])
+            .await
+        }
+        let client = |allow| {
+            reqwest::Client::builder()
+                .redirect(redirect_policy(DEFAULT_MAX_REDIRECTS, allow))
+                .build()
+        };
+
+        // Refused by default
+        let target = mock_server(http_response("200 OK", "application/json", INDEX)).await?;
+        let url = redirect_to(&target).await?;
+        let err = HTTPRegistry::new(url, client(false)?).await.err().unwrap();
+        assert!(
+            err.chain()
+                .any(|e| matches!(e.downcast_ref(), Some(Error::CrossOriginRedirect { .. }))),
+            "Unexpected error: {:#}",
+            err
+        );
+
+        // When allowed, package files are fetched from the final origin
+        let target = mock_server(http_response("200 OK", "application/json", INDEX)).await?;
+        let url = redirect_to(&target).await?;
+        let registry = HTTPRegistry::new(url, client(true)?).await?;
+        assert_eq!(registry.index_url, target.join("index.json")?);
+        assert_eq!(registry.get_info("A", "*")?.unwrap().file, "A.psi");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http_registry_get_info() -> Result<(), anyhow::Error> {
+        let url = mock_server(http_response("200 OK", "application/json", INDEX)).await?;
+        let registry = HTTPRegistry::new(url, reqwest::Client::new()).await?;
+        assert_eq!(registry.get_info("A", "*")?.unwrap().file, "A.psi");
+        assert!(registry.get_info("A", "=2.0.0")?.is_none());
+        assert!(registry.get_info("B", "*")?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn joint_registry_get_info() -> Result<(), anyhow::Error> {
+        let (_dir, local) = directory_registry(&[
+            ("A", "1.0.0", "local-A-1.0.0.psi"),
+            ("B", "1.0.0", "local-B.psi"),
+        ])?;
+        let url = mock_server(http_response(
+            "200 OK",
+            "application/json",
+            &index_json(&[
+                ("A", "1.0.0", "remote-A.psi"),
+                ("B", "1.1.0", "remote-B.psi"),
+            ]),
+        ))
+        .await?;
+        let remote = HTTPRegistry::new(url, reqwest::Client::new()).await?;
+        let mut registry = JointRegistry::new();
+        registry.push(local)?;
+        registry.push(remote)?;
+        // Earlier sources are preferred for the same version
+        assert_eq!(
+            registry.get_info("A", "*")?.unwrap().file,
+            "local-A-1.0.0.psi"
+        );
+        // but a newer version wins regardless of source
+        assert_eq!(registry.get_info("B", "*")?.unwrap().file, "remote-B.psi");
+        assert_eq!(
+            registry.get_info("B", "=1.0.0")?.unwrap().file,
+            "local-B.psi"
+        );
+        assert!(registry.get_info("C", "*")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn joint_registry_preferred_source() -> Result<(), anyhow::Error> {
+        let (_remote_dir, remote) = directory_registry(&[
+            ("A", "1.0.0", "remote-A-1.0.0.psi"),
+            ("A", "1.1.0", "remote-A-1.1.0.psi"),
+            ("B", "1.0.0", "remote-B.psi"),
+        ])?;
+        let (_local_dir, local) = directory_registry(&[("A", "1.0.0", "local-A.psi")])?;
+        let mut registry = JointRegistry::new();
+        registry.push(remote)?;
+        registry.push_preferred(local)?;
+        // The preferred source wins even though the other has a newer version
+        assert_eq!(registry.get_info("A", "*")?.unwrap().file, "local-A.psi");
+        assert!(registry.get_info("A", "=1.1.0")?.is_none());
+        assert_eq!(registry.get_info("B", "*")?.unwrap().file, "remote-B.psi");
+        let mut files: Vec<_> = registry.index()?.into_iter().map(|p| p.file).collect();
+        files.sort();
+        assert_eq!(files, ["local-A.psi", "remote-B.psi"]);
+        Ok(())
+    }
+
+    fn package_with_meta(meta: &Meta) -> Result<PackagedService<Cursor<Vec<u8>>>, anyhow::Error> {
+        package_with_files(meta, &[])
+    }
+
+    #[test]
+    fn package_dir_round_trip() -> Result<(), anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        let meta = Meta {
+            name: "A".to_string(),
+            version: "1.0.0".to_string(),
+            accounts: vec![AccountNumber::from("a"), AccountNumber::from("b")],
+            ..Default::default()
+        };
+        let files: &[(&str, &[u8])] = &[
+            ("meta.json", &serde_json::to_vec(&meta)?),
+            ("service/a.wasm", &[0; 100]),
+            ("service/a.json", br#"{"flags":["allowSudo"],"server":"a"}"#),
+            ("data/b/index.html", b"<html/>"),
+            ("data/b/css/style.css", b"body {}"),
+            ("script/postinstall.json", b"[]"),
+        ];
+        for (name, contents) in files {
+            let path = dir.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, contents)?;
+        }
+        let contents = write_package_dir(dir.path(), Cursor::new(Vec::new()))?.into_inner();
+        let mut package = PackagedService::new(Cursor::new(contents))?;
+        assert_eq!(package.meta(), &meta);
+        assert_eq!(package.total_size(), 100 + 7 + 7);
+        let manifest = package.manifest();
+        assert_eq!(
+            manifest.services[&AccountNumber::from("a")].flags,
+            ["allowSudo"]
+        );
+        let mut data: Vec<_> = manifest.data.iter().map(|f| f.filename.as_str()).collect();
+        data.sort();
+        assert_eq!(data, ["/css/style.css", "/index.html"]);
+        let mut actions = vec![];
+        package.install(&mut actions, AccountNumber::from("root"), true)?;
+        assert_eq!(actions.len(), 4);
+
+        std::fs::write(
+            dir.path().join("service/a.json"),
+            br#"{"flags":["allowSudoo"]}"#,
+        )?;
+        let err = write_package_dir(dir.path(), Cursor::new(Vec::new())).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::InvalidFlags { .. })
+        ));
+        std::fs::remove_file(dir.path().join("service/a.json"))?;
+        std::fs::write(dir.path().join("README.md"), b"")?;
+        let err = write_package_dir(dir.path(), Cursor::new(Vec::new())).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::UnexpectedPackageFile { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn total_size_matches_archive() -> Result<(), anyhow::Error> {
+        let meta = Meta {
+            name: "A".to_string(),
+            version: "1.0.0".to_string(),
+            accounts: vec![AccountNumber::from("a"), AccountNumber::from("b")],
+            ..Default::default()
+        };
+        let contents = package_bytes(
+            &meta,
+            &[
+                ("service/a.wasm", &[0; 1000]),
+                ("service/a.json", br#"{"flags":[],"server":null}"#),
+                ("data/a/index.html", &[b'x'; 300]),
+                ("data/b/dir/style.css", b"body {}"),
+                ("script/postinstall.json", b"[]"),
+            ],
+        )?;
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(contents.clone()))?;
+        let mut expected = 0;
+        for name in [
+            "service/a.wasm",
+            "data/a/index.html",
+            "data/b/dir/style.css",
+        ] {
+            expected += archive.by_name(name)?.size();
+        }
+        assert_eq!(expected, 1307);
+        assert!(archive.by_name("service/a.wasm")?.compressed_size() < 1000);
+
+        let package = PackagedService::new(Cursor::new(contents))?;
+        assert_eq!(package.total_size(), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn files_without_accounts() -> Result<(), anyhow::Error> {
+        let meta = Meta {
+            name: "A".to_string(),
+            version: "1.0.0".to_string(),
+            ..Default::default()
+        };
+        let err = package_with_files(
+            &meta,
+            &[
+                ("service/b.wasm", b""),
+                ("service/a.wasm", b""),
+                ("data/a/index.html", b""),
+            ],
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "The package A has files for a, b, but the accounts in its meta.json are empty. Add each of these accounts to `accounts`."
+        );
+
+        // A package with no files doesn't need accounts
+        package_with_meta(&meta)?;
+        Ok(())
+    }
+
+    fn meta_with_deps(name: &str, depends: &[&str]) -> Meta {
+        Meta {
+            name: name.to_string(),
+            version: "1.0.0".to_string(),
+            depends: depends
+                .iter()
+                .map(|dep| PackageRef {
+                    name: dep.to_string(),
+                    version: "*".to_string(),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn validate_dependencies_reports_cycle() -> Result<(), anyhow::Error> {
+        let mut packages = vec![
+            package_with_meta(&meta_with_deps("A", &["B"]))?,
+            package_with_meta(&meta_with_deps("B", &["A"]))?,
+            package_with_meta(&meta_with_deps("C", &["A", "External"]))?,
+        ];
+        let err = validate_dependencies(&mut packages).unwrap_err();
+        let Some(Error::DependencyCycle { cycle }) = err.downcast_ref::<Error>() else {
+            panic!("Unexpected error: {}", err);
+        };
+        assert_eq!(cycle, "A -> B -> A");
+
+        let mut packages = vec![
+            package_with_meta(&meta_with_deps("A", &["B", "External"]))?,
+            package_with_meta(&meta_with_deps("B", &[]))?,
+            package_with_meta(&meta_with_deps("C", &["A", "B"]))?,
+        ];
+        validate_dependencies(&mut packages)?;
+        Ok(())
+    }
+
+    // Writes a package with `meta` and `files`, without checking them
+    fn package_bytes(meta: &Meta, files: &[(&str, &[u8])]) -> Result<Vec<u8>, anyhow::Error> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        writer.start_file("meta.json", zip::write::FileOptions::default())?;
+        writer.write_all(&serde_json::to_vec(meta)?)?;
+        for (name, contents) in files {
+            writer.start_file(*name, zip::write::FileOptions::default())?;
+            writer.write_all(contents)?;
+        }
+        Ok(writer.finish()?.into_inner())
+    }
+
+    fn package_with_files(
+        meta: &Meta,
+        files: &[(&str, &[u8])],
+    ) -> Result<PackagedService<Cursor<Vec<u8>>>, anyhow::Error> {
+        PackagedService::new(Cursor::new(package_bytes(meta, files)?))
+    }
+
+    #[test]
+    fn data_routed_to_dependency_service() -> Result<(), anyhow::Error> {
+        let (a, b) = (AccountNumber::from("a"), AccountNumber::from("b"));
+        let mut meta = meta_with_deps("A", &["B"]);
+        meta.accounts = vec![a];
+        let files: &[(&str, &[u8])] = &[
+            ("service/a.wasm", b""),
+            ("data/a@b/config.json", b"{}"),
+            ("data/a/index.html", b"<html/>"),
+        ];
+        let mut package = package_with_files(&meta, files)?;
+
+        let mut actions = vec![];
+        package.store_data(&mut actions)?;
+        let mut routes: Vec<_> = actions
+            .iter()
+            .map(|act| {
+                let data = sites::action_structs::storeSys::unpacked(&act.rawData).unwrap();
+                (act.sender, act.service, data.path)
+            })
+            .collect();
+        routes.sort_by_key(|(_, _, path)| path.clone());
+        assert_eq!(
+            routes,
+            [
+                (a, b, "/config.json".to_string()),
+                (a, a, "/index.html".to_string()),
+            ]
+        );
+        let manifest = package.manifest();
+        assert!(manifest
+            .data
+            .iter()
+            .any(|f| f.filename == "/config.json" && f.service == b));
+
+        let mut dep = meta_with_deps("B", &[]);
+        dep.accounts = vec![b];
+        let dep_files: &[(&str, &[u8])] = &[("service/b.wasm", b"")];
+        let mut packages = vec![package_with_files(&dep, dep_files)?, package];
+        validate_dependencies(&mut packages)?;
+
+        // The target service must belong to a dependency
+        dep.name = "C".to_string();
+        let mut packages = vec![
+            package_with_files(&dep, dep_files)?,
+            package_with_files(&meta, files)?,
+        ];
+        let err = validate_dependencies(&mut packages).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The package A uses C but does not depend on it"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn upgrade_removes_files_before_services() -> Result<(), anyhow::Error> {
+        let (a, b, svc) = (
+            AccountNumber::from("a"),
+            AccountNumber::from("b"),
+            AccountNumber::from("svc"),
+        );
+        let file = |account, service, filename: &str| PackageDataFile {
+            account,
+            service,
+            filename: filename.to_string(),
+        };
+        let old = PackageManifest {
+            services: HashMap::from([(
+                svc,
+                ServiceInfo {
+                    flags: vec!["allowSudo".to_string()],
+                    server: Some(svc),
+                },
+            )]),
+            data: vec![
+                file(svc, svc, "/stored.json"),
+                file(svc, svc, "/stored.json"),
+                file(a, sites::SERVICE, "/moved.html"),
+                file(a, sites::SERVICE, "/kept.html"),
+            ],
+        };
+        // svc keeps its code, but loses its flags and proxy registration
+        // and no longer stores anything. The file moves to another account.
+        let new = PackageManifest {
+            services: HashMap::from([(svc, ServiceInfo::default())]),
+            data: vec![
+                file(b, sites::SERVICE, "/moved.html"),
+                file(a, sites::SERVICE, "/kept.html"),
+            ],
+        };
+        let mut actions = vec![];
+        old.upgrade(new.clone(), &mut actions)?;
+        let summary: Vec<_> = actions
+            .iter()
+            .map(|act| {
+                let path = if act.method == MethodNumber::from("removeSys") {
+                    sites::action_structs::removeSys::unpacked(&act.rawData)?.path
+                } else {
+                    String::new()
+                };
+                Ok((act.sender, act.service, act.method, path))
+            })
+            .collect::<Result<_, anyhow::Error>>()?;
+        assert_eq!(
+            summary,
+            [
+                (
+                    svc,
+                    svc,
+                    MethodNumber::from("removeSys"),
+                    "/stored.json".to_string()
+                ),
+                (
+                    a,
+                    sites::SERVICE,
+                    MethodNumber::from("removeSys"),
+                    "/moved.html".to_string()
+                ),
+                (
+                    svc,
+                    http_server::SERVICE,
+                    MethodNumber::from("registerServer"),
+                    String::new()
+                ),
+                (
+                    setcode::SERVICE,
+                    setcode::SERVICE,
+                    MethodNumber::from("setFlags"),
+                    String::new()
+                ),
+            ]
+        );
+
+        // Removing the package removes each file once
+        let mut actions = vec![];
+        old.remove(&mut actions)?;
+        let removed = actions
+            .iter()
+            .filter(|act| act.method == MethodNumber::from("removeSys"))
+            .count();
+        assert_eq!(removed, 3);
+        assert_eq!(
+            actions.last().unwrap().method,
+            MethodNumber::from("setCode")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rotate_key_only_owned_accounts() {
+        let key: AnyPublicKey = "PUB_K1_6MRyAjQq8ud7hVNYcfnVPJqcVpscN5So8BhtHuGYqET5BoDq63"
+            .parse()
+            .unwrap();
+        let owner = AccountNumber::from("owner");
+        let keyed = AccountNumber::from("keyed");
+        let custom = AccountNumber::from("custom");
+        let delegated = AccountNumber::from("delegated");
+        let foreign = AccountNumber::from("foreign");
+        let accounts = [
+            (owner, owner),
+            (keyed, owner),
+            (custom, owner),
+            (delegated, owner),
+            (foreign, owner),
+        ];
+        let owned = [owner, keyed, custom, delegated];
+        let auth_services = HashMap::from([
+            (owner, AccountNumber::from("auth-any")),
+            (keyed, key.auth_service()),
+            (custom, AccountNumber::from("auth-custom")),
+            (delegated, auth_delegate::SERVICE),
+            (foreign, key.auth_service()),
+        ]);
+        let methods = |actions: Vec<Vec<Action>>| -> Vec<(AccountNumber, Vec<MethodNumber>)> {
+            actions
+                .iter()
+                .map(|group| {
+                    (
+                        group[0].sender,
+                        group.iter().map(|act| act.method).collect(),
+                    )
+                })
+                .collect()
+        };
+        let rotate = vec![
+            MethodNumber::from("setKey"),
+            MethodNumber::from("setAuthServ"),
+        ];
+
+        let (actions, skipped) = rotate_key_actions(&accounts, &owned, &auth_services, &key, false);
+        assert_eq!(
+            methods(actions),
+            vec![
+                (owner, rotate.clone()),
+                (keyed, vec![MethodNumber::from("setKey")]),
+            ]
+        );
+        assert_eq!(skipped, vec![custom, delegated]);
+
+        let (actions, skipped) = rotate_key_actions(&accounts, &owned, &auth_services, &key, true);
+        assert_eq!(
+            methods(actions),
+            vec![
+                (owner, rotate.clone()),
+                (keyed, vec![MethodNumber::from("setKey")]),
+                (custom, rotate.clone()),
+                (delegated, rotate),
+            ]
+        );
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    fn translate_flags_suggests_closest() {
+        let flags = vec!["isSubjective".to_string(), "allowSudoo".to_string()];
+        let err = translate_flags(&flags).unwrap_err();
+        assert!(matches!(
+            &err,
+            Error::InvalidFlags { flag, closest: Some(closest) }
+                if flag == "allowSudoo" && closest == "allowSudo"
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Invalid service flag allowSudoo. Did you mean allowSudo?"
+        );
+    }
+
+    #[test]
+    fn translate_flags_lists_known_flags() {
+        let err = translate_flags(&["readOnly".to_string()]).unwrap_err();
+        assert!(matches!(&err, Error::InvalidFlags { closest: None, .. }));
+        assert!(err.to_string().contains(&known_flags().join(", ")));
+        assert_eq!(
+            translate_flags(&["allowSudo".to_string(), "isAuthService".to_string()]).unwrap(),
+            1 << 0 | 1 << 6
+        );
+    }
+}
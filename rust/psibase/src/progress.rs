@@ -0,0 +1,63 @@
+#[cfg(not(target_family = "wasm"))]
+use indicatif::ProgressBar;
+
+/// Receives progress updates from long running operations such as
+/// [push_transactions](crate::push_transactions).
+///
+/// The CLI shows these with an [indicatif::ProgressBar]. Programs that
+/// embed this crate can implement it to report progress in their own UI.
+pub trait ProgressSink {
+    /// Advances the current position by `delta`
+    fn inc(&self, delta: u64);
+
+    /// Describes the step that is currently running
+    fn set_message(&self, msg: String);
+
+    /// Stops reporting progress after a failure
+    fn abandon(&self);
+
+    /// Runs `f`, which may write to the terminal, without interfering
+    /// with the progress display
+    fn suspend(&self, f: &mut dyn FnMut());
+
+    /// Prints `text` to stdout in a single write, without interfering
+    /// with the progress display. The console output and trace of each
+    /// transaction go through this, so that the output of transactions
+    /// that are pushed concurrently is not interleaved.
+    fn write_output(&self, text: &str) {
+        self.suspend(&mut || write_stdout(text))
+    }
+}
+
+pub(crate) fn write_stdout(text: &str) {
+    use std::io::Write;
+    let mut stdout = std::io::stdout().lock();
+    let _ = stdout.write_all(text.as_bytes());
+    let _ = stdout.flush();
+}
+
+impl dyn ProgressSink + '_ {
+    /// Like [ProgressSink::suspend], but forwards the result of `f`
+    pub fn suspend_with<R>(&self, f: impl FnOnce() -> R) -> R {
+        let mut f = Some(f);
+        let mut result = None;
+        self.suspend(&mut || result = f.take().map(|f| f()));
+        result.expect("ProgressSink::suspend must run its callback")
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl ProgressSink for ProgressBar {
+    fn inc(&self, delta: u64) {
+        ProgressBar::inc(self, delta)
+    }
+    fn set_message(&self, msg: String) {
+        ProgressBar::set_message(self, msg)
+    }
+    fn abandon(&self) {
+        ProgressBar::abandon(self)
+    }
+    fn suspend(&self, f: &mut dyn FnMut()) {
+        ProgressBar::suspend(self, f)
+    }
+}
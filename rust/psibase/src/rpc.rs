@@ -1,12 +1,18 @@
-use crate::{AccountNumber, Action, ActionGroup, ActionSink, SignedTransaction, TransactionTrace};
+use crate::{
+    AccountNumber, Action, ActionGroup, ActionSink, Checksum256, MethodNumber, ProgressSink,
+    SignedTransaction, Transaction, TransactionTrace,
+};
 use anyhow::Context;
 use async_graphql::{InputObject, SimpleObject};
 use custom_error::custom_error;
-use fracpack::Pack;
-use indicatif::ProgressBar;
+use fracpack::{Pack, Unpack};
+use log::{debug, trace};
 use reqwest::Url;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::net::SocketAddr;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 custom_error! { Error
     Message{message:String}                         = "{message}",
@@ -15,28 +21,83 @@ custom_error! { Error
     NoDomain = "Virtual hosting requires a URL with a domain name",
     GraphQLError{message: String} = "{message}",
     GraphQLWrongResponse = "Missing field `data` in graphql response",
+    HttpStatus{status: reqwest::StatusCode, body: String} = "{status}: {body}",
+    InvalidJson{message: String, body: String} = "{message} in response: {body}",
+    InvalidTransaction{label: String, error: fracpack::Error} = "{label}: transaction failed local verification: {error}",
+    ChainIdMismatch{expected: Checksum256, actual: Checksum256} = "Expected chain {expected}, but the node is on chain {actual}",
+    Interrupted{pushed: usize, total: usize} = "Interrupted after pushing {pushed} of {total} transactions",
+    ConnectFailed{url: Url, reason: String} = "Cannot connect to {url}: {reason}",
+    NoResponse{url: Url} = "No response from {url} before the timeout",
+    NodeNotReady{url: Url, reason: String} = "{url} was not ready before the timeout: {reason}",
+    ActionTooLarge{label: String, service: AccountNumber, method: MethodNumber, size: usize, limit: usize} = "{label}: the action {service}::{method} needs {size} bytes of action data, which is more than the transaction size limit of {limit}",
 }
 
-async fn as_text(builder: reqwest::RequestBuilder) -> Result<String, anyhow::Error> {
-    let mut response = builder.send().await?;
-    if response.status().is_client_error() {
-        response = response.error_for_status()?;
+// Error bodies may be entire HTML pages. Only the beginning
+// is needed to tell the user what went wrong.
+const MAX_ERROR_BODY: usize = 1024;
+
+fn truncate_body(body: &str) -> String {
+    let body = body.trim();
+    if body.len() <= MAX_ERROR_BODY {
+        return body.to_string();
+    }
+    let mut end = MAX_ERROR_BODY;
+    while !body.is_char_boundary(end) {
+        end -= 1;
     }
-    if response.status().is_server_error() {
-        return Err(anyhow::Error::new(Error::Message {
-            message: response.text().await?,
-        }));
+    body[..end].to_string() + "..."
+}
+
+// Also returns the URL that the response came from, after redirects
+async fn as_text(
+    builder: reqwest::RequestBuilder,
+) -> Result<(reqwest::Url, String), anyhow::Error> {
+    let (client, request) = builder.build_split();
+    let request = request?;
+    debug!("{} {}", request.method(), request.url());
+    let response = client.execute(request).await?;
+    let status = response.status();
+    let url = response.url().clone();
+    trace!("{} from {}", status, url);
+    let text = response.text().await?;
+    if !status.is_success() {
+        Err(Error::HttpStatus {
+            status,
+            body: truncate_body(&text),
+        })?
     }
-    Ok(response.text().await?)
+    Ok((url, text))
+}
+
+/// Returns true if `err` was caused by a 404 response
+pub(crate) fn is_not_found(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<Error>(),
+        Some(Error::HttpStatus { status, .. }) if *status == reqwest::StatusCode::NOT_FOUND
+    )
 }
 
 pub async fn as_json<T: DeserializeOwned>(
     builder: reqwest::RequestBuilder,
 ) -> Result<T, anyhow::Error> {
-    Ok(serde_json::de::from_str(&as_text(builder).await?)?)
+    Ok(as_json_with_url(builder).await?.1)
 }
 
-#[derive(Debug, Serialize, Deserialize, SimpleObject, InputObject)]
+/// Like [as_json], but also returns the final URL of the response
+pub(crate) async fn as_json_with_url<T: DeserializeOwned>(
+    builder: reqwest::RequestBuilder,
+) -> Result<(reqwest::Url, T), anyhow::Error> {
+    let (url, text) = as_text(builder).await?;
+    match serde_json::de::from_str(&text) {
+        Ok(result) => Ok((url, result)),
+        Err(e) => Err(Error::InvalidJson {
+            message: e.to_string(),
+            body: truncate_body(&text),
+        })?,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, SimpleObject, InputObject)]
 #[serde(rename_all = "camelCase")]
 #[graphql(input_name = "TaposRefBlockInput")]
 pub struct TaposRefBlock {
@@ -44,6 +105,33 @@ pub struct TaposRefBlock {
     pub ref_block_index: u8,
 }
 
+/// Parses `SUFFIX:INDEX`, where the suffix may be decimal or hex with
+/// a `0x` prefix, or else reads the JSON returned by `common/tapos/head`
+/// from the file with that name.
+impl FromStr for TaposRefBlock {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((suffix, index)) = s.split_once(':') else {
+            let text = std::fs::read_to_string(s)
+                .with_context(|| format!("Cannot read tapos block from {}", s))?;
+            return serde_json::from_str(&text)
+                .with_context(|| format!("Invalid tapos block in {}", s));
+        };
+        let ref_block_suffix = match suffix.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => suffix.parse(),
+        }
+        .with_context(|| format!("Invalid tapos block suffix {}", suffix))?;
+        let ref_block_index = index
+            .parse()
+            .with_context(|| format!("Invalid tapos block index {}; it must be 0 to 255", index))?;
+        Ok(TaposRefBlock {
+            ref_block_suffix,
+            ref_block_index,
+        })
+    }
+}
+
 async fn get_tapos_for_head_impl(
     base_url: &Url,
     client: reqwest::Client,
@@ -60,19 +148,173 @@ pub async fn get_tapos_for_head(
         .context("Failed to get tapos for head block")
 }
 
+pub async fn get_chain_id(
+    base_url: &Url,
+    client: reqwest::Client,
+) -> Result<Checksum256, anyhow::Error> {
+    as_json(client.get(base_url.join("common/chainid")?))
+        .await
+        .context("Failed to get chain id")
+}
+
+/// The result of [ping]
+pub struct PingReply {
+    /// The address that the request was sent to. This is the proxy,
+    /// if one is in use.
+    pub remote_addr: Option<SocketAddr>,
+    /// The time until the response headers arrived
+    pub latency: Duration,
+}
+
+/// Sends a lightweight request to the node at `base_url` and measures how
+/// long it takes to respond. Any HTTP response counts, including errors,
+/// since they show that the node is reachable.
+pub async fn ping(
+    base_url: &Url,
+    client: &reqwest::Client,
+    timeout: Duration,
+) -> Result<PingReply, anyhow::Error> {
+    let url = base_url.join("common/chainid")?;
+    let start = Instant::now();
+    let response = match client.get(url).timeout(timeout).send().await {
+        Ok(response) => response,
+        Err(e) if e.is_timeout() => Err(Error::NoResponse {
+            url: base_url.clone(),
+        })?,
+        Err(e) if e.is_connect() => Err(Error::ConnectFailed {
+            url: base_url.clone(),
+            reason: root_cause(&e),
+        })?,
+        Err(e) => Err(e)?,
+    };
+    Ok(PingReply {
+        remote_addr: response.remote_addr(),
+        latency: start.elapsed(),
+    })
+}
+
+// The root cause names the actual problem, e.g. a DNS failure
+fn root_cause(e: &reqwest::Error) -> String {
+    let mut reason: &dyn std::error::Error = e;
+    while let Some(source) = reason.source() {
+        reason = source;
+    }
+    reason.to_string()
+}
+
+/// Polls the node at `base_url` every `interval` until it responds
+/// without a server error, or fails once `timeout` has passed. A node
+/// that was just started may refuse connections for a short while.
+pub async fn wait_for_node(
+    base_url: &Url,
+    client: &reqwest::Client,
+    timeout: Duration,
+    interval: Duration,
+) -> Result<(), anyhow::Error> {
+    let url = base_url.join("common/chainid")?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let request = client.get(url.clone()).timeout(remaining.max(interval));
+        let reason = match request.send().await {
+            Ok(response) if !response.status().is_server_error() => return Ok(()),
+            Ok(response) => response.status().to_string(),
+            Err(e) if e.is_timeout() => "no response".to_string(),
+            Err(e) => root_cause(&e),
+        };
+        debug!("{} is not ready: {}", base_url, reason);
+        if Instant::now() >= deadline {
+            Err(Error::NodeNotReady {
+                url: base_url.clone(),
+                reason,
+            })?
+        }
+        tokio::time::sleep(interval.min(deadline.saturating_duration_since(Instant::now()))).await;
+    }
+}
+
+/// Fetches the trace of a transaction that was recently pushed to the
+/// node at `base_url`, using the node's admin API. `id` is the sha256
+/// of the packed transaction.
+/// Returns `None` if the node does not have the trace, e.g. because
+/// the transaction was pushed elsewhere or has been pruned.
+pub async fn get_transaction_trace(
+    base_url: &Url,
+    client: reqwest::Client,
+    id: &Checksum256,
+) -> Result<Option<TransactionTrace>, anyhow::Error> {
+    match as_json(client.get(base_url.join(&format!("native/admin/trace/{}", id))?)).await {
+        Ok(trace) => Ok(Some(trace)),
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e.context("Failed to get transaction trace")),
+    }
+}
+
+/// Fails if the node at `base_url` is not on the chain `expected`
+pub async fn check_chain_id(
+    base_url: &Url,
+    client: reqwest::Client,
+    expected: &Checksum256,
+) -> Result<(), anyhow::Error> {
+    let actual = get_chain_id(base_url, client).await?;
+    if actual != *expected {
+        Err(Error::ChainIdMismatch {
+            expected: expected.clone(),
+            actual,
+        })?
+    }
+    Ok(())
+}
+
+/// How transaction traces are reported.
+///
+/// `Json` and `CompactJson` print the serde representation of
+/// [TransactionTrace], which is documented there. `Json` is pretty
+/// printed; `CompactJson` prints each trace on a single line.
 #[derive(Debug, Copy, Clone)]
 pub enum TraceFormat {
     Error,
     Stack,
     Full,
     Json,
+    CompactJson,
 }
 
 impl TraceFormat {
     pub fn error_for_trace(
         &self,
         trace: TransactionTrace,
-        progress: Option<&ProgressBar>,
+        progress: Option<&dyn ProgressSink>,
+    ) -> Result<(), anyhow::Error> {
+        self.report_trace(trace, false, progress)
+    }
+
+    /// Like [error_for_trace](Self::error_for_trace), but with `console`
+    /// the console output of the transaction is printed first. The
+    /// console output and the trace are printed in one write.
+    pub fn report_trace(
+        &self,
+        trace: TransactionTrace,
+        console: bool,
+        progress: Option<&dyn ProgressSink>,
+    ) -> Result<(), anyhow::Error> {
+        let mut output = String::new();
+        if console {
+            output = trace.console().to_string();
+        }
+        let result = self.format_trace(trace, &mut output);
+        if console || !output.is_empty() {
+            progress.write_output(&output);
+        }
+        result
+    }
+
+    // Appends the trace to output, or returns it as the error if the
+    // transaction failed
+    fn format_trace(
+        &self,
+        trace: TransactionTrace,
+        output: &mut String,
     ) -> Result<(), anyhow::Error> {
         if let Some(e) = &trace.error {
             if !e.is_empty() {
@@ -80,15 +322,15 @@ impl TraceFormat {
                     TraceFormat::Error => e.to_string(),
                     TraceFormat::Stack => trace.fmt_stack(),
                     TraceFormat::Full => trace.to_string(),
-                    TraceFormat::Json => serde_json::to_string(&trace)?,
+                    TraceFormat::Json | TraceFormat::CompactJson => serde_json::to_string(&trace)?,
                 };
                 Err(Error::ExecutionFailed { message })?;
             }
         }
         match self {
-            TraceFormat::Full => progress.suspend(|| print!("{}", trace.to_string())),
-            TraceFormat::Json => progress
-                .suspend(|| serde_json::to_writer_pretty(std::io::stdout().lock(), &trace))?,
+            TraceFormat::Full => *output += &trace.to_string(),
+            TraceFormat::Json => *output += &serde_json::to_string_pretty(&trace)?,
+            TraceFormat::CompactJson => *output += &(serde_json::to_string(&trace)? + "\n"),
             _ => {}
         }
         Ok(())
@@ -108,16 +350,16 @@ impl FromStr for TraceFormat {
     }
 }
 
-trait OptionProgressBar {
-    fn suspend<F: FnOnce() -> R, R>(&self, f: F) -> R;
+trait OptionProgressSink {
+    fn write_output(&self, text: &str);
 }
 
-impl OptionProgressBar for Option<&ProgressBar> {
-    fn suspend<F: FnOnce() -> R, R>(&self, f: F) -> R {
+impl OptionProgressSink for Option<&dyn ProgressSink> {
+    fn write_output(&self, text: &str) {
         if let Some(progress) = self {
-            progress.suspend(f)
+            progress.write_output(text)
         } else {
-            f()
+            crate::progress::write_stdout(text)
         }
     }
 }
@@ -128,7 +370,7 @@ async fn push_transaction_impl(
     packed: Vec<u8>,
     fmt: TraceFormat,
     console: bool,
-    progress: Option<&ProgressBar>,
+    progress: Option<&dyn ProgressSink>,
 ) -> Result<(), anyhow::Error> {
     let trace: TransactionTrace = as_json(
         client
@@ -136,10 +378,7 @@ async fn push_transaction_impl(
             .body(packed),
     )
     .await?;
-    if console {
-        progress.suspend(|| print!("{}", trace.console()));
-    }
-    fmt.error_for_trace(trace, progress)
+    fmt.report_trace(trace, console, progress)
 }
 
 pub async fn push_transaction(
@@ -148,7 +387,7 @@ pub async fn push_transaction(
     packed: Vec<u8>,
     fmt: TraceFormat,
     console: bool,
-    progress: Option<&ProgressBar>,
+    progress: Option<&dyn ProgressSink>,
 ) -> Result<(), anyhow::Error> {
     push_transaction_impl(base_url, client, packed, fmt, console, progress)
         .await
@@ -178,17 +417,49 @@ impl<F: Fn(Vec<Action>) -> Result<SignedTransaction, anyhow::Error>> Transaction
         self.transactions
             .push((label, vec![], !self.actions.is_empty()))
     }
+    /// Adds a group of actions, which will not be split across
+    /// transactions. The pending transaction is finished first if
+    /// the group would take it over the action limit.
+    ///
+    /// Fails if the group by itself is over the action limit, since
+    /// no transaction could hold it. The error names the largest
+    /// action in the group.
     pub fn push<T: ActionGroup>(&mut self, act: T) -> Result<(), anyhow::Error> {
-        act.append_to_tx(&mut self.actions, &mut self.size);
+        let mut group = vec![];
+        let mut size = 0;
+        act.append_to_tx(&mut group, &mut size);
+        if size > self.action_limit {
+            let act = group.iter().max_by_key(|act| act.rawData.len()).unwrap();
+            Err(Error::ActionTooLarge {
+                label: self
+                    .transactions
+                    .last()
+                    .map_or_else(String::new, |(label, _, _)| label.clone()),
+                service: act.service,
+                method: act.method,
+                size,
+                limit: self.action_limit,
+            })?
+        }
+        if !self.actions.is_empty() && self.size + size > self.action_limit {
+            self.flush()?;
+        }
+        self.actions.append(&mut group);
+        self.size += size;
         if self.size >= self.action_limit {
-            self.transactions
-                .last_mut()
-                .unwrap()
-                .1
-                .push((self.f)(std::mem::take(&mut self.actions))?);
+            self.flush()?;
         }
         Ok(())
     }
+    fn flush(&mut self) -> Result<(), anyhow::Error> {
+        self.transactions
+            .last_mut()
+            .unwrap()
+            .1
+            .push((self.f)(std::mem::take(&mut self.actions))?);
+        self.size = 0;
+        Ok(())
+    }
     pub fn push_all<T: ActionGroup>(&mut self, actions: Vec<T>) -> Result<(), anyhow::Error> {
         for act in actions {
             self.push(act)?;
@@ -212,14 +483,104 @@ impl<F: Fn(Vec<Action>) -> Result<SignedTransaction, anyhow::Error>> ActionSink
     }
 }
 
+/// Checks that the packed transaction inside a signed transaction,
+/// including its actions, is valid fracpack.
+pub fn verify_transaction(trx: &SignedTransaction) -> Result<(), fracpack::Error> {
+    Transaction::verify_all(&trx.transaction)
+}
+
+/// Verifies all transactions locally before they are pushed. The
+/// work is split across threads and stops at the first failure,
+/// which is reported with the label of its transaction group.
+pub fn verify_transactions(
+    transaction_groups: &[(String, Vec<SignedTransaction>, bool)],
+) -> Result<(), anyhow::Error> {
+    let transactions: Vec<(&str, &SignedTransaction)> = transaction_groups
+        .iter()
+        .flat_map(|(label, transactions, _)| transactions.iter().map(|trx| (label.as_str(), trx)))
+        .collect();
+    verify_labeled_transactions(&transactions)
+}
+
+/// Like [verify_transactions], for transactions that each have their
+/// own label
+pub fn verify_labeled_transactions(
+    transactions: &[(&str, &SignedTransaction)],
+) -> Result<(), anyhow::Error> {
+    if transactions.is_empty() {
+        return Ok(());
+    }
+    let num_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = (transactions.len() + num_threads - 1) / num_threads;
+    let failed = AtomicBool::new(false);
+    let result = std::thread::scope(|scope| {
+        let workers: Vec<_> = transactions
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let failed = &failed;
+                scope.spawn(move || {
+                    for (label, trx) in chunk {
+                        if failed.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        if let Err(error) = verify_transaction(trx) {
+                            failed.store(true, Ordering::Relaxed);
+                            return Err(Error::InvalidTransaction {
+                                label: label.to_string(),
+                                error,
+                            });
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect::<Result<Vec<()>, Error>>()
+    });
+    result?;
+    Ok(())
+}
+
 pub async fn push_transactions(
     base_url: &Url,
     client: reqwest::Client,
     transaction_groups: Vec<(String, Vec<SignedTransaction>, bool)>,
     fmt: TraceFormat,
     console: bool,
-    progress: &ProgressBar,
+    progress: &dyn ProgressSink,
 ) -> Result<(), anyhow::Error> {
+    push_transactions_until(
+        base_url,
+        client,
+        transaction_groups,
+        fmt,
+        console,
+        progress,
+        &AtomicBool::new(false),
+    )
+    .await
+}
+
+/// Like [push_transactions], but stops before pushing the next
+/// transaction once `interrupted` is set. Transactions that were
+/// already pushed are not affected.
+pub async fn push_transactions_until(
+    base_url: &Url,
+    client: reqwest::Client,
+    transaction_groups: Vec<(String, Vec<SignedTransaction>, bool)>,
+    fmt: TraceFormat,
+    console: bool,
+    progress: &dyn ProgressSink,
+    interrupted: &AtomicBool,
+) -> Result<(), anyhow::Error> {
+    let total = transaction_groups
+        .iter()
+        .map(|(_, transactions, _)| transactions.len())
+        .sum();
+    let mut pushed = 0;
     let mut n = 0;
     for (label, transactions, carry) in transaction_groups {
         progress.set_message(label);
@@ -228,6 +589,10 @@ pub async fn push_transactions(
             n = 0;
         }
         for trx in transactions {
+            if interrupted.load(Ordering::Relaxed) {
+                progress.abandon();
+                Err(Error::Interrupted { pushed, total })?
+            }
             let result = push_transaction(
                 base_url,
                 client.clone(),
@@ -242,6 +607,7 @@ pub async fn push_transactions(
                 progress.abandon();
                 return Err(err);
             }
+            pushed += 1;
             progress.inc(n);
             n = 0;
         }
@@ -250,6 +616,48 @@ pub async fn push_transactions(
     Ok(())
 }
 
+/// Pushes the transactions that follow [push_boot](crate::create_boot_transactions)
+/// after a boot was interrupted. The chain only accepts boot
+/// transactions in order, so the leading transactions that it rejects
+/// as duplicates have already been applied and are skipped. Returns
+/// the number of transactions that were pushed.
+pub async fn resume_boot_transactions(
+    base_url: &Url,
+    client: reqwest::Client,
+    transactions: Vec<SignedTransaction>,
+    fmt: TraceFormat,
+    console: bool,
+    progress: &dyn ProgressSink,
+) -> Result<usize, anyhow::Error> {
+    let mut pushed = 0;
+    for trx in transactions {
+        let result = push_transaction(
+            base_url,
+            client.clone(),
+            trx.packed(),
+            fmt,
+            console,
+            Some(progress),
+        )
+        .await;
+        match result {
+            Ok(()) => pushed += 1,
+            Err(err)
+                if pushed == 0
+                    && err
+                        .root_cause()
+                        .to_string()
+                        .contains("duplicate transaction") => {}
+            Err(err) => {
+                progress.abandon();
+                return Err(err);
+            }
+        }
+        progress.inc(1);
+    }
+    Ok(pushed)
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct GQLError {
     message: String,
@@ -300,3 +708,707 @@ pub async fn gql_query<T: DeserializeOwned>(
     };
     Ok(data)
 }
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use reqwest::Url;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    // Serves a single canned HTTP response
+    pub async fn mock_server(response: String) -> Result<Url, anyhow::Error> {
+        mock_server_bytes(vec![response.into_bytes()]).await
+    }
+
+    // Serves each response to a separate connection, in order
+    pub async fn mock_server_bytes(responses: Vec<Vec<u8>>) -> Result<Url, anyhow::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/", listener.local_addr()?))?;
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        Ok(url)
+    }
+
+    // Serves 200 responses with the given JSON bodies, in order, keeping
+    // connections open between requests. Returns the number of
+    // connections that were accepted.
+    pub async fn mock_keep_alive_server(
+        bodies: Vec<String>,
+    ) -> Result<(Url, Arc<AtomicUsize>), anyhow::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/", listener.local_addr()?))?;
+        let connections = Arc::new(AtomicUsize::new(0));
+        let bodies = Arc::new(Mutex::new(bodies.into_iter()));
+        let count = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                count.fetch_add(1, Ordering::SeqCst);
+                let bodies = bodies.clone();
+                tokio::spawn(async move {
+                    while read_request(&mut stream).await.is_some() {
+                        let Some(body) = bodies.lock().unwrap().next() else {
+                            return;
+                        };
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                            body.len(),
+                            body
+                        );
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        Ok((url, connections))
+    }
+
+    // Reads one request, including its body
+    async fn read_request(stream: &mut TcpStream) -> Option<()> {
+        let mut data = vec![];
+        let mut buf = [0u8; 4096];
+        loop {
+            if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                let headers = String::from_utf8_lossy(&data[..end]).to_ascii_lowercase();
+                let len: usize = headers
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .map_or(0, |len| len.trim().parse().unwrap());
+                if data.len() >= end + 4 + len {
+                    return Some(());
+                }
+            }
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    pub fn http_response(status: &str, content_type: &str, body: &str) -> String {
+        String::from_utf8(http_response_bytes(
+            status,
+            content_type,
+            &[],
+            body.as_bytes(),
+        ))
+        .unwrap()
+    }
+
+    pub fn http_response_bytes(
+        status: &str,
+        content_type: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Vec<u8> {
+        let mut result = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            status,
+            content_type,
+            body.len(),
+        );
+        for (name, value) in headers {
+            result += &format!("{}: {}\r\n", name, value);
+        }
+        result += "\r\n";
+        let mut result = result.into_bytes();
+        result.extend_from_slice(body);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::*;
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn as_json_reports_server_error_page() -> Result<(), anyhow::Error> {
+        let page = "<html><body><h1>502 Bad Gateway</h1>".to_string() + &"x".repeat(4000);
+        let url = mock_server(http_response(
+            "500 Internal Server Error",
+            "text/html",
+            &page,
+        ))
+        .await?;
+        let err = as_json::<TaposRefBlock>(reqwest::Client::new().get(url))
+            .await
+            .unwrap_err();
+        let Some(Error::HttpStatus { status, body }) = err.downcast_ref::<Error>() else {
+            panic!("Unexpected error: {}", err);
+        };
+        assert_eq!(*status, reqwest::StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body.starts_with("<html><body><h1>502 Bad Gateway</h1>"));
+        assert_eq!(body.len(), MAX_ERROR_BODY + 3);
+        Ok(())
+    }
+
+    fn signed(transaction: Vec<u8>) -> SignedTransaction {
+        SignedTransaction {
+            transaction: transaction.into(),
+            proofs: vec![],
+        }
+    }
+
+    #[test]
+    fn verify_transactions_reports_malformed_action() {
+        let trx = Transaction {
+            tapos: Default::default(),
+            actions: vec![Action {
+                sender: AccountNumber::from("alice"),
+                service: AccountNumber::from("sites"),
+                method: MethodNumber::from("storeSys"),
+                rawData: vec![1, 2, 3, 4, 5, 6, 7, 8].into(),
+            }],
+            claims: vec![],
+        };
+        let packed = trx.packed();
+        let mut malformed = packed.clone();
+        // The action's data is the last thing on the heap
+        malformed.truncate(packed.len() - 4);
+        let groups = vec![
+            ("good".to_string(), vec![signed(packed.clone())], false),
+            (
+                "bad".to_string(),
+                vec![signed(packed), signed(malformed)],
+                false,
+            ),
+        ];
+        let err = verify_transactions(&groups).unwrap_err();
+        let Some(Error::InvalidTransaction { label, .. }) = err.downcast_ref::<Error>() else {
+            panic!("Unexpected error: {}", err);
+        };
+        assert_eq!(label, "bad");
+        assert!(verify_transactions(&groups[..1]).is_ok());
+
+        let labeled = [("a.txt", &groups[1].1[0]), ("b.txt", &groups[1].1[1])];
+        let err = verify_labeled_transactions(&labeled).unwrap_err();
+        let Some(Error::InvalidTransaction { label, .. }) = err.downcast_ref::<Error>() else {
+            panic!("Unexpected error: {}", err);
+        };
+        assert_eq!(label, "b.txt");
+    }
+
+    #[tokio::test]
+    async fn as_json_reports_non_json_body() -> Result<(), anyhow::Error> {
+        let url = mock_server(http_response("200 OK", "text/plain", "not json")).await?;
+        let err = as_json::<TaposRefBlock>(reqwest::Client::new().get(url))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().ends_with("in response: not json"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ping_reports_latency_and_address() -> Result<(), anyhow::Error> {
+        let url = mock_server(http_response("200 OK", "application/json", "\"\"")).await?;
+        let reply = ping(&url, &reqwest::Client::new(), Duration::from_secs(10)).await?;
+        assert!(reply.latency > Duration::ZERO);
+        assert_eq!(
+            reply.remote_addr.unwrap().port(),
+            url.port_or_known_default().unwrap()
+        );
+
+        // Nothing listens on the port after the listener is dropped
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let url = Url::parse(&format!("http://{}/", listener.local_addr()?))?;
+        drop(listener);
+        let err = ping(&url, &reqwest::Client::new(), Duration::from_secs(10))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::ConnectFailed { .. })
+        ));
+        assert!(err
+            .to_string()
+            .starts_with(&format!("Cannot connect to {}: ", url)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wait_for_node_until_ready() -> Result<(), anyhow::Error> {
+        let interval = Duration::from_millis(20);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let url = Url::parse(&format!("http://{}/", addr))?;
+        drop(listener);
+
+        // Nobody is listening, so this times out
+        let err = wait_for_node(
+            &url,
+            &reqwest::Client::new(),
+            Duration::from_millis(100),
+            interval,
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::NodeNotReady { .. })
+        ));
+
+        // The node starts listening after a delay, and then reports
+        // that it is unavailable before it is ready
+        let started = Instant::now();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+            for response in [
+                http_response("503 Service Unavailable", "text/plain", "starting"),
+                http_response("200 OK", "application/json", "\"\""),
+            ] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        wait_for_node(
+            &url,
+            &reqwest::Client::new(),
+            Duration::from_secs(10),
+            interval,
+        )
+        .await?;
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_chain_id_matches_node() -> Result<(), anyhow::Error> {
+        let chain_id = "a".repeat(64);
+        let node_chain: Checksum256 = chain_id.parse().unwrap();
+        let body = format!("\"{}\"", chain_id);
+
+        let url = mock_server(http_response("200 OK", "application/json", &body)).await?;
+        check_chain_id(&url, reqwest::Client::new(), &node_chain).await?;
+
+        let other: Checksum256 = "b".repeat(64).parse().unwrap();
+        let url = mock_server(http_response("200 OK", "application/json", &body)).await?;
+        let err = check_chain_id(&url, reqwest::Client::new(), &other)
+            .await
+            .unwrap_err();
+        let Some(Error::ChainIdMismatch { expected, actual }) = err.downcast_ref::<Error>() else {
+            panic!("Unexpected error: {}", err);
+        };
+        assert_eq!(*expected, other);
+        assert_eq!(*actual, node_chain);
+        Ok(())
+    }
+
+    #[test]
+    fn transaction_builder_honors_action_limit() -> Result<(), anyhow::Error> {
+        let action = |len: usize| Action {
+            sender: AccountNumber::new(0),
+            service: AccountNumber::new(0),
+            method: MethodNumber::new(0),
+            rawData: vec![0; len].into(),
+        };
+        let mut builder = TransactionBuilder::new(1000, |actions: Vec<Action>| {
+            Ok(signed(
+                Transaction {
+                    actions,
+                    ..Default::default()
+                }
+                .packed(),
+            ))
+        });
+        builder.set_label("test".to_string());
+        for _ in 0..5 {
+            builder.push(action(300))?;
+        }
+        builder.push(vec![action(600), action(400)])?;
+        builder.push(action(100))?;
+        let sizes: Vec<Vec<usize>> = builder.finish()?[0]
+            .1
+            .iter()
+            .map(|trx| {
+                Transaction::unpacked(&trx.transaction)
+                    .unwrap()
+                    .actions
+                    .iter()
+                    .map(|a| a.rawData.len())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            sizes,
+            vec![
+                vec![300, 300, 300],
+                vec![300, 300],
+                vec![600, 400],
+                vec![100]
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn transaction_builder_rejects_oversized_action() {
+        let action = |len: usize| Action {
+            sender: AccountNumber::from("alice"),
+            service: AccountNumber::from("sites"),
+            method: MethodNumber::from("storeSys"),
+            rawData: vec![0; len].into(),
+        };
+        let mut builder = TransactionBuilder::new(1000, |actions: Vec<Action>| {
+            Ok(signed(
+                Transaction {
+                    actions,
+                    ..Default::default()
+                }
+                .packed(),
+            ))
+        });
+        builder.set_label("Installing Big-1.0.0".to_string());
+        let err = builder.push(action(1001)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Installing Big-1.0.0: the action sites::storesys needs 1001 bytes of action data, which is more than the transaction size limit of 1000"
+        );
+        // A group can't be split, so it must fit as a whole
+        let err = builder
+            .push(vec![action(600), action(100), action(600)])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Installing Big-1.0.0: the action sites::storesys needs 1300 bytes of action data, which is more than the transaction size limit of 1000"
+        );
+        assert!(builder.finish().unwrap()[0].1.is_empty());
+    }
+
+    #[tokio::test]
+    async fn resume_boot_skips_applied_transactions() -> Result<(), anyhow::Error> {
+        let trace = |error: &str| {
+            http_response(
+                "200 OK",
+                "application/json",
+                &format!(
+                    r#"{{"actionTraces":[],"error":{}}}"#,
+                    serde_json::to_string(&(!error.is_empty()).then_some(error)).unwrap()
+                ),
+            )
+            .into_bytes()
+        };
+        // The first two were applied before the boot was interrupted
+        let url = mock_server_bytes(vec![
+            trace("duplicate transaction"),
+            trace("duplicate transaction"),
+            trace(""),
+            trace(""),
+        ])
+        .await?;
+        let transactions = (0..4u8).map(|i| signed(vec![i])).collect();
+        let progress = indicatif::ProgressBar::hidden();
+        let pushed = resume_boot_transactions(
+            &url,
+            reqwest::Client::new(),
+            transactions,
+            TraceFormat::Error,
+            false,
+            &progress,
+        )
+        .await?;
+        assert_eq!(pushed, 2);
+        assert_eq!(progress.position(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn json_trace_has_documented_keys() -> Result<(), anyhow::Error> {
+        let trace: TransactionTrace = serde_json::from_str(
+            r#"{"actionTraces":[{"action":{"sender":"alice","service":"nop","method":"nop","rawData":"00"},"rawRetval":"","innerTraces":[{"inner":{"ConsoleTrace":{"console":"hello"}}},{"inner":{"EventTrace":{"name":"e","data":""}}}],"totalTime":"5","error":"failed"}],"error":"failed"}"#,
+        )?;
+        let keys = |value: &serde_json::Value| {
+            let mut keys: Vec<_> = value.as_object().unwrap().keys().cloned().collect();
+            keys.sort();
+            keys
+        };
+        let json = serde_json::to_value(&trace)?;
+        assert_eq!(keys(&json), ["actionTraces", "error"]);
+        let action_trace = &json["actionTraces"][0];
+        assert_eq!(
+            keys(action_trace),
+            ["action", "error", "innerTraces", "rawRetval", "totalTime"]
+        );
+        assert_eq!(
+            keys(&action_trace["action"]),
+            ["method", "rawData", "sender", "service"]
+        );
+        assert_eq!(action_trace["totalTime"], 5);
+        assert_eq!(
+            action_trace["innerTraces"][0]["inner"]["ConsoleTrace"]["console"],
+            "hello"
+        );
+        assert_eq!(
+            action_trace["innerTraces"][1]["inner"]["EventTrace"]["name"],
+            "e"
+        );
+
+        // Both JSON formats report failures as a single line
+        for format in [TraceFormat::Json, TraceFormat::CompactJson] {
+            let message = format
+                .error_for_trace(trace.clone(), None)
+                .unwrap_err()
+                .to_string();
+            assert!(!message.contains('\n'));
+            assert_eq!(serde_json::from_str::<serde_json::Value>(&message)?, json);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_transaction_trace_from_node() -> Result<(), anyhow::Error> {
+        let id = Checksum256::from([7; 32]);
+        let url = mock_server_bytes(vec![
+            http_response(
+                "200 OK",
+                "application/json",
+                r#"{"actionTraces":[{"action":{"sender":"alice","service":"nop","method":"nop","rawData":""},"rawRetval":"","innerTraces":[{"inner":{"ConsoleTrace":{"console":"hello"}}}],"totalTime":1,"error":null}],"error":null}"#,
+            )
+            .into_bytes(),
+            http_response("404 Not Found", "text/html", "The resource was not found.")
+                .into_bytes(),
+        ])
+        .await?;
+
+        let trace = get_transaction_trace(&url, reqwest::Client::new(), &id)
+            .await?
+            .unwrap();
+        assert_eq!(trace.console().to_string(), "hello");
+        assert!(trace.error.is_none());
+
+        let missing = get_transaction_trace(&url, reqwest::Client::new(), &id).await?;
+        assert!(missing.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn push_transactions_stops_when_interrupted() -> Result<(), anyhow::Error> {
+        let interrupted = std::sync::Arc::new(AtomicBool::new(false));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/", listener.local_addr()?))?;
+        let flag = interrupted.clone();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            for request in 1.. {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                // The user presses Ctrl-C while the second transaction is in flight
+                if request == 2 {
+                    flag.store(true, Ordering::Relaxed);
+                }
+                let response = http_response(
+                    "200 OK",
+                    "application/json",
+                    r#"{"actionTraces":[],"error":null}"#,
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let trx = || SignedTransaction {
+            transaction: Transaction::default().packed().into(),
+            proofs: vec![],
+        };
+        let groups = vec![
+            ("first".to_string(), vec![trx(), trx()], false),
+            ("second".to_string(), vec![trx()], false),
+        ];
+        let err = push_transactions_until(
+            &url,
+            reqwest::Client::new(),
+            groups,
+            TraceFormat::Error,
+            false,
+            &indicatif::ProgressBar::hidden(),
+            &interrupted,
+        )
+        .await
+        .unwrap_err();
+        let Some(Error::Interrupted { pushed, total }) = err.downcast_ref::<Error>() else {
+            panic!("Unexpected error: {}", err);
+        };
+        assert_eq!((*pushed, *total), (2, 3));
+        Ok(())
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum ProgressEvent {
+        Inc(u64),
+        Message(String),
+        Abandon,
+        Suspend,
+    }
+
+    #[derive(Default)]
+    struct RecordingSink {
+        events: Mutex<Vec<ProgressEvent>>,
+    }
+
+    impl ProgressSink for RecordingSink {
+        fn inc(&self, delta: u64) {
+            self.events.lock().unwrap().push(ProgressEvent::Inc(delta));
+        }
+        fn set_message(&self, msg: String) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(ProgressEvent::Message(msg));
+        }
+        fn abandon(&self) {
+            self.events.lock().unwrap().push(ProgressEvent::Abandon);
+        }
+        fn suspend(&self, f: &mut dyn FnMut()) {
+            self.events.lock().unwrap().push(ProgressEvent::Suspend);
+            f()
+        }
+    }
+
+    #[tokio::test]
+    async fn push_transactions_reports_progress() -> Result<(), anyhow::Error> {
+        let ok = || {
+            http_response(
+                "200 OK",
+                "application/json",
+                r#"{"actionTraces":[],"error":null}"#,
+            )
+            .into_bytes()
+        };
+        let failed = http_response(
+            "200 OK",
+            "application/json",
+            r#"{"actionTraces":[],"error":"failed"}"#,
+        )
+        .into_bytes();
+        let url = mock_server_bytes(vec![ok(), ok(), ok(), failed]).await?;
+        let trx = || SignedTransaction {
+            transaction: Transaction::default().packed().into(),
+            proofs: vec![],
+        };
+
+        // Two packages, where the first needs two transactions
+        let sink = RecordingSink::default();
+        push_transactions(
+            &url,
+            reqwest::Client::new(),
+            vec![
+                ("A".to_string(), vec![trx(), trx()], false),
+                ("B".to_string(), vec![trx()], false),
+            ],
+            TraceFormat::Error,
+            true,
+            &sink,
+        )
+        .await?;
+        use ProgressEvent::*;
+        assert_eq!(
+            *sink.events.lock().unwrap(),
+            vec![
+                Message("A".to_string()),
+                Inc(0),
+                Suspend,
+                Inc(0),
+                Suspend,
+                Inc(0),
+                Message("B".to_string()),
+                Inc(1),
+                Suspend,
+                Inc(0),
+            ]
+        );
+
+        let sink = RecordingSink::default();
+        push_transactions(
+            &url,
+            reqwest::Client::new(),
+            vec![("C".to_string(), vec![trx()], false)],
+            TraceFormat::Error,
+            false,
+            &sink,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            *sink.events.lock().unwrap(),
+            vec![Message("C".to_string()), Inc(0), Abandon]
+        );
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct OutputSink {
+        writes: Mutex<Vec<String>>,
+    }
+
+    impl ProgressSink for OutputSink {
+        fn inc(&self, _delta: u64) {}
+        fn set_message(&self, _msg: String) {}
+        fn abandon(&self) {}
+        fn suspend(&self, f: &mut dyn FnMut()) {
+            f()
+        }
+        fn write_output(&self, text: &str) {
+            self.writes.lock().unwrap().push(text.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_console_output_is_not_interleaved() -> Result<(), anyhow::Error> {
+        let response = |name: &str| {
+            http_response(
+                "200 OK",
+                "application/json",
+                &format!(
+                    r#"{{"actionTraces":[{{"action":{{"sender":"alice","service":"nop","method":"{0}","rawData":""}},"rawRetval":"","innerTraces":[{{"inner":{{"ConsoleTrace":{{"console":"{0} line 1\n{0} line 2\n"}}}}}}],"totalTime":1,"error":null}}],"error":null}}"#,
+                    name
+                ),
+            )
+            .into_bytes()
+        };
+        let url = mock_server_bytes(vec![response("first"), response("second")]).await?;
+        let sink = OutputSink::default();
+        let push = || {
+            push_transaction(
+                &url,
+                reqwest::Client::new(),
+                vec![],
+                TraceFormat::Full,
+                true,
+                Some(&sink),
+            )
+        };
+        let (a, b) = futures::join!(push(), push());
+        a?;
+        b?;
+
+        let mut writes = sink.writes.lock().unwrap().clone();
+        writes.sort();
+        assert_eq!(writes.len(), 2);
+        for (write, name) in writes.iter().zip(["first", "second"]) {
+            // The console output is followed by the trace of the same transaction
+            let console = format!("{0} line 1\n{0} line 2\n", name);
+            assert!(write.starts_with(&console), "{}", write);
+            assert!(write[console.len()..].contains(name), "{}", write);
+            let other = if name == "first" { "second" } else { "first" };
+            assert!(!write.contains(other), "{}", write);
+        }
+        Ok(())
+    }
+}
@@ -69,8 +69,8 @@ pub struct Field<String>
 where
     String: Reflect,
 {
-    name: String,
-    ty: TypeRef<String>,
+    pub(crate) name: String,
+    pub(crate) ty: TypeRef<String>,
 }
 
 #[derive(Debug, Clone, Reflect, Serialize, Deserialize)]
@@ -85,15 +85,20 @@ where
     args: Vec<Field<String>>,
 }
 
-type BuildString = Rc<RefCell<Cow<'static, str>>>;
+pub(crate) type BuildString = Rc<RefCell<Cow<'static, str>>>;
 
 fn extract_str(str: &BuildString) -> String {
     Rc::make_mut(&mut str.clone()).take().into_owned()
 }
 
 pub fn create_schema<T: Reflect>() -> Schema<BuildString> {
+    create_schema_with_root::<T>().0
+}
+
+// Also returns the type reference for T
+pub(crate) fn create_schema_with_root<T: Reflect>() -> (Schema<BuildString>, TypeRef<BuildString>) {
     let mut builder: SchemaBuilder = Default::default();
-    builder.get_type_ref::<T>(false);
+    let root = builder.get_type_ref::<T>(false);
     let mut names: HashSet<String> = HashSet::new();
     for shared_name in builder.names {
         let name = extract_str(&shared_name);
@@ -112,7 +117,7 @@ pub fn create_schema<T: Reflect>() -> Schema<BuildString> {
             names.insert(name);
         }
     }
-    builder.schema
+    (builder.schema, root)
 }
 
 #[derive(Default)]
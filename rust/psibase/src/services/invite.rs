@@ -45,7 +45,7 @@ mod service {
     }
 
     #[action]
-    fn createInvite(inviteKey: PublicKey) {
+    fn createInvite(inviteKey: PublicKey, secondsToLive: Option<u32>) {
         unimplemented!()
     }
 
@@ -1,8 +1,14 @@
 use crate::{Pack, Reflect, ToKey, Unpack};
 use async_graphql::{InputObject, SimpleObject};
+use chrono::{DateTime, TimeZone, Utc};
+use custom_error::custom_error;
 use serde::{Deserialize, Serialize};
 use std::ops::{Add, Sub};
 
+custom_error! { pub TimeError
+    OutOfRange{seconds: i64} = "{seconds} seconds since the epoch is outside the range of TimePointSec",
+}
+
 #[derive(
     Debug,
     Copy,
@@ -35,6 +41,25 @@ impl From<u32> for TimePointSec {
     }
 }
 
+/// Fails for times before 1970 or after 2106-02-07T06:28:15Z
+impl TryFrom<DateTime<Utc>> for TimePointSec {
+    type Error = TimeError;
+    fn try_from(time: DateTime<Utc>) -> Result<Self, Self::Error> {
+        let seconds = time.timestamp();
+        Ok(TimePointSec {
+            seconds: seconds
+                .try_into()
+                .map_err(|_| TimeError::OutOfRange { seconds })?,
+        })
+    }
+}
+
+impl From<TimePointSec> for DateTime<Utc> {
+    fn from(time: TimePointSec) -> Self {
+        Utc.timestamp_opt(time.seconds.into(), 0).unwrap()
+    }
+}
+
 impl Add for TimePointSec {
     type Output = Self;
 
@@ -59,3 +84,38 @@ impl Sub for TimePointSec {
 // TODO: string conversions
 // TODO: JSON
 // TODO: implement trait with the time functions helpers
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use fracpack::{Pack, Unpack};
+
+    #[test]
+    fn datetime_round_trip() {
+        let time = Utc.with_ymd_and_hms(2023, 7, 1, 12, 30, 15).unwrap();
+        let converted = TimePointSec::try_from(time).unwrap();
+        assert_eq!(converted.seconds, 1688214615);
+        assert_eq!(DateTime::<Utc>::from(converted), time);
+    }
+
+    #[test]
+    fn datetime_range() {
+        let max = Utc.with_ymd_and_hms(2106, 2, 7, 6, 28, 15).unwrap();
+        assert_eq!(TimePointSec::try_from(max).unwrap().seconds, u32::MAX);
+        assert!(TimePointSec::try_from(max + Duration::seconds(1)).is_err());
+        assert!(
+            TimePointSec::try_from(Utc.with_ymd_and_hms(2107, 1, 1, 0, 0, 0).unwrap()).is_err()
+        );
+        assert!(
+            TimePointSec::try_from(DateTime::<Utc>::UNIX_EPOCH - Duration::seconds(1)).is_err()
+        );
+    }
+
+    #[test]
+    fn pack_matches_u32() {
+        let time = TimePointSec::from(0x01020304);
+        assert_eq!(time.packed(), 0x01020304u32.packed());
+        assert_eq!(TimePointSec::unpacked(&time.packed()).unwrap(), time);
+    }
+}
@@ -57,6 +57,19 @@ pub struct InnerTrace {
     pub inner: InnerTraceEnum,
 }
 
+/// The result of executing a transaction.
+///
+/// The JSON form, which `psibase --trace json` prints, is stable:
+///
+/// - `actionTraces`: an array of action traces, each with `action`
+///   (`sender`, `service`, `method`, and hex `rawData`), hex `rawRetval`,
+///   `innerTraces`, `totalTime` in nanoseconds, and `error`
+/// - `error`: the error message, or `null` if the transaction succeeded
+///
+/// Each entry of `innerTraces` is an object with a single key, `inner`,
+/// which holds one of `{"ConsoleTrace": {"console": ...}}`,
+/// `{"EventTrace": {"name": ..., "data": ...}}`, or
+/// `{"ActionTrace": ...}`.
 #[derive(Debug, Clone, Pack, Unpack, Reflect, Serialize, Deserialize)]
 #[fracpack(fracpack_mod = "fracpack")]
 #[reflect(psibase_mod = "crate")]
@@ -12,6 +12,8 @@ use syn::{
 pub(crate) struct Options {
     pub(crate) definition_will_not_change: bool,
     pub(crate) fracpack_mod: String,
+    /// Pack a struct with a single field exactly like that field
+    pub(crate) transparent: bool,
 }
 
 impl Default for Options {
@@ -19,6 +21,7 @@ impl Default for Options {
         Self {
             definition_will_not_change: false,
             fracpack_mod: "psibase::fracpack".into(),
+            transparent: false,
         }
     }
 }
@@ -125,7 +128,7 @@ fn enum_named<'a>(
                 .fold(quote! {}, |acc, new| quote! {#acc #new});
             quote! {
                 {
-                    let data = <#as_type as #fracpack_mod::Unpack>::unpack(src, pos)?;
+                    let data = <#as_type as #fracpack_mod::Unpack>::unpack_at(src, pos, known_end, options)?;
                     #enum_name::#field_name{#init}
                 }
             }
@@ -148,7 +151,7 @@ fn enum_single<'a>(
         selector: quote! {(field_0)},
         pack: quote! {<#ty as #fracpack_mod::Pack>::pack(field_0, dest)},
         unpack: quote! {
-            #enum_name::#field_name(<#ty as #fracpack_mod::Unpack>::unpack(src, pos)?)
+            #enum_name::#field_name(<#ty as #fracpack_mod::Unpack>::unpack_at(src, pos, known_end, options)?)
         },
     }
 }
@@ -223,7 +226,7 @@ fn enum_tuple<'a>(
                 .fold(quote! {}, |acc, new| quote! {#acc #new});
             quote! {
                 {
-                    let data = <#as_type as #fracpack_mod::Unpack>::unpack(src, pos)?;
+                    let data = <#as_type as #fracpack_mod::Unpack>::unpack_at(src, pos, known_end, options)?;
                     #enum_name::#field_name(#numbered)
                 }
             }
@@ -287,8 +290,31 @@ fn process_struct(
     data: &DataStruct,
     opts: &Options,
 ) -> TokenStream {
+    if opts.transparent {
+        if opts.definition_will_not_change {
+            unimplemented!("transparent structs do not support definition_will_not_change")
+        }
+        let fields = match &data.fields {
+            Fields::Named(named) => &named.named,
+            Fields::Unnamed(unnamed) => &unnamed.unnamed,
+            Fields::Unit => unimplemented!("fracpack does not support unit struct"),
+        };
+        if fields.len() != 1 {
+            unimplemented!("transparent is only supported on structs with exactly one field")
+        }
+        return process_struct_as_tuple(fracpack_mod, input, impl_pack, impl_unpack, fields);
+    }
     if let Fields::Unnamed(unnamed) = &data.fields {
-        return process_struct_unnamed(fracpack_mod, input, impl_pack, impl_unpack, unnamed, opts);
+        if opts.definition_will_not_change {
+            unimplemented!("definition_will_not_change only supported on structs with named fields")
+        }
+        return process_struct_as_tuple(
+            fracpack_mod,
+            input,
+            impl_pack,
+            impl_unpack,
+            &unnamed.unnamed,
+        );
     }
     let name = &input.ident;
     let generics = &input.generics;
@@ -325,6 +351,11 @@ fn process_struct(
     } else {
         quote! {}
     };
+    let pack_heap_into = if !opts.definition_will_not_change {
+        quote! { std::io::Write::write_all(dest, &(heap as u16).to_le_bytes())?; }
+    } else {
+        quote! {}
+    };
     let unpack_heap_size = if !opts.definition_will_not_change {
         quote! { let fixed_size = <u16 as #fracpack_mod::Unpack>::unpack(src, pos)?; }
     } else {
@@ -357,23 +388,49 @@ fn process_struct(
             }
         })
         .fold(quote! {}, |acc, new| quote! {#acc #new});
+    let pack_fixed_members_into = fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            let name = &field.name;
+            let ty = &field.ty;
+            let pos = &positions[i];
+            quote! {
+                #[allow(non_snake_case)]
+                let #pos = std::io::Seek::stream_position(dest)?;
+                <#ty as #fracpack_mod::Pack>::embedded_fixed_pack_into(&self.#name, dest)?;
+            }
+        })
+        .fold(quote! {}, |acc, new| quote! {#acc #new});
+    let pack_variable_members_into = fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            let name = &field.name;
+            let ty = &field.ty;
+            let pos = &positions[i];
+            quote! {
+                <#ty as #fracpack_mod::Pack>::embedded_fixed_repack_into(&self.#name, #pos, dest)?;
+                <#ty as #fracpack_mod::Pack>::embedded_variable_pack_into(&self.#name, dest)?;
+            }
+        })
+        .fold(quote! {}, |acc, new| quote! {#acc #new});
     let unpack = fields
         .iter()
         .map(|field| {
             let name = &field.name;
             let ty = &field.ty;
             quote! {
-                #name: <#ty as #fracpack_mod::Unpack>::embedded_unpack(src, pos, &mut heap_pos)?,
+                #name: <#ty as #fracpack_mod::Unpack>::embedded_unpack(src, pos, &mut heap_pos, known_end, options)?,
             }
         })
         .fold(quote! {}, |acc, new| quote! {#acc #new});
-    // TODO: skip unknown members
     // TODO: option to verify no unknown members
     let verify = fields
         .iter()
         .map(|field| {
             let ty = &field.ty;
-            quote! { <#ty as #fracpack_mod::Unpack>::embedded_verify(src, pos, &mut heap_pos)?; }
+            quote! { <#ty as #fracpack_mod::Unpack>::embedded_verify(src, pos, &mut heap_pos, known_end, options)?; }
         })
         .fold(quote! {}, |acc, new| quote! {#acc #new});
 
@@ -390,6 +447,22 @@ fn process_struct(
                     #pack_fixed_members
                     #pack_variable_members
                 }
+                fn pack_into<W: std::io::Write + std::io::Seek>(
+                    &self,
+                    dest: &mut W,
+                ) -> std::io::Result<()> {
+                    let heap = #fixed_size;
+                    if heap as u16 as u32 != heap {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "fracpack fixed data too large",
+                        ));
+                    }
+                    #pack_heap_into
+                    #pack_fixed_members_into
+                    #pack_variable_members_into
+                    Ok(())
+                }
             }
         }
     } else {
@@ -403,24 +476,42 @@ fn process_struct(
                 const FIXED_SIZE: u32 =
                     if <Self as #fracpack_mod::Unpack>::VARIABLE_SIZE { 4 } else { #fixed_size };
                 fn unpack(src: &'a [u8], pos: &mut u32) -> #fracpack_mod::Result<Self> {
+                    #fracpack_mod::unpack_to_end(src, pos)
+                }
+                fn verify(src: &'a [u8], pos: &mut u32) -> #fracpack_mod::Result<()> {
+                    #fracpack_mod::verify_to_end::<Self>(src, pos)
+                }
+                fn unpack_at(
+                    src: &'a [u8],
+                    pos: &mut u32,
+                    known_end: &mut bool,
+                    options: &#fracpack_mod::UnpackOptions,
+                ) -> #fracpack_mod::Result<Self> {
                     #unpack_heap_size
-                    let mut heap_pos = *pos + fixed_size as u32;
-                    if heap_pos < *pos {
-                        return Err(#fracpack_mod::Error::BadOffset);
-                    }
+                    let heap_start = pos
+                        .checked_add(fixed_size as u32)
+                        .ok_or(#fracpack_mod::Error::BadOffset)?;
+                    let mut heap_pos = heap_start;
                     let result = Self {
                         #unpack
                     };
+                    #fracpack_mod::skip_unknown_fields(src, pos, heap_start, &mut heap_pos, known_end)?;
                     *pos = heap_pos;
                     Ok(result)
                 }
-                fn verify(src: &'a [u8], pos: &mut u32) -> #fracpack_mod::Result<()> {
+                fn verify_at(
+                    src: &'a [u8],
+                    pos: &mut u32,
+                    known_end: &mut bool,
+                    options: &#fracpack_mod::UnpackOptions,
+                ) -> #fracpack_mod::Result<()> {
                     #unpack_heap_size
-                    let mut heap_pos = *pos + fixed_size as u32;
-                    if heap_pos < *pos {
-                        return Err(#fracpack_mod::Error::BadOffset);
-                    }
+                    let heap_start = pos
+                        .checked_add(fixed_size as u32)
+                        .ok_or(#fracpack_mod::Error::BadOffset)?;
+                    let mut heap_pos = heap_start;
                     #verify
+                    #fracpack_mod::skip_unknown_fields(src, pos, heap_start, &mut heap_pos, known_end)?;
                     *pos = heap_pos;
                     Ok(())
                 }
@@ -436,26 +527,31 @@ fn process_struct(
     })
 } // process_struct
 
-fn process_struct_unnamed(
+// Packs tuple structs and transparent structs like a tuple of their fields,
+// or like the field itself if there is only one
+fn process_struct_as_tuple(
     fracpack_mod: &proc_macro2::TokenStream,
     input: &DeriveInput,
     impl_pack: bool,
     impl_unpack: bool,
-    unnamed: &FieldsUnnamed,
-    opts: &Options,
+    fields: &syn::punctuated::Punctuated<syn::Field, syn::Token![,]>,
 ) -> TokenStream {
-    if opts.definition_will_not_change {
-        unimplemented!("definition_will_not_change only supported on structs with named fields")
-    }
+    let member = match &fields[0].ident {
+        Some(ident) => quote! {#ident},
+        None => {
+            let index = syn::Index::from(0);
+            quote! {#index}
+        }
+    };
     let name = &input.ident;
     let generics = &input.generics;
     let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
 
-    let ty = if unnamed.unnamed.len() == 1 {
-        let ty = &unnamed.unnamed[0].ty;
+    let ty = if fields.len() == 1 {
+        let ty = &fields[0].ty;
         quote! {#ty}
     } else {
-        let ty = unnamed.unnamed.iter().fold(quote! {}, |acc, a| {
+        let ty = fields.iter().fold(quote! {}, |acc, a| {
             let ty = &a.ty;
             quote! {#acc #ty,}
         });
@@ -463,11 +559,11 @@ fn process_struct_unnamed(
     };
     let ty = quote! {<#ty as #fracpack_mod::Unpack>};
 
-    let ref_ty = if unnamed.unnamed.len() == 1 {
-        let ty = &unnamed.unnamed[0].ty;
+    let ref_ty = if fields.len() == 1 {
+        let ty = &fields[0].ty;
         quote! {&#ty}
     } else {
-        let ty = unnamed.unnamed.iter().fold(quote! {}, |acc, a| {
+        let ty = fields.iter().fold(quote! {}, |acc, a| {
             let ty = &a.ty;
             quote! {#acc &#ty,}
         });
@@ -475,44 +571,36 @@ fn process_struct_unnamed(
     };
     let ref_ty = quote! {<#ref_ty as #fracpack_mod::Pack>};
 
-    let to_value = if unnamed.unnamed.len() == 1 {
-        quote! {let value = &self.0;}
+    let to_value = if fields.len() == 1 {
+        quote! {let value = &self.#member;}
     } else {
-        let to_value = unnamed
-            .unnamed
-            .iter()
-            .enumerate()
-            .fold(quote! {}, |acc, (i, _)| {
-                let i = syn::Index::from(i);
-                quote! {#acc &self.#i,}
-            });
+        let to_value = fields.iter().enumerate().fold(quote! {}, |acc, (i, _)| {
+            let i = syn::Index::from(i);
+            quote! {#acc &self.#i,}
+        });
         quote! {let value = (#to_value);}
     };
 
-    let from_value = if unnamed.unnamed.len() == 1 {
-        quote! {Self(value)}
+    let from_value = if fields.len() == 1 {
+        quote! {Self { #member: value }}
     } else {
-        let from_value = unnamed
-            .unnamed
-            .iter()
-            .enumerate()
-            .fold(quote! {}, |acc, (i, _)| {
-                let i = syn::Index::from(i);
-                quote! {#acc value.#i,}
-            });
+        let from_value = fields.iter().enumerate().fold(quote! {}, |acc, (i, _)| {
+            let i = syn::Index::from(i);
+            quote! {#acc value.#i,}
+        });
         quote! {Self(#from_value)}
     };
 
-    let (is_empty_container, new_empty_container) = if unnamed.unnamed.len() == 1 {
+    let (is_empty_container, new_empty_container) = if fields.len() == 1 {
         (
             quote! {
                 fn is_empty_container(&self) -> bool {
-                    self.0.is_empty_container()
+                    self.#member.is_empty_container()
                 }
             },
             quote! {
                 fn new_empty_container() -> #fracpack_mod::Result<Self> {
-                    Ok(Self(#ty::new_empty_container()?))
+                    Ok(Self { #member: #ty::new_empty_container()? })
                 }
             },
         )
@@ -570,19 +658,46 @@ fn process_struct_unnamed(
                     #ty::verify(src, pos)
                 }
 
+                fn unpack_at(
+                    src: &'a [u8],
+                    pos: &mut u32,
+                    known_end: &mut bool,
+                    options: &#fracpack_mod::UnpackOptions,
+                ) -> #fracpack_mod::Result<Self> {
+                    let value = #ty::unpack_at(src, pos, known_end, options)?;
+                    Ok(#from_value)
+                }
+
+                fn verify_at(
+                    src: &'a [u8],
+                    pos: &mut u32,
+                    known_end: &mut bool,
+                    options: &#fracpack_mod::UnpackOptions,
+                ) -> #fracpack_mod::Result<()> {
+                    #ty::verify_at(src, pos, known_end, options)
+                }
+
                 #new_empty_container
 
                 fn embedded_variable_unpack(
                     src: &'a [u8],
                     fixed_pos: &mut u32,
                     heap_pos: &mut u32,
+                    known_end: &mut bool,
+                    options: &#fracpack_mod::UnpackOptions,
                 ) -> #fracpack_mod::Result<Self> {
-                    let value = #ty::embedded_variable_unpack(src, fixed_pos, heap_pos)?;
+                    let value = #ty::embedded_variable_unpack(src, fixed_pos, heap_pos, known_end, options)?;
                     Ok(#from_value)
                 }
 
-                fn embedded_unpack(src: &'a [u8], fixed_pos: &mut u32, heap_pos: &mut u32) -> #fracpack_mod::Result<Self> {
-                    let value = #ty::embedded_unpack(src, fixed_pos, heap_pos)?;
+                fn embedded_unpack(
+                    src: &'a [u8],
+                    fixed_pos: &mut u32,
+                    heap_pos: &mut u32,
+                    known_end: &mut bool,
+                    options: &#fracpack_mod::UnpackOptions,
+                ) -> #fracpack_mod::Result<Self> {
+                    let value = #ty::embedded_unpack(src, fixed_pos, heap_pos, known_end, options)?;
                     Ok(#from_value)
                 }
 
@@ -590,12 +705,20 @@ fn process_struct_unnamed(
                     src: &'a [u8],
                     fixed_pos: &mut u32,
                     heap_pos: &mut u32,
+                    known_end: &mut bool,
+                    options: &#fracpack_mod::UnpackOptions,
                 ) -> #fracpack_mod::Result<()> {
-                    #ty::embedded_variable_verify(src, fixed_pos, heap_pos)
+                    #ty::embedded_variable_verify(src, fixed_pos, heap_pos, known_end, options)
                 }
 
-                fn embedded_verify(src: &'a [u8], fixed_pos: &mut u32, heap_pos: &mut u32) -> #fracpack_mod::Result<()> {
-                    #ty::embedded_verify(src, fixed_pos, heap_pos)
+                fn embedded_verify(
+                    src: &'a [u8],
+                    fixed_pos: &mut u32,
+                    heap_pos: &mut u32,
+                    known_end: &mut bool,
+                    options: &#fracpack_mod::UnpackOptions,
+                ) -> #fracpack_mod::Result<()> {
+                    #ty::embedded_verify(src, fixed_pos, heap_pos, known_end, options)
                 }
             }
         }
@@ -656,7 +779,7 @@ fn process_enum(
             let index = i as u8;
             let as_type = &field.as_type;
             quote! {
-                #index => <#as_type as #fracpack_mod::Unpack>::verify(src, pos)?,
+                #index => <#as_type as #fracpack_mod::Unpack>::verify_at(src, pos, known_end, options)?,
             }
         })
         .fold(quote! {}, |acc, new| quote! {#acc #new});
@@ -686,34 +809,50 @@ fn process_enum(
                 const FIXED_SIZE: u32 = 4;
                 const VARIABLE_SIZE: bool = true;
                 fn unpack(src: &'a [u8], pos: &mut u32) -> #fracpack_mod::Result<Self> {
+                    #fracpack_mod::unpack_to_end(src, pos)
+                }
+                fn verify(src: &'a [u8], pos: &mut u32) -> #fracpack_mod::Result<()> {
+                    #fracpack_mod::verify_to_end::<Self>(src, pos)
+                }
+                fn unpack_at(
+                    src: &'a [u8],
+                    pos: &mut u32,
+                    known_end: &mut bool,
+                    options: &#fracpack_mod::UnpackOptions,
+                ) -> #fracpack_mod::Result<Self> {
                     let index = <u8 as #fracpack_mod::Unpack>::unpack(src, pos)?;
                     let size_pos = *pos;
                     let size = <u32 as #fracpack_mod::Unpack>::unpack(src, pos)?;
+                    let end = size_pos as u64 + 4 + size as u64;
                     let result = match index {
                         #unpack_items
                         _ => return Err(#fracpack_mod::Error::BadEnumIndex),
                     };
-                    if *pos != size_pos + 4 + size {
-                        return Err(#fracpack_mod::Error::BadSize);
-                    }
+                    #fracpack_mod::end_variant(src, pos, end, known_end)?;
                     Ok(result)
                 }
                 // TODO: option to error on unknown index
-                fn verify(src: &'a [u8], pos: &mut u32) -> #fracpack_mod::Result<()> {
+                fn verify_at(
+                    src: &'a [u8],
+                    pos: &mut u32,
+                    known_end: &mut bool,
+                    options: &#fracpack_mod::UnpackOptions,
+                ) -> #fracpack_mod::Result<()> {
                     let index = <u8 as #fracpack_mod::Unpack>::unpack(src, pos)?;
                     let size_pos = *pos;
                     let size = <u32 as #fracpack_mod::Unpack>::unpack(src, pos)?;
+                    let end = size_pos as u64 + 4 + size as u64;
+                    if end > src.len() as u64 {
+                        return Err(#fracpack_mod::Error::ReadPastEnd);
+                    }
                     match index {
                         #verify_items
                         _ => {
-                            *pos = size_pos + 4 + size;
+                            *pos = end as u32;
                             return Ok(());
                         }
                     }
-                    if *pos != size_pos + 4 + size {
-                        return Err(#fracpack_mod::Error::BadSize);
-                    }
-                    Ok(())
+                    #fracpack_mod::end_variant(src, pos, end, known_end)
                 }
             }
         }
@@ -113,6 +113,9 @@ pub fn reflect_derive_macro(input: TokenStream) -> TokenStream {
     let mut additional_defs = quote! {};
     let visit = match &input.data {
         Data::Struct(data) => match &data.fields {
+            Fields::Named(named) if fracpack_opts.transparent => {
+                visit_struct_transparent(&psibase_mod, &input, named, &opts)
+            }
             Fields::Named(named) => visit_struct(
                 &psibase_mod,
                 &input,
@@ -199,6 +202,26 @@ fn visit_struct(
     }
 }
 
+// A transparent struct is packed like its only field
+fn visit_struct_transparent(
+    psibase_mod: &TokenStream2,
+    input: &DeriveInput,
+    named: &FieldsNamed,
+    opts: &DeriveOptions,
+) -> TokenStream2 {
+    if opts.custom_json {
+        unimplemented!("custom_json is not supported on transparent structs")
+    }
+    let str_name = input.ident.to_string();
+    let inner = &named.named[0].ty;
+    quote! {
+        use #psibase_mod::reflect::UnnamedVisitor;
+
+        visitor
+        .struct_single::<Self, #inner>(#str_name.into())
+    }
+}
+
 fn visit_struct_unnamed(
     psibase_mod: &TokenStream2,
     input: &DeriveInput,
@@ -274,7 +274,7 @@ fn round_trip_field<T: Pack + UnpackOwned + PartialEq + std::fmt::Debug>(
 
     println!("packed > {}", hex::encode(&packed[..]).to_uppercase());
 
-    T::verify_no_extra(&packed[..]).unwrap();
+    T::verify_all(&packed[..]).unwrap();
 
     let unpacked = T::unpack(&packed[..], &mut 0).unwrap();
     assert_eq!(*field, unpacked);
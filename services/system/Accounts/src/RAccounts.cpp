@@ -3,6 +3,7 @@
 #include <psibase/dispatch.hpp>
 #include <psibase/nativeTables.hpp>
 #include <psibase/serveContent.hpp>
+#include <psibase/serveGraphQL.hpp>
 #include <psibase/serveSimpleUI.hpp>
 #include <psio/from_json.hpp>
 #include <psio/to_json.hpp>
@@ -16,6 +17,24 @@ using Tables = psibase::ServiceTables<psibase::WebContentTable>;
 
 namespace SystemService
 {
+   struct AccountsQuery
+   {
+      // Returns the accounts in names that exist
+      auto accounts(std::vector<AccountNumber> names) const
+      {
+         auto accountIndex =
+             Accounts::Tables(Accounts::service).open<AccountTable>().getIndex<0>();
+         std::vector<Account> result;
+         for (auto name : names)
+         {
+            if (auto row = accountIndex.get(name))
+               result.push_back(std::move(*row));
+         }
+         return result;
+      }
+   };
+   PSIO_REFLECT(AccountsQuery, method(accounts, names))
+
    std::optional<HttpReply> RAccounts::serveSys(HttpRequest request)
    {
       auto to_json = [](const auto& obj)
@@ -56,6 +75,8 @@ namespace SystemService
          }
       }
 
+      if (auto result = serveGraphQL(request, AccountsQuery{}))
+         return result;
       if (auto result = psibase::serveSimpleUI<Accounts, false>(request))
          return result;
       if (auto result = psibase::serveContent(request, Tables{getReceiver()}))
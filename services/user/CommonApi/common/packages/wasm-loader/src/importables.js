@@ -31,7 +31,7 @@ function send(req) {
             }
         }
         xhr.send(req.body && req.body.length > 0 ? req.body : null);
-        if (xhr.status === 500) {
+        if (xhr.status >= 500) {
             throw commonErr(`${xhr.response}`);
         }
         const body = xhr.response || undefined;
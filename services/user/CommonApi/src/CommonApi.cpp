@@ -51,6 +51,8 @@ namespace SystemService
                 .body        = {json.begin(), json.end()},
             };
          }
+         if (request.target == "/common/chainid")
+            return to_json(getStatus().chainId);
          if (request.target == "/common/wasm-loader")
          {
             if (auto result = psibase::serveContent(request, Tables{getReceiver()}))
@@ -34,6 +34,9 @@ namespace UserService
          static constexpr auto service = SystemService::Accounts::inviteService;
          /// "invited-sys"
          static constexpr auto payerAccount = psibase::AccountNumber("invited-sys");
+         /// The longest that an invite may last before it expires, in seconds (one week).
+         /// This is also the lifetime of invites that don't request one.
+         static constexpr uint32_t maxInviteLifetime = 60 * 60 * 24 * 7;
 
          /// Constructor prevents actions from being called until after init() has
          /// been called
@@ -47,7 +50,10 @@ namespace UserService
          void init();
 
          /// Creates and stores an invite object with the specified public key
-         void createInvite(psibase::PublicKey inviteKey);
+         ///
+         /// The invite expires `secondsToLive` seconds from now, which must be between 1 and
+         /// `maxInviteLifetime`. If it is not given, the invite lasts for `maxInviteLifetime`.
+         void createInvite(psibase::PublicKey inviteKey, std::optional<uint32_t> secondsToLive);
 
          /// Called by existing Psibase accounts to accept an invite without creating
          /// a new Psibase account
@@ -145,7 +151,7 @@ namespace UserService
       // clang-format off
       PSIO_REFLECT(Invite,
          method(init),
-         method(createInvite, inviteKey, inviter),
+         method(createInvite, inviteKey, secondsToLive),
          method(accept, inviteKey),
          method(acceptCreate, inviteKey, acceptedBy, newAccountKey),
          method(reject, inviteKey),
@@ -9,6 +9,9 @@ pub enum ErrorType {
     DecodeInviteError,
     QueryError,
     CorruptedInviteId,
+    InviteAlreadyUsed,
+    InvalidInviteKey,
+    InvalidArgument,
 }
 
 fn my_plugin_id() -> PluginId {
@@ -56,6 +59,21 @@ impl ErrorType {
                 producer: my_plugin_id(),
                 message: format!("Corrupted invite id: {}", msg),
             },
+            ErrorType::InviteAlreadyUsed => Error {
+                code: self as u32,
+                producer: my_plugin_id(),
+                message: format!("Invite has already been {}", msg),
+            },
+            ErrorType::InvalidInviteKey => Error {
+                code: self as u32,
+                producer: my_plugin_id(),
+                message: format!("Invalid invite key: {}", msg),
+            },
+            ErrorType::InvalidArgument => Error {
+                code: self as u32,
+                producer: my_plugin_id(),
+                message: format!("Invalid argument: {}", msg),
+            },
         }
     }
 }
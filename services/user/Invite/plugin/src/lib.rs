@@ -8,21 +8,13 @@ use bindings::exports::invite::plugin::{
     admin::Guest as Admin, invitee::Guest as Invitee, inviter::Guest as Inviter,
 };
 use bindings::invite::plugin::types::{Invite, InviteId, Url};
-use fracpack::Pack;
+use fracpack::{Pack, Unpack};
 use psibase::services::invite as invite_service;
 use serde::{Deserialize, Serialize};
 
 mod errors;
 use errors::ErrorType::*;
 
-/*
-    /// This doesn't need to be exposed, it can just be jammed into various plugin functions
-    /// when the user is submitting another action anyways.
-    /// Used by anyone to garbage collect expired invites. Up to 'maxDeleted' invites
-    /// can be deleted by calling this action
-    void delExpired(uint32_t maxDeleted);
-*/
-
 #[derive(Serialize, Deserialize)]
 struct InviteParams {
     inviter: String,
@@ -47,6 +39,188 @@ struct Data {
 struct GetInvite {
     pubkey: String,
     inviter: String,
+    state: u8,
+}
+
+// Matches InviteStates in InviteTables.hpp
+const STATE_ACCEPTED: u8 = 1;
+const STATE_REJECTED: u8 = 2;
+
+/// Fails if the invite can no longer be accepted, so that the UI can
+/// report it without attempting a transaction
+fn check_unused(invite: &GetInvite) -> Result<(), CommonTypes::Error> {
+    match invite.state {
+        STATE_ACCEPTED => Err(InviteAlreadyUsed.err("accepted")),
+        STATE_REJECTED => Err(InviteAlreadyUsed.err("rejected")),
+        _ => Ok(()),
+    }
+}
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Converts the decoded invite id to a string. A leading byte-order mark
+/// is dropped, and malformed UTF-8 is reported with the offset of the
+/// first bad byte.
+fn decode_utf8(enc: &[u8]) -> Result<&str, CommonTypes::Error> {
+    let enc = enc.strip_prefix(UTF8_BOM).unwrap_or(enc);
+    if enc.is_empty() {
+        return Err(DecodeInviteError.err("Invite id is empty"));
+    }
+    std::str::from_utf8(enc).map_err(|e| {
+        let pos = e.valid_up_to();
+        match e.error_len() {
+            Some(_) => DecodeInviteError.err(&format!(
+                "Invalid UTF8 byte 0x{:02x} at position {}",
+                enc[pos], pos
+            )),
+            None => DecodeInviteError.err(&format!("Truncated UTF8 sequence at position {}", pos)),
+        }
+    })
+}
+
+fn decode_params(id: &InviteId) -> Result<InviteParams, CommonTypes::Error> {
+    URL_SAFE
+        .decode(id.to_owned())
+        .map_err(|_| DecodeInviteError.err("Error decoding base64"))
+        .and_then(|enc| {
+            let decoded = decode_utf8(&enc)?;
+            serde_json::from_str(decoded)
+                .map_err(|_| DecodeInviteError.err("Error deserializing JSON string into object"))
+        })
+}
+
+// Number of times a GraphQL query is posted before giving up
+const QUERY_ATTEMPTS: u32 = 3;
+
+/// Posts a GraphQL query, retrying failures a bounded number of times.
+///
+/// The host fails a post when the request could not be sent or the server
+/// answered with a 5xx status; only those are retried. Any other response
+/// is returned to the caller, except that a response which is not JSON
+/// (such as the text of a 4xx status) or which reports GraphQL errors
+/// fails right away, since posting the same query again won't change it.
+///
+/// Plugins have no threads or timers, so there is no delay between attempts
+/// and no timeout of our own; a request that hangs is cut off by the host's
+/// fetch, which then surfaces as a failed attempt here.
+fn post_graphql_with_retries(
+    url: &str,
+    query: &str,
+    mut post: impl FnMut(&str, &str) -> Result<String, CommonTypes::Error>,
+) -> Result<String, CommonTypes::Error> {
+    let mut last_error = String::new();
+    for _ in 0..QUERY_ATTEMPTS {
+        match post(url, query) {
+            Ok(result) => return check_graphql_response(url, result),
+            Err(e) => last_error = e.message,
+        }
+    }
+    Err(QueryError.err(&format!(
+        "{} failed after {} attempts: {}",
+        url, QUERY_ATTEMPTS, last_error
+    )))
+}
+
+/// Rejects a response that the server sent instead of a query result
+fn check_graphql_response(url: &str, body: String) -> Result<String, CommonTypes::Error> {
+    let Ok(response) = serde_json::from_str::<serde_json::Value>(&body) else {
+        return Err(QueryError.err(&format!("{} responded with {}", url, body)));
+    };
+    if let Some(errors) = response.get("errors").and_then(|e| e.as_array()) {
+        let messages: Vec<_> = errors
+            .iter()
+            .map(|e| {
+                e.get("message")
+                    .and_then(|m| m.as_str())
+                    .unwrap_or("unknown error")
+            })
+            .collect();
+        return Err(QueryError.err(&format!(
+            "{} rejected the query: {}",
+            url,
+            messages.join("; ")
+        )));
+    }
+    Ok(body)
+}
+
+fn query_invite(pubkey: &str) -> Result<GetInvite, CommonTypes::Error> {
+    let url = format!("{}/graphql", client::my_service_origin()?);
+    let query = format!(
+        r#"query {{
+            getInvite(pubkey: "{pubkey}") {{
+                pubkey,
+                inviter,
+                state
+            }}
+        }}"#,
+        pubkey = pubkey
+    );
+
+    post_graphql_with_retries(&url, &query, server::post_graphql_get_json)
+        .and_then(|result| {
+            serde_json::from_str(&result).map_err(|e| QueryError.err(&e.to_string()))
+        })
+        .and_then(|response_root: ResponseRoot| {
+            response_root
+                .data
+                .getInvite
+                .ok_or_else(|| QueryError.err("Invite not found"))
+        })
+}
+
+fn parse_invite_key(key: &[u8]) -> Result<psibase::PublicKey, fracpack::Error> {
+    psibase::PublicKey::unpacked(key)
+}
+
+/// Parses every key before any action is added, so that a bad key
+/// leaves the transaction unchanged
+fn parse_invite_keys(keys: &[Vec<u8>]) -> Result<Vec<psibase::PublicKey>, CommonTypes::Error> {
+    keys.iter()
+        .enumerate()
+        .map(|(i, key)| {
+            parse_invite_key(key).map_err(|e| {
+                InvalidInviteKey.err(&format!("key {} of {}: {}", i + 1, keys.len(), e))
+            })
+        })
+        .collect()
+}
+
+fn add_del_invite(invite_key: psibase::PublicKey) -> Result<(), CommonTypes::Error> {
+    server::add_action_to_transaction(
+        "delInvite",
+        &invite_service::action_structs::delInvite {
+            inviteKey: invite_key,
+        }
+        .packed(),
+    )
+}
+
+/// Decodes an invite id and checks it against the invite on chain
+fn lookup_invite(id: &InviteId) -> Result<InviteParams, CommonTypes::Error> {
+    let decoded = decode_params(id)?;
+    let invite = query_invite(&decoded.pk)?;
+
+    if invite.inviter != decoded.inviter {
+        return Err(CorruptedInviteId.err(id));
+    }
+    check_unused(&invite)?;
+
+    Ok(decoded)
+}
+
+// Matches Invite::maxInviteLifetime. Invites last this long by default.
+const MAX_INVITE_LIFETIME_SECS: u32 = 60 * 60 * 24 * 7;
+
+/// Fails if the service would reject the requested invite lifetime
+fn check_expiry(expires_in_secs: u32) -> Result<(), CommonTypes::Error> {
+    if expires_in_secs == 0 || expires_in_secs > MAX_INVITE_LIFETIME_SECS {
+        return Err(InvalidArgument.err(&format!(
+            "expires_in_secs must be between 1 and {} (one week), got {}",
+            MAX_INVITE_LIFETIME_SECS, expires_in_secs
+        )));
+    }
+    Ok(())
 }
 
 struct Component;
@@ -63,11 +237,24 @@ impl Admin for Component {
 }
 
 impl Invitee for Component {
-    fn accept(_id: InviteId) -> Result<(), CommonTypes::Error> {
-        Err(NotYetImplemented.err("accept_with_existing_account"))
+    fn accept(id: InviteId) -> Result<(), CommonTypes::Error> {
         // The thinking for only a single accept method is that the invite ID is passed
         // to the account plugin. If the invite contains a valid invite private key
         // then the login page can also show a Create Account button.
+        let decoded = lookup_invite(&id)?;
+        let invite_key: psibase::PublicKey = decoded
+            .pk
+            .parse()
+            .map_err(|_| PubKeyParse.err(&decoded.pk))?;
+
+        server::add_action_to_transaction(
+            "accept",
+            &invite_service::action_structs::accept {
+                inviteKey: invite_key,
+            }
+            .packed(),
+        )?;
+        Ok(())
     }
 
     fn reject(_id: InviteId) -> Result<(), CommonTypes::Error> {
@@ -75,46 +262,7 @@ impl Invitee for Component {
     }
 
     fn decode_invite(id: InviteId) -> Result<Invite, CommonTypes::Error> {
-        let decoded: InviteParams = URL_SAFE
-            .decode(id.to_owned())
-            .map_err(|_| DecodeInviteError.err("Error decoding base64"))
-            .and_then(|enc| {
-                String::from_utf8(enc)
-                    .map_err(|_| DecodeInviteError.err("Error converting from UTF8"))
-            })
-            .and_then(|decoded| {
-                serde_json::from_str(&decoded).map_err(|_| {
-                    DecodeInviteError.err("Error deserializing JSON string into object")
-                })
-            })?;
-
-        let url = format!("{}/graphql", client::my_service_origin()?);
-        let pubkey = &decoded.pk;
-        let query = format!(
-            r#"query {{
-                getInvite(pubkey: "{pubkey}") {{
-                    pubkey,
-                    inviter
-                }}
-            }}"#,
-            pubkey = pubkey
-        );
-
-        let invite: GetInvite = server::post_graphql_get_json(&url, &query)
-            .map_err(|e| QueryError.err(&e.message))
-            .and_then(|result| {
-                serde_json::from_str(&result).map_err(|e| QueryError.err(&e.to_string()))
-            })
-            .and_then(|response_root: ResponseRoot| {
-                response_root
-                    .data
-                    .getInvite
-                    .ok_or_else(|| QueryError.err("Invite not found"))
-            })?;
-
-        if invite.inviter != decoded.inviter {
-            return Err(CorruptedInviteId.err(&id));
-        }
+        let decoded = lookup_invite(&id)?;
 
         Ok(Invite {
             inviter: decoded.inviter,
@@ -126,6 +274,14 @@ impl Invitee for Component {
 
 impl Inviter for Component {
     fn generate_invite(callback_subpath: String) -> Result<Url, CommonTypes::Error> {
+        Self::generate_invite_with_expiry(callback_subpath, MAX_INVITE_LIFETIME_SECS)
+    }
+
+    fn generate_invite_with_expiry(
+        callback_subpath: String,
+        expires_in_secs: u32,
+    ) -> Result<Url, CommonTypes::Error> {
+        check_expiry(expires_in_secs)?;
         let inviter = accounts::get_logged_in_user()?.ok_or_else(|| InviterLoggedIn.err(""))?;
 
         // TODO: I actually need a function here to generate both a private and
@@ -141,6 +297,7 @@ impl Inviter for Component {
             "createInvite",
             &invite_service::action_structs::createInvite {
                 inviteKey: pubkey.to_owned(),
+                secondsToLive: Some(expires_in_secs),
             }
             .packed(),
         )?;
@@ -165,9 +322,226 @@ impl Inviter for Component {
         Ok(format!("{}?{}", link_root, query_string))
     }
 
-    fn delete_invite(_invite_public_key: Vec<u8>) -> Result<(), CommonTypes::Error> {
-        Err(NotYetImplemented.err("delete_invite"))
+    fn delete_invite(invite_public_key: Vec<u8>) -> Result<(), CommonTypes::Error> {
+        let invite_key = parse_invite_key(&invite_public_key)
+            .map_err(|e| InvalidInviteKey.err(&e.to_string()))?;
+        add_del_invite(invite_key)
+    }
+
+    fn delete_invites(invite_public_keys: Vec<Vec<u8>>) -> Result<(), CommonTypes::Error> {
+        for invite_key in parse_invite_keys(&invite_public_keys)? {
+            add_del_invite(invite_key)?;
+        }
+        Ok(())
+    }
+
+    fn delete_expired(max_deleted: u32) -> Result<(), CommonTypes::Error> {
+        if max_deleted == 0 {
+            return Err(InvalidArgument.err("max_deleted must be greater than 0"));
+        }
+        server::add_action_to_transaction(
+            "delExpired",
+            &invite_service::action_structs::delExpired {
+                maxDeleted: max_deleted,
+            }
+            .packed(),
+        )
     }
 }
 
 bindings::export!(Component with_types_in bindings);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invite(state: u8) -> GetInvite {
+        serde_json::from_str(&format!(
+            r#"{{"pubkey":"PUB_K1_6MRyAjQq8ud7hVNYcfnVPJqcVpscN5So8BhtHuGYqET5BoDq63","inviter":"alice","state":{}}}"#,
+            state
+        ))
+        .unwrap()
+    }
+
+    fn packed_key() -> Vec<u8> {
+        let key: psibase::PublicKey = "PUB_K1_6MRyAjQq8ud7hVNYcfnVPJqcVpscN5So8BhtHuGYqET5BoDq63"
+            .parse()
+            .unwrap();
+        key.packed()
+    }
+
+    fn encoded(params: &[u8]) -> InviteId {
+        URL_SAFE.encode(params)
+    }
+
+    const PARAMS: &str = r#"{"inviter":"alice","app":"app","pk":"PUB_K1_6MRyAjQq8ud7hVNYcfnVPJqcVpscN5So8BhtHuGYqET5BoDq63","cb":"https://app/cb"}"#;
+
+    #[test]
+    fn decode_params_strips_bom() {
+        let decoded = decode_params(&encoded(&[UTF8_BOM, PARAMS.as_bytes()].concat())).unwrap();
+        assert_eq!(decoded.inviter, "alice");
+        assert_eq!(decoded.cb, "https://app/cb");
+    }
+
+    #[test]
+    fn decode_params_reports_invalid_utf8() {
+        let mut params = PARAMS.as_bytes().to_vec();
+        params[4] = 0xff;
+        let err = decode_params(&encoded(&params)).unwrap_err();
+        assert_eq!(err.code, DecodeInviteError as u32);
+        assert_eq!(
+            err.message,
+            "Failed to decode invite ID: Invalid UTF8 byte 0xff at position 4"
+        );
+    }
+
+    #[test]
+    fn decode_params_reports_truncated_utf8() {
+        let params = [PARAMS.as_bytes(), "é".as_bytes()].concat();
+        let err = decode_params(&encoded(&params[..params.len() - 1])).unwrap_err();
+        assert_eq!(
+            err.message,
+            format!(
+                "Failed to decode invite ID: Truncated UTF8 sequence at position {}",
+                PARAMS.len()
+            )
+        );
+    }
+
+    #[test]
+    fn decode_params_rejects_empty_payload() {
+        for id in [encoded(b""), encoded(UTF8_BOM)] {
+            let err = decode_params(&id).unwrap_err();
+            assert_eq!(
+                err.message,
+                "Failed to decode invite ID: Invite id is empty"
+            );
+        }
+    }
+
+    /// Fails the first `failures` posts, then answers with `response`
+    fn mock_server(
+        failures: u32,
+        response: &'static str,
+    ) -> (
+        std::rc::Rc<std::cell::Cell<u32>>,
+        impl FnMut(&str, &str) -> Result<String, CommonTypes::Error>,
+    ) {
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let counter = calls.clone();
+        let post = move |_: &str, _: &str| {
+            counter.set(counter.get() + 1);
+            if counter.get() <= failures {
+                // The host reports failed posts as common:plugin errors
+                Err(CommonTypes::Error {
+                    code: 0,
+                    producer: CommonTypes::PluginId {
+                        service: "common".to_string(),
+                        plugin: "plugin".to_string(),
+                    },
+                    message: format!("Query failed: attempt {} failed", counter.get()),
+                })
+            } else {
+                Ok(response.to_string())
+            }
+        };
+        (calls, post)
+    }
+
+    #[test]
+    fn graphql_retries_transient_failures() {
+        let (calls, post) = mock_server(QUERY_ATTEMPTS - 1, "{}");
+        let result = post_graphql_with_retries("https://invite/graphql", "query", post);
+        assert_eq!(result.unwrap(), "{}");
+        assert_eq!(calls.get(), QUERY_ATTEMPTS);
+    }
+
+    #[test]
+    fn graphql_reports_persistent_failures() {
+        let (calls, post) = mock_server(u32::MAX, "{}");
+        let err = post_graphql_with_retries("https://invite/graphql", "query", post).unwrap_err();
+        assert_eq!(calls.get(), QUERY_ATTEMPTS);
+        assert_eq!(err.code, QueryError as u32);
+        assert_eq!(
+            err.message,
+            format!(
+                "Failed to post graphql query: https://invite/graphql failed after {} attempts: Query failed: attempt {} failed",
+                QUERY_ATTEMPTS, QUERY_ATTEMPTS
+            )
+        );
+    }
+
+    #[test]
+    fn graphql_does_not_retry_rejected_queries() {
+        let (calls, post) = mock_server(0, r#"{"errors":[{"message":"Unknown field"}]}"#);
+        let err = post_graphql_with_retries("https://invite/graphql", "query", post).unwrap_err();
+        assert_eq!(calls.get(), 1);
+        assert_eq!(
+            err.message,
+            "Failed to post graphql query: https://invite/graphql rejected the query: Unknown field"
+        );
+
+        let (calls, post) = mock_server(0, "Not Found");
+        let err = post_graphql_with_retries("https://invite/graphql", "query", post).unwrap_err();
+        assert_eq!(calls.get(), 1);
+        assert_eq!(
+            err.message,
+            "Failed to post graphql query: https://invite/graphql responded with Not Found"
+        );
+    }
+
+    #[test]
+    fn parse_invite_keys_accepts_packed_keys() {
+        let keys = parse_invite_keys(&[packed_key(), packed_key()]).unwrap();
+        assert_eq!(keys.len(), 2);
+    }
+
+    #[test]
+    fn parse_invite_keys_reports_bad_key() {
+        let keys = vec![packed_key(), vec![1, 2, 3], packed_key()];
+        let err = parse_invite_keys(&keys).unwrap_err();
+        assert_eq!(err.code, InvalidInviteKey as u32);
+        assert!(err.message.starts_with("Invalid invite key: key 2 of 3: "));
+    }
+
+    #[test]
+    fn expiry_within_a_week_is_valid() {
+        assert!(check_expiry(1).is_ok());
+        assert!(check_expiry(60 * 60).is_ok());
+        assert!(check_expiry(MAX_INVITE_LIFETIME_SECS).is_ok());
+    }
+
+    #[test]
+    fn expiry_out_of_range_is_rejected() {
+        for secs in [0, MAX_INVITE_LIFETIME_SECS + 1, u32::MAX] {
+            let err = check_expiry(secs).unwrap_err();
+            assert_eq!(err.code, InvalidArgument as u32);
+            assert_eq!(
+                err.message,
+                format!(
+                    "Invalid argument: expires_in_secs must be between 1 and 604800 (one week), got {}",
+                    secs
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn unused_invite_is_accepted() {
+        assert!(check_unused(&invite(0)).is_ok());
+    }
+
+    #[test]
+    fn accepted_invite_is_already_used() {
+        let err = check_unused(&invite(STATE_ACCEPTED)).unwrap_err();
+        assert_eq!(err.code, InviteAlreadyUsed as u32);
+        assert_eq!(err.message, "Invite has already been accepted");
+    }
+
+    #[test]
+    fn rejected_invite_is_already_used() {
+        let err = check_unused(&invite(STATE_REJECTED)).unwrap_err();
+        assert_eq!(err.code, InviteAlreadyUsed as u32);
+        assert_eq!(err.message, "Invite has already been rejected");
+    }
+}
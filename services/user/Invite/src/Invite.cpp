@@ -59,8 +59,11 @@ void Invite::init()
    // setWhitelist({"fractally"_m})
 }
 
-void Invite::createInvite(PublicKey inviteKey)
+void Invite::createInvite(PublicKey inviteKey, optional<uint32_t> secondsToLive)
 {
+   auto lifetime = secondsToLive.value_or(maxInviteLifetime);
+   check(lifetime > 0 && lifetime <= maxInviteLifetime, invalidLifetime.data());
+
    auto inviteTable = Tables().open<InviteTable>();
    check(not inviteTable.get(inviteKey).has_value(), inviteAlreadyExists.data());
 
@@ -82,11 +85,10 @@ void Invite::createInvite(PublicKey inviteKey)
    }
 
    // Add invite
-   uint32_t     secondsInWeek{60 * 60 * 24 * 7};
    InviteRecord newInvite{
        .pubkey          = inviteKey,
        .inviter         = inviter,
-       .expiry          = to<Transact>().currentBlock().time.seconds + secondsInWeek,
+       .expiry          = to<Transact>().currentBlock().time.seconds + lifetime,
        .newAccountToken = true,
        .state           = InviteStates::pending,
    };
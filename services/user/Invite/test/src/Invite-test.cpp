@@ -80,23 +80,23 @@ SCENARIO("Creating an invite")
 
       THEN("Alice can create an invite")
       {
-         auto createInvite = a.createInvite(invPub);
+         auto createInvite = a.createInvite(invPub, std::nullopt);
          CHECK(createInvite.succeeded());
 
          AND_THEN("Alice cannot create another invite with the same key")
          {
-            auto createInvite2 = a.createInvite(invPub);
+            auto createInvite2 = a.createInvite(invPub, std::nullopt);
             CHECK(createInvite2.failed(inviteAlreadyExists));
          }
          AND_THEN("Alice can create another invite with a different key")
          {
-            auto createInvite2 = a.createInvite(thrdPub);
+            auto createInvite2 = a.createInvite(thrdPub, std::nullopt);
             CHECK(createInvite2.succeeded());
          }
       }
       THEN("Invited-sys cannot create an invite")
       {
-         auto createInvite = i.createInvite(userPub);
+         auto createInvite = i.createInvite(userPub, std::nullopt);
          CHECK(createInvite.failed(restrictedActions));
       }
    }
@@ -124,7 +124,7 @@ SCENARIO("Rejecting an invite")
 
       WHEN("Alice creates an invite")
       {
-         alice.with({{userPub, userPriv}}).to<Invite>().createInvite(invPub);
+         alice.with({{userPub, userPriv}}).to<Invite>().createInvite(invPub, std::nullopt);
 
          THEN("Invitee can reject an invite as invited-sys")
          {
@@ -182,7 +182,7 @@ SCENARIO("Deleting an invite")
 
       WHEN("Alice creates an invite")
       {
-         a.createInvite(invPub);
+         a.createInvite(invPub, std::nullopt);
 
          THEN("Only alice can delete the invite")
          {
@@ -227,14 +227,14 @@ SCENARIO("Expired invites")
 
       WHEN("Alice creates an invite and it isn't expired")
       {
-         a.createInvite(invPub);
+         a.createInvite(invPub, std::nullopt);
 
          int64_t oneWeek  = (60 * 60 * 24 * 7);
          int64_t passTime = oneWeek - 2;  // 2 seconds before expiration
          t.startBlock(passTime * 1000);
 
          // Add another invite that is not close to expiring
-         b.createInvite(thrdPub);
+         b.createInvite(thrdPub, std::nullopt);
 
          THEN("It can be rejected")
          {
@@ -285,6 +285,40 @@ SCENARIO("Expired invites")
    }
 }
 
+SCENARIO("Invites with a requested lifetime", "[invite]")
+{
+   GIVEN("Chain with initialized invite system")
+   {
+      DefaultTestChain t;
+      t.setAutoBlockStart(false);
+
+      auto alice = t.from(t.addAccount("alice"_a));
+      auto a     = alice.to<Invite>();
+
+      THEN("The lifetime must be between 1 second and a week")
+      {
+         CHECK(a.createInvite(invPub, 0).failed(invalidLifetime));
+         CHECK(a.createInvite(invPub, Invite::maxInviteLifetime + 1).failed(invalidLifetime));
+         CHECK(a.createInvite(invPub, Invite::maxInviteLifetime).succeeded());
+      }
+      WHEN("Alice creates an invite that lasts a minute")
+      {
+         a.createInvite(invPub, 60);
+         t.startBlock((60 - 2) * 1000);  // 2 seconds before expiration
+         CHECK_FALSE(a.isExpired(invPub).returnVal());
+
+         AND_WHEN("The minute is over")
+         {
+            t.startBlock(1000);
+            THEN("It is expired")
+            {
+               CHECK(a.isExpired(invPub).returnVal());
+            }
+         }
+      }
+   }
+}
+
 // - Setting a whitelist
 //    - A whitelist can be set by invite
 //    - All whitelisted accounts must exist
@@ -357,12 +391,12 @@ SCENARIO("Setting a whitelist", "[whiteblack]")
 
          THEN("A non-whitelisted account cannot create an invite")
          {
-            auto createInvite = b.createInvite(invPub);
+            auto createInvite = b.createInvite(invPub, std::nullopt);
             CHECK(createInvite.failed(onlyWhitelisted));
          }
          THEN("A whitelisted account can create an invite")
          {
-            auto createInvite = a.createInvite(invPub);
+            auto createInvite = a.createInvite(invPub, std::nullopt);
             CHECK(createInvite.succeeded());
          }
          THEN("The whitelist can be cleared")
@@ -370,7 +404,7 @@ SCENARIO("Setting a whitelist", "[whiteblack]")
             auto setWhitelist = invite.setWhitelist(ListType{});
             AND_THEN("A formerly non-whitelisted account can create an invite")
             {
-               auto createInvite = b.createInvite(invPub);
+               auto createInvite = b.createInvite(invPub, std::nullopt);
                CHECK(createInvite.succeeded());
             }
          }
@@ -443,12 +477,12 @@ SCENARIO("Setting a blacklist", "[whiteblack]")
 
          THEN("A nonblacklisted account can create an invite")
          {
-            auto createInvite = a.createInvite(invPub);
+            auto createInvite = a.createInvite(invPub, std::nullopt);
             CHECK(createInvite.succeeded());
          }
          THEN("A blacklisted account cannot create an invite")
          {
-            auto createInvite = b.createInvite(invPub);
+            auto createInvite = b.createInvite(invPub, std::nullopt);
             CHECK(createInvite.failed(noBlacklisted));
          }
          THEN("A blacklist can be cleared")
@@ -458,7 +492,7 @@ SCENARIO("Setting a blacklist", "[whiteblack]")
 
             AND_THEN("A formerly blacklisted account can create an invite")
             {
-               auto createInvite = b.createInvite(invPub);
+               auto createInvite = b.createInvite(invPub, std::nullopt);
                CHECK(createInvite.succeeded());
             }
          }
@@ -495,7 +529,7 @@ SCENARIO("Accepting an invite")
 
       WHEN("Alice creates an invite")
       {
-         alice.to<Invite>().createInvite(invPub);
+         alice.to<Invite>().createInvite(invPub, std::nullopt);
 
          THEN("The invite can be accepted by a normal user")
          {
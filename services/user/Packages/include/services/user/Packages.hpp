@@ -18,8 +18,9 @@ namespace UserService
       std::string                         description;
       std::vector<PackageRef>             depends;
       std::vector<psibase::AccountNumber> accounts;
+      std::vector<PackageRef>             provides;
    };
-   PSIO_REFLECT(PackageMeta, name, version, description, depends, accounts)
+   PSIO_REFLECT(PackageMeta, name, version, description, depends, accounts, provides)
 
    struct PackageKey
    {
@@ -36,10 +37,12 @@ namespace UserService
       std::vector<PackageRef>             depends;
       std::vector<psibase::AccountNumber> accounts;
       psibase::AccountNumber              owner;
+      // Absent from rows written before packages recorded it
+      std::optional<std::vector<PackageRef>> provides;
 
       auto byName() const { return PackageKey(name, owner); }
    };
-   PSIO_REFLECT(InstalledPackage, name, version, description, depends, accounts, owner)
+   PSIO_REFLECT(InstalledPackage, name, version, description, depends, accounts, owner, provides)
 
    struct PackageDataFile
    {
@@ -60,7 +60,8 @@ namespace UserService
                  .description = std::move(package.description),
                  .depends     = std::move(package.depends),
                  .accounts    = std::move(package.accounts),
-                 .owner       = sender});
+                 .owner       = sender,
+                 .provides    = std::move(package.provides)});
    }
 }  // namespace UserService
 